@@ -0,0 +1,358 @@
+//! Backing device selection
+//!
+//! Every urdma device forwards to one backing device, configured by entries like
+//!
+//! ```text
+//! # urdma device = backing device
+//! urdma0 = rxe0
+//! urdma1 = guid:0002:c9ff:fe00:0001
+//! urdma2 = netdev:eth1
+//! urdma3 = udp:192.168.1.10:4791
+//! ```
+//!
+//! read from the file named by `URDMA_CONFIG` (default `/etc/urdma.conf`) and from `URDMA_DEVICES`, which holds the
+//! same entries separated by `,` and takes precedence over the file. A device without an entry is backed by the rxe
+//! device with the same index, `urdma1` by `rxe1`. The RoCEv2 backend is backed by a local UDP address instead, given
+//! by a `udp:` entry; the port defaults to 4791.
+//!
+//! `URDMA_PROFILE` overrides attributes the devices report, and limits objects accordingly, to mimic other hardware.
+//! It holds `,` separated items, each a built-in profile like `connectx5`, a profile file or an attribute entry:
+//!
+//! ```text
+//! # profile file
+//! max_qp = 1024
+//! max_sge = 4
+//! max_mr_size = 0x100000000
+//! atomic_cap = none      # none, hca or glob
+//! active_mtu = 1024      # 256 to 4096
+//! active_speed = edr     # sdr, ddr, qdr, fdr10, fdr, edr, hdr or ndr
+//! active_width = 4x      # 1x, 2x, 4x, 8x or 12x
+//! link_layer = ethernet  # ethernet or infiniband
+//! ```
+//!
+//! Later items take precedence, so `URDMA_PROFILE=connectx5,max_qp=16` is a ConnectX-5 with 16 QPs.
+
+use core::ffi::CStr;
+use core::fmt;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use provider::Profile;
+
+const ENV_DEVICES: &str = "URDMA_DEVICES";
+const ENV_CONFIG: &str = "URDMA_CONFIG";
+const DEFAULT_CONFIG: &str = "/etc/urdma.conf";
+const ENV_PROFILE: &str = "URDMA_PROFILE";
+
+/// Built-in profiles, in the syntax of profile files
+const PROFILES: &[(&str, &str)] = &[(
+    // 100 GbE ConnectX-5 on a netdev with the default MTU
+    "connectx5",
+    "max_qp = 262144
+     max_sge = 30
+     max_mr_size = 0xffffffffffffffff
+     atomic_cap = hca
+     active_mtu = 1024
+     active_speed = edr
+     active_width = 4x
+     link_layer = ethernet",
+)];
+
+const URDMA_DEVICE_NAME: &str = "urdma";
+const RXE_DEVICE_NAME: &str = "rxe";
+
+/// RoCEv2 UDP destination port
+pub const ROCE_V2_PORT: u16 = 4791;
+
+/// How to find a backing device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selector {
+    /// exact device name, e.g. `rxe0`
+    Name(String),
+    /// node GUID, in host order
+    Guid(u64),
+    /// network interface the device is bound to
+    Netdev(String),
+    /// local UDP address, for the RoCEv2 backend
+    Udp(SocketAddr),
+}
+
+impl Selector {
+    fn parse(s: &str) -> Result<Self, String> {
+        if let Some(guid) = s.strip_prefix("guid:") {
+            let hex: String = guid.trim_start_matches("0x").chars().filter(|&c| c != ':').collect();
+            u64::from_str_radix(&hex, 16)
+                .map(Self::Guid)
+                .map_err(|err| format!("invalid GUID `{guid}`: {err}"))
+        } else if let Some(netdev) = s.strip_prefix("netdev:") {
+            Ok(Self::Netdev(netdev.to_owned()))
+        } else if let Some(addr) = s.strip_prefix("udp:") {
+            addr.parse()
+                .or_else(|_| addr.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, ROCE_V2_PORT)))
+                .map(Self::Udp)
+                .map_err(|err| format!("invalid UDP address `{addr}`: {err}"))
+        } else {
+            Ok(Self::Name(s.strip_prefix("name:").unwrap_or(s).to_owned()))
+        }
+    }
+
+    /// Backing device of `urdma_name` when nothing is configured.
+    fn default_for(urdma_name: &str) -> Option<Self> {
+        let index = urdma_name.strip_prefix(URDMA_DEVICE_NAME)?;
+        index.parse::<u32>().ok()?;
+        Some(Self::Name(format!("{RXE_DEVICE_NAME}{index}")))
+    }
+
+    /// Whether `device` is the one selected.
+    pub fn matches(&self, device: *mut ffi::ibv_device) -> bool {
+        let Some(dev) = (unsafe { device.as_ref() }) else {
+            return false;
+        };
+
+        match self {
+            Self::Name(name) => device_name(dev) == name,
+            Self::Guid(guid) => u64::from_be(unsafe { ffi::ibv_get_device_guid(device) }) == *guid,
+            Self::Netdev(netdev) => device_netdevs(dev).iter().any(|ndev| ndev == netdev),
+            Self::Udp(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "{name}"),
+            Self::Guid(guid) => write!(
+                f,
+                "guid:{:04x}:{:04x}:{:04x}:{:04x}",
+                guid >> 48,
+                (guid >> 32) & 0xffff,
+                (guid >> 16) & 0xffff,
+                guid & 0xffff
+            ),
+            Self::Netdev(netdev) => write!(f, "netdev:{netdev}"),
+            Self::Udp(addr) => write!(f, "udp:{addr}"),
+        }
+    }
+}
+
+/// Name of `device`.
+pub fn device_name(device: &ffi::ibv_device) -> &str {
+    unsafe { CStr::from_ptr(device.name.as_ptr()) }
+        .to_str()
+        .unwrap_or_default()
+}
+
+/// Network interfaces `device` is bound to, from `<ibdev_path>/ports/*/gid_attrs/ndevs/0`.
+fn device_netdevs(device: &ffi::ibv_device) -> Vec<String> {
+    let ibdev_path = unsafe { CStr::from_ptr(device.ibdev_path.as_ptr()) }.to_string_lossy();
+    let Ok(ports) = std::fs::read_dir(Path::new(ibdev_path.as_ref()).join("ports")) else {
+        return Vec::new();
+    };
+
+    ports
+        .flatten()
+        .filter_map(|port| std::fs::read_to_string(port.path().join("gid_attrs/ndevs/0")).ok())
+        .map(|netdev| netdev.trim().to_owned())
+        .collect()
+}
+
+/// Parse `urdma0 = rxe0` entries, `#` starts a comment.
+fn parse_entries<'a>(entries: impl Iterator<Item = &'a str>) -> Result<HashMap<String, Selector>, String> {
+    let mut map = HashMap::new();
+
+    for entry in entries {
+        let entry = entry.split_once('#').map_or(entry, |(entry, _comment)| entry).trim();
+        if entry.is_empty() {
+            continue;
+        }
+
+        let Some((urdma, backing)) = entry.split_once('=') else {
+            return Err(format!(
+                "invalid entry `{entry}`, expect `<urdma device> = <backing device>`"
+            ));
+        };
+        map.insert(urdma.trim().to_owned(), Selector::parse(backing.trim())?);
+    }
+
+    Ok(map)
+}
+
+/// Backing device configured for `urdma_name`.
+pub fn backing_device(urdma_name: &str) -> Result<Selector, String> {
+    let path = std::env::var(ENV_CONFIG).ok();
+    let file = match std::fs::read_to_string(path.as_deref().unwrap_or(DEFAULT_CONFIG)) {
+        Ok(file) => file,
+        // the default file is optional, an explicitly named one is not
+        Err(err) if path.is_some() => return Err(format!("failed to read {ENV_CONFIG}: {err}")),
+        Err(_) => String::new(),
+    };
+    let mut map = parse_entries(file.lines()).map_err(|err| format!("config file: {err}"))?;
+
+    if let Ok(devices) = std::env::var(ENV_DEVICES) {
+        map.extend(parse_entries(devices.split(',')).map_err(|err| format!("{ENV_DEVICES}: {err}"))?);
+    }
+
+    map.remove(urdma_name)
+        .or_else(|| Selector::default_for(urdma_name))
+        .ok_or_else(|| format!("no backing device configured, set {ENV_DEVICES}=\"{urdma_name}=<device>\""))
+}
+
+/// Profile configured by `URDMA_PROFILE`, overriding nothing if unset.
+pub fn profile() -> Result<Profile, String> {
+    match std::env::var(ENV_PROFILE) {
+        Ok(items) => parse_profile(&items).map_err(|err| format!("{ENV_PROFILE}: {err}")),
+        Err(_) => Ok(Profile::NONE),
+    }
+}
+
+/// Parse `,` separated profile names, files and entries.
+fn parse_profile(items: &str) -> Result<Profile, String> {
+    let mut profile = Profile::NONE;
+
+    for item in items.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        if item.contains('=') {
+            apply_profile_entries(&mut profile, [item].into_iter())?;
+        } else if let Some((_, entries)) = PROFILES.iter().find(|(name, _)| *name == item) {
+            apply_profile_entries(&mut profile, entries.lines())?;
+        } else {
+            let file = std::fs::read_to_string(item).map_err(|err| format!("no profile `{item}`: {err}"))?;
+            apply_profile_entries(&mut profile, file.lines()).map_err(|err| format!("{item}: {err}"))?;
+        }
+    }
+
+    Ok(profile)
+}
+
+/// Apply `max_qp = 1024` entries to `profile`, `#` starts a comment.
+fn apply_profile_entries<'a>(profile: &mut Profile, entries: impl Iterator<Item = &'a str>) -> Result<(), String> {
+    for entry in entries {
+        let entry = entry.split_once('#').map_or(entry, |(entry, _comment)| entry).trim();
+        if entry.is_empty() {
+            continue;
+        }
+
+        let Some((key, value)) = entry.split_once('=') else {
+            return Err(format!("invalid entry `{entry}`, expect `<attribute> = <value>`"));
+        };
+        let (key, value) = (key.trim(), value.trim());
+        let invalid = || format!("invalid {key} `{value}`");
+
+        match key {
+            "max_qp" => profile.max_qp = Some(parse_int(value).ok_or_else(invalid)?),
+            "max_sge" => profile.max_sge = Some(parse_int(value).ok_or_else(invalid)?),
+            "max_mr_size" => profile.max_mr_size = Some(parse_int(value).ok_or_else(invalid)?),
+            "atomic_cap" => {
+                profile.atomic_cap = Some(match value {
+                    "none" => ffi::IBV_ATOMIC_NONE,
+                    "hca" => ffi::IBV_ATOMIC_HCA,
+                    "glob" => ffi::IBV_ATOMIC_GLOB,
+                    _ => return Err(invalid()),
+                });
+            }
+            "active_mtu" => {
+                profile.active_mtu = Some(match value {
+                    "256" => ffi::IBV_MTU_256,
+                    "512" => ffi::IBV_MTU_512,
+                    "1024" => ffi::IBV_MTU_1024,
+                    "2048" => ffi::IBV_MTU_2048,
+                    "4096" => ffi::IBV_MTU_4096,
+                    _ => return Err(invalid()),
+                });
+            }
+            "active_speed" => {
+                // IBV_SPEED_* of verbs.h
+                let speeds = ["sdr", "ddr", "qdr", "fdr10", "fdr", "edr", "hdr", "ndr"];
+                let index = speeds.iter().position(|&speed| speed == value).ok_or_else(invalid)?;
+                profile.active_speed = Some(1 << index);
+            }
+            "active_width" => {
+                // IBV_WIDTH_* of verbs.h
+                let widths = ["1x", "4x", "8x", "12x", "2x"];
+                let index = widths.iter().position(|&width| width == value).ok_or_else(invalid)?;
+                profile.active_width = Some(1 << index);
+            }
+            "link_layer" => {
+                profile.link_layer = Some(match value {
+                    "infiniband" => ffi::IBV_LINK_LAYER_INFINIBAND as u8,
+                    "ethernet" => ffi::IBV_LINK_LAYER_ETHERNET as u8,
+                    _ => return Err(invalid()),
+                });
+            }
+            _ => return Err(format!("unknown attribute `{key}`")),
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse a decimal or `0x` prefixed hexadecimal integer.
+fn parse_int<T: TryFrom<u64>>(value: &str) -> Option<T> {
+    let value = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => value.parse().ok()?,
+    };
+    T::try_from(value).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_selectors() {
+        let map = parse_entries(
+            [
+                "# comment",
+                "urdma0 = rxe1",
+                "",
+                "urdma1=guid:0002:c9ff:fe00:0001 # trailing",
+                "urdma2 = netdev:eth1",
+                "urdma3 = udp:10.0.0.1",
+                "urdma4 = udp:[fe80::1]:4792",
+            ]
+            .into_iter(),
+        )
+        .unwrap();
+
+        assert_eq!(map["urdma0"], Selector::Name("rxe1".to_owned()));
+        assert_eq!(map["urdma1"], Selector::Guid(0x0002_c9ff_fe00_0001));
+        assert_eq!(map["urdma2"], Selector::Netdev("eth1".to_owned()));
+        assert_eq!(map["urdma3"], Selector::Udp("10.0.0.1:4791".parse().unwrap()));
+        assert_eq!(map["urdma4"], Selector::Udp("[fe80::1]:4792".parse().unwrap()));
+        assert_eq!(map["urdma1"].to_string(), "guid:0002:c9ff:fe00:0001");
+    }
+
+    #[test]
+    fn reject_invalid_entries() {
+        assert!(parse_entries(["urdma0"].into_iter()).is_err());
+        assert!(parse_entries(["urdma0 = guid:xyz"].into_iter()).is_err());
+        assert!(parse_entries(["urdma0 = udp:eth0"].into_iter()).is_err());
+    }
+
+    #[test]
+    fn default_to_same_index() {
+        assert_eq!(Selector::default_for("urdma1"), Some(Selector::Name("rxe1".to_owned())));
+        assert_eq!(Selector::default_for("uverbs1"), None);
+    }
+
+    #[test]
+    fn parse_profiles() {
+        let profile = parse_profile("connectx5, max_qp = 16,atomic_cap=none").unwrap();
+        assert_eq!(profile.max_qp, Some(16));
+        assert_eq!(profile.max_sge, Some(30));
+        assert_eq!(profile.max_mr_size, Some(u64::MAX));
+        assert_eq!(profile.atomic_cap, Some(ffi::IBV_ATOMIC_NONE));
+        assert_eq!(profile.active_mtu, Some(ffi::IBV_MTU_1024));
+        // IBV_SPEED_EDR, IBV_WIDTH_4X
+        assert_eq!((profile.active_speed, profile.active_width), (Some(32), Some(2)));
+
+        assert_eq!(parse_profile("").unwrap(), Profile::NONE);
+        assert!(parse_profile("max_qp = -1").is_err());
+        assert!(parse_profile("max_qp = 0x100000000").is_err());
+        assert!(parse_profile("active_mtu = 9000").is_err());
+        assert!(parse_profile("max_cq = 1").is_err());
+        assert!(parse_profile("/nonexistent/profile").is_err());
+    }
+}
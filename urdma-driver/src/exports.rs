@@ -1 +1,14 @@
+// the features are additive, with several enabled loopback wins over shm and shm over roce
+#[cfg(not(any(feature = "loopback", feature = "shm", feature = "roce")))]
+provider::export_provider!(crate::rxe::Rxe);
 
+// software backends for machines without rxe, e.g. CI
+#[cfg(feature = "loopback")]
+provider::export_provider!(crate::loopback::Loopback);
+
+#[cfg(all(feature = "shm", not(feature = "loopback")))]
+provider::export_provider!(crate::shm::Shm);
+
+// RoCEv2 in userspace, for hosts where no rxe device can be created
+#[cfg(all(feature = "roce", not(any(feature = "loopback", feature = "shm"))))]
+provider::export_provider!(crate::roce::Roce);
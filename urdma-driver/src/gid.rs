@@ -0,0 +1,212 @@
+//! GID tables of the software backends
+//!
+//! Like the kernel does for a RoCEv2 port, every IPv4 and IPv6 address of a network interface becomes a GID, IPv4
+//! addresses mapped into IPv6. A device configured with `netdev:` (see [`crate::config`]) takes the addresses of that
+//! interface, any other one those of all interfaces.
+//!
+//! libibverbs answers `ibv_query_gid` and friends from the kernel, never asking the provider, so the kernel table is
+//! the one applications see and is used as it is whenever it has entries. If the kernel module binds the device to a
+//! network interface (its `netdev` parameter) the kernel core maintains a RoCEv2 table, otherwise the module fills an
+//! IB table with the addresses of all interfaces, the way this module builds its own. The table built here is only
+//! used if the kernel has none, e.g. when the kernel module is an older one.
+
+use core::ffi::CStr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::config::{self, Selector};
+
+/// GID of the address `ip`.
+pub fn of(ip: IpAddr) -> ffi::ibv_gid {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    ffi::ibv_gid { raw: ip.octets() }
+}
+
+/// Address held by `gid`.
+pub fn address(gid: &ffi::ibv_gid) -> IpAddr {
+    // Safety: every member of the union is plain bytes.
+    let ip = Ipv6Addr::from(unsafe { gid.raw });
+    ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4)
+}
+
+/// Table of RoCEv2 GIDs on port 1 for `addresses` of interfaces, given with their index.
+pub fn table(addresses: impl IntoIterator<Item = (IpAddr, u32)>) -> Vec<ffi::ibv_gid_entry> {
+    let mut entries: Vec<ffi::ibv_gid_entry> = Vec::new();
+
+    for (ip, ifindex) in addresses {
+        let gid = of(ip);
+        // Safety: every member of the union is plain bytes.
+        if entries.iter().any(|entry| unsafe { entry.gid.raw == gid.raw }) {
+            continue;
+        }
+        entries.push(ffi::ibv_gid_entry {
+            gid,
+            gid_index: entries.len() as u32,
+            port_num: 1,
+            gid_type: ffi::IBV_GID_TYPE_ROCE_V2,
+            ndev_ifindex: ifindex,
+        });
+    }
+
+    entries
+}
+
+/// GID table of the software device `urdma_name`.
+pub fn local(urdma_name: &str) -> Vec<ffi::ibv_gid_entry> {
+    let kernel = kernel(urdma_name);
+    if !kernel.is_empty() {
+        return kernel;
+    }
+
+    let netdev = match config::backing_device(urdma_name) {
+        Ok(Selector::Netdev(netdev)) => Some(netdev),
+        _ => None,
+    };
+
+    let addresses = interface_addresses()
+        .into_iter()
+        .filter(|(name, _)| netdev.as_ref().is_none_or(|netdev| netdev == name))
+        .map(|(name, ip)| (ip, ifindex(&name)));
+    let entries = table(addresses);
+    if entries.is_empty() {
+        log::warn!("{urdma_name}: no addresses, the GID table is empty");
+    }
+    entries
+}
+
+/// GID table of the software device `urdma_name` bound to the address `ip`.
+pub fn bound(urdma_name: &str, ip: IpAddr) -> Vec<ffi::ibv_gid_entry> {
+    let kernel: Vec<_> = kernel(urdma_name)
+        .into_iter()
+        .filter(|entry| address(&entry.gid) == ip)
+        .collect();
+    if !kernel.is_empty() {
+        return kernel;
+    }
+
+    let name = interface_addresses()
+        .into_iter()
+        .find(|(_, addr)| *addr == ip)
+        .map(|(name, _)| name);
+    table([(ip, name.map_or(0, |name| ifindex(&name)))])
+}
+
+/// GIDs the kernel holds for port 1 of `urdma_name`, read from sysfs as libibverbs does. Only the RoCEv2 ones of a
+/// RoCE port, which has RoCEv1 duplicates.
+fn kernel(urdma_name: &str) -> Vec<ffi::ibv_gid_entry> {
+    let port = format!("/sys/class/infiniband/{urdma_name}/ports/1");
+    let read = |path: String| std::fs::read_to_string(path).ok();
+    let roce = read(format!("{port}/link_layer")).is_some_and(|link_layer| link_layer.trim() == "Ethernet");
+
+    let Some(gid_tbl_len) = std::fs::read_dir(format!("{port}/gids")).ok().map(Iterator::count) else {
+        return Vec::new();
+    };
+    (0..gid_tbl_len as u32)
+        .filter_map(|index| {
+            // unused entries fail to read their type
+            let gid_type = match read(format!("{port}/gid_attrs/types/{index}"))?.trim() {
+                "RoCE v2" => ffi::IBV_GID_TYPE_ROCE_V2,
+                "IB/RoCE v1" if !roce => ffi::IBV_GID_TYPE_IB,
+                _ => return None,
+            };
+            let gid = parse(&read(format!("{port}/gids/{index}"))?)?;
+            let ndev = read(format!("{port}/gid_attrs/ndevs/{index}")).unwrap_or_default();
+            Some(ffi::ibv_gid_entry {
+                gid,
+                gid_index: index,
+                port_num: 1,
+                gid_type,
+                ndev_ifindex: ifindex(ndev.trim()),
+            })
+        })
+        .collect()
+}
+
+/// Parse a GID in the sysfs format, 8 groups of 4 hex digits.
+fn parse(gid: &str) -> Option<ffi::ibv_gid> {
+    let mut raw = [0_u8; 16];
+    let mut groups = gid.trim().split(':');
+    for pair in raw.chunks_exact_mut(2) {
+        let group = groups.next().filter(|group| group.len() == 4)?;
+        pair.copy_from_slice(&u16::from_str_radix(group, 16).ok()?.to_be_bytes());
+    }
+    if groups.next().is_some() || raw == [0; 16] {
+        return None;
+    }
+    Some(ffi::ibv_gid { raw })
+}
+
+fn ifindex(name: &str) -> u32 {
+    let name = std::ffi::CString::new(name).unwrap_or_default();
+    unsafe { libc::if_nametoindex(name.as_ptr()) }
+}
+
+/// IPv4 and IPv6 addresses of the network interfaces, with the interface name.
+fn interface_addresses() -> Vec<(String, IpAddr)> {
+    let mut ifaddrs = core::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        log::warn!(
+            "Failed to list interface addresses: {}",
+            std::io::Error::last_os_error()
+        );
+        return Vec::new();
+    }
+
+    let mut addresses = Vec::new();
+    let mut cur = ifaddrs;
+    // Safety: getifaddrs returns a null terminated list, valid until it is freed.
+    while let Some(ifa) = unsafe { cur.as_ref() } {
+        cur = ifa.ifa_next;
+
+        let Some(addr) = (unsafe { ifa.ifa_addr.as_ref() }) else {
+            continue;
+        };
+        let ip = match i32::from(addr.sa_family) {
+            libc::AF_INET => {
+                let addr = unsafe { &*ifa.ifa_addr.cast::<libc::sockaddr_in>() };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*ifa.ifa_addr.cast::<libc::sockaddr_in6>() };
+                IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr))
+            }
+            _ => continue,
+        };
+        let name = unsafe { CStr::from_ptr(ifa.ifa_name) }.to_string_lossy().into_owned();
+        addresses.push((name, ip));
+    }
+
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_become_gids() {
+        let v4 = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+        let v6 = IpAddr::V6("fe80::1".parse().unwrap());
+
+        let table = table([(v4, 2), (v6, 2), (v4, 3)]);
+        assert_eq!(table.len(), 2);
+        assert_eq!(unsafe { table[0].gid.raw }[10..], [0xff, 0xff, 192, 168, 1, 10]);
+        assert_eq!((table[1].gid_index, table[1].ndev_ifindex), (1, 2));
+        assert_eq!(table[1].gid_type, ffi::IBV_GID_TYPE_ROCE_V2);
+        assert_eq!(address(&table[0].gid), v4);
+        assert_eq!(address(&table[1].gid), v6);
+    }
+
+    #[test]
+    fn sysfs_gids() {
+        let gid = parse("0000:0000:0000:0000:0000:ffff:c0a8:010a\n").unwrap();
+        assert_eq!(address(&gid), IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10)));
+        // unused entries are all zero
+        assert!(parse("0000:0000:0000:0000:0000:0000:0000:0000").is_none());
+        assert!(parse("fe80:0000:0000:0000").is_none());
+        assert!(parse("fe80::1").is_none());
+    }
+}
@@ -1,3 +1,19 @@
+// the rxe backend is unused when a software one is exported instead
+#[cfg_attr(any(feature = "loopback", feature = "shm", feature = "roce"), allow(dead_code))]
+mod config;
+mod exports;
+mod gid;
+mod loopback;
+#[cfg_attr(any(feature = "loopback", feature = "shm", feature = "roce"), allow(dead_code))]
 mod ops;
+mod roce;
+#[cfg_attr(any(feature = "loopback", feature = "shm", feature = "roce"), allow(dead_code))]
 mod rxe;
+mod shm;
+#[cfg(test)]
+mod testing;
 mod urdma;
+
+pub use loopback::Loopback;
+pub use roce::Roce;
+pub use shm::Shm;
@@ -0,0 +1,1460 @@
+//! Pure software backend
+//!
+//! Every object lives in process memory and work requests are executed by memcpy while they are posted, so QPs of one
+//! process can talk to each other without any kernel RDMA device. QP numbers and memory keys are process wide, a QP on
+//! `urdma0` may connect to a QP on `urdma1`.
+//!
+//! A SEND that finds no posted receive waits on the destination QP until one is posted, like an RC requester retrying
+//! on RNR NAK forever; its send completion is generated once the message is delivered. UD datagrams are dropped
+//! instead, and complete as soon as they are sent. Ports are Ethernet, so every datagram arrives with a GRH.
+//!
+//! A QP on a shared receive queue reports `LastWqeReached` when it enters the error state, an SRQ reports
+//! `LimitReached` once fewer receive requests than its armed limit are left.
+
+mod ops;
+
+use std::collections::{HashMap, VecDeque};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, PoisonError, RwLock, Weak};
+
+use ffi::{ibv_access_flags, ibv_qp_state, ibv_wc_opcode, ibv_wc_status};
+use provider::{
+    AsyncEvent, AsyncEvents, Completion, CompletionQueue, CqEvent, CqNotifier, MemoryRegion, Payload, QpEvent,
+    QueuePair, SendOp, SendWr, SharedReceiveQueue, SrqEvent, UdDest, VerbsError,
+};
+
+/// Objects shared by all loopback devices of the process
+struct Fabric {
+    /// source of QP, CQ and SRQ numbers, memory keys and PD ids
+    next_id: AtomicU32,
+    qps: RwLock<HashMap<u32, Weak<Qp>>>,
+    mrs: RwLock<HashMap<u32, MrEntry>>,
+}
+
+static FABRIC: LazyLock<Fabric> = LazyLock::new(|| Fabric {
+    // QP 0 and 1 are reserved for SMI and GSI
+    next_id: AtomicU32::new(2),
+    qps: RwLock::default(),
+    mrs: RwLock::default(),
+});
+
+impl Fabric {
+    fn alloc_id(&self) -> u32 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn qp(&self, qp_num: u32) -> Option<Arc<Qp>> {
+        self.qps
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&qp_num)
+            .and_then(Weak::upgrade)
+    }
+
+    /// Resolve `len` bytes at I/O virtual address `addr` through `key` and run `f` on them.
+    ///
+    /// The region must belong to `pd` and allow `access`, an empty access only requires the key to be valid. It cannot
+    /// be deregistered before `f` returns, so `f` must not resolve another key or register memory.
+    fn resolve<R>(
+        &self,
+        key: u32,
+        pd: u32,
+        addr: u64,
+        len: usize,
+        access: ibv_access_flags,
+        f: impl FnOnce(*mut u8) -> R,
+    ) -> Option<R> {
+        let mrs = self.mrs.read().unwrap_or_else(PoisonError::into_inner);
+        let mr = mrs
+            .get(&key)
+            .filter(|mr| mr.pd == pd && (mr.access & access) == access)?;
+
+        let offset = usize::try_from(addr.checked_sub(mr.iova)?).ok()?;
+        if offset.checked_add(len)? > mr.length {
+            return None;
+        }
+
+        let ptr = mr.addr.wrapping_add(offset);
+        // on-demand regions are resolved lazily, their memory may be gone
+        if (mr.access & ibv_access_flags::IBV_ACCESS_ON_DEMAND).0 != 0 && !mapped(ptr, len) {
+            return None;
+        }
+        Some(f(ptr as *mut u8))
+    }
+
+    /// Copy the memory `payload` describes into one buffer.
+    fn gather(&self, pd: u32, payload: &Payload) -> Result<Vec<u8>, ibv_wc_status::Type> {
+        let sges = match payload {
+            Payload::Sges(sges) => sges,
+            Payload::Inline(data) => return Ok(data.clone()),
+        };
+        let mut buf = Vec::new();
+
+        for sge in sges {
+            let len = sge.length as usize;
+            self.resolve(sge.lkey, pd, sge.addr, len, ibv_access_flags(0), |src| {
+                // Safety: the range lies in a registered region.
+                buf.extend_from_slice(unsafe { core::slice::from_raw_parts(src, len) });
+            })
+            .ok_or(ibv_wc_status::IBV_WC_LOC_PROT_ERR)?;
+        }
+
+        Ok(buf)
+    }
+
+    /// Copy `data` into the memory described by `sges`.
+    fn scatter(&self, pd: u32, sges: &[ffi::ibv_sge], mut data: &[u8]) -> Result<(), ibv_wc_status::Type> {
+        let capacity: usize = sges.iter().map(|sge| sge.length as usize).sum();
+        if capacity < data.len() {
+            return Err(ibv_wc_status::IBV_WC_LOC_LEN_ERR);
+        }
+
+        for sge in sges {
+            if data.is_empty() {
+                break;
+            }
+            let len = data.len().min(sge.length as usize);
+            self.resolve(
+                sge.lkey,
+                pd,
+                sge.addr,
+                len,
+                ibv_access_flags::IBV_ACCESS_LOCAL_WRITE,
+                |dst| {
+                    // Safety: the range lies in a writable registered region.
+                    unsafe { core::ptr::copy(data.as_ptr(), dst, len) };
+                },
+            )
+            .ok_or(ibv_wc_status::IBV_WC_LOC_PROT_ERR)?;
+            data = &data[len..];
+        }
+
+        Ok(())
+    }
+}
+
+/// Registered memory
+struct MrEntry {
+    pd: u32,
+    addr: usize,
+    length: usize,
+    /// address peers and SGEs use for the first byte
+    iova: u64,
+    access: ibv_access_flags,
+}
+
+/// Loopback device
+pub struct Loopback {
+    name: String,
+    events: AsyncEvents,
+    /// GIDs of port 1, from the local addresses
+    gids: Vec<ffi::ibv_gid_entry>,
+}
+
+/// Loopback protection domain
+pub struct LoopbackPd {
+    id: u32,
+}
+
+/// Loopback completion queue
+pub struct LoopbackCq(Arc<Cq>);
+
+/// Loopback queue pair
+pub struct LoopbackQp(Arc<Qp>);
+
+/// Loopback shared receive queue
+pub struct LoopbackSrq(Arc<Srq>);
+
+/// Loopback address handle
+pub struct LoopbackAh {
+    pd: u32,
+    attr: ffi::ibv_ah_attr,
+    /// GID at the source GID index of `attr`
+    sgid: ffi::ibv_gid,
+}
+
+/// Loopback memory region, the same key serves as lkey and rkey
+pub struct LoopbackMr {
+    key: u32,
+    /// mapping of a dma-buf region, unmapped once deregistered
+    dmabuf: Option<DmaBuf>,
+}
+
+impl QueuePair for LoopbackQp {
+    fn qp_num(&self) -> u32 {
+        self.0.qp_num
+    }
+}
+
+impl CompletionQueue for LoopbackCq {
+    fn cq_num(&self) -> u32 {
+        self.0.cq_num
+    }
+}
+
+impl SharedReceiveQueue for LoopbackSrq {
+    fn srq_num(&self) -> u32 {
+        self.0.srq_num
+    }
+}
+
+impl MemoryRegion for LoopbackMr {
+    fn lkey(&self) -> u32 {
+        self.key
+    }
+
+    fn rkey(&self) -> u32 {
+        self.key
+    }
+}
+
+struct Cq {
+    cq_num: u32,
+    /// completions the CQ holds at most
+    cqe: usize,
+    /// every completion is stamped, for CQs polled through the extended API
+    entries: Mutex<VecDeque<Completion>>,
+    /// set if the CQ was created on a completion channel
+    notifier: Option<CqNotifier>,
+    /// async events of the device the CQ was created on
+    events: AsyncEvents,
+}
+
+impl Cq {
+    /// Add `wc`, `solicited` if it completes a receive of a solicited message.
+    ///
+    /// Like rxe, a full CQ drops the completion and reports a CQ error.
+    fn push(&self, wc: ffi::ibv_wc, solicited: bool) {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() >= self.cqe {
+            drop(entries);
+            log::debug!(
+                "CQ {:#x}: overrun, completion of {:#x} dropped",
+                self.cq_num,
+                wc.wr_id()
+            );
+            self.events.post(AsyncEvent::Cq(self.cq_num, CqEvent::Error));
+            return;
+        }
+        entries.push_back(Completion::stamped(wc));
+        drop(entries);
+
+        if let Some(notifier) = &self.notifier {
+            notifier.completed(solicited || !wc.is_valid());
+        }
+    }
+}
+
+struct Qp {
+    qp_num: u32,
+    pd: u32,
+    qp_type: ffi::ibv_qp_type::Type,
+    sq_sig_all: bool,
+    cap: ffi::ibv_qp_cap,
+    send_cq: Arc<Cq>,
+    recv_cq: Arc<Cq>,
+    /// takes the place of `QpState::recv` if set
+    srq: Option<Arc<Srq>>,
+    /// async events of the device the QP was created on
+    events: AsyncEvents,
+    state: Mutex<QpState>,
+}
+
+struct QpState {
+    state: ibv_qp_state::Type,
+    dest_qp_num: u32,
+    access: ibv_access_flags,
+    /// Q_Key datagrams must carry to be received, and the one sent with if a request asks for it
+    qkey: u32,
+    /// posted receive requests
+    recv: VecDeque<RecvWqe>,
+    /// incoming messages waiting for a receive request
+    inbound: VecDeque<Inbound>,
+}
+
+/// Receive requests shared by several QPs
+///
+/// Locked after the state of a QP, never before.
+struct Srq {
+    srq_num: u32,
+    pd: u32,
+    /// async events of the device the SRQ was created on
+    events: AsyncEvents,
+    state: Mutex<SrqState>,
+}
+
+struct SrqState {
+    /// `max_wr` and `max_sge` as allocated, `srq_limit` as last armed
+    attr: ffi::ibv_srq_attr,
+    /// posted receive requests
+    recv: VecDeque<RecvWqe>,
+    /// QPs taking their receive requests from the queue
+    qps: Vec<Weak<Qp>>,
+}
+
+struct RecvWqe {
+    wr_id: u64,
+    sges: Vec<ffi::ibv_sge>,
+}
+
+/// Message consuming a receive request
+struct Inbound {
+    src_qp: u32,
+    opcode: ibv_wc_opcode::Type,
+    payload: Vec<u8>,
+    byte_len: u32,
+    imm_data: Option<u32>,
+    /// the sender asked for a solicited event
+    solicited: bool,
+    /// `payload` starts with a GRH
+    grh: bool,
+    /// `None` for datagrams, whose requester completed once they were sent
+    sender: Option<Sender>,
+}
+
+/// Requester side of a work request whose outcome is known only once the responder handled it
+struct Sender {
+    qp: Arc<Qp>,
+    wr_id: u64,
+    opcode: ibv_wc_opcode::Type,
+    byte_len: u32,
+    signaled: bool,
+}
+
+impl Sender {
+    /// Report the outcome to the requester, errors are always completed and move its QP to the error state.
+    ///
+    /// Must not be called with any QP state locked, the requester may be the responder itself.
+    fn complete(self, status: ibv_wc_status::Type) {
+        let success = status == ibv_wc_status::IBV_WC_SUCCESS;
+        if success && !self.signaled {
+            return;
+        }
+
+        let mut wc = ffi::ibv_wc::new(self.wr_id, status, self.opcode, self.byte_len);
+        wc.qp_num = self.qp.qp_num;
+        self.qp.send_cq.push(wc, false);
+
+        if !success {
+            self.qp.set_error();
+        }
+    }
+}
+
+/// Requester and the status to complete it with
+type Outcome = (Sender, ibv_wc_status::Type);
+
+/// Complete requesters, once no QP state is locked.
+fn report(outcomes: Vec<Outcome>) {
+    for (sender, status) in outcomes {
+        sender.complete(status);
+    }
+}
+
+impl Qp {
+    /// Move to the error state, flushing outstanding receive requests and failing queued messages.
+    fn set_error(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let outcomes = self.enter_error(&mut state);
+        drop(state);
+        report(outcomes);
+    }
+
+    /// Move to the error state with `state` locked.
+    fn enter_error(&self, state: &mut QpState) -> Vec<Outcome> {
+        // receive requests are flushed to the QP's own CQ, an SRQ keeps its requests for the other QPs
+        if state.state != ibv_qp_state::IBV_QPS_ERR && self.srq.is_some() {
+            self.events.post(AsyncEvent::Qp(self.qp_num, QpEvent::LastWqeReached));
+        }
+        state.state = ibv_qp_state::IBV_QPS_ERR;
+        for wqe in state.recv.drain(..) {
+            let mut wc = ffi::ibv_wc::new(
+                wqe.wr_id,
+                ibv_wc_status::IBV_WC_WR_FLUSH_ERR,
+                ibv_wc_opcode::IBV_WC_RECV,
+                0,
+            );
+            wc.qp_num = self.qp_num;
+            self.recv_cq.push(wc, false);
+        }
+        state
+            .inbound
+            .drain(..)
+            .filter_map(|msg| msg.sender.map(|sender| (sender, ibv_wc_status::IBV_WC_WR_FLUSH_ERR)))
+            .collect()
+    }
+
+    /// Hand `msg` to the next receive request, or queue it until one is posted.
+    fn deliver(&self, msg: Inbound) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if !matches!(state.state, ibv_qp_state::IBV_QPS_RTR | ibv_qp_state::IBV_QPS_RTS) {
+            drop(state);
+            // nobody answers, the requester gives up after its retries
+            if let Some(sender) = msg.sender {
+                sender.complete(ibv_wc_status::IBV_WC_RETRY_EXC_ERR);
+            }
+            return;
+        }
+
+        // messages queued earlier go first, they are waiting for a shared receive request being posted
+        let wqe = if state.inbound.is_empty() {
+            self.next_recv(&mut state)
+        } else {
+            None
+        };
+        let outcomes = match wqe {
+            Some(wqe) => self.complete_recv(&mut state, wqe, msg),
+            None => {
+                state.inbound.push_back(msg);
+                Vec::new()
+            }
+        };
+        drop(state);
+        report(outcomes);
+    }
+
+    /// Hand datagram `msg`, sent with `qkey`, to the next receive request, or drop it.
+    fn deliver_datagram(&self, qkey: u32, msg: Inbound) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if !matches!(state.state, ibv_qp_state::IBV_QPS_RTR | ibv_qp_state::IBV_QPS_RTS) || state.qkey != qkey {
+            return;
+        }
+
+        let Some(wqe) = self.next_recv(&mut state) else {
+            log::debug!("QP {:#x}: datagram dropped, no receive request posted", self.qp_num);
+            return;
+        };
+        let outcomes = self.complete_recv(&mut state, wqe, msg);
+        drop(state);
+        report(outcomes);
+    }
+
+    /// Take the next receive request, from the shared receive queue if the QP has one.
+    fn next_recv(&self, state: &mut QpState) -> Option<RecvWqe> {
+        match &self.srq {
+            Some(srq) => srq.take(),
+            None => state.recv.pop_front(),
+        }
+    }
+
+    /// Hand queued messages to receive requests posted to the shared receive queue.
+    fn drain_inbound(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut outcomes = Vec::new();
+        while matches!(state.state, ibv_qp_state::IBV_QPS_RTR | ibv_qp_state::IBV_QPS_RTS) && !state.inbound.is_empty()
+        {
+            let Some(wqe) = self.next_recv(&mut state) else {
+                break;
+            };
+            let msg = state.inbound.pop_front().unwrap();
+            outcomes.extend(self.complete_recv(&mut state, wqe, msg));
+        }
+        drop(state);
+        report(outcomes);
+    }
+
+    /// Consume `wqe` with `msg`.
+    fn complete_recv(&self, state: &mut QpState, wqe: RecvWqe, msg: Inbound) -> Vec<Outcome> {
+        // shared receive requests point into memory of the SRQ's protection domain
+        let pd = self.srq.as_ref().map_or(self.pd, |srq| srq.pd);
+        let status = match FABRIC.scatter(pd, &wqe.sges, &msg.payload) {
+            Ok(()) => ibv_wc_status::IBV_WC_SUCCESS,
+            Err(status) => status,
+        };
+
+        let mut wc = ffi::ibv_wc::new(wqe.wr_id, status, msg.opcode, msg.byte_len);
+        wc.qp_num = self.qp_num;
+        wc.src_qp = msg.src_qp;
+        if let Some(imm_data) = msg.imm_data {
+            wc.imm_data = imm_data;
+            wc.wc_flags |= ffi::ibv_wc_flags::IBV_WC_WITH_IMM;
+        }
+        if msg.grh {
+            wc.wc_flags |= ffi::ibv_wc_flags::IBV_WC_GRH;
+        }
+        self.recv_cq.push(wc, msg.solicited);
+
+        if status == ibv_wc_status::IBV_WC_SUCCESS {
+            return msg.sender.map(|sender| (sender, status)).into_iter().collect();
+        }
+
+        log::debug!("QP {:#x}: receive failed with status {status}", self.qp_num);
+        let mut outcomes: Vec<_> = msg
+            .sender
+            .map(|sender| (sender, ibv_wc_status::IBV_WC_REM_INV_REQ_ERR))
+            .into_iter()
+            .collect();
+        outcomes.extend(self.enter_error(state));
+        outcomes
+    }
+}
+
+impl Qp {
+    /// Execute one send request, UD requests are sent to `ud`.
+    ///
+    /// Failures of the transfer itself are reported by a completion, `Err` rejects the request without one.
+    fn post_send(self: &Arc<Self>, wr: &SendWr, ud: Option<&UdDest<'_, Loopback>>) -> provider::Result {
+        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_UD {
+            return self.post_datagram(wr, ud.ok_or(VerbsError::InvalidArgument)?);
+        }
+
+        let (state, dest_qp_num) = {
+            let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            (state.state, state.dest_qp_num)
+        };
+
+        let sges = match &wr.payload {
+            Payload::Sges(sges) => sges.as_slice(),
+            Payload::Inline(_) => &[],
+        };
+        if sges.len() > self.cap.max_send_sge as usize {
+            return Err(VerbsError::InvalidArgument);
+        }
+
+        if matches!(wr.op, SendOp::LocalInv(_) | SendOp::SendWithInv(_)) {
+            log::debug!("QP {:#x}: unsupported send operation {:?}", self.qp_num, wr.op);
+            return Err(VerbsError::InvalidArgument);
+        }
+
+        let mut sender = Sender {
+            qp: Arc::clone(self),
+            wr_id: wr.wr_id,
+            opcode: wr.op.wc_opcode(),
+            byte_len: 0,
+            signaled: self.sq_sig_all || wr.signaled(),
+        };
+
+        match state {
+            ibv_qp_state::IBV_QPS_RTS => {}
+            ibv_qp_state::IBV_QPS_ERR => {
+                sender.complete(ibv_wc_status::IBV_WC_WR_FLUSH_ERR);
+                return Ok(());
+            }
+            _ => return Err(VerbsError::InvalidArgument),
+        }
+
+        let imm_data = wr.op.imm_data();
+
+        let Some(dest) = FABRIC.qp(dest_qp_num) else {
+            log::debug!("QP {:#x}: destination QP {dest_qp_num:#x} does not exist", self.qp_num);
+            sender.complete(ibv_wc_status::IBV_WC_RETRY_EXC_ERR);
+            return Ok(());
+        };
+
+        // message for the receive queue of `dest`, as (opcode, byte_len, payload)
+        let transfer = match wr.op {
+            SendOp::Send | SendOp::SendWithImm(_) => FABRIC
+                .gather(self.pd, &wr.payload)
+                .map(|payload| Some((ibv_wc_opcode::IBV_WC_RECV, payload.len(), payload))),
+            SendOp::RdmaWrite(remote) | SendOp::RdmaWriteWithImm(remote, _) => {
+                FABRIC.gather(self.pd, &wr.payload).and_then(|payload| {
+                    dest.remote(remote.rkey, remote.addr, payload.len(), REMOTE_WRITE, |dst| {
+                        // Safety: the range lies in a remotely writable registered region.
+                        unsafe { core::ptr::copy(payload.as_ptr(), dst, payload.len()) };
+                    })?;
+                    Ok(imm_data.map(|_| (ibv_wc_opcode::IBV_WC_RECV_RDMA_WITH_IMM, payload.len(), Vec::new())))
+                })
+            }
+            SendOp::RdmaRead(remote) => {
+                let len: usize = sges.iter().map(|sge| sge.length as usize).sum();
+                sender.byte_len = len as u32;
+                dest.remote(remote.rkey, remote.addr, len, REMOTE_READ, |src| {
+                    // Safety: the range lies in a remotely readable registered region.
+                    unsafe { core::slice::from_raw_parts(src, len) }.to_vec()
+                })
+                .and_then(|data| FABRIC.scatter(self.pd, sges, &data).map(|()| None))
+            }
+            SendOp::AtomicCmpAndSwp { remote, .. } | SendOp::AtomicFetchAndAdd { remote, .. } => {
+                sender.byte_len = 8;
+                dest.remote(remote.rkey, remote.addr, 8, REMOTE_ATOMIC, |dst| {
+                    // Safety: the range lies in a registered region allowing remote atomics.
+                    unsafe { atomic(wr.op, dst) }
+                })
+                .flatten()
+                .and_then(|original| FABRIC.scatter(self.pd, sges, &original.to_ne_bytes()).map(|()| None))
+            }
+            // rejected above
+            SendOp::LocalInv(_) | SendOp::SendWithInv(_) => Err(ibv_wc_status::IBV_WC_LOC_QP_OP_ERR),
+        };
+
+        match transfer {
+            Ok(Some((opcode, byte_len, payload))) => dest.deliver(Inbound {
+                src_qp: self.qp_num,
+                opcode,
+                payload,
+                byte_len: byte_len as u32,
+                imm_data,
+                solicited: wr.solicited(),
+                grh: false,
+                sender: Some(sender),
+            }),
+            Ok(None) => sender.complete(ibv_wc_status::IBV_WC_SUCCESS),
+            Err(status) => sender.complete(status),
+        }
+
+        Ok(())
+    }
+
+    /// Send one datagram to `dest`.
+    fn post_datagram(self: &Arc<Self>, wr: &SendWr, dest: &UdDest<'_, Loopback>) -> provider::Result {
+        if !matches!(wr.op, SendOp::Send | SendOp::SendWithImm(_)) || dest.ah.pd != self.pd {
+            log::debug!("QP {:#x}: unsupported datagram {:?}", self.qp_num, wr.op);
+            return Err(VerbsError::InvalidArgument);
+        }
+        let num_sge = match &wr.payload {
+            Payload::Sges(sges) => sges.len(),
+            Payload::Inline(_) => 0,
+        };
+        if num_sge > self.cap.max_send_sge as usize {
+            return Err(VerbsError::InvalidArgument);
+        }
+
+        let (state, own_qkey) = {
+            let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            (state.state, state.qkey)
+        };
+        let sender = Sender {
+            qp: Arc::clone(self),
+            wr_id: wr.wr_id,
+            opcode: wr.op.wc_opcode(),
+            byte_len: 0,
+            signaled: self.sq_sig_all || wr.signaled(),
+        };
+        match state {
+            ibv_qp_state::IBV_QPS_RTS => {}
+            ibv_qp_state::IBV_QPS_ERR => {
+                sender.complete(ibv_wc_status::IBV_WC_WR_FLUSH_ERR);
+                return Ok(());
+            }
+            _ => return Err(VerbsError::InvalidArgument),
+        }
+
+        let payload = match FABRIC.gather(self.pd, &wr.payload) {
+            Ok(payload) if payload.len() > UD_MTU => Err(ibv_wc_status::IBV_WC_LOC_LEN_ERR),
+            payload => payload,
+        };
+        let payload = match payload {
+            Ok(payload) => payload,
+            Err(status) => {
+                sender.complete(status);
+                return Ok(());
+            }
+        };
+
+        // a Q_Key with the high bit set asks for the QP's own
+        let qkey = if dest.remote_qkey & 0x8000_0000 != 0 {
+            own_qkey
+        } else {
+            dest.remote_qkey
+        };
+        let peer = FABRIC
+            .qp(dest.remote_qpn)
+            .filter(|peer| peer.qp_type == ffi::ibv_qp_type::IBV_QPT_UD);
+        match peer {
+            Some(peer) => {
+                let mut datagram = grh(dest.ah, payload.len()).to_vec();
+                datagram.extend_from_slice(&payload);
+                peer.deliver_datagram(
+                    qkey,
+                    Inbound {
+                        src_qp: self.qp_num,
+                        opcode: ibv_wc_opcode::IBV_WC_RECV,
+                        byte_len: datagram.len() as u32,
+                        payload: datagram,
+                        imm_data: wr.op.imm_data(),
+                        solicited: wr.solicited(),
+                        grh: true,
+                        sender: None,
+                    },
+                );
+            }
+            None => log::debug!(
+                "QP {:#x}: datagram dropped, no UD QP {:#x}",
+                self.qp_num,
+                dest.remote_qpn
+            ),
+        }
+        sender.complete(ibv_wc_status::IBV_WC_SUCCESS);
+
+        Ok(())
+    }
+
+    /// Run `f` on memory targeted by a remote request, the QP and the region must both allow `access`.
+    fn remote<R>(
+        &self,
+        rkey: u32,
+        addr: u64,
+        len: usize,
+        access: ibv_access_flags,
+        f: impl FnOnce(*mut u8) -> R,
+    ) -> Result<R, ibv_wc_status::Type> {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if !matches!(state.state, ibv_qp_state::IBV_QPS_RTR | ibv_qp_state::IBV_QPS_RTS) {
+            return Err(ibv_wc_status::IBV_WC_RETRY_EXC_ERR);
+        }
+        if (state.access & access) != access {
+            return Err(ibv_wc_status::IBV_WC_REM_ACCESS_ERR);
+        }
+        drop(state);
+
+        FABRIC
+            .resolve(rkey, self.pd, addr, len, access, f)
+            .ok_or(ibv_wc_status::IBV_WC_REM_ACCESS_ERR)
+    }
+
+    /// Post one receive request, matching it with a queued message if any.
+    fn post_recv(&self, wr: &ffi::ibv_recv_wr) -> provider::Result {
+        // receive requests go to the shared receive queue
+        if self.srq.is_some() {
+            return Err(VerbsError::InvalidArgument);
+        }
+
+        // Safety: the application hands us `num_sge` valid entries.
+        let sges = unsafe { sge_list(wr.sg_list, wr.num_sge) };
+        if sges.len() > self.cap.max_recv_sge as usize {
+            return Err(VerbsError::InvalidArgument);
+        }
+        let wqe = RecvWqe {
+            wr_id: wr.wr_id,
+            sges: sges.to_vec(),
+        };
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let outcomes = match state.state {
+            ibv_qp_state::IBV_QPS_RESET => return Err(VerbsError::InvalidArgument),
+            _ if state.recv.len() >= self.cap.max_recv_wr as usize => return Err(VerbsError::OutOfResources),
+            ibv_qp_state::IBV_QPS_ERR => {
+                state.recv.push_back(wqe);
+                self.enter_error(&mut state)
+            }
+            _ => match state.inbound.pop_front() {
+                Some(msg) => self.complete_recv(&mut state, wqe, msg),
+                None => {
+                    state.recv.push_back(wqe);
+                    Vec::new()
+                }
+            },
+        };
+        drop(state);
+        report(outcomes);
+
+        Ok(())
+    }
+}
+
+impl Srq {
+    /// Take the next receive request, firing the armed limit if too few are left.
+    fn take(&self) -> Option<RecvWqe> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let wqe = state.recv.pop_front()?;
+
+        let limit = state.attr.srq_limit;
+        if limit > 0 && state.recv.len() < limit as usize {
+            // the limit fires once, the application re-arms it with modify_srq
+            state.attr.srq_limit = 0;
+            self.events.post(AsyncEvent::Srq(self.srq_num, SrqEvent::LimitReached));
+        }
+
+        Some(wqe)
+    }
+
+    /// Post one receive request, then let the QPs consume it with a queued message.
+    fn post_recv(&self, wr: &ffi::ibv_recv_wr) -> provider::Result {
+        // Safety: the application hands us `num_sge` valid entries.
+        let sges = unsafe { sge_list(wr.sg_list, wr.num_sge) };
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if sges.len() > state.attr.max_sge as usize {
+            return Err(VerbsError::InvalidArgument);
+        }
+        if state.recv.len() >= state.attr.max_wr as usize {
+            return Err(VerbsError::OutOfResources);
+        }
+        state.recv.push_back(RecvWqe {
+            wr_id: wr.wr_id,
+            sges: sges.to_vec(),
+        });
+        let qps: Vec<_> = state.qps.iter().filter_map(Weak::upgrade).collect();
+        drop(state);
+
+        for qp in qps {
+            qp.drain_inbound();
+        }
+
+        Ok(())
+    }
+}
+
+/// Largest datagram, the MTU of the ports
+const UD_MTU: usize = 4096;
+
+/// Length of the GRH ahead of every datagram in its receive buffer
+const GRH_LEN: usize = 40;
+
+/// GRH of a datagram of `len` bytes sent through `ah`.
+fn grh(ah: &LoopbackAh, len: usize) -> [u8; GRH_LEN] {
+    let route = &ah.attr.grh;
+    let version_tclass_flow = (6 << 28) | (u32::from(route.traffic_class) << 20) | (route.flow_label & 0xf_ffff);
+
+    let mut grh = [0; GRH_LEN];
+    grh[..4].copy_from_slice(&version_tclass_flow.to_be_bytes());
+    grh[4..6].copy_from_slice(&u16::try_from(len).unwrap_or(u16::MAX).to_be_bytes());
+    // next header: IBA transport
+    grh[6] = 0x1b;
+    grh[7] = route.hop_limit;
+    // Safety: every GID is plain bytes.
+    grh[8..24].copy_from_slice(unsafe { &ah.sgid.raw });
+    grh[24..].copy_from_slice(unsafe { &route.dgid.raw });
+    grh
+}
+
+const REMOTE_WRITE: ibv_access_flags = ibv_access_flags::IBV_ACCESS_REMOTE_WRITE;
+const REMOTE_READ: ibv_access_flags = ibv_access_flags::IBV_ACCESS_REMOTE_READ;
+const REMOTE_ATOMIC: ibv_access_flags = ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC;
+
+/// View a work request's scatter/gather list.
+///
+/// Safety: `sg_list` must point to `num_sge` entries if `num_sge` is positive.
+pub(crate) unsafe fn sge_list<'a>(sg_list: *const ffi::ibv_sge, num_sge: core::ffi::c_int) -> &'a [ffi::ibv_sge] {
+    match usize::try_from(num_sge) {
+        Ok(len) if len > 0 && !sg_list.is_null() => unsafe { core::slice::from_raw_parts(sg_list, len) },
+        _ => &[],
+    }
+}
+
+/// Execute the atomic operation `op` on the 8 bytes at `dst`, returning their original value.
+///
+/// Safety: `dst` must be valid for reads and writes of 8 bytes.
+pub(crate) unsafe fn atomic(op: SendOp, dst: *mut u8) -> Result<u64, ibv_wc_status::Type> {
+    // the requester only checked the alignment of the I/O virtual address, not of the memory behind it
+    if !dst.cast::<u64>().is_aligned() {
+        return Err(ibv_wc_status::IBV_WC_REM_INV_REQ_ERR);
+    }
+    // Safety: the caller guarantees validity, concurrent requests only access it atomically.
+    let target = unsafe { AtomicU64::from_ptr(dst.cast()) };
+
+    match op {
+        SendOp::AtomicCmpAndSwp { compare, swap, .. } => Ok(target
+            .compare_exchange(compare, swap, Ordering::SeqCst, Ordering::SeqCst)
+            .unwrap_or_else(|original| original)),
+        SendOp::AtomicFetchAndAdd { add, .. } => Ok(target.fetch_add(add, Ordering::SeqCst)),
+        _ => Err(ibv_wc_status::IBV_WC_LOC_QP_OP_ERR),
+    }
+}
+
+pub(crate) fn page_size() -> usize {
+    static PAGE_SIZE: LazyLock<usize> = LazyLock::new(|| unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize);
+    *PAGE_SIZE
+}
+
+/// Whether the pages of the `len` bytes at `addr` are all mapped.
+///
+/// Registrations check this up front, where a device would pin the pages, on-demand regions each time they are
+/// accessed. Only missing mappings are caught, not missing permissions.
+pub(crate) fn mapped(addr: usize, len: usize) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let Some(end) = addr.checked_add(len) else {
+        return false;
+    };
+    let start = addr & !(page_size() - 1);
+    // fails with ENOMEM on unmapped pages, MS_ASYNC writes nothing back
+    unsafe { libc::msync(start as *mut libc::c_void, end - start, libc::MS_ASYNC) == 0 }
+}
+
+/// dma-buf mapped into this process
+pub(crate) struct DmaBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+    /// offset of the registered memory in the mapping
+    start: usize,
+}
+
+// Safety: the mapping is plain memory, accessed like any registered region.
+unsafe impl Send for DmaBuf {}
+unsafe impl Sync for DmaBuf {}
+
+impl DmaBuf {
+    /// Map `length` bytes at `offset` of the dma-buf `fd`.
+    pub(crate) fn map(fd: core::ffi::c_int, offset: u64, length: usize) -> provider::Result<Self> {
+        let start = (offset % page_size() as u64) as usize;
+        let len = start.checked_add(length).ok_or(VerbsError::InvalidArgument)?;
+        let file_offset = libc::off_t::try_from(offset - start as u64).map_err(|_| VerbsError::InvalidArgument)?;
+
+        let ptr = unsafe {
+            libc::mmap(
+                core::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                file_offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(VerbsError::last_os_error());
+        }
+
+        Ok(Self {
+            ptr: NonNull::new(ptr.cast()).unwrap(),
+            len,
+            start,
+        })
+    }
+
+    /// Address of the registered memory.
+    pub(crate) fn addr(&self) -> usize {
+        self.ptr.as_ptr() as usize + self.start
+    }
+}
+
+impl Drop for DmaBuf {
+    fn drop(&mut self) {
+        // Safety: the mapping is ours, regions using it are deregistered.
+        unsafe { libc::munmap(self.ptr.as_ptr().cast(), self.len) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use provider::{DeviceAttrEx, MrRereg, Provider, Remote, UdDest};
+
+    use super::*;
+    use crate::testing::{self, ACCESS};
+
+    type Side = testing::Side<Loopback>;
+
+    fn device(name: &str) -> Arc<Loopback> {
+        Loopback::new(name, provider::AsyncEvents::default()).unwrap()
+    }
+
+    /// RC QP on a new device `name`.
+    fn side(name: &str) -> Side {
+        Side::new(device(name), 64)
+    }
+
+    /// Two connected RC QPs, on different devices.
+    fn connect() -> (Side, Side) {
+        let (a, b) = (side("urdma0"), side("urdma1"));
+        link(&a, &b);
+        (a, b)
+    }
+
+    /// Connect the QPs of `a` and `b`.
+    fn link(a: &Side, b: &Side) {
+        a.connect(b.qp.qp_num(), Default::default(), ffi::ibv_qp_attr_mask(0));
+        b.connect(a.qp.qp_num(), Default::default(), ffi::ibv_qp_attr_mask(0));
+    }
+
+    #[test]
+    fn send_recv() {
+        let (mut a, b) = connect();
+        a.bytes_mut()[..5].copy_from_slice(b"hello");
+
+        // the message waits for a receive request
+        a.post_send(1, SendOp::Send, a.sge(0, 5));
+        assert!(a.poll().is_empty());
+        b.post_recv(2, b.sge(8, 16));
+
+        let recv = b.poll();
+        assert_eq!(recv.len(), 1);
+        assert_eq!(
+            (recv[0].wr_id(), recv[0].opcode(), recv[0].len()),
+            (2, ibv_wc_opcode::IBV_WC_RECV, 5)
+        );
+        assert_eq!(recv[0].src_qp, a.qp.qp_num());
+        assert_eq!(&b.bytes()[8..13], b"hello");
+
+        let send = a.poll();
+        assert_eq!(send.len(), 1);
+        assert!(send[0].is_valid());
+        assert_eq!((send[0].wr_id(), send[0].opcode()), (1, ibv_wc_opcode::IBV_WC_SEND));
+    }
+
+    #[test]
+    fn inline_send() {
+        let (a, b) = connect();
+        b.post_recv(1, b.sge(0, 16));
+
+        let wr = SendWr {
+            wr_id: 2,
+            op: SendOp::SendWithImm(0x1234),
+            flags: ffi::ibv_send_flags::IBV_SEND_SIGNALED,
+            payload: Payload::Inline(b"inline".to_vec()),
+        };
+        a.dev.post_send(&a.qp, &wr, None).unwrap();
+
+        let recv = b.poll();
+        assert_eq!(
+            (recv[0].opcode(), recv[0].len(), recv[0].imm_data),
+            (ibv_wc_opcode::IBV_WC_RECV, 6, 0x1234)
+        );
+        assert_eq!(&b.bytes()[..6], b"inline");
+        assert!(a.poll()[0].is_valid());
+    }
+
+    #[test]
+    fn completions_are_stamped() {
+        let (a, b) = connect();
+        let before = Completion::stamped(ffi::ibv_wc::default()).timestamp.unwrap();
+
+        b.post_recv(1, b.sge(0, 8));
+        a.post_send(2, SendOp::Send, a.sge(0, 4));
+        a.post_send(3, SendOp::Send, a.sge(0, 4));
+        b.post_recv(4, b.sge(0, 8));
+
+        let mut completions = [Completion::default(); 4];
+        let polled = a.dev.poll_cq_ex(&a.cq, &mut completions).unwrap();
+        let stamps: Vec<_> = completions[..polled].iter().map(|c| c.timestamp.unwrap()).collect();
+        assert_eq!(stamps.len(), 2);
+        assert!(before <= stamps[0] && stamps[0] <= stamps[1]);
+        assert_eq!(completions[1].wc.wr_id(), 3);
+
+        // legacy polling sees the same completions
+        assert_eq!(b.poll().iter().map(|wc| wc.wr_id()).collect::<Vec<_>>(), [1, 4]);
+    }
+
+    #[test]
+    fn full_cq_drops_completions() {
+        let (a, b) = connect();
+        // one more than the CQ holds
+        for wr_id in 0..17 {
+            a.post_send(wr_id, SendOp::RdmaWrite(b.remote(0)), a.sge(0, 4));
+        }
+
+        let mut wr_ids = Vec::new();
+        loop {
+            let wc = a.poll();
+            if wc.is_empty() {
+                break;
+            }
+            wr_ids.extend(wc.iter().map(|wc| wc.wr_id()));
+        }
+        assert_eq!(wr_ids, (0..16).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn rdma_write_and_read() {
+        let (mut a, mut b) = connect();
+        a.bytes_mut()[..4].copy_from_slice(b"ping");
+        b.bytes_mut()[32..36].copy_from_slice(b"pong");
+
+        b.post_recv(1, b.sge(0, 0));
+        a.post_send(2, SendOp::RdmaWriteWithImm(b.remote(16), 0x1234), a.sge(0, 4));
+        a.post_send(3, SendOp::RdmaRead(b.remote(32)), a.sge(8, 4));
+
+        assert_eq!(&b.bytes()[16..20], b"ping");
+        assert_eq!(&a.bytes()[8..12], b"pong");
+
+        let recv = b.poll();
+        assert_eq!(recv.len(), 1);
+        assert_eq!(recv[0].opcode(), ibv_wc_opcode::IBV_WC_RECV_RDMA_WITH_IMM);
+        assert_eq!((recv[0].len(), recv[0].imm_data()), (4, Some(0x1234)));
+
+        let send = a.poll();
+        let opcodes: Vec<_> = send.iter().map(|wc| (wc.wr_id(), wc.opcode(), wc.is_valid())).collect();
+        assert_eq!(
+            opcodes,
+            [
+                (2, ibv_wc_opcode::IBV_WC_RDMA_WRITE, true),
+                (3, ibv_wc_opcode::IBV_WC_RDMA_READ, true)
+            ]
+        );
+        assert_eq!(send[1].len(), 4);
+    }
+
+    #[test]
+    fn errors_complete_and_flush() {
+        let (a, b) = connect();
+
+        b.post_recv(1, b.sge(0, 2));
+        b.post_recv(2, b.sge(0, 2));
+        a.post_send(3, SendOp::Send, a.sge(0, 8));
+
+        // too long for the receive request, the responder goes to error and flushes the rest
+        let recv: Vec<_> = b
+            .poll()
+            .iter()
+            .map(|wc| (wc.wr_id(), wc.error().map(|(status, _)| status)))
+            .collect();
+        assert_eq!(
+            recv,
+            [
+                (1, Some(ibv_wc_status::IBV_WC_LOC_LEN_ERR)),
+                (2, Some(ibv_wc_status::IBV_WC_WR_FLUSH_ERR))
+            ]
+        );
+        let send = a.poll();
+        assert_eq!(
+            send[0].error().map(|(status, _)| status),
+            Some(ibv_wc_status::IBV_WC_REM_INV_REQ_ERR)
+        );
+
+        // remote access to memory outside the region
+        let (c, d) = connect();
+        c.post_send(4, SendOp::RdmaWrite(d.remote(60)), c.sge(0, 8));
+        let send = c.poll();
+        assert_eq!(
+            send[0].error().map(|(status, _)| status),
+            Some(ibv_wc_status::IBV_WC_REM_ACCESS_ERR)
+        );
+    }
+
+    #[test]
+    fn atomics() {
+        let (a, mut b) = connect();
+        let at = 8;
+        b.bytes_mut()[at..at + 8].copy_from_slice(&5_u64.to_ne_bytes());
+
+        let remote = b.remote(at);
+        a.post_send(1, SendOp::AtomicFetchAndAdd { remote, add: 3 }, a.sge(0, 8));
+        let cmp_swp = |compare, swap| SendOp::AtomicCmpAndSwp { remote, compare, swap };
+        a.post_send(2, cmp_swp(8, 42), a.sge(8, 8));
+        // compares unequal, nothing is swapped
+        a.post_send(3, cmp_swp(8, 0), a.sge(16, 8));
+
+        let send: Vec<_> = a.poll().iter().map(|wc| (wc.wr_id(), wc.opcode(), wc.len())).collect();
+        assert_eq!(
+            send,
+            [
+                (1, ibv_wc_opcode::IBV_WC_FETCH_ADD, 8),
+                (2, ibv_wc_opcode::IBV_WC_COMP_SWAP, 8),
+                (3, ibv_wc_opcode::IBV_WC_COMP_SWAP, 8)
+            ]
+        );
+        let original = |offset: usize| u64::from_ne_bytes(a.bytes()[offset..offset + 8].try_into().unwrap());
+        assert_eq!([original(0), original(8), original(16)], [5, 8, 42]);
+        assert_eq!(b.bytes()[at..at + 8], 42_u64.to_ne_bytes());
+
+        // memory the responder cannot access atomically
+        let misaligned = SendOp::AtomicFetchAndAdd {
+            remote: b.remote(at + 4),
+            add: 1,
+        };
+        a.post_send(4, misaligned, a.sge(0, 8));
+        assert_eq!(
+            a.poll()[0].error().map(|(status, _)| status),
+            Some(ibv_wc_status::IBV_WC_REM_INV_REQ_ERR)
+        );
+    }
+
+    #[test]
+    fn rereg_mr_keeps_keys() {
+        let (mut a, b) = connect();
+        a.bytes_mut()[..4].copy_from_slice(b"ping");
+        let (lkey, rkey) = (b.mr.lkey(), b.mr.rkey());
+
+        let mut moved = vec![0_u8; 16];
+        let rereg = MrRereg {
+            pd: None,
+            translation: Some((moved.as_mut_ptr().cast(), moved.len())),
+            access: None,
+        };
+        b.dev.rereg_mr(&b.mr, &rereg).unwrap();
+        assert_eq!((b.mr.lkey(), b.mr.rkey()), (lkey, rkey));
+
+        // peers reach the new memory with the key they hold
+        let remote = Remote {
+            addr: moved.as_ptr() as u64,
+            rkey,
+        };
+        a.post_send(1, SendOp::RdmaWrite(remote), a.sge(0, 4));
+        assert!(a.poll()[0].is_valid());
+        assert_eq!(&moved[..4], b"ping");
+
+        let rereg = |access| MrRereg {
+            pd: None,
+            translation: None,
+            access: Some(access),
+        };
+        assert_eq!(
+            b.dev.rereg_mr(&b.mr, &rereg(ibv_access_flags::IBV_ACCESS_REMOTE_WRITE)),
+            Err(VerbsError::InvalidArgument)
+        );
+        b.dev
+            .rereg_mr(&b.mr, &rereg(ibv_access_flags::IBV_ACCESS_LOCAL_WRITE))
+            .unwrap();
+        a.post_send(2, SendOp::RdmaWrite(remote), a.sge(0, 4));
+        assert_eq!(
+            a.poll()[0].error().map(|(status, _)| status),
+            Some(ibv_wc_status::IBV_WC_REM_ACCESS_ERR)
+        );
+    }
+
+    #[test]
+    fn query_device_ex() {
+        let dev = Loopback::new("urdma0", provider::AsyncEvents::default()).unwrap();
+
+        let mut attr = DeviceAttrEx::new(usize::MAX);
+        assert_eq!(attr.size(), size_of::<ffi::ibv_device_attr_ex>());
+        dev.query_device_ex(&mut attr).unwrap();
+        assert_eq!(attr.orig_attr.phys_port_cnt, 1);
+        assert_eq!(attr.odp_caps.general_caps, u64::from(ffi::IBV_ODP_SUPPORT));
+        assert_ne!(
+            attr.odp_caps.per_transport_caps.rc_odp_caps & ffi::IBV_ODP_SUPPORT_WRITE,
+            0
+        );
+        assert_eq!(attr.completion_timestamp_mask, u64::MAX);
+    }
+
+    #[test]
+    fn on_demand_paging() {
+        let (mut a, b) = connect();
+        a.bytes_mut()[..4].copy_from_slice(b"ping");
+
+        // two pages, the second one unmapped
+        let page = page_size();
+        let mem = unsafe {
+            libc::mmap(
+                core::ptr::null_mut(),
+                2 * page,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(mem, libc::MAP_FAILED);
+        assert_eq!(unsafe { libc::munmap(mem.wrapping_byte_add(page), page) }, 0);
+
+        assert_eq!(
+            b.dev.reg_mr(&b.pd, mem, 2 * page, mem as u64, ACCESS).err(),
+            Some(VerbsError::Errno(libc::EFAULT))
+        );
+        let odp = b
+            .dev
+            .reg_mr(
+                &b.pd,
+                mem,
+                2 * page,
+                mem as u64,
+                ACCESS | ibv_access_flags::IBV_ACCESS_ON_DEMAND,
+            )
+            .unwrap();
+
+        let remote = |offset| Remote {
+            addr: mem as u64 + offset as u64,
+            rkey: odp.rkey(),
+        };
+        a.post_send(1, SendOp::RdmaWrite(remote(page - 4)), a.sge(0, 4));
+        assert!(a.poll()[0].is_valid());
+        assert_eq!(
+            unsafe { core::slice::from_raw_parts(mem.cast::<u8>().add(page - 4), 4) },
+            b"ping"
+        );
+
+        a.post_send(2, SendOp::RdmaWrite(remote(page - 2)), a.sge(0, 4));
+        assert_eq!(
+            a.poll()[0].error().map(|(status, _)| status),
+            Some(ibv_wc_status::IBV_WC_REM_ACCESS_ERR)
+        );
+
+        b.dev.dereg_mr(&odp).unwrap();
+        unsafe { libc::munmap(mem, page) };
+    }
+
+    #[test]
+    fn dmabuf_mr() {
+        let (mut a, b) = connect();
+        a.bytes_mut()[..4].copy_from_slice(b"ping");
+
+        // any mappable file will do in place of an exporter
+        let fd = unsafe { libc::memfd_create(c"dmabuf".as_ptr(), 0) };
+        assert!(fd >= 0);
+        let page = page_size();
+        assert_eq!(unsafe { libc::ftruncate(fd, 2 * page as libc::off_t) }, 0);
+
+        let offset = page as u64 + 8;
+        let mr = b.dev.reg_dmabuf_mr(&b.pd, offset, 16, 0x1000, fd, ACCESS).unwrap();
+        let remote = Remote {
+            addr: 0x1004,
+            rkey: mr.rkey(),
+        };
+        a.post_send(1, SendOp::RdmaWrite(remote), a.sge(0, 4));
+        assert!(a.poll()[0].is_valid());
+
+        let mut data = [0_u8; 4];
+        let read = unsafe { libc::pread(fd, data.as_mut_ptr().cast(), 4, offset as libc::off_t + 4) };
+        assert_eq!(read, 4);
+        assert_eq!(&data, b"ping");
+
+        // the mapping can't be swapped for process memory
+        let mut other = [0_u8; 16];
+        let rereg = MrRereg {
+            pd: None,
+            translation: Some((other.as_mut_ptr().cast(), other.len())),
+            access: None,
+        };
+        assert_eq!(b.dev.rereg_mr(&mr, &rereg), Err(VerbsError::InvalidArgument));
+
+        b.dev.dereg_mr(&mr).unwrap();
+        unsafe { libc::close(fd) };
+    }
+
+    #[test]
+    fn shared_receive_queue() {
+        let pool = side("urdma1");
+        let mut attr = ffi::ibv_srq_attr {
+            max_wr: 4,
+            max_sge: 1,
+            srq_limit: 0,
+        };
+        let srq = pool.dev.create_srq(&pool.pd, &mut attr).unwrap();
+
+        let (mut a1, mut a2) = (side("urdma0"), side("urdma0"));
+        let (b1, b2) = (
+            Side::with_qp(device("urdma1"), 64, Some(&srq), ffi::ibv_qp_type::IBV_QPT_RC),
+            Side::with_qp(device("urdma1"), 64, Some(&srq), ffi::ibv_qp_type::IBV_QPT_RC),
+        );
+        link(&a1, &b1);
+        link(&a2, &b2);
+
+        // receive requests go to the SRQ only
+        let mut sge = b1.sge(0, 4);
+        let mut wr = ffi::ibv_recv_wr {
+            wr_id: 0,
+            next: core::ptr::null_mut(),
+            sg_list: &raw mut sge,
+            num_sge: 1,
+        };
+        let mut bad_wr = core::ptr::null_mut();
+        assert_eq!(
+            b1.dev.post_recv(&b1.qp, &raw mut wr, &mut bad_wr),
+            Err(VerbsError::InvalidArgument)
+        );
+
+        // both messages wait for the shared pool
+        a1.bytes_mut()[..3].copy_from_slice(b"one");
+        a2.bytes_mut()[..3].copy_from_slice(b"two");
+        a1.post_send(1, SendOp::Send, a1.sge(0, 3));
+        a2.post_send(2, SendOp::Send, a2.sge(0, 3));
+
+        let mut sges = [pool.sge(0, 8), pool.sge(8, 8)];
+        let mut wrs: Vec<_> = sges
+            .iter_mut()
+            .zip([10, 11])
+            .map(|(sge, wr_id)| ffi::ibv_recv_wr {
+                wr_id,
+                next: core::ptr::null_mut(),
+                sg_list: sge,
+                num_sge: 1,
+            })
+            .collect();
+        let second = &raw mut wrs[1];
+        wrs[0].next = second;
+        pool.dev.post_srq_recv(&srq, &raw mut wrs[0], &mut bad_wr).unwrap();
+
+        let (recv1, recv2) = (b1.poll(), b2.poll());
+        assert_eq!((recv1.len(), recv2.len()), (1, 1));
+        assert_eq!((recv1[0].wr_id(), recv1[0].qp_num), (10, b1.qp.qp_num()));
+        assert_eq!((recv2[0].wr_id(), recv2[0].qp_num), (11, b2.qp.qp_num()));
+        assert_eq!(&pool.bytes()[..3], b"one");
+        assert_eq!(&pool.bytes()[8..11], b"two");
+        assert_eq!((a1.poll().len(), a2.poll().len()), (1, 1));
+
+        let mut queried = ffi::ibv_srq_attr::default();
+        pool.dev
+            .modify_srq(
+                &srq,
+                &mut ffi::ibv_srq_attr { srq_limit: 2, ..attr },
+                ffi::ibv_srq_attr_mask::IBV_SRQ_LIMIT,
+            )
+            .unwrap();
+        pool.dev.query_srq(&srq, &mut queried).unwrap();
+        assert_eq!((queried.max_wr, queried.srq_limit), (4, 2));
+
+        // still used by the QPs of b1 and b2
+        assert_eq!(pool.dev.destroy_srq(&srq), Err(VerbsError::Busy));
+        drop((b1, b2));
+        pool.dev.destroy_srq(&srq).unwrap();
+    }
+
+    #[test]
+    fn ud_pingpong() {
+        const QKEY: u32 = 0x1111_1111;
+        let ud = ffi::ibv_qp_type::IBV_QPT_UD;
+        let (mut a, mut b) = (
+            Side::with_qp(device("urdma0"), 64, None, ud),
+            Side::with_qp(device("urdma1"), 64, None, ud),
+        );
+        for side in [&a, &b] {
+            for state in [
+                ibv_qp_state::IBV_QPS_INIT,
+                ibv_qp_state::IBV_QPS_RTR,
+                ibv_qp_state::IBV_QPS_RTS,
+            ] {
+                // Safety: all-zero is a valid `ibv_qp_attr`.
+                let mut attr: ffi::ibv_qp_attr = unsafe { core::mem::zeroed() };
+                attr.qp_state = state;
+                attr.qkey = QKEY;
+                let mask = ffi::ibv_qp_attr_mask::IBV_QP_STATE | ffi::ibv_qp_attr_mask::IBV_QP_QKEY;
+                side.dev.modify_qp(&side.qp, &mut attr, mask).unwrap();
+            }
+        }
+
+        // the peer's QP number doubles as its GID
+        let ah = |side: &Side, peer: &Side| {
+            let mut attr = ffi::ibv_ah_attr {
+                is_global: 1,
+                port_num: 1,
+                ..Default::default()
+            };
+            let mut raw = [0; 16];
+            raw[12..].copy_from_slice(&peer.qp.qp_num().to_be_bytes());
+            attr.grh.dgid = ffi::ibv_gid { raw };
+            attr.grh.hop_limit = 1;
+            side.dev.create_ah(&side.pd, &attr).unwrap()
+        };
+        let (a_to_b, b_to_a) = (ah(&a, &b), ah(&b, &a));
+        let send_to = |side: &Side, ah: &LoopbackAh, peer: &Side, qkey: u32| {
+            let wr = SendWr {
+                wr_id: 1,
+                op: SendOp::Send,
+                flags: ffi::ibv_send_flags::IBV_SEND_SIGNALED,
+                payload: Payload::Sges(vec![side.sge(0, 4)]),
+            };
+            let dest = UdDest {
+                ah,
+                remote_qpn: peer.qp.qp_num(),
+                remote_qkey: qkey,
+            };
+            side.dev.post_send(&side.qp, &wr, Some(&dest)).unwrap();
+        };
+        a.bytes_mut()[..4].copy_from_slice(b"ping");
+        b.bytes_mut()[..4].copy_from_slice(b"pong");
+
+        // nobody is waiting, the datagram is lost but sent all the same
+        send_to(&a, &a_to_b, &b, QKEY);
+        assert!(a.poll()[0].is_valid());
+
+        for _ in 0..3 {
+            b.post_recv(2, b.sge(8, 48));
+            // a wrong Q_Key is dropped by the receiver, the receive request stays posted
+            send_to(&a, &a_to_b, &b, 0x2222_2222);
+            send_to(&a, &a_to_b, &b, QKEY);
+            let recv = b.poll();
+            assert_eq!(recv.len(), 1);
+            assert_eq!((recv[0].wr_id(), recv[0].len(), recv[0].src_qp), (2, 44, a.qp.qp_num()));
+            assert_ne!((recv[0].wc_flags & ffi::ibv_wc_flags::IBV_WC_GRH).0, 0);
+            assert_eq!(&b.bytes()[44..48], &b.qp.qp_num().to_be_bytes());
+            // the source GID is entry 0 of the sender's table
+            let sgid = a.dev.gids.first().map_or([0; 16], |entry| unsafe { entry.gid.raw });
+            assert_eq!(b.bytes()[16..32], sgid);
+            assert_eq!(&b.bytes()[48..52], b"ping");
+            assert_eq!(a.poll().len(), 2);
+
+            // the high bit sends with the QP's own Q_Key
+            a.post_recv(3, a.sge(8, 48));
+            send_to(&b, &b_to_a, &a, 0x8000_0000);
+            let recv = a.poll();
+            assert_eq!((recv[0].wr_id(), recv[0].src_qp), (3, b.qp.qp_num()));
+            assert_eq!(&a.bytes()[48..52], b"pong");
+            assert!(b.poll()[0].is_valid());
+        }
+
+        a.dev.destroy_ah(&a_to_b).unwrap();
+        b.dev.destroy_ah(&b_to_a).unwrap();
+    }
+}
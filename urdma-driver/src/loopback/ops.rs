@@ -0,0 +1,629 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use ffi::{ibv_access_flags, ibv_qp_attr_mask, ibv_qp_state, ibv_srq_attr_mask};
+use provider::{
+    AsyncEvents, Capabilities, Completion, CqNotifier, DeviceAttrEx, MrRereg, QpInitAttr, Result, SendWr, UdDest,
+    Verbs, VerbsError,
+};
+
+use super::{
+    Cq, DmaBuf, FABRIC, Loopback, LoopbackAh, LoopbackCq, LoopbackMr, LoopbackPd, LoopbackQp, LoopbackSrq, MrEntry, Qp,
+    QpState, Srq, SrqState, mapped, report,
+};
+use crate::{config, gid, urdma};
+
+const FW_VER: &str = "loopback";
+
+const MAX_QP: u32 = 1 << 16;
+const MAX_QP_WR: u32 = 1 << 14;
+const MAX_SGE: u32 = 32;
+const MAX_INLINE_DATA: u32 = 1 << 12;
+const MAX_CQ: u32 = 1 << 16;
+const MAX_CQE: u32 = 1 << 20;
+const MAX_MR: u32 = 1 << 20;
+const MAX_PD: u32 = 1 << 16;
+const MAX_MSG_SZ: u32 = 1 << 31;
+const MAX_SRQ: u32 = 1 << 16;
+
+impl provider::Provider for Loopback {
+    type Ah = LoopbackAh;
+    type Cq = LoopbackCq;
+    type Mr = LoopbackMr;
+    type Mw = core::convert::Infallible;
+    type Pd = LoopbackPd;
+    type Qp = LoopbackQp;
+    type Srq = LoopbackSrq;
+
+    fn init() -> Result {
+        let _ = env_logger::try_init();
+
+        let profile = config::profile().map_err(|err| {
+            log::error!("{err}");
+            VerbsError::InvalidArgument
+        })?;
+        profile.install();
+        Ok(())
+    }
+
+    unsafe fn from_ibv_device(ibdev: *mut ffi::ibv_device) -> Result<*const Self> {
+        unsafe { urdma::driver_data(ibdev) }.map(|driver_data| driver_data.cast())
+    }
+
+    fn new(sysfs_name: &str, events: AsyncEvents) -> Result<Arc<Self>> {
+        log::info!("{sysfs_name}: backed by the software loopback");
+
+        Ok(Arc::new(Loopback {
+            name: sysfs_name.to_owned(),
+            events,
+            gids: gid::local(sysfs_name),
+        }))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            verbs: Verbs::ALL.without(Verbs::MW),
+            qp_types: &[
+                ffi::ibv_qp_type::IBV_QPT_RC,
+                ffi::ibv_qp_type::IBV_QPT_UC,
+                ffi::ibv_qp_type::IBV_QPT_UD,
+            ],
+        }
+    }
+
+    fn alloc_pd(&self) -> Result<LoopbackPd> {
+        log::info!("{}: Allocating protection domain", self.name);
+
+        Ok(LoopbackPd { id: FABRIC.alloc_id() })
+    }
+
+    fn dealloc_pd(&self, _pd: &LoopbackPd) -> Result {
+        log::info!("{}: Deallocating protection domain", self.name);
+
+        Ok(())
+    }
+
+    fn query_device(&self, device_attr: &mut ffi::ibv_device_attr) -> Result {
+        log::info!("{}: Querying device attributes", self.name);
+
+        for (dst, src) in device_attr.fw_ver.iter_mut().zip(FW_VER.bytes()) {
+            *dst = src as _;
+        }
+        device_attr.max_mr_size = u64::MAX;
+        device_attr.page_size_cap = 4096;
+        device_attr.max_qp = MAX_QP as _;
+        device_attr.max_qp_wr = MAX_QP_WR as _;
+        device_attr.max_sge = MAX_SGE as _;
+        device_attr.max_sge_rd = MAX_SGE as _;
+        device_attr.max_cq = MAX_CQ as _;
+        device_attr.max_cqe = MAX_CQE as _;
+        device_attr.max_mr = MAX_MR as _;
+        device_attr.max_pd = MAX_PD as _;
+        device_attr.max_srq = MAX_SRQ as _;
+        device_attr.max_srq_wr = MAX_QP_WR as _;
+        device_attr.max_srq_sge = MAX_SGE as _;
+        device_attr.max_qp_rd_atom = 128;
+        device_attr.max_qp_init_rd_atom = 128;
+        device_attr.atomic_cap = ffi::IBV_ATOMIC_HCA;
+        device_attr.max_pkeys = 1;
+        device_attr.phys_port_cnt = 1;
+
+        Ok(())
+    }
+
+    fn query_device_ex(&self, attr: &mut DeviceAttrEx) -> Result {
+        self.query_device(&mut attr.orig_attr)?;
+
+        // memory of on-demand regions is resolved on each access, whatever the transport
+        let odp = ffi::IBV_ODP_SUPPORT_SEND
+            | ffi::IBV_ODP_SUPPORT_RECV
+            | ffi::IBV_ODP_SUPPORT_WRITE
+            | ffi::IBV_ODP_SUPPORT_READ
+            | ffi::IBV_ODP_SUPPORT_ATOMIC
+            | ffi::IBV_ODP_SUPPORT_SRQ_RECV;
+        attr.odp_caps.general_caps = ffi::IBV_ODP_SUPPORT.into();
+        attr.odp_caps.per_transport_caps.rc_odp_caps = odp;
+        attr.odp_caps.per_transport_caps.uc_odp_caps = odp;
+        attr.odp_caps.per_transport_caps.ud_odp_caps = odp;
+        // completions are stamped in nanoseconds, a 1 GHz clock
+        attr.completion_timestamp_mask = u64::MAX;
+        attr.hca_core_clock = 1_000_000;
+
+        Ok(())
+    }
+
+    fn query_port(&self, port_num: u8, port_attr: &mut ffi::ibv_port_attr) -> Result {
+        log::info!("{}: Querying port attributes", self.name);
+
+        if port_num != 1 {
+            return Err(VerbsError::InvalidArgument);
+        }
+
+        port_attr.state = ffi::ibv_port_state::IBV_PORT_ACTIVE;
+        port_attr.max_mtu = ffi::IBV_MTU_4096;
+        port_attr.active_mtu = ffi::IBV_MTU_4096;
+        port_attr.gid_tbl_len = self.gids.len().max(1) as _;
+        port_attr.max_msg_sz = MAX_MSG_SZ;
+        port_attr.pkey_tbl_len = 1;
+        port_attr.active_width = 1;
+        port_attr.active_speed = 1;
+        // LinkUp
+        port_attr.phys_state = 5;
+        port_attr.link_layer = ffi::IBV_LINK_LAYER_ETHERNET as _;
+
+        Ok(())
+    }
+
+    fn query_gid_table(&self, port_num: u8) -> Result<Vec<ffi::ibv_gid_entry>> {
+        log::info!("{}: Querying GID table", self.name);
+
+        if port_num != 1 {
+            return Err(VerbsError::InvalidArgument);
+        }
+
+        Ok(self.gids.clone())
+    }
+
+    fn create_cq(
+        &self,
+        cqe: core::ffi::c_int,
+        notifier: Option<CqNotifier>,
+        _comp_vector: core::ffi::c_int,
+    ) -> Result<LoopbackCq> {
+        log::info!("{}: Creating completion queue", self.name);
+
+        match u32::try_from(cqe) {
+            Ok(1..=MAX_CQE) => {}
+            _ => return Err(VerbsError::InvalidArgument),
+        }
+
+        Ok(LoopbackCq(Arc::new(Cq {
+            cq_num: FABRIC.alloc_id(),
+            cqe: cqe as usize,
+            entries: Mutex::new(VecDeque::with_capacity(cqe as usize)),
+            notifier,
+            events: self.events.clone(),
+        })))
+    }
+
+    fn create_cq_ex(
+        &self,
+        cqe: core::ffi::c_int,
+        notifier: Option<CqNotifier>,
+        comp_vector: core::ffi::c_int,
+        _wc_flags: u64,
+    ) -> Result<LoopbackCq> {
+        // completions are always stamped
+        self.create_cq(cqe, notifier, comp_vector)
+    }
+
+    fn destroy_cq(&self, cq: &LoopbackCq) -> Result {
+        log::info!("{}: Destroying completion queue", self.name);
+
+        // still referenced by a QP
+        if Arc::strong_count(&cq.0) > 1 {
+            return Err(VerbsError::Busy);
+        }
+
+        Ok(())
+    }
+
+    fn req_notify_cq(&self, cq: &LoopbackCq, solicited_only: bool) -> Result {
+        log::trace!("{}: Requesting completion notification", self.name);
+
+        // only CQs on a completion channel can generate events
+        let notifier = cq.0.notifier.as_ref().ok_or(VerbsError::InvalidArgument)?;
+        notifier.arm(solicited_only);
+
+        Ok(())
+    }
+
+    fn create_qp(&self, pd: &LoopbackPd, init_attr: &mut QpInitAttr<'_, Self>) -> Result<LoopbackQp> {
+        log::info!("{}: Creating queue pair", self.name);
+
+        let cap = &mut init_attr.cap;
+        if cap.max_send_wr > MAX_QP_WR
+            || cap.max_recv_wr > MAX_QP_WR
+            || cap.max_send_sge > MAX_SGE
+            || cap.max_recv_sge > MAX_SGE
+            || cap.max_inline_data > MAX_INLINE_DATA
+        {
+            return Err(VerbsError::InvalidArgument);
+        }
+        cap.max_inline_data = MAX_INLINE_DATA;
+
+        let qp = Arc::new(Qp {
+            qp_num: FABRIC.alloc_id(),
+            pd: pd.id,
+            qp_type: init_attr.qp_type,
+            sq_sig_all: init_attr.sq_sig_all,
+            cap: *cap,
+            send_cq: Arc::clone(&init_attr.send_cq.0),
+            recv_cq: Arc::clone(&init_attr.recv_cq.0),
+            srq: init_attr.srq.map(|srq| Arc::clone(&srq.0)),
+            events: self.events.clone(),
+            state: Mutex::new(QpState {
+                state: ibv_qp_state::IBV_QPS_RESET,
+                dest_qp_num: 0,
+                access: ibv_access_flags(0),
+                qkey: 0,
+                recv: VecDeque::new(),
+                inbound: VecDeque::new(),
+            }),
+        });
+        FABRIC
+            .qps
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(qp.qp_num, Arc::downgrade(&qp));
+        if let Some(srq) = &qp.srq {
+            let mut state = srq.state.lock().unwrap_or_else(PoisonError::into_inner);
+            state.qps.retain(|qp| qp.strong_count() > 0);
+            state.qps.push(Arc::downgrade(&qp));
+        }
+
+        Ok(LoopbackQp(qp))
+    }
+
+    fn destroy_qp(&self, qp: &LoopbackQp) -> Result {
+        log::info!("{}: Destroying queue pair", self.name);
+
+        let qp = &qp.0;
+        FABRIC
+            .qps
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&qp.qp_num);
+
+        // outstanding receive requests are discarded, queued messages fail
+        let mut state = qp.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.recv.clear();
+        let outcomes = qp.enter_error(&mut state);
+        drop(state);
+        report(outcomes);
+
+        Ok(())
+    }
+
+    fn modify_qp(&self, qp: &LoopbackQp, attr: &mut ffi::ibv_qp_attr, attr_mask: ibv_qp_attr_mask) -> Result {
+        log::info!("{}: Modifying queue pair", self.name);
+
+        let qp = &qp.0;
+        let mut state = qp.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if (attr_mask & ibv_qp_attr_mask::IBV_QP_DEST_QPN).0 != 0 {
+            state.dest_qp_num = attr.dest_qp_num;
+        }
+        if (attr_mask & ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS).0 != 0 {
+            state.access = ibv_access_flags(attr.qp_access_flags as _);
+        }
+        if (attr_mask & ibv_qp_attr_mask::IBV_QP_QKEY).0 != 0 {
+            state.qkey = attr.qkey;
+        }
+
+        let mut outcomes = Vec::new();
+        if (attr_mask & ibv_qp_attr_mask::IBV_QP_STATE).0 != 0 {
+            match attr.qp_state {
+                ibv_qp_state::IBV_QPS_ERR => outcomes = qp.enter_error(&mut state),
+                ibv_qp_state::IBV_QPS_RESET => {
+                    state.recv.clear();
+                    outcomes = qp.enter_error(&mut state);
+                }
+                _ => {}
+            }
+            state.state = attr.qp_state;
+        }
+        drop(state);
+        report(outcomes);
+
+        Ok(())
+    }
+
+    fn query_qp(
+        &self,
+        qp: &LoopbackQp,
+        attr: &mut ffi::ibv_qp_attr,
+        _attr_mask: ibv_qp_attr_mask,
+        init_attr: &mut ffi::ibv_qp_init_attr,
+    ) -> Result {
+        log::info!("{}: Querying queue pair", self.name);
+
+        let qp = &qp.0;
+        let state = qp.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        attr.qp_state = state.state;
+        attr.cur_qp_state = state.state;
+        attr.path_mtu = ffi::IBV_MTU_4096;
+        attr.dest_qp_num = state.dest_qp_num;
+        attr.qp_access_flags = state.access.0 as _;
+        attr.qkey = state.qkey;
+        attr.cap = qp.cap;
+        attr.port_num = 1;
+
+        init_attr.cap = qp.cap;
+        init_attr.qp_type = qp.qp_type;
+        init_attr.sq_sig_all = qp.sq_sig_all.into();
+
+        Ok(())
+    }
+
+    fn reg_mr(
+        &self,
+        pd: &LoopbackPd,
+        addr: *mut ::std::os::raw::c_void,
+        length: usize,
+        hca_va: u64,
+        access: ibv_access_flags,
+    ) -> Result<LoopbackMr> {
+        log::info!("{}: Registering memory region", self.name);
+
+        check_access(access)?;
+        // pinned up front, on-demand regions are checked on every access
+        if (access & ibv_access_flags::IBV_ACCESS_ON_DEMAND).0 == 0 && !mapped(addr as usize, length) {
+            return Err(VerbsError::Errno(libc::EFAULT));
+        }
+
+        let key = FABRIC.alloc_id();
+        FABRIC.mrs.write().unwrap_or_else(PoisonError::into_inner).insert(
+            key,
+            MrEntry {
+                pd: pd.id,
+                addr: addr as usize,
+                length,
+                iova: hca_va,
+                access,
+            },
+        );
+
+        Ok(LoopbackMr { key, dmabuf: None })
+    }
+
+    fn reg_dmabuf_mr(
+        &self,
+        pd: &LoopbackPd,
+        offset: u64,
+        length: usize,
+        iova: u64,
+        fd: core::ffi::c_int,
+        access: ibv_access_flags,
+    ) -> Result<LoopbackMr> {
+        log::info!("{}: Registering dma-buf memory region", self.name);
+
+        check_access(access)?;
+        let dmabuf = DmaBuf::map(fd, offset, length)?;
+
+        let key = FABRIC.alloc_id();
+        FABRIC.mrs.write().unwrap_or_else(PoisonError::into_inner).insert(
+            key,
+            MrEntry {
+                pd: pd.id,
+                addr: dmabuf.addr(),
+                length,
+                iova,
+                access,
+            },
+        );
+
+        Ok(LoopbackMr {
+            key,
+            dmabuf: Some(dmabuf),
+        })
+    }
+
+    fn dereg_mr(&self, mr: &LoopbackMr) -> Result {
+        log::info!("{}: Deregistering memory region", self.name);
+
+        FABRIC
+            .mrs
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&mr.key);
+
+        Ok(())
+    }
+
+    fn rereg_mr(&self, mr: &LoopbackMr, rereg: &MrRereg<'_, Self>) -> Result {
+        log::info!("{}: Reregistering memory region", self.name);
+
+        let mut mrs = FABRIC.mrs.write().unwrap_or_else(PoisonError::into_inner);
+        let entry = mrs.get_mut(&mr.key).ok_or(VerbsError::InvalidArgument)?;
+        if let Some(access) = rereg.access {
+            check_access(access)?;
+        }
+        if let Some((addr, length)) = rereg.translation {
+            // the dma-buf mapping is fixed, it would leak into process memory
+            if mr.dmabuf.is_some() {
+                return Err(VerbsError::InvalidArgument);
+            }
+            let access = rereg.access.unwrap_or(entry.access);
+            if (access & ibv_access_flags::IBV_ACCESS_ON_DEMAND).0 == 0 && !mapped(addr as usize, length) {
+                return Err(VerbsError::Errno(libc::EFAULT));
+            }
+        }
+
+        // the key stays, requests resolve it again each time
+        if let Some(pd) = rereg.pd {
+            entry.pd = pd.id;
+        }
+        if let Some((addr, length)) = rereg.translation {
+            entry.addr = addr as usize;
+            entry.length = length;
+            entry.iova = addr as u64;
+        }
+        if let Some(access) = rereg.access {
+            entry.access = access;
+        }
+
+        Ok(())
+    }
+
+    fn create_ah(&self, pd: &LoopbackPd, attr: &ffi::ibv_ah_attr) -> Result<LoopbackAh> {
+        log::info!("{}: Creating address handle", self.name);
+
+        // ports are Ethernet, destinations are GIDs
+        if attr.is_global == 0 || attr.port_num != 1 {
+            return Err(VerbsError::InvalidArgument);
+        }
+
+        // no addresses, no table, the port has only the zero GID
+        let sgid = self
+            .gids
+            .iter()
+            .find(|entry| entry.gid_index == u32::from(attr.grh.sgid_index))
+            .map(|entry| entry.gid);
+        if sgid.is_none() && !self.gids.is_empty() {
+            return Err(VerbsError::InvalidArgument);
+        }
+
+        Ok(LoopbackAh {
+            pd: pd.id,
+            attr: *attr,
+            sgid: sgid.unwrap_or_default(),
+        })
+    }
+
+    fn destroy_ah(&self, _ah: &LoopbackAh) -> Result {
+        log::info!("{}: Destroying address handle", self.name);
+
+        Ok(())
+    }
+
+    fn post_send(&self, qp: &LoopbackQp, wr: &SendWr, ud: Option<&UdDest<'_, Self>>) -> Result {
+        log::trace!("{}: Posting send work request", self.name);
+
+        qp.0.post_send(wr, ud)
+    }
+
+    fn post_recv(&self, qp: &LoopbackQp, wr: *mut ffi::ibv_recv_wr, bad_wr: &mut *mut ffi::ibv_recv_wr) -> Result {
+        log::trace!("{}: Posting receive work request", self.name);
+
+        let mut cur = wr;
+        // Safety: the application hands us a valid, null terminated list.
+        while let Some(wr) = unsafe { cur.as_ref() } {
+            if let Err(err) = qp.0.post_recv(wr) {
+                *bad_wr = cur;
+                return Err(err);
+            }
+            cur = wr.next;
+        }
+
+        Ok(())
+    }
+
+    fn poll_cq(&self, cq: &LoopbackCq, wc: &mut [ffi::ibv_wc]) -> Result<usize> {
+        log::trace!("{}: Polling completion queue", self.name);
+
+        let mut entries = cq.0.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let polled = wc.len().min(entries.len());
+        for (dst, src) in wc.iter_mut().zip(entries.drain(..polled)) {
+            *dst = src.wc;
+        }
+
+        Ok(polled)
+    }
+
+    fn poll_cq_ex(&self, cq: &LoopbackCq, completions: &mut [Completion]) -> Result<usize> {
+        log::trace!("{}: Polling completion queue", self.name);
+
+        let mut entries = cq.0.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let polled = completions.len().min(entries.len());
+        for (dst, src) in completions.iter_mut().zip(entries.drain(..polled)) {
+            *dst = src;
+        }
+
+        Ok(polled)
+    }
+
+    fn create_srq(&self, pd: &LoopbackPd, attr: &mut ffi::ibv_srq_attr) -> Result<LoopbackSrq> {
+        log::info!("{}: Creating shared receive queue", self.name);
+
+        if !(1..=MAX_QP_WR).contains(&attr.max_wr) || attr.max_sge > MAX_SGE {
+            return Err(VerbsError::InvalidArgument);
+        }
+        // the limit is only armed by modify_srq
+        attr.srq_limit = 0;
+
+        Ok(LoopbackSrq(Arc::new(Srq {
+            srq_num: FABRIC.alloc_id(),
+            pd: pd.id,
+            events: self.events.clone(),
+            state: Mutex::new(SrqState {
+                attr: *attr,
+                recv: VecDeque::new(),
+                qps: Vec::new(),
+            }),
+        })))
+    }
+
+    fn modify_srq(&self, srq: &LoopbackSrq, attr: &mut ffi::ibv_srq_attr, attr_mask: ibv_srq_attr_mask) -> Result {
+        log::info!("{}: Modifying shared receive queue", self.name);
+
+        let mut state = srq.0.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if (attr_mask & ibv_srq_attr_mask::IBV_SRQ_MAX_WR).0 != 0 {
+            // resizing below the posted requests would drop some
+            if attr.max_wr > MAX_QP_WR || (attr.max_wr as usize) < state.recv.len() {
+                return Err(VerbsError::InvalidArgument);
+            }
+            state.attr.max_wr = attr.max_wr;
+        }
+        if (attr_mask & ibv_srq_attr_mask::IBV_SRQ_LIMIT).0 != 0 {
+            if attr.srq_limit > state.attr.max_wr {
+                return Err(VerbsError::InvalidArgument);
+            }
+            state.attr.srq_limit = attr.srq_limit;
+        }
+
+        Ok(())
+    }
+
+    fn query_srq(&self, srq: &LoopbackSrq, attr: &mut ffi::ibv_srq_attr) -> Result {
+        log::info!("{}: Querying shared receive queue", self.name);
+
+        *attr = srq.0.state.lock().unwrap_or_else(PoisonError::into_inner).attr;
+
+        Ok(())
+    }
+
+    fn destroy_srq(&self, srq: &LoopbackSrq) -> Result {
+        log::info!("{}: Destroying shared receive queue", self.name);
+
+        // still referenced by a QP
+        if Arc::strong_count(&srq.0) > 1 {
+            return Err(VerbsError::Busy);
+        }
+
+        Ok(())
+    }
+
+    fn post_srq_recv(
+        &self,
+        srq: &LoopbackSrq,
+        wr: *mut ffi::ibv_recv_wr,
+        bad_wr: &mut *mut ffi::ibv_recv_wr,
+    ) -> Result {
+        log::trace!("{}: Posting shared receive work request", self.name);
+
+        let mut cur = wr;
+        // Safety: the application hands us a valid, null terminated list.
+        while let Some(wr) = unsafe { cur.as_ref() } {
+            if let Err(err) = srq.0.post_recv(wr) {
+                *bad_wr = cur;
+                return Err(err);
+            }
+            cur = wr.next;
+        }
+
+        Ok(())
+    }
+}
+
+/// Check `access` is valid for a memory region.
+fn check_access(access: ibv_access_flags) -> Result {
+    // remote write and atomics are only allowed on locally writable memory
+    let needs_local_write = ibv_access_flags::IBV_ACCESS_REMOTE_WRITE | ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC;
+    if (access & needs_local_write).0 != 0 && (access & ibv_access_flags::IBV_ACCESS_LOCAL_WRITE).0 == 0 {
+        return Err(VerbsError::InvalidArgument);
+    }
+    Ok(())
+}
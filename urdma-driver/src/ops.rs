@@ -1,278 +1,551 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, PoisonError};
 
-use provider::Result;
+use provider::{
+    AsyncEvents, Capabilities, CqNotifier, DeviceAttrEx, MemoryWindow, MwBind, Payload, QpInitAttr, Result, SendOp,
+    SendWr, UdDest, Verbs, VerbsError,
+};
 
-use super::rxe::Rxe;
-use crate::urdma::urdma_device;
-
-const RXE_DEVICE_NAME: &str = "rxe";
+use super::rxe::{CompChannel, Relay, Rxe, RxeAh, RxeCq, RxeMr, RxeMw, RxePd, RxeQp, RxeSrq};
+use crate::{config, urdma};
 
 impl provider::Provider for Rxe {
+    type Ah = RxeAh;
+    type Cq = RxeCq;
+    type Mr = RxeMr;
+    type Mw = RxeMw;
+    type Pd = RxePd;
+    type Qp = RxeQp;
+    type Srq = RxeSrq;
+
     fn init() -> Result {
         let _ = env_logger::try_init();
+
+        let profile = config::profile().map_err(|err| {
+            log::error!("{err}");
+            VerbsError::InvalidArgument
+        })?;
+        profile.install();
         Ok(())
     }
 
-    unsafe fn from_ibv_device(ibdev: *mut ffi::ibv_device) -> *const Self {
-        let ptr = ibdev.cast::<u8>().cast_const();
-        let offset: usize = core::mem::offset_of!(urdma_device, verbs_dev);
-        let urdma = unsafe { ptr.sub(offset) }.cast::<urdma_device>();
-        let urdma = unsafe { urdma.as_ref() }.unwrap();
-        urdma.driver_data.cast_const().cast()
+    unsafe fn from_ibv_device(ibdev: *mut ffi::ibv_device) -> Result<*const Self> {
+        unsafe { urdma::driver_data(ibdev) }.map(|driver_data| driver_data.cast())
     }
 
-    fn new(sysfs_name: &str) -> Result<Arc<Self>> {
+    fn new(sysfs_name: &str, events: AsyncEvents) -> Result<Arc<Self>> {
         log::info!("Creating new RDMA device with sysfs name: {sysfs_name}");
 
+        let selector = config::backing_device(sysfs_name).map_err(|err| {
+            log::error!("{sysfs_name}: {err}");
+            VerbsError::InvalidArgument
+        })?;
+
         let mut num_devices = 0;
 
         // Safety: `num_devices` is a valid pointer.
         let list = unsafe { ffi::ibv_get_device_list(&raw mut num_devices) };
+        if list.is_null() {
+            return Err(VerbsError::last_os_error());
+        }
 
         // Safety: `list` is at least `num_devices` long.
-        let device_list = unsafe { std::slice::from_raw_parts(list, num_devices.try_into().unwrap()) };
-
-        let Some(rxe_device) = device_list.iter().copied().find(|dev_ptr| {
-            unsafe { dev_ptr.as_ref() }
-                .map(|dev| dev.name.as_ptr())
-                .map(|name| unsafe { core::ffi::CStr::from_ptr(name) })
-                .map(|name| name.to_str().map(|name| name.contains(RXE_DEVICE_NAME)))
-                == Some(Ok(true))
-        }) else {
-            return Err(-1);
+        let device_list = unsafe { std::slice::from_raw_parts(list, usize::try_from(num_devices).unwrap_or(0)) };
+
+        let rxe_device = device_list.iter().copied().find(|&dev| selector.matches(dev));
+
+        let rxe = match rxe_device {
+            Some(rxe_device) => {
+                log::info!("{sysfs_name}: backed by {selector}");
+                unsafe { ffi::ibv_open_device(rxe_device) }
+            }
+            None => {
+                let available = device_list
+                    .iter()
+                    .filter_map(|dev| unsafe { dev.as_ref() })
+                    .map(config::device_name)
+                    .collect::<Vec<_>>();
+                log::error!("{sysfs_name}: backing device {selector} not found, available devices: {available:?}");
+                core::ptr::null_mut()
+            }
         };
 
-        let rxe = unsafe { ffi::ibv_open_device(rxe_device) };
-
         unsafe { ffi::ibv_free_device_list(list) };
 
-        Ok(Arc::new(Rxe { rxe_context: rxe }))
+        if rxe.is_null() {
+            return Err(if rxe_device.is_some() {
+                VerbsError::last_os_error()
+            } else {
+                VerbsError::DeviceGone
+            });
+        }
+
+        let async_relay = Relay::async_events(rxe, events).inspect_err(|_| {
+            unsafe { ffi::ibv_close_device(rxe) };
+        })?;
+
+        Ok(Arc::new(Rxe {
+            rxe_context: rxe,
+            comp_channel: Mutex::default(),
+            async_relay,
+        }))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            // the rdma-core rxe provider implements neither, libibverbs would fail them with EOPNOTSUPP
+            verbs: Verbs::ALL.without(Verbs::REREG_MR | Verbs::REG_DMABUF_MR),
+            qp_types: &[
+                ffi::ibv_qp_type::IBV_QPT_RC,
+                ffi::ibv_qp_type::IBV_QPT_UC,
+                ffi::ibv_qp_type::IBV_QPT_UD,
+            ],
+        }
     }
 
-    fn alloc_pd(&self) -> *mut ffi::ibv_pd {
+    fn alloc_pd(&self) -> Result<RxePd> {
         log::info!("Allocating protection domain");
 
-        unsafe { ffi::ibv_alloc_pd(self.rxe_context) }
+        let pd = unsafe { ffi::ibv_alloc_pd(self.rxe_context) };
+
+        RxePd::new(pd).ok_or_else(VerbsError::last_os_error)
     }
 
-    fn dealloc_pd(&self, pd: *mut ffi::ibv_pd) -> Result {
+    fn dealloc_pd(&self, pd: &RxePd) -> Result {
         log::info!("Deallocating protection domain");
 
-        let _old_context = unsafe { pd.as_ref() }.unwrap().context;
-        let rxe_context = self.rxe_context;
+        let rc = unsafe { ffi::ibv_dealloc_pd(pd.as_ptr()) };
+
+        VerbsError::check(rc)
+    }
+
+    fn query_device(&self, device_attr: &mut ffi::ibv_device_attr) -> Result {
+        log::info!("Querying device attributes");
 
-        let pd_mut = unsafe { pd.as_mut() }.unwrap();
+        let rxe_context = self.rxe_context;
+        let ctx = unsafe { rxe_context.as_ref() }.ok_or(VerbsError::DeviceGone)?;
 
-        pd_mut.context = rxe_context;
-        let rc = unsafe { ffi::ibv_dealloc_pd(pd) };
+        let query_device = ctx.ops._compat_query_device.ok_or(VerbsError::NotSupported)?;
+        let rc = unsafe { query_device(rxe_context, device_attr) };
 
-        if rc == 0 { Ok(()) } else { Err(rc) }
+        VerbsError::check(rc)
     }
 
-    fn query_device(
-        &self,
-        _input: *const ffi::ibv_query_device_ex_input,
-        device_attr: *mut ffi::ibv_device_attr,
-        _attr_size: usize,
-    ) -> Result {
-        log::info!("Querying device attributes");
+    fn query_device_ex(&self, attr: &mut DeviceAttrEx) -> Result {
+        log::info!("Querying extended device attributes");
 
         let rxe_context = self.rxe_context;
-        let ctx = unsafe { rxe_context.as_ref() }.unwrap();
+        let ctx = unsafe { rxe_context.as_ref() }.ok_or(VerbsError::DeviceGone)?;
+
+        // what verbs_get_ctx_op() does for the inline ibv_query_device_ex()
+        if ctx.abi_compat != core::ptr::without_provenance_mut(usize::MAX) {
+            return self.query_device(&mut attr.orig_attr);
+        }
+        let vctx = unsafe {
+            &*rxe_context
+                .byte_sub(core::mem::offset_of!(ffi::verbs_context, context))
+                .cast::<ffi::verbs_context>()
+        };
+        let query_device_ex = vctx.query_device_ex.filter(|_| {
+            vctx.sz >= size_of::<ffi::verbs_context>() - core::mem::offset_of!(ffi::verbs_context, query_device_ex)
+        });
+        let Some(query_device_ex) = query_device_ex else {
+            return self.query_device(&mut attr.orig_attr);
+        };
 
-        let rc = unsafe { ctx.ops._compat_query_device.unwrap()(rxe_context, device_attr) };
+        // rxe fills only the `attr_size` bytes we hand back
+        let size = attr.size();
+        let rc = unsafe { query_device_ex(rxe_context, core::ptr::null(), &raw mut **attr, size) };
 
-        if rc == 0 { Ok(()) } else { Err(rc) }
+        VerbsError::check(rc)
     }
 
-    fn query_port(&self, port_num: u8, port_attr: *mut ffi::ibv_port_attr) -> Result {
+    fn query_port(&self, port_num: u8, port_attr: &mut ffi::ibv_port_attr) -> Result {
         log::info!("Querying port attributes");
 
         let rxe_context = self.rxe_context;
-        let ctx = unsafe { rxe_context.as_ref() }.unwrap();
+        let ctx = unsafe { rxe_context.as_ref() }.ok_or(VerbsError::DeviceGone)?;
+
+        let query_port = ctx.ops._compat_query_port.ok_or(VerbsError::NotSupported)?;
+        let rc = unsafe { query_port(rxe_context, port_num, (&raw mut *port_attr).cast()) };
+
+        VerbsError::check(rc)
+    }
 
-        let rc = unsafe { ctx.ops._compat_query_port.unwrap()(rxe_context, port_num, port_attr.cast()) };
+    fn query_gid_table(&self, port_num: u8) -> Result<Vec<ffi::ibv_gid_entry>> {
+        log::info!("Querying GID table");
+
+        let mut port_attr = ffi::ibv_port_attr::default();
+        self.query_port(port_num, &mut port_attr)?;
+
+        // rxe has a single port, whose table is all there is
+        let mut entries = vec![ffi::ibv_gid_entry::default(); usize::try_from(port_attr.gid_tbl_len).unwrap_or(0)];
+        let rc = unsafe {
+            ffi::_ibv_query_gid_table(
+                self.rxe_context,
+                entries.as_mut_ptr(),
+                entries.len(),
+                0,
+                size_of::<ffi::ibv_gid_entry>(),
+            )
+        };
+        let len = usize::try_from(rc).map_err(|_| VerbsError::from_errno(rc.unsigned_abs() as _))?;
+        entries.truncate(len);
+        entries.retain(|entry| entry.port_num == u32::from(port_num));
 
-        if rc == 0 { Ok(()) } else { Err(rc) }
+        Ok(entries)
     }
 
     fn create_cq(
         &self,
         cqe: core::ffi::c_int,
-        channel: *mut ffi::ibv_comp_channel,
+        notifier: Option<CqNotifier>,
         comp_vector: core::ffi::c_int,
-    ) -> *mut ffi::ibv_cq {
+    ) -> Result<RxeCq> {
         log::info!("Creating completion queue");
 
-        unsafe { ffi::ibv_create_cq(self.rxe_context, cqe, core::ptr::null_mut(), channel, comp_vector) }
+        // events of the shadow CQ arrive on our own channel and are relayed to the notifier
+        let (channel, cq_context) = match notifier {
+            Some(notifier) => {
+                let mut comp_channel = self.comp_channel.lock().unwrap_or_else(PoisonError::into_inner);
+                if comp_channel.is_none() {
+                    *comp_channel = Some(CompChannel::new(self.rxe_context)?);
+                }
+                let channel = comp_channel.as_ref().map_or(core::ptr::null_mut(), CompChannel::as_ptr);
+                (channel, Box::into_raw(Box::new(notifier)))
+            }
+            None => (core::ptr::null_mut(), core::ptr::null_mut()),
+        };
+
+        let cq = unsafe { ffi::ibv_create_cq(self.rxe_context, cqe, cq_context.cast(), channel, comp_vector) };
+
+        RxeCq::new(cq).ok_or_else(|| {
+            let err = VerbsError::last_os_error();
+            if !cq_context.is_null() {
+                drop(unsafe { Box::from_raw(cq_context) });
+            }
+            err
+        })
     }
 
-    fn destroy_cq(&self, cq: *mut ffi::ibv_cq) -> Result {
+    fn destroy_cq(&self, cq: &RxeCq) -> Result {
         log::info!("Destroying completion queue");
 
-        let _old_context = unsafe { cq.as_ref() }.unwrap().context;
-        let rxe_context = self.rxe_context;
+        let cq_context = unsafe { (*cq.as_ptr()).cq_context }.cast::<CqNotifier>();
+        let rc = unsafe { ffi::ibv_destroy_cq(cq.as_ptr()) };
+        VerbsError::check(rc)?;
+
+        if !cq_context.is_null() {
+            drop(unsafe { Box::from_raw(cq_context) });
+        }
+
+        Ok(())
+    }
+
+    fn req_notify_cq(&self, cq: &RxeCq, solicited_only: bool) -> Result {
+        log::trace!("Requesting completion notification");
 
-        let cq_mut = unsafe { cq.as_mut() }.unwrap();
+        let ctx = unsafe { self.rxe_context.as_ref() }.ok_or(VerbsError::DeviceGone)?;
 
-        cq_mut.context = rxe_context;
-        let rc = unsafe { ffi::ibv_destroy_cq(cq) };
+        let req_notify_cq = ctx.ops.req_notify_cq.ok_or(VerbsError::NotSupported)?;
+        let rc = unsafe { req_notify_cq(cq.as_ptr(), solicited_only.into()) };
 
-        if rc == 0 { Ok(()) } else { Err(rc) }
+        VerbsError::check(rc)
     }
 
-    fn create_qp(&self, pd: *mut ffi::ibv_pd, init_attr: *mut ffi::ibv_qp_init_attr) -> *mut ffi::ibv_qp {
+    fn create_qp(&self, pd: &RxePd, init_attr: &mut QpInitAttr<'_, Self>) -> Result<RxeQp> {
         log::info!("Creating queue pair");
 
-        let old_context = unsafe { pd.as_ref() }.unwrap().context;
-        let rxe_context = self.rxe_context;
-
-        let pd_mut = unsafe { pd.as_mut() }.unwrap();
+        let mut attr = ffi::ibv_qp_init_attr {
+            send_cq: init_attr.send_cq.as_ptr(),
+            recv_cq: init_attr.recv_cq.as_ptr(),
+            srq: init_attr.srq.map_or(core::ptr::null_mut(), RxeSrq::as_ptr),
+            cap: init_attr.cap,
+            qp_type: init_attr.qp_type,
+            sq_sig_all: init_attr.sq_sig_all.into(),
+            ..Default::default()
+        };
 
-        pd_mut.context = rxe_context;
-        let qp = unsafe { ffi::ibv_create_qp(pd, init_attr) };
-        pd_mut.context = old_context;
+        let qp = unsafe { ffi::ibv_create_qp(pd.as_ptr(), &raw mut attr) };
+        let qp = RxeQp::new(qp).ok_or_else(VerbsError::last_os_error)?;
 
-        let qp_mut = unsafe { qp.as_mut() }.unwrap();
-        qp_mut.context = old_context;
+        init_attr.cap = attr.cap;
 
-        qp
+        Ok(qp)
     }
 
-    fn destroy_qp(&self, qp: *mut ffi::ibv_qp) -> Result {
+    fn destroy_qp(&self, qp: &RxeQp) -> Result {
         log::info!("Destroying queue pair");
 
-        let _old_context = unsafe { qp.as_ref() }.unwrap().context;
-        let rxe_context = self.rxe_context;
-
-        let qp_mut = unsafe { qp.as_mut() }.unwrap();
-
-        qp_mut.context = rxe_context;
-        let rc = unsafe { ffi::ibv_destroy_qp(qp) };
+        let rc = unsafe { ffi::ibv_destroy_qp(qp.as_ptr()) };
 
-        if rc == 0 { Ok(()) } else { Err(rc) }
+        VerbsError::check(rc)
     }
 
-    fn modify_qp(&self, qp: *mut ffi::ibv_qp, attr: *mut ffi::ibv_qp_attr, attr_mask: core::ffi::c_int) -> Result {
+    fn modify_qp(&self, qp: &RxeQp, attr: &mut ffi::ibv_qp_attr, attr_mask: ffi::ibv_qp_attr_mask) -> Result {
         log::info!("Modifying queue pair");
 
-        let old_context = unsafe { qp.as_ref() }.unwrap().context;
-        let rxe_context = self.rxe_context;
-
-        let qp_mut = unsafe { qp.as_mut() }.unwrap();
-
-        qp_mut.context = rxe_context;
-        let rc = unsafe { ffi::ibv_modify_qp(qp, attr, attr_mask) };
-        qp_mut.context = old_context;
+        let rc = unsafe { ffi::ibv_modify_qp(qp.as_ptr(), attr, attr_mask.0 as _) };
 
-        if rc == 0 { Ok(()) } else { Err(rc) }
+        VerbsError::check(rc)
     }
 
     fn query_qp(
         &self,
-        qp: *mut ffi::ibv_qp,
-        attr: *mut ffi::ibv_qp_attr,
-        attr_mask: core::ffi::c_int,
-        init_attr: *mut ffi::ibv_qp_init_attr,
+        qp: &RxeQp,
+        attr: &mut ffi::ibv_qp_attr,
+        attr_mask: ffi::ibv_qp_attr_mask,
+        init_attr: &mut ffi::ibv_qp_init_attr,
     ) -> Result {
         log::info!("Querying queue pair");
 
-        let old_context = unsafe { qp.as_ref() }.unwrap().context;
-        let rxe_context = self.rxe_context;
-
-        let qp_mut = unsafe { qp.as_mut() }.unwrap();
-
-        qp_mut.context = rxe_context;
-        let rc = unsafe { ffi::ibv_query_qp(qp, attr, attr_mask, init_attr) };
-        qp_mut.context = old_context;
+        let rc = unsafe { ffi::ibv_query_qp(qp.as_ptr(), attr, attr_mask.0 as _, init_attr) };
 
-        if rc == 0 { Ok(()) } else { Err(rc) }
+        VerbsError::check(rc)
     }
 
     fn reg_mr(
         &self,
-        pd: *mut ffi::ibv_pd,
+        pd: &RxePd,
         addr: *mut ::std::os::raw::c_void,
         length: usize,
-        _hca_va: u64,
-        access: core::ffi::c_int,
-    ) -> *mut ffi::ibv_mr {
+        hca_va: u64,
+        access: ffi::ibv_access_flags,
+    ) -> Result<RxeMr> {
         log::info!("Registering memory region");
 
-        let old_context = unsafe { pd.as_ref() }.unwrap().context;
-        let rxe_context = self.rxe_context;
+        // rxe fails on-demand paging with EOPNOTSUPP unless the kernel supports it
+        let mr = unsafe { ffi::ibv_reg_mr_iova2(pd.as_ptr(), addr, length, hca_va, access.0 as _) };
 
-        let pd_mut = unsafe { pd.as_mut() }.unwrap();
+        RxeMr::new(mr).ok_or_else(VerbsError::last_os_error)
+    }
 
-        pd_mut.context = rxe_context;
-        let mr = unsafe { ffi::ibv_reg_mr(pd, addr, length, access) };
-        pd_mut.context = old_context;
+    fn dereg_mr(&self, mr: &RxeMr) -> Result {
+        log::info!("Deregistering memory region");
 
-        let mr_mut = unsafe { mr.as_mut() }.unwrap();
-        mr_mut.context = old_context;
+        let rc = unsafe { ffi::ibv_dereg_mr(mr.as_ptr()) };
 
-        mr
+        VerbsError::check(rc)
     }
 
-    fn dereg_mr(&self, mr: *mut ffi::ibv_mr) -> Result {
-        log::info!("Deregistering memory region");
+    fn alloc_mw(&self, pd: &RxePd, mw_type: ffi::ibv_mw_type) -> Result<RxeMw> {
+        log::info!("Allocating memory window");
 
-        let _old_context = unsafe { mr.as_ref() }.unwrap().context;
-        let rxe_context = self.rxe_context;
+        let ctx = unsafe { self.rxe_context.as_ref() }.ok_or(VerbsError::DeviceGone)?;
+
+        let alloc_mw = ctx.ops.alloc_mw.ok_or(VerbsError::NotSupported)?;
+        let mw = unsafe { alloc_mw(pd.as_ptr(), mw_type) };
+
+        RxeMw::new(mw).ok_or_else(VerbsError::last_os_error)
+    }
+
+    fn dealloc_mw(&self, mw: &RxeMw) -> Result {
+        log::info!("Deallocating memory window");
 
-        let mr_mut = unsafe { mr.as_mut() }.unwrap();
+        let ctx = unsafe { self.rxe_context.as_ref() }.ok_or(VerbsError::DeviceGone)?;
 
-        mr_mut.context = rxe_context;
-        let rc = unsafe { ffi::ibv_dereg_mr(mr) };
+        let dealloc_mw = ctx.ops.dealloc_mw.ok_or(VerbsError::NotSupported)?;
+        let rc = unsafe { dealloc_mw(mw.as_ptr()) };
 
-        if rc == 0 { Ok(()) } else { Err(rc) }
+        VerbsError::check(rc)
     }
 
-    fn post_send(&self, qp: *mut ffi::ibv_qp, wr: *mut ffi::ibv_send_wr, bad_wr: *mut *mut ffi::ibv_send_wr) -> Result {
-        log::trace!("Posting send work request");
+    fn bind_mw(&self, qp: &RxeQp, mw: &RxeMw, bind: &MwBind<'_, Self>) -> Result<u32> {
+        log::trace!("Binding memory window");
 
-        let old_context = unsafe { qp.as_ref() }.unwrap().context;
-        let rxe_context = self.rxe_context;
-        let ctx = unsafe { rxe_context.as_ref() }.unwrap();
+        let ctx = unsafe { self.rxe_context.as_ref() }.ok_or(VerbsError::DeviceGone)?;
+
+        let bind_info = ffi::ibv_mw_bind_info {
+            mr: bind.mr.map_or(core::ptr::null_mut(), RxeMr::as_ptr),
+            addr: bind.addr,
+            length: bind.length,
+            mw_access_flags: bind.access.0 as _,
+        };
+        let rc = match bind.rkey {
+            // type 2 windows are bound by a work request with the key the application chose
+            Some(rkey) => {
+                let mut shadow_wr = ffi::ibv_send_wr {
+                    wr_id: bind.wr_id,
+                    opcode: ffi::ibv_wr_opcode::IBV_WR_BIND_MW,
+                    send_flags: bind.flags.0,
+                    ..Default::default()
+                };
+                shadow_wr.__bindgen_anon_2.bind_mw.mw = mw.as_ptr();
+                shadow_wr.__bindgen_anon_2.bind_mw.rkey = rkey;
+                shadow_wr.__bindgen_anon_2.bind_mw.bind_info = bind_info;
+
+                let post_send = ctx.ops.post_send.ok_or(VerbsError::NotSupported)?;
+                let mut bad_wr = core::ptr::null_mut();
+                unsafe { post_send(qp.as_ptr(), &raw mut shadow_wr, &raw mut bad_wr) }
+            }
+            None => {
+                let mut mw_bind = ffi::ibv_mw_bind {
+                    wr_id: bind.wr_id,
+                    send_flags: bind.flags.0,
+                    bind_info,
+                };
+
+                let bind_mw = ctx.ops.bind_mw.ok_or(VerbsError::NotSupported)?;
+                unsafe { bind_mw(qp.as_ptr(), mw.as_ptr(), &raw mut mw_bind) }
+            }
+        };
+        VerbsError::check(rc)?;
+
+        // rxe updates the key of the shadow on bind
+        Ok(bind.rkey.unwrap_or_else(|| mw.rkey()))
+    }
 
-        let qp_mut = unsafe { qp.as_mut() }.unwrap();
+    fn create_ah(&self, pd: &RxePd, attr: &ffi::ibv_ah_attr) -> Result<RxeAh> {
+        log::info!("Creating address handle");
 
-        qp_mut.context = rxe_context;
-        let rc = unsafe { ctx.ops.post_send.unwrap()(qp, wr, bad_wr) };
-        qp_mut.context = old_context;
+        // rxe resolves the destination MAC while creating the AH, the attributes are only read
+        let ah = unsafe { ffi::ibv_create_ah(pd.as_ptr(), core::ptr::from_ref(attr).cast_mut()) };
 
-        if rc == 0 { Ok(()) } else { Err(rc) }
+        RxeAh::new(ah).ok_or_else(VerbsError::last_os_error)
     }
 
-    fn post_recv(&self, qp: *mut ffi::ibv_qp, wr: *mut ffi::ibv_recv_wr, bad_wr: *mut *mut ffi::ibv_recv_wr) -> Result {
-        log::trace!("Posting receive work request");
+    fn destroy_ah(&self, ah: &RxeAh) -> Result {
+        log::info!("Destroying address handle");
 
-        let old_context = unsafe { qp.as_ref() }.unwrap().context;
-        let rxe_context = self.rxe_context;
-        let ctx = unsafe { rxe_context.as_ref() }.unwrap();
+        let rc = unsafe { ffi::ibv_destroy_ah(ah.as_ptr()) };
+
+        VerbsError::check(rc)
+    }
+
+    fn post_send(&self, qp: &RxeQp, wr: &SendWr, ud: Option<&UdDest<'_, Self>>) -> Result {
+        log::trace!("Posting send work request");
+
+        let ctx = unsafe { self.rxe_context.as_ref() }.ok_or(VerbsError::DeviceGone)?;
+
+        // rxe copies inline data while posting, the request may point into `wr`
+        let (mut sges, flags) = match &wr.payload {
+            Payload::Sges(sges) => (sges.clone(), wr.flags),
+            Payload::Inline(data) => {
+                let sge = ffi::ibv_sge {
+                    addr: data.as_ptr() as u64,
+                    length: u32::try_from(data.len()).map_err(|_| VerbsError::InvalidArgument)?,
+                    lkey: 0,
+                };
+                (vec![sge], wr.flags | ffi::ibv_send_flags::IBV_SEND_INLINE)
+            }
+        };
+        let mut shadow_wr = ffi::ibv_send_wr {
+            wr_id: wr.wr_id,
+            sg_list: sges.as_mut_ptr(),
+            num_sge: core::ffi::c_int::try_from(sges.len()).map_err(|_| VerbsError::InvalidArgument)?,
+            opcode: wr.op.wr_opcode(),
+            send_flags: flags.0,
+            ..Default::default()
+        };
+        if let Some(imm_data) = wr.op.imm_data() {
+            shadow_wr.__bindgen_anon_1.imm_data = imm_data;
+        }
+        if let Some(invalidate_rkey) = wr.op.invalidate_rkey() {
+            shadow_wr.__bindgen_anon_1.invalidate_rkey = invalidate_rkey;
+        }
+        // the shadow AH, rxe must never see one of the urdma context
+        if let Some(ud) = ud {
+            shadow_wr.wr.ud.ah = ud.ah.as_ptr();
+            shadow_wr.wr.ud.remote_qpn = ud.remote_qpn;
+            shadow_wr.wr.ud.remote_qkey = ud.remote_qkey;
+        }
+        match wr.op {
+            SendOp::Send | SendOp::SendWithImm(_) | SendOp::LocalInv(_) | SendOp::SendWithInv(_) => {}
+            SendOp::RdmaWrite(remote) | SendOp::RdmaWriteWithImm(remote, _) | SendOp::RdmaRead(remote) => {
+                shadow_wr.wr.rdma.remote_addr = remote.addr;
+                shadow_wr.wr.rdma.rkey = remote.rkey;
+            }
+            SendOp::AtomicCmpAndSwp { remote, compare, swap } => {
+                shadow_wr.wr.atomic.remote_addr = remote.addr;
+                shadow_wr.wr.atomic.rkey = remote.rkey;
+                shadow_wr.wr.atomic.compare_add = compare;
+                shadow_wr.wr.atomic.swap = swap;
+            }
+            SendOp::AtomicFetchAndAdd { remote, add } => {
+                shadow_wr.wr.atomic.remote_addr = remote.addr;
+                shadow_wr.wr.atomic.rkey = remote.rkey;
+                shadow_wr.wr.atomic.compare_add = add;
+            }
+        }
+
+        let post_send = ctx.ops.post_send.ok_or(VerbsError::NotSupported)?;
+        let mut bad_wr = core::ptr::null_mut();
+        let rc = unsafe { post_send(qp.as_ptr(), &raw mut shadow_wr, &raw mut bad_wr) };
+
+        VerbsError::check(rc)
+    }
+
+    fn post_recv(&self, qp: &RxeQp, wr: *mut ffi::ibv_recv_wr, bad_wr: &mut *mut ffi::ibv_recv_wr) -> Result {
+        log::trace!("Posting receive work request");
 
-        let qp_mut = unsafe { qp.as_mut() }.unwrap();
+        let ctx = unsafe { self.rxe_context.as_ref() }.ok_or(VerbsError::DeviceGone)?;
 
-        qp_mut.context = rxe_context;
-        let rc = unsafe { ctx.ops.post_recv.unwrap()(qp, wr, bad_wr) };
-        qp_mut.context = old_context;
+        let post_recv = ctx.ops.post_recv.ok_or(VerbsError::NotSupported)?;
+        let rc = unsafe { post_recv(qp.as_ptr(), wr, bad_wr) };
 
-        if rc == 0 { Ok(()) } else { Err(rc) }
+        VerbsError::check(rc)
     }
 
-    fn poll_cq(&self, cq: *mut ffi::ibv_cq, num_entries: ::std::os::raw::c_int, wc: *mut ffi::ibv_wc) -> Result {
+    fn poll_cq(&self, cq: &RxeCq, wc: &mut [ffi::ibv_wc]) -> Result<usize> {
         log::trace!("Polling completion queue");
 
-        let old_context = unsafe { cq.as_ref() }.unwrap().context;
-        let rxe_context = self.rxe_context;
-        let ctx = unsafe { rxe_context.as_ref() }.unwrap();
+        let ctx = unsafe { self.rxe_context.as_ref() }.ok_or(VerbsError::DeviceGone)?;
+        let num_entries = wc.len().try_into().unwrap_or(core::ffi::c_int::MAX);
+
+        let poll_cq = ctx.ops.poll_cq.ok_or(VerbsError::NotSupported)?;
+        // number of completions polled, negative errno on failure
+        let rc = unsafe { poll_cq(cq.as_ptr(), num_entries, wc.as_mut_ptr()) };
+
+        usize::try_from(rc).map_err(|_| VerbsError::from_errno(-rc))
+    }
+
+    fn create_srq(&self, pd: &RxePd, attr: &mut ffi::ibv_srq_attr) -> Result<RxeSrq> {
+        log::info!("Creating shared receive queue");
+
+        let mut init_attr = ffi::ibv_srq_init_attr {
+            attr: *attr,
+            ..Default::default()
+        };
+
+        let srq = unsafe { ffi::ibv_create_srq(pd.as_ptr(), &raw mut init_attr) };
+        let srq = RxeSrq::new(srq).ok_or_else(VerbsError::last_os_error)?;
+
+        *attr = init_attr.attr;
+
+        Ok(srq)
+    }
+
+    fn modify_srq(&self, srq: &RxeSrq, attr: &mut ffi::ibv_srq_attr, attr_mask: ffi::ibv_srq_attr_mask) -> Result {
+        log::info!("Modifying shared receive queue");
+
+        let rc = unsafe { ffi::ibv_modify_srq(srq.as_ptr(), attr, attr_mask.0 as _) };
+
+        VerbsError::check(rc)
+    }
+
+    fn query_srq(&self, srq: &RxeSrq, attr: &mut ffi::ibv_srq_attr) -> Result {
+        log::info!("Querying shared receive queue");
+
+        let rc = unsafe { ffi::ibv_query_srq(srq.as_ptr(), attr) };
+
+        VerbsError::check(rc)
+    }
+
+    fn destroy_srq(&self, srq: &RxeSrq) -> Result {
+        log::info!("Destroying shared receive queue");
+
+        let rc = unsafe { ffi::ibv_destroy_srq(srq.as_ptr()) };
+
+        VerbsError::check(rc)
+    }
+
+    fn post_srq_recv(&self, srq: &RxeSrq, wr: *mut ffi::ibv_recv_wr, bad_wr: &mut *mut ffi::ibv_recv_wr) -> Result {
+        log::trace!("Posting shared receive work request");
 
-        let cq_mut = unsafe { cq.as_mut() }.unwrap();
+        let ctx = unsafe { self.rxe_context.as_ref() }.ok_or(VerbsError::DeviceGone)?;
 
-        cq_mut.context = rxe_context;
-        let rc = unsafe { ctx.ops.poll_cq.unwrap()(cq, num_entries, wc) };
-        cq_mut.context = old_context;
+        let post_srq_recv = ctx.ops.post_srq_recv.ok_or(VerbsError::NotSupported)?;
+        let rc = unsafe { post_srq_recv(srq.as_ptr(), wr, bad_wr) };
 
-        if rc == 0 { Ok(()) } else { Err(rc) }
+        VerbsError::check(rc)
     }
 }
@@ -0,0 +1,522 @@
+//! Userspace RoCEv2 backend
+//!
+//! Implements the RC transport of RoCEv2 on a plain UDP socket bound to the `udp:` address configured for the device
+//! (see [`crate::config`]), so urdma devices talk to each other, or to any RoCEv2 peer, without a kernel RDMA device.
+//! A peer is addressed by the IPv4-mapped or IPv6 GID in the address vector of the QP and is expected on the same UDP
+//! port as the local device.
+//!
+//! Each device runs a progress thread that receives packets and drives the retransmission and RNR timers. Packets with
+//! an invalid ICRC, or from another address than the peer of their QP, are dropped. QP numbers and keys are random, so
+//! other hosts can't guess them.
+
+mod ops;
+mod packet;
+mod qp;
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{SocketAddr, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, Weak};
+use std::time::{Duration, Instant};
+
+use ffi::{ibv_access_flags, ibv_wc_status};
+use provider::{CompletionQueue, MemoryRegion, Payload, QueuePair, VerbsError};
+
+use self::packet::Packet;
+use self::qp::Qp;
+pub(crate) use self::qp::{RNR_RETRY_INFINITE, RNR_TIMER_US};
+use crate::loopback::{DmaBuf, mapped};
+
+/// How often the progress thread checks timers when no packet arrives
+const TICK: Duration = Duration::from_millis(1);
+
+/// RoCEv2 device
+pub struct Roce {
+    name: String,
+    dev: Arc<Device>,
+    /// GID of the bound address, the only one of port 1
+    gids: Vec<ffi::ibv_gid_entry>,
+}
+
+/// State shared with the progress thread
+struct Device {
+    name: String,
+    socket: UdpSocket,
+    addr: SocketAddr,
+    next_id: AtomicU32,
+    qps: RwLock<HashMap<u32, Arc<Qp>>>,
+    mrs: RwLock<HashMap<u32, MrEntry>>,
+}
+
+/// Registered memory
+struct MrEntry {
+    pd: u32,
+    addr: usize,
+    length: usize,
+    iova: u64,
+    access: ibv_access_flags,
+}
+
+/// RoCEv2 protection domain
+pub struct RocePd {
+    id: u32,
+}
+
+/// RoCEv2 completion queue
+pub struct RoceCq(Arc<Cq>);
+
+/// RoCEv2 queue pair
+pub struct RoceQp(Arc<Qp>);
+
+/// RoCEv2 memory region, the same key serves as lkey and rkey
+pub struct RoceMr {
+    key: u32,
+    /// mapping of a dma-buf region, only held until the region is deregistered
+    _dmabuf: Option<DmaBuf>,
+}
+
+impl QueuePair for RoceQp {
+    fn qp_num(&self) -> u32 {
+        self.0.qp_num
+    }
+}
+
+impl CompletionQueue for RoceCq {
+    fn cq_num(&self) -> u32 {
+        self.0.cq_num
+    }
+}
+
+impl MemoryRegion for RoceMr {
+    fn lkey(&self) -> u32 {
+        self.key
+    }
+
+    fn rkey(&self) -> u32 {
+        self.key
+    }
+}
+
+struct Cq {
+    cq_num: u32,
+    entries: Mutex<VecDeque<ffi::ibv_wc>>,
+}
+
+impl Cq {
+    fn push(&self, wc: ffi::ibv_wc) {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push_back(wc);
+    }
+}
+
+impl Device {
+    /// Bind the device to `addr` and start its progress thread.
+    fn start(name: &str, addr: SocketAddr) -> std::io::Result<Arc<Self>> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_read_timeout(Some(TICK))?;
+        // port 0 picks a free one
+        let addr = socket.local_addr()?;
+
+        if addr.is_ipv4() {
+            // with DF set the kernel sends a zero IP identification, which the ICRC covers
+            let value: libc::c_int = libc::IP_PMTUDISC_DO;
+            // Safety: `value` is a valid `c_int` for the duration of the call.
+            let rc = unsafe {
+                libc::setsockopt(
+                    socket.as_raw_fd(),
+                    libc::IPPROTO_IP,
+                    libc::IP_MTU_DISCOVER,
+                    (&raw const value).cast(),
+                    size_of_val(&value) as _,
+                )
+            };
+            if rc != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        let dev = Arc::new(Self {
+            name: name.to_owned(),
+            socket,
+            addr,
+            next_id: AtomicU32::new(1),
+            qps: RwLock::default(),
+            mrs: RwLock::default(),
+        });
+
+        let weak = Arc::downgrade(&dev);
+        std::thread::Builder::new()
+            .name(format!("{name}-roce"))
+            .spawn(move || progress(&weak))?;
+
+        Ok(dev)
+    }
+
+    fn alloc_id(&self) -> u32 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Register `mr` under a random unused key.
+    fn insert_mr(&self, mr: MrEntry) -> provider::Result<u32> {
+        let mut mrs = self.mrs.write().unwrap_or_else(PoisonError::into_inner);
+        let key = random_id(u32::MAX, |key| mrs.contains_key(&key))?;
+        mrs.insert(key, mr);
+        Ok(key)
+    }
+
+    /// Send `packet` to `dst`.
+    fn send(&self, dst: SocketAddr, packet: &Packet<'_>) {
+        let mut buf = Vec::with_capacity(packet::BTH_LEN + packet::RETH_LEN + packet.payload.len() + 8);
+        packet.encode(&mut buf);
+        let icrc = packet::icrc(self.addr, dst, &buf);
+        buf.extend_from_slice(&icrc.to_le_bytes());
+
+        // lost packets are recovered by retransmission
+        if let Err(err) = self.socket.send_to(&buf, dst) {
+            log::debug!("{}: failed to send to {dst}: {err}", self.name);
+        }
+    }
+
+    /// Resolve `len` bytes at I/O virtual address `addr` through `key` and run `f` on them.
+    ///
+    /// The region must belong to `pd` and allow `access`, an empty access only requires the key to be valid. It cannot
+    /// be deregistered before `f` returns, so `f` must not resolve another key or register memory.
+    fn resolve<R>(
+        &self,
+        key: u32,
+        pd: u32,
+        addr: u64,
+        len: usize,
+        access: ibv_access_flags,
+        f: impl FnOnce(*mut u8) -> R,
+    ) -> Option<R> {
+        let mrs = self.mrs.read().unwrap_or_else(PoisonError::into_inner);
+        let mr = mrs
+            .get(&key)
+            .filter(|mr| mr.pd == pd && (mr.access & access) == access)?;
+
+        let offset = usize::try_from(addr.checked_sub(mr.iova)?).ok()?;
+        if offset.checked_add(len)? > mr.length {
+            return None;
+        }
+
+        let ptr = mr.addr.wrapping_add(offset);
+        // on-demand regions are resolved lazily, their memory may be gone
+        if (mr.access & ibv_access_flags::IBV_ACCESS_ON_DEMAND).0 != 0 && !mapped(ptr, len) {
+            return None;
+        }
+        Some(f(ptr as *mut u8))
+    }
+
+    /// Copy the memory `payload` describes into one buffer.
+    fn gather(&self, pd: u32, payload: &Payload) -> Result<Vec<u8>, ibv_wc_status::Type> {
+        let sges = match payload {
+            Payload::Sges(sges) => sges,
+            Payload::Inline(data) => return Ok(data.clone()),
+        };
+        let mut buf = Vec::new();
+
+        for sge in sges {
+            let len = sge.length as usize;
+            self.resolve(sge.lkey, pd, sge.addr, len, ibv_access_flags(0), |src| {
+                // Safety: the range lies in a registered region.
+                buf.extend_from_slice(unsafe { core::slice::from_raw_parts(src, len) });
+            })
+            .ok_or(ibv_wc_status::IBV_WC_LOC_PROT_ERR)?;
+        }
+
+        Ok(buf)
+    }
+
+    /// Copy `data` into the memory described by `sges`, starting `offset` bytes into it.
+    fn scatter(
+        &self,
+        pd: u32,
+        sges: &[ffi::ibv_sge],
+        mut offset: usize,
+        mut data: &[u8],
+    ) -> Result<(), ibv_wc_status::Type> {
+        for sge in sges {
+            if data.is_empty() {
+                return Ok(());
+            }
+            let sge_len = sge.length as usize;
+            if offset >= sge_len {
+                offset -= sge_len;
+                continue;
+            }
+
+            let len = data.len().min(sge_len - offset);
+            self.resolve(
+                sge.lkey,
+                pd,
+                sge.addr + offset as u64,
+                len,
+                ibv_access_flags::IBV_ACCESS_LOCAL_WRITE,
+                |dst| {
+                    // Safety: the range lies in a writable registered region.
+                    unsafe { core::ptr::copy(data.as_ptr(), dst, len) };
+                },
+            )
+            .ok_or(ibv_wc_status::IBV_WC_LOC_PROT_ERR)?;
+            data = &data[len..];
+            offset = 0;
+        }
+
+        if data.is_empty() {
+            Ok(())
+        } else {
+            Err(ibv_wc_status::IBV_WC_LOC_LEN_ERR)
+        }
+    }
+}
+
+/// Random id within `mask` for which `taken` is false.
+fn random_id(mask: u32, taken: impl Fn(u32) -> bool) -> provider::Result<u32> {
+    loop {
+        let mut id = 0_u32;
+        // Safety: `id` is valid for writes of its size.
+        let len = unsafe { libc::getrandom((&raw mut id).cast(), size_of_val(&id), 0) };
+        if len < 0 {
+            return Err(VerbsError::last_os_error());
+        }
+        if len == size_of_val(&id) as isize && !taken(id & mask) {
+            return Ok(id & mask);
+        }
+    }
+}
+
+/// Receive packets and fire timers until the device is gone.
+fn progress(dev: &Weak<Device>) {
+    let mut buf = vec![0; 1 << 16];
+    let mut last_tick = Instant::now();
+
+    while let Some(dev) = dev.upgrade() {
+        match dev.socket.recv_from(&mut buf) {
+            Ok((len, src)) if !packet::check_icrc(src, dev.addr, &buf[..len]) => {
+                log::trace!("{}: packet with invalid ICRC from {src}", dev.name);
+            }
+            Ok((len, src)) => match Packet::parse(&buf[..len]) {
+                Some(packet) => {
+                    let qp = dev
+                        .qps
+                        .read()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .get(&packet.bth.dest_qp)
+                        .cloned();
+                    match qp {
+                        Some(qp) => qp.receive(&dev, src, &packet),
+                        None => log::trace!("{}: packet for unknown QP {:#x}", dev.name, packet.bth.dest_qp),
+                    }
+                }
+                None => log::trace!("{}: malformed packet from {src}", dev.name),
+            },
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) => {}
+            Err(err) => log::warn!("{}: receive failed: {err}", dev.name),
+        }
+
+        let now = Instant::now();
+        if now.duration_since(last_tick) >= TICK {
+            last_tick = now;
+            let qps: Vec<_> = dev
+                .qps
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .values()
+                .cloned()
+                .collect();
+            for qp in qps {
+                qp.tick(&dev, now);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use ffi::ibv_wc_opcode;
+    use provider::SendOp;
+
+    use super::*;
+    use crate::testing;
+
+    /// Longer than three packets at the default path MTU
+    const LEN: usize = 3500;
+
+    type Side = testing::Side<Roce>;
+
+    /// RC QP on a new device `name` at `addr`.
+    fn side(name: &str, addr: SocketAddr) -> Side {
+        let dev = Arc::new(Roce {
+            name: name.to_owned(),
+            dev: Device::start(name, addr).unwrap(),
+            gids: crate::gid::bound(name, addr.ip()),
+        });
+        Side::new(dev, 4 * LEN)
+    }
+
+    /// Connect the QP of `side` to `peer_qp_num` at `peer`, giving up after a single retry.
+    fn connect_to(side: &Side, peer: IpAddr, peer_qp_num: u32) {
+        let mut attr = ffi::ibv_qp_attr {
+            min_rnr_timer: 1,
+            // 4.096 µs * 2^12, about 17 ms
+            timeout: 12,
+            retry_cnt: 1,
+            rnr_retry: 7,
+            ..Default::default()
+        };
+        attr.ah_attr.is_global = 1;
+        attr.ah_attr.grh.dgid.raw = match peer {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+            IpAddr::V6(ip) => ip.octets(),
+        };
+        let mask = ffi::ibv_qp_attr_mask::IBV_QP_AV
+            | ffi::ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER
+            | ffi::ibv_qp_attr_mask::IBV_QP_TIMEOUT
+            | ffi::ibv_qp_attr_mask::IBV_QP_RETRY_CNT
+            | ffi::ibv_qp_attr_mask::IBV_QP_RNR_RETRY;
+        side.connect(peer_qp_num, attr, mask);
+    }
+
+    /// Two connected RC QPs on devices at 127.0.0.1 and 127.0.0.2, sharing a free port.
+    fn connect() -> (Side, Side) {
+        let ip_a = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let ip_b = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+
+        let a = side("urdma0", SocketAddr::new(ip_a, 0));
+        let b = side("urdma1", SocketAddr::new(ip_b, a.dev.dev.addr.port()));
+        connect_to(&a, ip_b, b.qp.qp_num());
+        connect_to(&b, ip_a, a.qp.qp_num());
+        (a, b)
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn send_waits_for_receive() {
+        let (mut a, b) = connect();
+        a.bytes_mut()[..LEN].copy_from_slice(&pattern(LEN));
+
+        // NAKed with RNR until the receive request is posted
+        a.post_send(1, SendOp::Send, a.sge(0, LEN));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(a.wait(0).is_empty());
+        b.post_recv(2, b.sge(LEN, 2 * LEN));
+
+        let recv = b.wait(1);
+        assert_eq!(recv.len(), 1);
+        assert!(recv[0].is_valid());
+        assert_eq!(
+            (recv[0].wr_id(), recv[0].opcode(), recv[0].len()),
+            (2, ibv_wc_opcode::IBV_WC_RECV, LEN)
+        );
+        assert_eq!(recv[0].src_qp, a.qp.qp_num());
+        assert_eq!(b.bytes()[LEN..2 * LEN], pattern(LEN));
+
+        let send = a.wait(1);
+        assert_eq!(send.len(), 1);
+        assert!(send[0].is_valid());
+        assert_eq!((send[0].wr_id(), send[0].opcode()), (1, ibv_wc_opcode::IBV_WC_SEND));
+    }
+
+    #[test]
+    fn rdma_write_and_read() {
+        let (mut a, mut b) = connect();
+        a.bytes_mut()[..LEN].copy_from_slice(&pattern(LEN));
+        b.bytes_mut()[2 * LEN..3 * LEN].copy_from_slice(&pattern(LEN));
+
+        b.post_recv(1, b.sge(0, 0));
+        a.post_send(2, SendOp::RdmaWriteWithImm(b.remote(LEN), 0x1234), a.sge(0, LEN));
+        a.post_send(3, SendOp::RdmaRead(b.remote(2 * LEN)), a.sge(LEN, LEN));
+
+        let recv = b.wait(1);
+        assert_eq!(recv.len(), 1);
+        assert_eq!(recv[0].opcode(), ibv_wc_opcode::IBV_WC_RECV_RDMA_WITH_IMM);
+        assert_eq!((recv[0].len(), recv[0].imm_data()), (LEN, Some(0x1234)));
+
+        let send = a.wait(2);
+        let opcodes: Vec<_> = send.iter().map(|wc| (wc.wr_id(), wc.opcode(), wc.is_valid())).collect();
+        assert_eq!(
+            opcodes,
+            [
+                (2, ibv_wc_opcode::IBV_WC_RDMA_WRITE, true),
+                (3, ibv_wc_opcode::IBV_WC_RDMA_READ, true)
+            ]
+        );
+        assert_eq!(send[1].len(), LEN);
+        assert_eq!(b.bytes()[LEN..2 * LEN], pattern(LEN));
+        assert_eq!(a.bytes()[LEN..2 * LEN], pattern(LEN));
+    }
+
+    #[test]
+    fn atomics() {
+        let (a, mut b) = connect();
+        let at = 8;
+        b.bytes_mut()[at..at + 8].copy_from_slice(&5_u64.to_ne_bytes());
+
+        let remote = b.remote(at);
+        a.post_send(1, SendOp::AtomicFetchAndAdd { remote, add: 3 }, a.sge(0, 8));
+        a.post_send(
+            2,
+            SendOp::AtomicCmpAndSwp {
+                remote,
+                compare: 8,
+                swap: 42,
+            },
+            a.sge(8, 8),
+        );
+
+        let send: Vec<_> = a.wait(2).iter().map(|wc| (wc.wr_id(), wc.opcode(), wc.len())).collect();
+        assert_eq!(
+            send,
+            [
+                (1, ibv_wc_opcode::IBV_WC_FETCH_ADD, 8),
+                (2, ibv_wc_opcode::IBV_WC_COMP_SWAP, 8)
+            ]
+        );
+        assert_eq!(a.bytes()[..16], [5_u64.to_ne_bytes(), 8_u64.to_ne_bytes()].concat());
+        assert_eq!(b.bytes()[at..at + 8], 42_u64.to_ne_bytes());
+    }
+
+    #[test]
+    fn errors_complete_and_flush() {
+        let (a, b) = connect();
+
+        // remote access past the end of the region
+        a.post_send(1, SendOp::RdmaWrite(b.remote(4 * LEN - 4)), a.sge(0, 8));
+        a.post_send(2, SendOp::Send, a.sge(0, 8));
+        let send: Vec<_> = a
+            .wait(2)
+            .iter()
+            .map(|wc| (wc.wr_id(), wc.error().map(|(status, _)| status)))
+            .collect();
+        assert_eq!(
+            send,
+            [
+                (1, Some(ibv_wc_status::IBV_WC_REM_ACCESS_ERR)),
+                (2, Some(ibv_wc_status::IBV_WC_WR_FLUSH_ERR))
+            ]
+        );
+
+        // nobody answers
+        let c = side("urdma2", SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0));
+        connect_to(&c, IpAddr::V4(Ipv4Addr::LOCALHOST), 0xdead);
+        c.post_send(3, SendOp::Send, c.sge(0, 8));
+        let send = c.wait(1);
+        assert_eq!(
+            send[0].error().map(|(status, _)| status),
+            Some(ibv_wc_status::IBV_WC_RETRY_EXC_ERR)
+        );
+    }
+}
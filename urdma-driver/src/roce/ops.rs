@@ -0,0 +1,383 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use ffi::{ibv_access_flags, ibv_qp_attr_mask};
+use provider::{
+    AsyncEvents, Capabilities, CqNotifier, DeviceAttrEx, QpInitAttr, Result, SendWr, UdDest, Verbs, VerbsError,
+};
+
+use super::qp::Qp;
+use super::{Cq, Device, MrEntry, Roce, RoceCq, RoceMr, RocePd, RoceQp, random_id};
+use crate::config::{self, Selector};
+use crate::loopback::{DmaBuf, mapped};
+use crate::{gid, urdma};
+
+const FW_VER: &str = "roce";
+
+const MAX_QP: u32 = 1 << 16;
+const MAX_QP_WR: u32 = 1 << 14;
+const MAX_SGE: u32 = 32;
+const MAX_INLINE_DATA: u32 = 1 << 12;
+const MAX_CQ: u32 = 1 << 16;
+const MAX_CQE: u32 = 1 << 20;
+const MAX_MR: u32 = 1 << 20;
+const MAX_PD: u32 = 1 << 16;
+const MAX_MSG_SZ: u32 = 1 << 31;
+
+impl provider::Provider for Roce {
+    type Ah = core::convert::Infallible;
+    type Cq = RoceCq;
+    type Mr = RoceMr;
+    type Mw = core::convert::Infallible;
+    type Pd = RocePd;
+    type Qp = RoceQp;
+    /// no shared receive queues
+    type Srq = core::convert::Infallible;
+
+    fn init() -> Result {
+        let _ = env_logger::try_init();
+
+        let profile = config::profile().map_err(|err| {
+            log::error!("{err}");
+            VerbsError::InvalidArgument
+        })?;
+        profile.install();
+        Ok(())
+    }
+
+    unsafe fn from_ibv_device(ibdev: *mut ffi::ibv_device) -> Result<*const Self> {
+        unsafe { urdma::driver_data(ibdev) }.map(|driver_data| driver_data.cast())
+    }
+
+    fn new(sysfs_name: &str, _events: AsyncEvents) -> Result<Arc<Self>> {
+        let selector = config::backing_device(sysfs_name).map_err(|err| {
+            log::error!("{sysfs_name}: {err}");
+            VerbsError::InvalidArgument
+        })?;
+
+        // the address goes into the ICRC of every packet, so it must be a concrete one
+        let addr = match selector {
+            Selector::Udp(addr) if !addr.ip().is_unspecified() => addr,
+            selector => {
+                log::error!(
+                    "{sysfs_name}: {selector} is not a local UDP address, configure e.g. \"{sysfs_name}=udp:<ip>\""
+                );
+                return Err(VerbsError::InvalidArgument);
+            }
+        };
+
+        let dev = Device::start(sysfs_name, addr).map_err(|err| {
+            log::error!("{sysfs_name}: failed to bind {addr}: {err}");
+            VerbsError::from(err)
+        })?;
+        log::info!("{sysfs_name}: RoCEv2 on {addr}");
+
+        Ok(Arc::new(Roce {
+            name: sysfs_name.to_owned(),
+            dev,
+            gids: gid::bound(sysfs_name, addr.ip()),
+        }))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            verbs: Verbs::ALL.without(Verbs::SRQ | Verbs::REQ_NOTIFY_CQ | Verbs::MW | Verbs::AH | Verbs::REREG_MR),
+            qp_types: &[ffi::ibv_qp_type::IBV_QPT_RC],
+        }
+    }
+
+    fn alloc_pd(&self) -> Result<RocePd> {
+        log::info!("{}: Allocating protection domain", self.name);
+
+        Ok(RocePd {
+            id: self.dev.alloc_id(),
+        })
+    }
+
+    fn dealloc_pd(&self, _pd: &RocePd) -> Result {
+        log::info!("{}: Deallocating protection domain", self.name);
+
+        Ok(())
+    }
+
+    fn query_device(&self, device_attr: &mut ffi::ibv_device_attr) -> Result {
+        log::info!("{}: Querying device attributes", self.name);
+
+        for (dst, src) in device_attr.fw_ver.iter_mut().zip(FW_VER.bytes()) {
+            *dst = src as _;
+        }
+        device_attr.max_mr_size = u64::MAX;
+        device_attr.page_size_cap = 4096;
+        device_attr.max_qp = MAX_QP as _;
+        device_attr.max_qp_wr = MAX_QP_WR as _;
+        device_attr.max_sge = MAX_SGE as _;
+        device_attr.max_sge_rd = MAX_SGE as _;
+        device_attr.max_cq = MAX_CQ as _;
+        device_attr.max_cqe = MAX_CQE as _;
+        device_attr.max_mr = MAX_MR as _;
+        device_attr.max_pd = MAX_PD as _;
+        device_attr.max_qp_rd_atom = 128;
+        device_attr.max_qp_init_rd_atom = 128;
+        device_attr.atomic_cap = ffi::IBV_ATOMIC_HCA;
+        device_attr.max_pkeys = 1;
+        device_attr.phys_port_cnt = 1;
+
+        Ok(())
+    }
+
+    fn query_device_ex(&self, attr: &mut DeviceAttrEx) -> Result {
+        self.query_device(&mut attr.orig_attr)?;
+
+        // memory of on-demand regions is resolved on each access
+        attr.odp_caps.general_caps = ffi::IBV_ODP_SUPPORT.into();
+        attr.odp_caps.per_transport_caps.rc_odp_caps = ffi::IBV_ODP_SUPPORT_SEND
+            | ffi::IBV_ODP_SUPPORT_RECV
+            | ffi::IBV_ODP_SUPPORT_WRITE
+            | ffi::IBV_ODP_SUPPORT_READ
+            | ffi::IBV_ODP_SUPPORT_ATOMIC;
+
+        Ok(())
+    }
+
+    fn query_port(&self, port_num: u8, port_attr: &mut ffi::ibv_port_attr) -> Result {
+        log::info!("{}: Querying port attributes", self.name);
+
+        if port_num != 1 {
+            return Err(VerbsError::InvalidArgument);
+        }
+
+        port_attr.state = ffi::ibv_port_state::IBV_PORT_ACTIVE;
+        port_attr.max_mtu = ffi::IBV_MTU_4096;
+        port_attr.active_mtu = ffi::IBV_MTU_1024;
+        port_attr.gid_tbl_len = self.gids.len() as _;
+        port_attr.max_msg_sz = MAX_MSG_SZ;
+        port_attr.pkey_tbl_len = 1;
+        port_attr.active_width = 1;
+        port_attr.active_speed = 1;
+        // LinkUp
+        port_attr.phys_state = 5;
+        port_attr.link_layer = ffi::IBV_LINK_LAYER_ETHERNET as _;
+
+        Ok(())
+    }
+
+    fn query_gid_table(&self, port_num: u8) -> Result<Vec<ffi::ibv_gid_entry>> {
+        log::info!("{}: Querying GID table", self.name);
+
+        if port_num != 1 {
+            return Err(VerbsError::InvalidArgument);
+        }
+
+        Ok(self.gids.clone())
+    }
+
+    fn create_cq(
+        &self,
+        cqe: core::ffi::c_int,
+        _notifier: Option<CqNotifier>,
+        _comp_vector: core::ffi::c_int,
+    ) -> Result<RoceCq> {
+        log::info!("{}: Creating completion queue", self.name);
+
+        match u32::try_from(cqe) {
+            Ok(1..=MAX_CQE) => {}
+            _ => return Err(VerbsError::InvalidArgument),
+        }
+
+        Ok(RoceCq(Arc::new(Cq {
+            cq_num: self.dev.alloc_id(),
+            entries: Mutex::new(VecDeque::with_capacity(cqe as usize)),
+        })))
+    }
+
+    fn destroy_cq(&self, cq: &RoceCq) -> Result {
+        log::info!("{}: Destroying completion queue", self.name);
+
+        // still referenced by a QP
+        if Arc::strong_count(&cq.0) > 1 {
+            return Err(VerbsError::Busy);
+        }
+
+        Ok(())
+    }
+
+    fn create_qp(&self, pd: &RocePd, init_attr: &mut QpInitAttr<'_, Self>) -> Result<RoceQp> {
+        log::info!("{}: Creating queue pair", self.name);
+
+        let cap = &mut init_attr.cap;
+        if cap.max_send_wr > MAX_QP_WR
+            || cap.max_recv_wr > MAX_QP_WR
+            || cap.max_send_sge > MAX_SGE
+            || cap.max_recv_sge > MAX_SGE
+            || cap.max_inline_data > MAX_INLINE_DATA
+        {
+            return Err(VerbsError::InvalidArgument);
+        }
+        cap.max_inline_data = MAX_INLINE_DATA;
+
+        let mut qps = self.dev.qps.write().unwrap_or_else(PoisonError::into_inner);
+        // QP numbers are 24 bits on the wire, 0 and 1 are reserved for SMI and GSI
+        let qp_num = random_id(0xff_ffff, |qp_num| qp_num < 2 || qps.contains_key(&qp_num))?;
+        let qp = Arc::new(Qp {
+            qp_num,
+            pd: pd.id,
+            qp_type: init_attr.qp_type,
+            sq_sig_all: init_attr.sq_sig_all,
+            cap: *cap,
+            send_cq: Arc::clone(&init_attr.send_cq.0),
+            recv_cq: Arc::clone(&init_attr.recv_cq.0),
+            state: Mutex::default(),
+        });
+        qps.insert(qp_num, Arc::clone(&qp));
+
+        Ok(RoceQp(qp))
+    }
+
+    fn destroy_qp(&self, qp: &RoceQp) -> Result {
+        log::info!("{}: Destroying queue pair", self.name);
+
+        self.dev
+            .qps
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&qp.0.qp_num);
+
+        Ok(())
+    }
+
+    fn modify_qp(&self, qp: &RoceQp, attr: &mut ffi::ibv_qp_attr, attr_mask: ibv_qp_attr_mask) -> Result {
+        log::info!("{}: Modifying queue pair", self.name);
+
+        qp.0.modify(&self.dev, attr, attr_mask)
+    }
+
+    fn query_qp(
+        &self,
+        qp: &RoceQp,
+        attr: &mut ffi::ibv_qp_attr,
+        _attr_mask: ibv_qp_attr_mask,
+        init_attr: &mut ffi::ibv_qp_init_attr,
+    ) -> Result {
+        log::info!("{}: Querying queue pair", self.name);
+
+        let qp = &qp.0;
+        qp.query(attr);
+
+        init_attr.cap = qp.cap;
+        init_attr.qp_type = qp.qp_type;
+        init_attr.sq_sig_all = qp.sq_sig_all.into();
+
+        Ok(())
+    }
+
+    fn reg_mr(
+        &self,
+        pd: &RocePd,
+        addr: *mut ::std::os::raw::c_void,
+        length: usize,
+        hca_va: u64,
+        access: ibv_access_flags,
+    ) -> Result<RoceMr> {
+        log::info!("{}: Registering memory region", self.name);
+
+        check_access(access)?;
+        // pinned up front, on-demand regions are checked on every access
+        if (access & ibv_access_flags::IBV_ACCESS_ON_DEMAND).0 == 0 && !mapped(addr as usize, length) {
+            return Err(VerbsError::Errno(libc::EFAULT));
+        }
+
+        let key = self.dev.insert_mr(MrEntry {
+            pd: pd.id,
+            addr: addr as usize,
+            length,
+            iova: hca_va,
+            access,
+        })?;
+
+        Ok(RoceMr { key, _dmabuf: None })
+    }
+
+    fn reg_dmabuf_mr(
+        &self,
+        pd: &RocePd,
+        offset: u64,
+        length: usize,
+        iova: u64,
+        fd: core::ffi::c_int,
+        access: ibv_access_flags,
+    ) -> Result<RoceMr> {
+        log::info!("{}: Registering dma-buf memory region", self.name);
+
+        check_access(access)?;
+        let dmabuf = DmaBuf::map(fd, offset, length)?;
+
+        let key = self.dev.insert_mr(MrEntry {
+            pd: pd.id,
+            addr: dmabuf.addr(),
+            length,
+            iova,
+            access,
+        })?;
+
+        Ok(RoceMr {
+            key,
+            _dmabuf: Some(dmabuf),
+        })
+    }
+
+    fn dereg_mr(&self, mr: &RoceMr) -> Result {
+        log::info!("{}: Deregistering memory region", self.name);
+
+        self.dev
+            .mrs
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&mr.key);
+
+        Ok(())
+    }
+
+    fn post_send(&self, qp: &RoceQp, wr: &SendWr, _ud: Option<&UdDest<'_, Self>>) -> Result {
+        log::trace!("{}: Posting send work request", self.name);
+
+        qp.0.post_send(&self.dev, wr)
+    }
+
+    fn post_recv(&self, qp: &RoceQp, wr: *mut ffi::ibv_recv_wr, bad_wr: &mut *mut ffi::ibv_recv_wr) -> Result {
+        log::trace!("{}: Posting receive work request", self.name);
+
+        let mut cur = wr;
+        // Safety: the application hands us a valid, null terminated list.
+        while let Some(wr) = unsafe { cur.as_ref() } {
+            if let Err(err) = qp.0.post_recv(wr) {
+                *bad_wr = cur;
+                return Err(err);
+            }
+            cur = wr.next;
+        }
+
+        Ok(())
+    }
+
+    fn poll_cq(&self, cq: &RoceCq, wc: &mut [ffi::ibv_wc]) -> Result<usize> {
+        log::trace!("{}: Polling completion queue", self.name);
+
+        let mut entries = cq.0.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let polled = wc.len().min(entries.len());
+        for (dst, src) in wc.iter_mut().zip(entries.drain(..polled)) {
+            *dst = src;
+        }
+
+        Ok(polled)
+    }
+}
+
+/// Check `access` is valid for a memory region.
+fn check_access(access: ibv_access_flags) -> Result {
+    // remote write and atomics are only allowed on locally writable memory
+    let needs_local_write = ibv_access_flags::IBV_ACCESS_REMOTE_WRITE | ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC;
+    if (access & needs_local_write).0 != 0 && (access & ibv_access_flags::IBV_ACCESS_LOCAL_WRITE).0 == 0 {
+        return Err(VerbsError::InvalidArgument);
+    }
+    Ok(())
+}
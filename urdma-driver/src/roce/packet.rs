@@ -0,0 +1,551 @@
+//! RoCEv2 packet format
+//!
+//! Only reliable connected transport is handled: a BTH, optionally followed by RETH or AtomicETH, AETH, AtomicAckETH
+//! and ImmDt, the payload padded to 4 bytes and the ICRC.
+
+use std::net::{IpAddr, SocketAddr};
+
+pub const BTH_LEN: usize = 12;
+pub const RETH_LEN: usize = 16;
+pub const AETH_LEN: usize = 4;
+pub const ATOMIC_ETH_LEN: usize = 28;
+pub const ATOMIC_ACK_ETH_LEN: usize = 8;
+pub const IMM_LEN: usize = 4;
+pub const ICRC_LEN: usize = 4;
+
+pub const DEFAULT_PKEY: u16 = 0xffff;
+
+/// RC opcodes
+pub mod opcode {
+    pub const SEND_FIRST: u8 = 0x00;
+    pub const SEND_MIDDLE: u8 = 0x01;
+    pub const SEND_LAST: u8 = 0x02;
+    pub const SEND_LAST_IMM: u8 = 0x03;
+    pub const SEND_ONLY: u8 = 0x04;
+    pub const SEND_ONLY_IMM: u8 = 0x05;
+    pub const WRITE_FIRST: u8 = 0x06;
+    pub const WRITE_MIDDLE: u8 = 0x07;
+    pub const WRITE_LAST: u8 = 0x08;
+    pub const WRITE_LAST_IMM: u8 = 0x09;
+    pub const WRITE_ONLY: u8 = 0x0a;
+    pub const WRITE_ONLY_IMM: u8 = 0x0b;
+    pub const READ_REQUEST: u8 = 0x0c;
+    pub const READ_RESPONSE_FIRST: u8 = 0x0d;
+    pub const READ_RESPONSE_MIDDLE: u8 = 0x0e;
+    pub const READ_RESPONSE_LAST: u8 = 0x0f;
+    pub const READ_RESPONSE_ONLY: u8 = 0x10;
+    pub const ACK: u8 = 0x11;
+    pub const ATOMIC_ACKNOWLEDGE: u8 = 0x12;
+    pub const COMPARE_SWAP: u8 = 0x13;
+    pub const FETCH_ADD: u8 = 0x14;
+}
+
+/// AETH syndromes
+pub mod syndrome {
+    /// ACK without end to end credits
+    pub const ACK: u8 = 0b000_11111;
+    /// RNR NAK, the low 5 bits hold the RNR timer
+    pub const RNR_NAK: u8 = 0b001_00000;
+    pub const NAK_PSN_SEQUENCE: u8 = 0b011_00000;
+    pub const NAK_INVALID_REQUEST: u8 = 0b011_00001;
+    pub const NAK_REMOTE_ACCESS: u8 = 0b011_00010;
+    pub const NAK_REMOTE_OPERATIONAL: u8 = 0b011_00011;
+
+    pub const TYPE_MASK: u8 = 0b111_00000;
+}
+
+/// Base transport header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bth {
+    pub opcode: u8,
+    /// solicited event
+    pub solicited: bool,
+    pub pkey: u16,
+    pub dest_qp: u32,
+    pub ack_req: bool,
+    pub psn: u32,
+}
+
+/// RDMA extended transport header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reth {
+    pub va: u64,
+    pub rkey: u32,
+    pub len: u32,
+}
+
+/// Atomic extended transport header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtomicEth {
+    pub va: u64,
+    pub rkey: u32,
+    /// value to swap in, or to add
+    pub swap_add: u64,
+    /// only meaningful for compare and swap
+    pub compare: u64,
+}
+
+/// ACK extended transport header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Aeth {
+    pub syndrome: u8,
+    pub msn: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet<'a> {
+    pub bth: Bth,
+    pub reth: Option<Reth>,
+    pub atomic: Option<AtomicEth>,
+    pub aeth: Option<Aeth>,
+    /// original value of the remote memory, in atomic acknowledgements
+    pub atomic_ack: Option<u64>,
+    pub imm: Option<u32>,
+    pub payload: &'a [u8],
+}
+
+pub fn has_reth(opcode: u8) -> bool {
+    matches!(
+        opcode,
+        opcode::WRITE_FIRST | opcode::WRITE_ONLY | opcode::WRITE_ONLY_IMM | opcode::READ_REQUEST
+    )
+}
+
+pub fn has_atomic_eth(opcode: u8) -> bool {
+    matches!(opcode, opcode::COMPARE_SWAP | opcode::FETCH_ADD)
+}
+
+pub fn has_aeth(opcode: u8) -> bool {
+    matches!(
+        opcode,
+        opcode::READ_RESPONSE_FIRST
+            | opcode::READ_RESPONSE_LAST
+            | opcode::READ_RESPONSE_ONLY
+            | opcode::ACK
+            | opcode::ATOMIC_ACKNOWLEDGE
+    )
+}
+
+pub fn has_imm(opcode: u8) -> bool {
+    matches!(
+        opcode,
+        opcode::SEND_LAST_IMM | opcode::SEND_ONLY_IMM | opcode::WRITE_LAST_IMM | opcode::WRITE_ONLY_IMM
+    )
+}
+
+impl<'a> Packet<'a> {
+    /// Parse a UDP payload, the ICRC is stripped but not checked.
+    pub fn parse(buf: &'a [u8]) -> Option<Self> {
+        let buf = buf.get(..buf.len().checked_sub(ICRC_LEN)?)?;
+        let (bth, mut rest) = buf.split_first_chunk::<BTH_LEN>()?;
+
+        let opcode = bth[0];
+        let bth = Bth {
+            opcode,
+            solicited: bth[1] & 0x80 != 0,
+            pkey: u16::from_be_bytes([bth[2], bth[3]]),
+            dest_qp: u32::from_be_bytes([0, bth[5], bth[6], bth[7]]),
+            ack_req: bth[8] & 0x80 != 0,
+            psn: u32::from_be_bytes([0, bth[9], bth[10], bth[11]]),
+        };
+        let pad = usize::from((buf[1] >> 4) & 0x3);
+
+        let reth = if has_reth(opcode) {
+            let (reth, tail) = rest.split_first_chunk::<RETH_LEN>()?;
+            rest = tail;
+            Some(Reth {
+                va: u64::from_be_bytes(reth[..8].try_into().unwrap()),
+                rkey: u32::from_be_bytes(reth[8..12].try_into().unwrap()),
+                len: u32::from_be_bytes(reth[12..].try_into().unwrap()),
+            })
+        } else {
+            None
+        };
+        let atomic = if has_atomic_eth(opcode) {
+            let (atomic, tail) = rest.split_first_chunk::<ATOMIC_ETH_LEN>()?;
+            rest = tail;
+            Some(AtomicEth {
+                va: u64::from_be_bytes(atomic[..8].try_into().unwrap()),
+                rkey: u32::from_be_bytes(atomic[8..12].try_into().unwrap()),
+                swap_add: u64::from_be_bytes(atomic[12..20].try_into().unwrap()),
+                compare: u64::from_be_bytes(atomic[20..].try_into().unwrap()),
+            })
+        } else {
+            None
+        };
+        let aeth = if has_aeth(opcode) {
+            let (aeth, tail) = rest.split_first_chunk::<AETH_LEN>()?;
+            rest = tail;
+            Some(Aeth {
+                syndrome: aeth[0],
+                msn: u32::from_be_bytes([0, aeth[1], aeth[2], aeth[3]]),
+            })
+        } else {
+            None
+        };
+        let atomic_ack = if opcode == opcode::ATOMIC_ACKNOWLEDGE {
+            let (orig, tail) = rest.split_first_chunk::<ATOMIC_ACK_ETH_LEN>()?;
+            rest = tail;
+            Some(u64::from_be_bytes(*orig))
+        } else {
+            None
+        };
+        let imm = if has_imm(opcode) {
+            let (imm, tail) = rest.split_first_chunk::<IMM_LEN>()?;
+            rest = tail;
+            Some(u32::from_be_bytes(*imm))
+        } else {
+            None
+        };
+
+        let payload = rest.get(..rest.len().checked_sub(pad)?)?;
+        Some(Self {
+            bth,
+            reth,
+            atomic,
+            aeth,
+            atomic_ack,
+            imm,
+            payload,
+        })
+    }
+
+    /// Serialize into `out`, without the ICRC.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let bth = &self.bth;
+        let pad = self.payload.len().wrapping_neg() % 4;
+
+        out.push(bth.opcode);
+        out.push(u8::from(bth.solicited) << 7 | (pad as u8) << 4);
+        out.extend_from_slice(&bth.pkey.to_be_bytes());
+        out.push(0);
+        out.extend_from_slice(&bth.dest_qp.to_be_bytes()[1..]);
+        out.push(u8::from(bth.ack_req) << 7);
+        out.extend_from_slice(&bth.psn.to_be_bytes()[1..]);
+
+        if let Some(reth) = self.reth {
+            out.extend_from_slice(&reth.va.to_be_bytes());
+            out.extend_from_slice(&reth.rkey.to_be_bytes());
+            out.extend_from_slice(&reth.len.to_be_bytes());
+        }
+        if let Some(atomic) = self.atomic {
+            out.extend_from_slice(&atomic.va.to_be_bytes());
+            out.extend_from_slice(&atomic.rkey.to_be_bytes());
+            out.extend_from_slice(&atomic.swap_add.to_be_bytes());
+            out.extend_from_slice(&atomic.compare.to_be_bytes());
+        }
+        if let Some(aeth) = self.aeth {
+            out.push(aeth.syndrome);
+            out.extend_from_slice(&aeth.msn.to_be_bytes()[1..]);
+        }
+        if let Some(orig) = self.atomic_ack {
+            out.extend_from_slice(&orig.to_be_bytes());
+        }
+        if let Some(imm) = self.imm {
+            out.extend_from_slice(&imm.to_be_bytes());
+        }
+
+        out.extend_from_slice(self.payload);
+        out.resize(out.len() + pad, 0);
+    }
+}
+
+/// Invariant CRC of a packet sent from `src` to `dst`, `packet` starts at the BTH and ends before the ICRC.
+pub fn icrc(src: SocketAddr, dst: SocketAddr, packet: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(&icrc_input(src, dst, packet));
+    crc.finish()
+}
+
+/// Whether the ICRC at the end of `packet` is valid for a packet sent from `src` to `dst`.
+///
+/// The ICRC covers the IPv4 identification, which the socket does not report. Ours send it as 0, rxe picks one per
+/// packet, so if 0 does not match the CRC is run forward to the identification and backward from the received value
+/// to it, and any identification joining the two is accepted.
+pub fn check_icrc(src: SocketAddr, dst: SocketAddr, packet: &[u8]) -> bool {
+    let Some((packet, icrc)) = packet.split_last_chunk::<ICRC_LEN>() else {
+        return false;
+    };
+    if packet.len() < BTH_LEN {
+        return false;
+    }
+    let received = u32::from_le_bytes(*icrc);
+
+    let input = icrc_input(src, dst, packet);
+    let mut crc = Crc32::new();
+    crc.update(&input);
+    if crc.finish() == received {
+        return true;
+    }
+    if !(src.ip().to_canonical().is_ipv4() && dst.ip().to_canonical().is_ipv4()) {
+        return false;
+    }
+
+    let mut before = Crc32::new();
+    before.update(&input[..IPV4_ID_OFFSET]);
+    let mut after = Crc32(!received);
+    after.revert(&input[IPV4_ID_OFFSET + 2..]);
+    Crc32::joined(before.0, after.0)
+}
+
+/// Offset of the IPv4 identification in the input of the ICRC
+const IPV4_ID_OFFSET: usize = 8 + 4;
+
+/// What the ICRC of `packet` covers: the IP and UDP headers with variant fields masked, then the packet.
+///
+/// The kernel fills the IPv4 identification of a packet from an unconnected socket with DF set with zero, so it is
+/// predictable too.
+fn icrc_input(src: SocketAddr, dst: SocketAddr, packet: &[u8]) -> Vec<u8> {
+    let udp_len = (8 + packet.len() + ICRC_LEN) as u16;
+    let mut input = Vec::with_capacity(8 + 40 + 8 + packet.len());
+    input.extend_from_slice(&[0xff; 8]);
+
+    match (src.ip().to_canonical(), dst.ip().to_canonical()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let total_len = 20 + udp_len;
+            // version/IHL, TOS masked
+            input.extend_from_slice(&[0x45, 0xff]);
+            input.extend_from_slice(&total_len.to_be_bytes());
+            // identification 0, DF, TTL and checksum masked, UDP
+            input.extend_from_slice(&[0, 0, 0x40, 0, 0xff, 17, 0xff, 0xff]);
+            input.extend_from_slice(&src.octets());
+            input.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            let ipv6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            // version 6, traffic class and flow label masked
+            input.extend_from_slice(&[0x6f, 0xff, 0xff, 0xff]);
+            input.extend_from_slice(&udp_len.to_be_bytes());
+            // UDP, hop limit masked
+            input.extend_from_slice(&[17, 0xff]);
+            input.extend_from_slice(&ipv6(src).octets());
+            input.extend_from_slice(&ipv6(dst).octets());
+        }
+    }
+
+    input.extend_from_slice(&src.port().to_be_bytes());
+    input.extend_from_slice(&dst.port().to_be_bytes());
+    input.extend_from_slice(&udp_len.to_be_bytes());
+    // checksum masked
+    input.extend_from_slice(&[0xff, 0xff]);
+
+    // BTH with the reserved byte before the destination QP masked
+    input.extend_from_slice(&packet[..4]);
+    input.push(0xff);
+    input.extend_from_slice(&packet[5..]);
+
+    input
+}
+
+/// CRC-32 as used by Ethernet, which the ICRC is
+struct Crc32(u32);
+
+impl Crc32 {
+    /// Index of each entry of `TABLE` by its top byte, which all of them differ in
+    const INDEX: [u8; 256] = {
+        let mut index = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            index[(Self::TABLE[i] >> 24) as usize] = i as u8;
+            i += 1;
+        }
+        index
+    };
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 {
+                    0xedb8_8320 ^ (crc >> 1)
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    fn new() -> Self {
+        Self(!0)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = Self::TABLE[((self.0 ^ u32::from(byte)) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    /// Undo `update(data)`.
+    fn revert(&mut self, data: &[u8]) {
+        for &byte in data.iter().rev() {
+            // the entry of the table was picked by the low byte of the previous state, it shows in the top byte
+            let index = Self::INDEX[(self.0 >> 24) as usize];
+            self.0 = ((self.0 ^ Self::TABLE[usize::from(index)]) << 8) | u32::from(index ^ byte);
+        }
+    }
+
+    /// Whether two bytes lead from state `from` to state `to`.
+    fn joined(from: u32, to: u32) -> bool {
+        // the state between them, as far as it follows from `to` without the second byte
+        let second = Self::INDEX[(to >> 24) as usize];
+        let mid_high = (to ^ Self::TABLE[usize::from(second)]) << 8;
+        // the first byte is the one giving that state
+        let first = Self::INDEX[(mid_high >> 24) as usize];
+        let mid = Self::TABLE[usize::from(first)] ^ (from >> 8);
+        mid & !0xff == mid_high
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+    }
+
+    /// IPv6 RoCEv2 SEND as rxe sends it: traffic class 2, flow label 0x12345, hop limit 64, ICRC per `rxe_icrc.c`
+    const RXE_IPV6_SEND: [u8; 80] = [
+        0x60, 0x21, 0x23, 0x45, 0x00, 0x28, 0x11, 0x40, 0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x02, 0xc0, 0x00, 0x12, 0xb7, 0x00, 0x28, 0x6e, 0x60, 0x04, 0x00, 0xff, 0xff, 0x00, 0x00,
+        0x00, 0x11, 0x80, 0x00, 0x00, 0x01, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0x72, 0x78, 0x65, 0x20, 0x69, 0x70,
+        0x76, 0x36, 0x21, 0x21, 0x9b, 0x47, 0x27, 0x64,
+    ];
+
+    #[test]
+    fn ipv6_icrc_matches_rxe() {
+        let ip = |octets: &[u8]| IpAddr::from(<[u8; 16]>::try_from(octets).unwrap());
+        let port = |bytes: &[u8]| u16::from_be_bytes(bytes.try_into().unwrap());
+        let src = SocketAddr::new(ip(&RXE_IPV6_SEND[8..24]), port(&RXE_IPV6_SEND[40..42]));
+        let dst = SocketAddr::new(ip(&RXE_IPV6_SEND[24..40]), port(&RXE_IPV6_SEND[42..44]));
+        let (packet, expected) = RXE_IPV6_SEND[48..].split_last_chunk::<ICRC_LEN>().unwrap();
+
+        assert_eq!(icrc(src, dst, packet).to_le_bytes(), *expected);
+        assert!(check_icrc(src, dst, &RXE_IPV6_SEND[48..]));
+
+        let mut corrupted = RXE_IPV6_SEND;
+        corrupted[60] ^= 1;
+        assert!(!check_icrc(src, dst, &corrupted[48..]));
+    }
+
+    #[test]
+    fn ipv4_icrc_with_any_identification() {
+        let src: SocketAddr = "192.168.1.1:49152".parse().unwrap();
+        let dst: SocketAddr = "192.168.1.2:4791".parse().unwrap();
+        let packet = [
+            0x04, 0, 0xff, 0xff, 0, 0, 0, 0x11, 0x80, 0, 0, 1, b'p', b'i', b'n', b'g',
+        ];
+
+        for id in [0_u16, 1, 0x8000, 0xbeef] {
+            let mut input = icrc_input(src, dst, &packet);
+            input[IPV4_ID_OFFSET..][..2].copy_from_slice(&id.to_be_bytes());
+            let mut crc = Crc32::new();
+            crc.update(&input);
+
+            let mut buf = packet.to_vec();
+            buf.extend_from_slice(&crc.finish().to_le_bytes());
+            assert!(check_icrc(src, dst, &buf), "identification {id:#x}");
+            buf[13] ^= 0x40;
+            assert!(!check_icrc(src, dst, &buf), "identification {id:#x}");
+        }
+    }
+
+    #[test]
+    fn encode_parse_roundtrip() {
+        let packet = Packet {
+            bth: Bth {
+                opcode: opcode::WRITE_ONLY_IMM,
+                solicited: true,
+                pkey: DEFAULT_PKEY,
+                dest_qp: 0x12_3456,
+                ack_req: true,
+                psn: 0xab_cdef,
+            },
+            reth: Some(Reth {
+                va: 0x1122_3344_5566_7788,
+                rkey: 0x99,
+                len: 5,
+            }),
+            atomic: None,
+            aeth: None,
+            atomic_ack: None,
+            imm: Some(0xdead_beef),
+            payload: b"hello",
+        };
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf);
+        assert_eq!(buf.len(), BTH_LEN + RETH_LEN + IMM_LEN + 8);
+        buf.extend_from_slice(&[0; ICRC_LEN]);
+
+        assert_eq!(Packet::parse(&buf), Some(packet));
+        assert_eq!(Packet::parse(&buf[..BTH_LEN]), None);
+    }
+
+    #[test]
+    fn atomic_roundtrip() {
+        let bth = Bth {
+            opcode: opcode::COMPARE_SWAP,
+            solicited: false,
+            pkey: DEFAULT_PKEY,
+            dest_qp: 0x11,
+            ack_req: true,
+            psn: 3,
+        };
+        let request = Packet {
+            bth,
+            reth: None,
+            atomic: Some(AtomicEth {
+                va: 0x1000,
+                rkey: 0x22,
+                swap_add: 7,
+                compare: 5,
+            }),
+            aeth: None,
+            atomic_ack: None,
+            imm: None,
+            payload: &[],
+        };
+        let ack = Packet {
+            bth: Bth {
+                opcode: opcode::ATOMIC_ACKNOWLEDGE,
+                ack_req: false,
+                ..bth
+            },
+            reth: None,
+            atomic: None,
+            aeth: Some(Aeth {
+                syndrome: syndrome::ACK,
+                msn: 1,
+            }),
+            atomic_ack: Some(5),
+            imm: None,
+            payload: &[],
+        };
+
+        for (packet, len) in [(request, ATOMIC_ETH_LEN), (ack, AETH_LEN + ATOMIC_ACK_ETH_LEN)] {
+            let mut buf = Vec::new();
+            packet.encode(&mut buf);
+            assert_eq!(buf.len(), BTH_LEN + len);
+            buf.extend_from_slice(&[0; ICRC_LEN]);
+            assert_eq!(Packet::parse(&buf), Some(packet));
+        }
+    }
+}
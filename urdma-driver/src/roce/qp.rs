@@ -0,0 +1,1202 @@
+//! Reliable connected transport
+//!
+//! The requester keeps every unacknowledged work request with its payload, assigns PSNs at post time and resends
+//! all of them from the oldest one (go-back-N) when the retransmission timer fires, a PSN sequence NAK arrives or an
+//! RNR wait ends. The responder executes requests in PSN order, answers duplicates without executing them again,
+//! except RDMA reads, and NAKs the first out of order packet. Duplicate atomics get the result saved when they were
+//! executed.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use ffi::{ibv_access_flags, ibv_qp_attr_mask, ibv_qp_state, ibv_wc_opcode, ibv_wc_status};
+use provider::{Payload, Remote, SendOp, SendWr, VerbsError};
+
+use super::packet::{self, Aeth, AtomicEth, Bth, Packet, Reth, opcode, syndrome};
+use super::{Cq, Device};
+use crate::gid;
+use crate::loopback::{atomic, sge_list};
+
+const PSN_MASK: u32 = 0xff_ffff;
+
+/// Path MTU until one is set by `modify_qp`
+const DEFAULT_MTU: usize = 1024;
+
+/// Local ACK timeout exponent until one is set by `modify_qp`
+const DEFAULT_TIMEOUT: u8 = 14;
+
+/// RNR retry count meaning "retry forever"
+pub(crate) const RNR_RETRY_INFINITE: u8 = 7;
+
+/// RNR NAK timer values in microseconds, indexed by the encoded timer
+pub(crate) const RNR_TIMER_US: [u64; 32] = [
+    655_360, 10, 20, 30, 40, 60, 80, 120, 160, 240, 320, 480, 640, 960, 1_280, 1_920, 2_560, 3_840, 5_120, 7_680,
+    10_240, 15_360, 20_480, 30_720, 40_960, 61_440, 81_920, 122_880, 163_840, 245_760, 327_680, 491_520,
+];
+
+const REMOTE_READ: ibv_access_flags = ibv_access_flags::IBV_ACCESS_REMOTE_READ;
+const REMOTE_WRITE: ibv_access_flags = ibv_access_flags::IBV_ACCESS_REMOTE_WRITE;
+const REMOTE_ATOMIC: ibv_access_flags = ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC;
+
+/// Results of executed atomics the responder keeps for duplicates, as many as `max_qp_rd_atom`
+const ATOMIC_RESULTS: usize = 128;
+
+fn psn_add(psn: u32, n: u32) -> u32 {
+    psn.wrapping_add(n) & PSN_MASK
+}
+
+/// Signed distance from `b` to `a` in the 24 bit PSN space.
+fn psn_diff(a: u32, b: u32) -> i32 {
+    (a.wrapping_sub(b) << 8) as i32 >> 8
+}
+
+/// Number of packets a message of `len` bytes takes, zero length messages still take one.
+fn packets(len: usize, mtu: usize) -> u32 {
+    len.div_ceil(mtu).max(1) as u32
+}
+
+pub(super) struct Qp {
+    pub(super) qp_num: u32,
+    pub(super) pd: u32,
+    pub(super) qp_type: ffi::ibv_qp_type::Type,
+    pub(super) sq_sig_all: bool,
+    pub(super) cap: ffi::ibv_qp_cap,
+    pub(super) send_cq: Arc<Cq>,
+    pub(super) recv_cq: Arc<Cq>,
+    pub(super) state: Mutex<QpState>,
+}
+
+#[derive(Default)]
+pub(super) struct QpState {
+    state: ibv_qp_state::Type,
+    conn: Connection,
+    req: Requester,
+    resp: Responder,
+}
+
+/// Attributes of the connection set by `modify_qp`
+struct Connection {
+    dest_qp_num: u32,
+    dest: Option<SocketAddr>,
+    /// encoded `ibv_mtu`
+    path_mtu: u32,
+    access: ibv_access_flags,
+    timeout: u8,
+    retry_cnt: u8,
+    rnr_retry: u8,
+    min_rnr_timer: u8,
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        Self {
+            dest_qp_num: 0,
+            dest: None,
+            path_mtu: ffi::IBV_MTU_1024 as _,
+            access: ibv_access_flags(0),
+            timeout: DEFAULT_TIMEOUT,
+            retry_cnt: 7,
+            rnr_retry: 7,
+            min_rnr_timer: 0,
+        }
+    }
+}
+
+impl Connection {
+    fn mtu(&self) -> usize {
+        match self.path_mtu {
+            1..=5 => 128 << self.path_mtu,
+            _ => DEFAULT_MTU,
+        }
+    }
+
+    /// Local ACK timeout, none when it is infinite.
+    fn timeout(&self) -> Option<Duration> {
+        (self.timeout != 0).then(|| Duration::from_nanos(4096 << self.timeout))
+    }
+}
+
+#[derive(Default)]
+struct Requester {
+    /// PSN of the next request
+    psn: u32,
+    /// requests not yet acknowledged, oldest first
+    pending: VecDeque<SendWqe>,
+    retry_left: u8,
+    rnr_left: u8,
+    /// when the retransmission timer, or the RNR timer with `rnr_wait`, fires
+    deadline: Option<Instant>,
+    rnr_wait: bool,
+}
+
+struct SendWqe {
+    wr_id: u64,
+    opcode: ibv_wc_opcode::Type,
+    signaled: bool,
+    request: Request,
+    len: usize,
+    first_psn: u32,
+    /// number of PSNs taken, the number of response packets for reads
+    npkts: u32,
+}
+
+enum Request {
+    Send {
+        payload: Vec<u8>,
+        imm: Option<u32>,
+    },
+    Write {
+        payload: Vec<u8>,
+        imm: Option<u32>,
+        va: u64,
+        rkey: u32,
+    },
+    Read {
+        sges: Vec<ffi::ibv_sge>,
+        va: u64,
+        rkey: u32,
+        /// PSN of the next expected response
+        next_psn: u32,
+        received: usize,
+    },
+    Atomic {
+        /// where the original value goes
+        sges: Vec<ffi::ibv_sge>,
+        atomic: AtomicEth,
+    },
+}
+
+impl SendWqe {
+    fn last_psn(&self) -> u32 {
+        psn_add(self.first_psn, self.npkts - 1)
+    }
+}
+
+#[derive(Default)]
+struct Responder {
+    /// expected PSN
+    epsn: u32,
+    /// message sequence number, counts completed requests
+    msn: u32,
+    /// posted receive requests
+    recv: VecDeque<RecvWqe>,
+    /// receive request of a multi-packet SEND and the bytes placed so far
+    send: Option<(RecvWqe, usize)>,
+    /// target of a multi-packet RDMA WRITE
+    write: Option<WriteTarget>,
+    /// a PSN sequence NAK is outstanding, later out of order packets are dropped silently
+    nak_sent: bool,
+    /// PSN and original value of the latest atomics, oldest first
+    atomics: VecDeque<(u32, u64)>,
+}
+
+struct RecvWqe {
+    wr_id: u64,
+    sges: Vec<ffi::ibv_sge>,
+}
+
+struct WriteTarget {
+    va: u64,
+    rkey: u32,
+    len: usize,
+    written: usize,
+}
+
+/// How the responder rejects a request, carried in the syndrome of the NAK
+type Nak = u8;
+
+impl Qp {
+    fn complete_send(&self, wqe: &SendWqe, status: ibv_wc_status::Type) {
+        if status == ibv_wc_status::IBV_WC_SUCCESS && !wqe.signaled {
+            return;
+        }
+
+        let byte_len = if matches!(wqe.request, Request::Read { .. } | Request::Atomic { .. }) {
+            wqe.len as u32
+        } else {
+            0
+        };
+        let mut wc = ffi::ibv_wc::new(wqe.wr_id, status, wqe.opcode, byte_len);
+        wc.qp_num = self.qp_num;
+        self.send_cq.push(wc);
+    }
+
+    fn complete_recv(
+        &self,
+        state: &QpState,
+        wqe: &RecvWqe,
+        status: ibv_wc_status::Type,
+        opcode: ibv_wc_opcode::Type,
+        byte_len: usize,
+        imm: Option<u32>,
+    ) {
+        let mut wc = ffi::ibv_wc::new(wqe.wr_id, status, opcode, byte_len as u32);
+        wc.qp_num = self.qp_num;
+        wc.src_qp = state.conn.dest_qp_num;
+        if let Some(imm) = imm {
+            // immediate data stays in network byte order
+            wc.imm_data = imm.to_be();
+            wc.wc_flags = ffi::ibv_wc_flags::IBV_WC_WITH_IMM;
+        }
+        self.recv_cq.push(wc);
+    }
+
+    /// Move to the error state, flushing all outstanding work requests.
+    fn enter_error(&self, state: &mut QpState) {
+        state.state = ibv_qp_state::IBV_QPS_ERR;
+        state.req.deadline = None;
+
+        for wqe in core::mem::take(&mut state.req.pending) {
+            self.complete_send(&wqe, ibv_wc_status::IBV_WC_WR_FLUSH_ERR);
+        }
+
+        let partial = state.resp.send.take().map(|(wqe, _)| wqe);
+        state.resp.write = None;
+        let recv = core::mem::take(&mut state.resp.recv);
+        for wqe in partial.iter().chain(&recv) {
+            self.complete_recv(
+                state,
+                wqe,
+                ibv_wc_status::IBV_WC_WR_FLUSH_ERR,
+                ibv_wc_opcode::IBV_WC_RECV,
+                0,
+                None,
+            );
+        }
+    }
+
+    /// Complete the oldest request with `status` and move to the error state.
+    fn fail(&self, state: &mut QpState, status: ibv_wc_status::Type) {
+        if let Some(wqe) = state.req.pending.pop_front() {
+            log::debug!(
+                "QP {:#x}: request {:#x} failed with status {status}",
+                self.qp_num,
+                wqe.wr_id
+            );
+            self.complete_send(&wqe, status);
+        }
+        self.enter_error(state);
+    }
+}
+
+impl Qp {
+    /// Apply the attributes selected by `attr_mask`.
+    pub(super) fn modify(
+        &self,
+        dev: &Device,
+        attr: &ffi::ibv_qp_attr,
+        attr_mask: ibv_qp_attr_mask,
+    ) -> provider::Result {
+        let has = |bit: ibv_qp_attr_mask| (attr_mask & bit).0 != 0;
+
+        let dest = if has(ibv_qp_attr_mask::IBV_QP_AV) {
+            let ah = &attr.ah_attr;
+            // RoCEv2 always needs a GRH, whose destination GID holds the peer address
+            if ah.is_global == 0 {
+                return Err(VerbsError::InvalidArgument);
+            }
+            Some(SocketAddr::new(gid::address(&ah.grh.dgid), dev.addr.port()))
+        } else {
+            None
+        };
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let conn = &mut state.conn;
+
+        if let Some(dest) = dest {
+            conn.dest = Some(dest);
+        }
+        if has(ibv_qp_attr_mask::IBV_QP_DEST_QPN) {
+            conn.dest_qp_num = attr.dest_qp_num & PSN_MASK;
+        }
+        if has(ibv_qp_attr_mask::IBV_QP_PATH_MTU) {
+            conn.path_mtu = attr.path_mtu as _;
+        }
+        if has(ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS) {
+            conn.access = ibv_access_flags(attr.qp_access_flags as _);
+        }
+        if has(ibv_qp_attr_mask::IBV_QP_TIMEOUT) {
+            conn.timeout = attr.timeout;
+        }
+        if has(ibv_qp_attr_mask::IBV_QP_RETRY_CNT) {
+            conn.retry_cnt = attr.retry_cnt.min(7);
+        }
+        if has(ibv_qp_attr_mask::IBV_QP_RNR_RETRY) {
+            conn.rnr_retry = attr.rnr_retry.min(7);
+        }
+        if has(ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER) {
+            conn.min_rnr_timer = attr.min_rnr_timer & 0x1f;
+        }
+        let (retry_cnt, rnr_retry) = (conn.retry_cnt, conn.rnr_retry);
+        if has(ibv_qp_attr_mask::IBV_QP_RQ_PSN) {
+            state.resp.epsn = attr.rq_psn & PSN_MASK;
+        }
+        if has(ibv_qp_attr_mask::IBV_QP_SQ_PSN) {
+            state.req.psn = attr.sq_psn & PSN_MASK;
+        }
+        // a request in flight keeps its remaining retries unless they are set anew
+        let to_rts = has(ibv_qp_attr_mask::IBV_QP_STATE)
+            && state.state == ibv_qp_state::IBV_QPS_RTR
+            && attr.qp_state == ibv_qp_state::IBV_QPS_RTS;
+        if to_rts || has(ibv_qp_attr_mask::IBV_QP_RETRY_CNT) {
+            state.req.retry_left = retry_cnt;
+        }
+        if to_rts || has(ibv_qp_attr_mask::IBV_QP_RNR_RETRY) {
+            state.req.rnr_left = rnr_retry;
+        }
+
+        if has(ibv_qp_attr_mask::IBV_QP_STATE) {
+            match attr.qp_state {
+                ibv_qp_state::IBV_QPS_ERR => self.enter_error(&mut state),
+                ibv_qp_state::IBV_QPS_RESET => {
+                    self.enter_error(&mut state);
+                    *state = QpState::default();
+                }
+                _ => {}
+            }
+            state.state = attr.qp_state;
+        }
+
+        Ok(())
+    }
+
+    /// Report the current attributes.
+    pub(super) fn query(&self, attr: &mut ffi::ibv_qp_attr) {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let conn = &state.conn;
+
+        attr.qp_state = state.state;
+        attr.cur_qp_state = state.state;
+        attr.path_mtu = conn.path_mtu as _;
+        attr.dest_qp_num = conn.dest_qp_num;
+        attr.qp_access_flags = conn.access.0 as _;
+        attr.rq_psn = state.resp.epsn;
+        attr.sq_psn = state.req.psn;
+        attr.timeout = conn.timeout;
+        attr.retry_cnt = conn.retry_cnt;
+        attr.rnr_retry = conn.rnr_retry;
+        attr.min_rnr_timer = conn.min_rnr_timer;
+        attr.cap = self.cap;
+        attr.port_num = 1;
+        if let Some(dest) = conn.dest {
+            attr.ah_attr.is_global = 1;
+            attr.ah_attr.grh.dgid = gid::of(dest.ip());
+            attr.ah_attr.port_num = 1;
+        }
+    }
+}
+
+/// Requester
+impl Qp {
+    /// Post one send request and transmit it.
+    pub(super) fn post_send(&self, dev: &Device, wr: &SendWr) -> provider::Result {
+        let sges = match &wr.payload {
+            Payload::Sges(sges) => sges.as_slice(),
+            Payload::Inline(_) => &[],
+        };
+        if sges.len() > self.cap.max_send_sge as usize {
+            return Err(VerbsError::InvalidArgument);
+        }
+
+        if matches!(wr.op, SendOp::LocalInv(_) | SendOp::SendWithInv(_)) {
+            log::debug!("QP {:#x}: unsupported send operation {:?}", self.qp_num, wr.op);
+            return Err(VerbsError::InvalidArgument);
+        }
+        let opcode = wr.op.wc_opcode();
+        let signaled = self.sq_sig_all || wr.signaled();
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        match state.state {
+            ibv_qp_state::IBV_QPS_RTS => {}
+            ibv_qp_state::IBV_QPS_ERR => {
+                let mut wc = ffi::ibv_wc::new(wr.wr_id, ibv_wc_status::IBV_WC_WR_FLUSH_ERR, opcode, 0);
+                wc.qp_num = self.qp_num;
+                self.send_cq.push(wc);
+                return Ok(());
+            }
+            _ => return Err(VerbsError::InvalidArgument),
+        }
+        if state.req.pending.len() >= self.cap.max_send_wr as usize {
+            return Err(VerbsError::OutOfResources);
+        }
+
+        // immediate data travels in network byte order
+        let imm = wr.op.imm_data().map(u32::from_be);
+
+        let request = match wr.op {
+            SendOp::RdmaRead(remote) => Ok(Request::Read {
+                sges: sges.to_vec(),
+                va: remote.addr,
+                rkey: remote.rkey,
+                next_psn: 0,
+                received: 0,
+            }),
+            SendOp::Send | SendOp::SendWithImm(_) => dev
+                .gather(self.pd, &wr.payload)
+                .map(|payload| Request::Send { payload, imm }),
+            SendOp::RdmaWrite(remote) | SendOp::RdmaWriteWithImm(remote, _) => {
+                dev.gather(self.pd, &wr.payload).map(|payload| Request::Write {
+                    payload,
+                    imm,
+                    va: remote.addr,
+                    rkey: remote.rkey,
+                })
+            }
+            SendOp::AtomicCmpAndSwp { remote, compare, swap } => Ok(Request::Atomic {
+                sges: sges.to_vec(),
+                atomic: AtomicEth {
+                    va: remote.addr,
+                    rkey: remote.rkey,
+                    swap_add: swap,
+                    compare,
+                },
+            }),
+            SendOp::AtomicFetchAndAdd { remote, add } => Ok(Request::Atomic {
+                sges: sges.to_vec(),
+                atomic: AtomicEth {
+                    va: remote.addr,
+                    rkey: remote.rkey,
+                    swap_add: add,
+                    compare: 0,
+                },
+            }),
+            // rejected above
+            SendOp::LocalInv(_) | SendOp::SendWithInv(_) => Err(ibv_wc_status::IBV_WC_LOC_QP_OP_ERR),
+        };
+
+        let len = match &request {
+            Ok(Request::Send { payload, .. } | Request::Write { payload, .. }) => payload.len(),
+            _ => sges.iter().map(|sge| sge.length as usize).sum(),
+        };
+        let mut wqe = SendWqe {
+            wr_id: wr.wr_id,
+            opcode,
+            signaled,
+            request: Request::Send {
+                payload: Vec::new(),
+                imm: None,
+            },
+            len,
+            first_psn: state.req.psn,
+            npkts: packets(len, state.conn.mtu()),
+        };
+
+        match request {
+            Ok(request) => wqe.request = request,
+            Err(status) => {
+                self.complete_send(&wqe, status);
+                self.enter_error(&mut state);
+                return Ok(());
+            }
+        }
+
+        state.req.psn = psn_add(wqe.first_psn, wqe.npkts);
+        self.transmit(dev, &state, &mut wqe);
+        if state.req.deadline.is_none() {
+            state.req.deadline = state.conn.timeout().map(|timeout| Instant::now() + timeout);
+        }
+        state.req.pending.push_back(wqe);
+
+        Ok(())
+    }
+
+    /// Send all packets of `wqe`.
+    fn transmit(&self, dev: &Device, state: &QpState, wqe: &mut SendWqe) {
+        let conn = &state.conn;
+        let Some(dst) = conn.dest else {
+            return;
+        };
+        let bth = |opcode, psn, ack_req| Bth {
+            opcode,
+            solicited: false,
+            pkey: packet::DEFAULT_PKEY,
+            dest_qp: conn.dest_qp_num,
+            ack_req,
+            psn,
+        };
+
+        let (payload, imm, reth, ops) = match &mut wqe.request {
+            Request::Read {
+                va,
+                rkey,
+                next_psn,
+                received,
+                ..
+            } => {
+                *next_psn = wqe.first_psn;
+                *received = 0;
+                let reth = Reth {
+                    va: *va,
+                    rkey: *rkey,
+                    len: wqe.len as u32,
+                };
+                dev.send(
+                    dst,
+                    &Packet {
+                        bth: bth(opcode::READ_REQUEST, wqe.first_psn, true),
+                        reth: Some(reth),
+                        atomic: None,
+                        aeth: None,
+                        atomic_ack: None,
+                        imm: None,
+                        payload: &[],
+                    },
+                );
+                return;
+            }
+            Request::Atomic { atomic, .. } => {
+                let opcode = match wqe.opcode {
+                    ibv_wc_opcode::IBV_WC_COMP_SWAP => opcode::COMPARE_SWAP,
+                    _ => opcode::FETCH_ADD,
+                };
+                dev.send(
+                    dst,
+                    &Packet {
+                        bth: bth(opcode, wqe.first_psn, true),
+                        reth: None,
+                        atomic: Some(*atomic),
+                        aeth: None,
+                        atomic_ack: None,
+                        imm: None,
+                        payload: &[],
+                    },
+                );
+                return;
+            }
+            Request::Send { payload, imm } => (
+                payload,
+                *imm,
+                None,
+                [
+                    opcode::SEND_ONLY,
+                    opcode::SEND_ONLY_IMM,
+                    opcode::SEND_FIRST,
+                    opcode::SEND_MIDDLE,
+                    opcode::SEND_LAST,
+                    opcode::SEND_LAST_IMM,
+                ],
+            ),
+            Request::Write { payload, imm, va, rkey } => (
+                payload,
+                *imm,
+                Some(Reth {
+                    va: *va,
+                    rkey: *rkey,
+                    len: wqe.len as u32,
+                }),
+                [
+                    opcode::WRITE_ONLY,
+                    opcode::WRITE_ONLY_IMM,
+                    opcode::WRITE_FIRST,
+                    opcode::WRITE_MIDDLE,
+                    opcode::WRITE_LAST,
+                    opcode::WRITE_LAST_IMM,
+                ],
+            ),
+        };
+        let [only, only_imm, first, middle, last, last_imm] = ops;
+
+        let mtu = conn.mtu();
+        for i in 0..wqe.npkts {
+            let start = i as usize * mtu;
+            let chunk = &payload[start..payload.len().min(start + mtu)];
+            let is_first = i == 0;
+            let is_last = i + 1 == wqe.npkts;
+            let opcode = match (is_first, is_last, imm.is_some()) {
+                (true, true, false) => only,
+                (true, true, true) => only_imm,
+                (true, false, _) => first,
+                (false, false, _) => middle,
+                (false, true, false) => last,
+                (false, true, true) => last_imm,
+            };
+
+            dev.send(
+                dst,
+                &Packet {
+                    bth: bth(opcode, psn_add(wqe.first_psn, i), is_last),
+                    reth: reth.filter(|_| is_first),
+                    atomic: None,
+                    aeth: None,
+                    atomic_ack: None,
+                    imm: imm.filter(|_| is_last),
+                    payload: chunk,
+                },
+            );
+        }
+    }
+
+    /// Resend every pending request, oldest first.
+    fn retransmit(&self, dev: &Device, state: &mut QpState) {
+        let mut pending = core::mem::take(&mut state.req.pending);
+        for wqe in &mut pending {
+            self.transmit(dev, state, wqe);
+        }
+        state.req.pending = pending;
+        state.req.rnr_wait = false;
+        state.req.deadline = state.conn.timeout().map(|timeout| Instant::now() + timeout);
+    }
+
+    /// Complete requests up to and including `psn`, except reads and atomics which complete with their responses.
+    fn acknowledge(&self, state: &mut QpState, psn: u32) {
+        let mut progress = false;
+        while let Some(wqe) = state.req.pending.front() {
+            if matches!(wqe.request, Request::Read { .. } | Request::Atomic { .. }) || psn_diff(wqe.last_psn(), psn) > 0
+            {
+                break;
+            }
+            let wqe = state.req.pending.pop_front().unwrap();
+            self.complete_send(&wqe, ibv_wc_status::IBV_WC_SUCCESS);
+            progress = true;
+        }
+
+        if progress {
+            self.made_progress(state);
+        }
+    }
+
+    /// Reset the retry counters and restart the retransmission timer.
+    fn made_progress(&self, state: &mut QpState) {
+        state.req.retry_left = state.conn.retry_cnt;
+        state.req.rnr_left = state.conn.rnr_retry;
+        state.req.rnr_wait = false;
+        state.req.deadline = if state.req.pending.is_empty() {
+            None
+        } else {
+            state.conn.timeout().map(|timeout| Instant::now() + timeout)
+        };
+    }
+
+    /// Handle an ACK, NAK, read response or atomic acknowledgement.
+    fn response(&self, dev: &Device, state: &mut QpState, pkt: &Packet<'_>) {
+        let psn = pkt.bth.psn;
+
+        match pkt.bth.opcode {
+            opcode::ACK => {}
+            opcode::ATOMIC_ACKNOWLEDGE => return self.atomic_response(state, dev, pkt),
+            _ => return self.read_response(state, dev, pkt),
+        }
+
+        let Some(aeth) = pkt.aeth else {
+            return;
+        };
+        let before = psn_add(psn, PSN_MASK);
+        match aeth.syndrome & syndrome::TYPE_MASK {
+            0 => self.acknowledge(state, psn),
+            syndrome::RNR_NAK => {
+                self.acknowledge(state, before);
+                if state.req.rnr_left == 0 {
+                    return self.fail(state, ibv_wc_status::IBV_WC_RNR_RETRY_EXC_ERR);
+                }
+                if state.req.rnr_left != RNR_RETRY_INFINITE {
+                    state.req.rnr_left -= 1;
+                }
+                let delay = Duration::from_micros(RNR_TIMER_US[usize::from(aeth.syndrome & 0x1f)]);
+                state.req.rnr_wait = true;
+                state.req.deadline = Some(Instant::now() + delay);
+            }
+            _ if aeth.syndrome == syndrome::NAK_PSN_SEQUENCE => {
+                self.acknowledge(state, before);
+                self.retransmit(dev, state);
+            }
+            _ => {
+                self.acknowledge(state, before);
+                let status = match aeth.syndrome {
+                    syndrome::NAK_INVALID_REQUEST => ibv_wc_status::IBV_WC_REM_INV_REQ_ERR,
+                    syndrome::NAK_REMOTE_ACCESS => ibv_wc_status::IBV_WC_REM_ACCESS_ERR,
+                    _ => ibv_wc_status::IBV_WC_REM_OP_ERR,
+                };
+                self.fail(state, status);
+            }
+        }
+    }
+
+    fn read_response(&self, state: &mut QpState, dev: &Device, pkt: &Packet<'_>) {
+        let psn = pkt.bth.psn;
+
+        // a response implies every earlier request was executed
+        self.acknowledge(state, psn_add(psn, PSN_MASK));
+
+        let Some(wqe) = state.req.pending.front_mut() else {
+            return;
+        };
+        let Request::Read {
+            sges,
+            next_psn,
+            received,
+            ..
+        } = &mut wqe.request
+        else {
+            return;
+        };
+        // duplicates and responses after a lost one are dropped, the timer recovers
+        if psn != *next_psn {
+            return;
+        }
+
+        if let Err(status) = dev.scatter(self.pd, sges, *received, pkt.payload) {
+            return self.fail(state, status);
+        }
+        *received += pkt.payload.len();
+        *next_psn = psn_add(psn, 1);
+
+        if psn == wqe.last_psn() {
+            let wqe = state.req.pending.pop_front().unwrap();
+            self.complete_send(&wqe, ibv_wc_status::IBV_WC_SUCCESS);
+        }
+        self.made_progress(state);
+    }
+
+    fn atomic_response(&self, state: &mut QpState, dev: &Device, pkt: &Packet<'_>) {
+        let psn = pkt.bth.psn;
+
+        // a response implies every earlier request was executed
+        self.acknowledge(state, psn_add(psn, PSN_MASK));
+
+        let Some(wqe) = state.req.pending.front() else {
+            return;
+        };
+        let (Request::Atomic { sges, .. }, Some(original)) = (&wqe.request, pkt.atomic_ack) else {
+            return;
+        };
+        if psn != wqe.first_psn {
+            return;
+        }
+
+        if let Err(status) = dev.scatter(self.pd, sges, 0, &original.to_ne_bytes()) {
+            return self.fail(state, status);
+        }
+        let wqe = state.req.pending.pop_front().unwrap();
+        self.complete_send(&wqe, ibv_wc_status::IBV_WC_SUCCESS);
+        self.made_progress(state);
+    }
+
+    /// Fire the retransmission or RNR timer if it expired by `now`.
+    pub(super) fn tick(&self, dev: &Device, now: Instant) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.state != ibv_qp_state::IBV_QPS_RTS || state.req.deadline.is_none_or(|deadline| now < deadline) {
+            return;
+        }
+        if state.req.pending.is_empty() {
+            state.req.deadline = None;
+            return;
+        }
+
+        if !state.req.rnr_wait {
+            if state.req.retry_left == 0 {
+                return self.fail(&mut state, ibv_wc_status::IBV_WC_RETRY_EXC_ERR);
+            }
+            state.req.retry_left -= 1;
+            log::trace!(
+                "QP {:#x}: retransmitting from PSN {:#x}",
+                self.qp_num,
+                state.req.pending[0].first_psn
+            );
+        }
+        self.retransmit(dev, &mut state);
+    }
+}
+
+/// Responder
+impl Qp {
+    /// Post one receive request.
+    pub(super) fn post_recv(&self, wr: &ffi::ibv_recv_wr) -> provider::Result {
+        // Safety: the application hands us `num_sge` valid entries.
+        let sges = unsafe { sge_list(wr.sg_list, wr.num_sge) };
+        if sges.len() > self.cap.max_recv_sge as usize {
+            return Err(VerbsError::InvalidArgument);
+        }
+        let wqe = RecvWqe {
+            wr_id: wr.wr_id,
+            sges: sges.to_vec(),
+        };
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        match state.state {
+            ibv_qp_state::IBV_QPS_RESET => return Err(VerbsError::InvalidArgument),
+            _ if state.resp.recv.len() >= self.cap.max_recv_wr as usize => return Err(VerbsError::OutOfResources),
+            ibv_qp_state::IBV_QPS_ERR => self.complete_recv(
+                &state,
+                &wqe,
+                ibv_wc_status::IBV_WC_WR_FLUSH_ERR,
+                ibv_wc_opcode::IBV_WC_RECV,
+                0,
+                None,
+            ),
+            _ => state.resp.recv.push_back(wqe),
+        }
+
+        Ok(())
+    }
+
+    /// Handle a packet from `src`.
+    pub(super) fn receive(&self, dev: &Device, src: SocketAddr, pkt: &Packet<'_>) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // peers such as rxe pick the source port per QP, answers go to the RoCEv2 port of the peer
+        let from_peer = state
+            .conn
+            .dest
+            .filter(|dest| dest.ip().to_canonical() == src.ip().to_canonical());
+        let Some(src) = from_peer else {
+            log::trace!("QP {:#x}: dropped packet from {src}", self.qp_num);
+            return;
+        };
+
+        match pkt.bth.opcode {
+            opcode::READ_RESPONSE_FIRST..=opcode::ATOMIC_ACKNOWLEDGE => {
+                if state.state == ibv_qp_state::IBV_QPS_RTS {
+                    self.response(dev, &mut state, pkt);
+                }
+            }
+            _ => {
+                if matches!(state.state, ibv_qp_state::IBV_QPS_RTR | ibv_qp_state::IBV_QPS_RTS) {
+                    self.request(dev, &mut state, src, pkt);
+                }
+            }
+        }
+    }
+
+    fn request(&self, dev: &Device, state: &mut QpState, src: SocketAddr, pkt: &Packet<'_>) {
+        let psn = pkt.bth.psn;
+        let diff = psn_diff(psn, state.resp.epsn);
+
+        if diff > 0 {
+            if !state.resp.nak_sent {
+                state.resp.nak_sent = true;
+                let epsn = state.resp.epsn;
+                self.ack(dev, state, src, syndrome::NAK_PSN_SEQUENCE, epsn);
+            }
+            return;
+        }
+
+        if diff < 0 {
+            // the response got lost, answer again without executing the request twice
+            if pkt.bth.opcode == opcode::READ_REQUEST {
+                let _ = self.read(dev, state, src, pkt);
+            } else if packet::has_atomic_eth(pkt.bth.opcode) {
+                // results too old to be kept belong to requests the requester saw completing
+                let result = state.resp.atomics.iter().find(|&&(atomic_psn, _)| atomic_psn == psn);
+                if let Some(&(_, original)) = result {
+                    self.atomic_ack(dev, state, src, psn, original);
+                }
+            } else if pkt.bth.ack_req {
+                let last = psn_add(state.resp.epsn, PSN_MASK);
+                self.ack(dev, state, src, syndrome::ACK, last);
+            }
+            return;
+        }
+
+        let result = match pkt.bth.opcode {
+            opcode::SEND_FIRST..=opcode::SEND_ONLY_IMM => self.recv_send(dev, state, pkt),
+            opcode::WRITE_FIRST..=opcode::WRITE_ONLY_IMM => self.recv_write(dev, state, pkt),
+            opcode::READ_REQUEST => self.read(dev, state, src, pkt).inspect(|_| {
+                state.resp.msn = psn_add(state.resp.msn, 1);
+            }),
+            opcode::COMPARE_SWAP | opcode::FETCH_ADD => self.execute_atomic(dev, state, src, pkt),
+            _ => Err(syndrome::NAK_INVALID_REQUEST),
+        };
+
+        match result {
+            Ok(npkts) => {
+                state.resp.nak_sent = false;
+                state.resp.epsn = psn_add(psn, npkts);
+                // reads and atomics are answered by their responses
+                let responded = matches!(
+                    pkt.bth.opcode,
+                    opcode::READ_REQUEST | opcode::COMPARE_SWAP | opcode::FETCH_ADD
+                );
+                if pkt.bth.ack_req && !responded {
+                    self.ack(dev, state, src, syndrome::ACK, psn);
+                }
+            }
+            Err(nak) if nak & syndrome::TYPE_MASK == syndrome::RNR_NAK => self.ack(dev, state, src, nak, psn),
+            Err(nak) => {
+                log::debug!(
+                    "QP {:#x}: rejecting request at PSN {psn:#x} with syndrome {nak:#x}",
+                    self.qp_num
+                );
+                self.ack(dev, state, src, nak, psn);
+                self.enter_error(state);
+            }
+        }
+    }
+
+    /// Send an ACK or NAK carrying `psn` and the current MSN.
+    fn ack(&self, dev: &Device, state: &QpState, dst: SocketAddr, syndrome: u8, psn: u32) {
+        dev.send(
+            dst,
+            &Packet {
+                bth: Bth {
+                    opcode: opcode::ACK,
+                    solicited: false,
+                    pkey: packet::DEFAULT_PKEY,
+                    dest_qp: state.conn.dest_qp_num,
+                    ack_req: false,
+                    psn,
+                },
+                reth: None,
+                atomic: None,
+                aeth: Some(Aeth {
+                    syndrome,
+                    msn: state.resp.msn,
+                }),
+                atomic_ack: None,
+                imm: None,
+                payload: &[],
+            },
+        );
+    }
+
+    /// Place one SEND packet into the current receive request.
+    fn recv_send(&self, dev: &Device, state: &mut QpState, pkt: &Packet<'_>) -> Result<u32, Nak> {
+        let op = pkt.bth.opcode;
+        let first = matches!(op, opcode::SEND_FIRST | opcode::SEND_ONLY | opcode::SEND_ONLY_IMM);
+        let last = !matches!(op, opcode::SEND_FIRST | opcode::SEND_MIDDLE);
+
+        if first {
+            if state.resp.send.is_some() {
+                return Err(syndrome::NAK_INVALID_REQUEST);
+            }
+            let wqe = state
+                .resp
+                .recv
+                .pop_front()
+                .ok_or(syndrome::RNR_NAK | state.conn.min_rnr_timer)?;
+            state.resp.send = Some((wqe, 0));
+        }
+        let Some((wqe, offset)) = &mut state.resp.send else {
+            return Err(syndrome::NAK_INVALID_REQUEST);
+        };
+
+        if let Err(status) = dev.scatter(self.pd, &wqe.sges, *offset, pkt.payload) {
+            let (wqe, offset) = state.resp.send.take().unwrap();
+            self.complete_recv(state, &wqe, status, ibv_wc_opcode::IBV_WC_RECV, offset, None);
+            return Err(match status {
+                ibv_wc_status::IBV_WC_LOC_LEN_ERR => syndrome::NAK_INVALID_REQUEST,
+                _ => syndrome::NAK_REMOTE_OPERATIONAL,
+            });
+        }
+        *offset += pkt.payload.len();
+
+        if last {
+            let (wqe, byte_len) = state.resp.send.take().unwrap();
+            state.resp.msn = psn_add(state.resp.msn, 1);
+            self.complete_recv(
+                state,
+                &wqe,
+                ibv_wc_status::IBV_WC_SUCCESS,
+                ibv_wc_opcode::IBV_WC_RECV,
+                byte_len,
+                pkt.imm,
+            );
+        }
+
+        Ok(1)
+    }
+
+    /// Write one RDMA WRITE packet to memory.
+    fn recv_write(&self, dev: &Device, state: &mut QpState, pkt: &Packet<'_>) -> Result<u32, Nak> {
+        let op = pkt.bth.opcode;
+        let first = matches!(op, opcode::WRITE_FIRST | opcode::WRITE_ONLY | opcode::WRITE_ONLY_IMM);
+        let last = !matches!(op, opcode::WRITE_FIRST | opcode::WRITE_MIDDLE);
+
+        // nothing is written before a receive request for the immediate data is known to exist
+        if pkt.imm.is_some() && state.resp.recv.is_empty() {
+            return Err(syndrome::RNR_NAK | state.conn.min_rnr_timer);
+        }
+
+        if first {
+            let reth = pkt.reth.ok_or(syndrome::NAK_INVALID_REQUEST)?;
+            if state.resp.write.is_some() {
+                return Err(syndrome::NAK_INVALID_REQUEST);
+            }
+            let len = reth.len as usize;
+            if (state.conn.access & REMOTE_WRITE).0 == 0
+                || (len > 0
+                    && dev
+                        .resolve(reth.rkey, self.pd, reth.va, len, REMOTE_WRITE, |_| ())
+                        .is_none())
+            {
+                return Err(syndrome::NAK_REMOTE_ACCESS);
+            }
+            state.resp.write = Some(WriteTarget {
+                va: reth.va,
+                rkey: reth.rkey,
+                len,
+                written: 0,
+            });
+        }
+        let Some(target) = &mut state.resp.write else {
+            return Err(syndrome::NAK_INVALID_REQUEST);
+        };
+
+        let len = pkt.payload.len();
+        if target.written + len > target.len || (last && target.written + len != target.len) {
+            return Err(syndrome::NAK_INVALID_REQUEST);
+        }
+        if len > 0 {
+            // the region may have been deregistered since the first packet
+            dev.resolve(
+                target.rkey,
+                self.pd,
+                target.va + target.written as u64,
+                len,
+                REMOTE_WRITE,
+                |dst| {
+                    // Safety: the range lies in a remotely writable registered region.
+                    unsafe { core::ptr::copy(pkt.payload.as_ptr(), dst, len) };
+                },
+            )
+            .ok_or(syndrome::NAK_REMOTE_ACCESS)?;
+        }
+        target.written += len;
+
+        if last {
+            let byte_len = target.len;
+            state.resp.write = None;
+            state.resp.msn = psn_add(state.resp.msn, 1);
+            if pkt.imm.is_some() {
+                let wqe = state.resp.recv.pop_front().unwrap();
+                self.complete_recv(
+                    state,
+                    &wqe,
+                    ibv_wc_status::IBV_WC_SUCCESS,
+                    ibv_wc_opcode::IBV_WC_RECV_RDMA_WITH_IMM,
+                    byte_len,
+                    pkt.imm,
+                );
+            }
+        }
+
+        Ok(1)
+    }
+
+    /// Answer an RDMA READ request, returning the number of PSNs its responses take.
+    fn read(&self, dev: &Device, state: &QpState, src: SocketAddr, pkt: &Packet<'_>) -> Result<u32, Nak> {
+        let reth = pkt.reth.ok_or(syndrome::NAK_INVALID_REQUEST)?;
+        let len = reth.len as usize;
+
+        let data = if len == 0 {
+            Vec::new()
+        } else {
+            if (state.conn.access & REMOTE_READ).0 == 0 {
+                return Err(syndrome::NAK_REMOTE_ACCESS);
+            }
+            dev.resolve(reth.rkey, self.pd, reth.va, len, REMOTE_READ, |src| {
+                // Safety: the range lies in a remotely readable registered region.
+                unsafe { core::slice::from_raw_parts(src, len) }.to_vec()
+            })
+            .ok_or(syndrome::NAK_REMOTE_ACCESS)?
+        };
+
+        let mtu = state.conn.mtu();
+        let npkts = packets(len, mtu);
+        // the responses complete this request, whose MSN is the next one
+        let msn = psn_add(state.resp.msn, 1);
+        for i in 0..npkts {
+            let start = i as usize * mtu;
+            let (opcode, aeth) = match (i == 0, i + 1 == npkts) {
+                (true, true) => (opcode::READ_RESPONSE_ONLY, true),
+                (true, false) => (opcode::READ_RESPONSE_FIRST, true),
+                (false, false) => (opcode::READ_RESPONSE_MIDDLE, false),
+                (false, true) => (opcode::READ_RESPONSE_LAST, true),
+            };
+
+            dev.send(
+                src,
+                &Packet {
+                    bth: Bth {
+                        opcode,
+                        solicited: false,
+                        pkey: packet::DEFAULT_PKEY,
+                        dest_qp: state.conn.dest_qp_num,
+                        ack_req: false,
+                        psn: psn_add(pkt.bth.psn, i),
+                    },
+                    reth: None,
+                    atomic: None,
+                    aeth: aeth.then_some(Aeth {
+                        syndrome: syndrome::ACK,
+                        msn,
+                    }),
+                    atomic_ack: None,
+                    imm: None,
+                    payload: &data[start..len.min(start + mtu)],
+                },
+            );
+        }
+
+        Ok(npkts)
+    }
+
+    /// Execute an atomic request, returning the number of PSNs it takes.
+    fn execute_atomic(&self, dev: &Device, state: &mut QpState, src: SocketAddr, pkt: &Packet<'_>) -> Result<u32, Nak> {
+        let eth = pkt.atomic.ok_or(syndrome::NAK_INVALID_REQUEST)?;
+        if eth.va % 8 != 0 {
+            return Err(syndrome::NAK_INVALID_REQUEST);
+        }
+        if (state.conn.access & REMOTE_ATOMIC).0 == 0 {
+            return Err(syndrome::NAK_REMOTE_ACCESS);
+        }
+        let remote = Remote {
+            addr: eth.va,
+            rkey: eth.rkey,
+        };
+        let op = match pkt.bth.opcode {
+            opcode::COMPARE_SWAP => SendOp::AtomicCmpAndSwp {
+                remote,
+                compare: eth.compare,
+                swap: eth.swap_add,
+            },
+            _ => SendOp::AtomicFetchAndAdd {
+                remote,
+                add: eth.swap_add,
+            },
+        };
+        let original = dev
+            // Safety: the range lies in a registered region allowing remote atomics.
+            .resolve(eth.rkey, self.pd, eth.va, 8, REMOTE_ATOMIC, |dst| unsafe {
+                atomic(op, dst)
+            })
+            .ok_or(syndrome::NAK_REMOTE_ACCESS)?
+            .map_err(|_| syndrome::NAK_INVALID_REQUEST)?;
+
+        state.resp.msn = psn_add(state.resp.msn, 1);
+        if state.resp.atomics.len() == ATOMIC_RESULTS {
+            state.resp.atomics.pop_front();
+        }
+        state.resp.atomics.push_back((pkt.bth.psn, original));
+        self.atomic_ack(dev, state, src, pkt.bth.psn, original);
+
+        Ok(1)
+    }
+
+    /// Send the atomic acknowledgement of `psn`, carrying the original value.
+    fn atomic_ack(&self, dev: &Device, state: &QpState, dst: SocketAddr, psn: u32, original: u64) {
+        dev.send(
+            dst,
+            &Packet {
+                bth: Bth {
+                    opcode: opcode::ATOMIC_ACKNOWLEDGE,
+                    solicited: false,
+                    pkey: packet::DEFAULT_PKEY,
+                    dest_qp: state.conn.dest_qp_num,
+                    ack_req: false,
+                    psn,
+                },
+                reth: None,
+                atomic: None,
+                aeth: Some(Aeth {
+                    syndrome: syndrome::ACK,
+                    msn: state.resp.msn,
+                }),
+                atomic_ack: Some(original),
+                imm: None,
+                payload: &[],
+            },
+        );
+    }
+}
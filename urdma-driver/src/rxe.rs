@@ -1,9 +1,313 @@
+use core::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+
+use provider::{
+    AsyncEvent, AsyncEvents, CompletionQueue, CqEvent, CqNotifier, MemoryRegion, MemoryWindow, PortEvent, QpEvent,
+    QueuePair, SharedReceiveQueue, SrqEvent, VerbsError,
+};
+
 pub struct Rxe {
     pub(crate) rxe_context: *mut ffi::ibv_context,
+    /// created with the first CQ on a completion channel
+    pub(crate) comp_channel: Mutex<Option<CompChannel>>,
+    /// relays the async events of `rxe_context`
+    pub(crate) async_relay: Relay,
 }
 
+// Safety: a libibverbs context can be used from any thread.
+unsafe impl Send for Rxe {}
+unsafe impl Sync for Rxe {}
+
 impl Drop for Rxe {
     fn drop(&mut self) {
+        // the channel and the events belong to the context
+        drop(
+            self.comp_channel
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take(),
+        );
+        self.async_relay.stop();
         unsafe { ffi::ibv_close_device(self.rxe_context) };
     }
 }
+
+/// How long relay threads wait for an event before checking whether to stop
+const POLL_TIMEOUT_MS: core::ffi::c_int = 100;
+
+/// Thread relaying events of the rxe context until stopped
+pub(crate) struct Relay {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Relay {
+    /// Run `relay` on a thread named `name`, it must return once its argument is set.
+    fn spawn(name: &str, relay: impl FnOnce(&AtomicBool) + Send + 'static) -> provider::Result<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = Arc::clone(&stop);
+            std::thread::Builder::new()
+                .name(name.into())
+                .spawn(move || relay(&stop))?
+        };
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Relay the async events of `rxe_context` to `events`.
+    pub(crate) fn async_events(rxe_context: *mut ffi::ibv_context, events: AsyncEvents) -> provider::Result<Self> {
+        // a spurious wakeup must not block the relay in `ibv_get_async_event`
+        set_nonblocking(unsafe { (*rxe_context).async_fd });
+
+        let context = SendPtr(rxe_context);
+        Self::spawn("urdma-rxe-async", move |stop| {
+            relay_async_events(&context, &events, stop)
+        })
+    }
+
+    /// Stop and join the thread, the objects it uses may be destroyed afterwards.
+    pub(crate) fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Pointer moved to a relay thread
+struct SendPtr<T>(*mut T);
+
+// Safety: the pointee is only destroyed after the relay thread is joined.
+unsafe impl<T> Send for SendPtr<T> {}
+
+fn set_nonblocking(fd: core::ffi::c_int) {
+    unsafe { libc::fcntl(fd, libc::F_SETFL, libc::fcntl(fd, libc::F_GETFL) | libc::O_NONBLOCK) };
+}
+
+/// Wait up to [`POLL_TIMEOUT_MS`] for `fd` to become readable.
+fn readable(fd: core::ffi::c_int) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&raw mut pollfd, 1, POLL_TIMEOUT_MS) == 1 }
+}
+
+/// Forward the async events of `context` until `stop` is set.
+fn relay_async_events(context: &SendPtr<ffi::ibv_context>, events: &AsyncEvents, stop: &AtomicBool) {
+    let context = context.0;
+    let fd = unsafe { (*context).async_fd };
+
+    while !stop.load(Ordering::Relaxed) {
+        if !readable(fd) {
+            continue;
+        }
+
+        let mut event = ffi::ibv_async_event::default();
+        if unsafe { ffi::ibv_get_async_event(context, &raw mut event) } != 0 {
+            continue;
+        }
+        // Safety: the element matching the event type is set, and the object lives until the event is acknowledged.
+        match unsafe { async_event(&event) } {
+            Some(event) => events.post(event),
+            None => log::debug!("Async event {} of rxe not relayed", event.event_type),
+        }
+        unsafe { ffi::ibv_ack_async_event(&raw mut event) };
+    }
+}
+
+/// Translate an event of the rxe context, whose objects are shadows.
+///
+/// Safety: the element of `event` must point to a live object.
+unsafe fn async_event(event: &ffi::ibv_async_event) -> Option<AsyncEvent> {
+    let element = &event.element;
+    let qp = |qp_event| Some(AsyncEvent::Qp(unsafe { (*element.qp).qp_num }, qp_event));
+    let cq = |cq_event| Some(AsyncEvent::Cq(unsafe { (*element.cq).handle }, cq_event));
+    let srq = |srq_event| Some(AsyncEvent::Srq(unsafe { (*element.srq).handle }, srq_event));
+    let port = |port_event| {
+        Some(AsyncEvent::Port(
+            u8::try_from(unsafe { element.port_num }).ok()?,
+            port_event,
+        ))
+    };
+
+    match event.event_type {
+        ffi::IBV_EVENT_QP_FATAL => qp(QpEvent::Fatal),
+        ffi::IBV_EVENT_QP_REQ_ERR => qp(QpEvent::RequestError),
+        ffi::IBV_EVENT_QP_ACCESS_ERR => qp(QpEvent::AccessError),
+        ffi::IBV_EVENT_COMM_EST => qp(QpEvent::CommunicationEstablished),
+        ffi::IBV_EVENT_SQ_DRAINED => qp(QpEvent::SqDrained),
+        ffi::IBV_EVENT_PATH_MIG => qp(QpEvent::PathMigrated),
+        ffi::IBV_EVENT_PATH_MIG_ERR => qp(QpEvent::PathMigrationError),
+        ffi::IBV_EVENT_QP_LAST_WQE_REACHED => qp(QpEvent::LastWqeReached),
+        ffi::IBV_EVENT_CQ_ERR => cq(CqEvent::Error),
+        ffi::IBV_EVENT_SRQ_ERR => srq(SrqEvent::Error),
+        ffi::IBV_EVENT_SRQ_LIMIT_REACHED => srq(SrqEvent::LimitReached),
+        ffi::IBV_EVENT_PORT_ACTIVE => port(PortEvent::Active),
+        ffi::IBV_EVENT_PORT_ERR => port(PortEvent::Error),
+        ffi::IBV_EVENT_LID_CHANGE => port(PortEvent::LidChange),
+        ffi::IBV_EVENT_PKEY_CHANGE => port(PortEvent::PkeyChange),
+        ffi::IBV_EVENT_GID_CHANGE => port(PortEvent::GidChange),
+        ffi::IBV_EVENT_SM_CHANGE => port(PortEvent::SmChange),
+        ffi::IBV_EVENT_CLIENT_REREGISTER => port(PortEvent::ClientReregister),
+        ffi::IBV_EVENT_DEVICE_FATAL => Some(AsyncEvent::DeviceFatal),
+        // urdma has no work queues
+        _ => None,
+    }
+}
+
+/// Completion channel on the rxe context
+///
+/// Every shadow CQ created for a urdma CQ on a completion channel uses it, with its [`CqNotifier`] as `cq_context`. A
+/// thread relays the events rxe generates to the notifiers, rxe itself tracks how the CQs are armed.
+pub(crate) struct CompChannel {
+    channel: *mut ffi::ibv_comp_channel,
+    relay: Relay,
+}
+
+impl CompChannel {
+    pub(crate) fn new(rxe_context: *mut ffi::ibv_context) -> provider::Result<Self> {
+        let channel = unsafe { ffi::ibv_create_comp_channel(rxe_context) };
+        if channel.is_null() {
+            return Err(VerbsError::last_os_error());
+        }
+
+        // a spurious wakeup must not block the relay in `ibv_get_cq_event`
+        set_nonblocking(unsafe { (*channel).fd });
+
+        let relay = {
+            let channel = SendPtr(channel);
+            Relay::spawn("urdma-rxe-cq", move |stop| relay_cq_events(&channel, stop))
+        };
+        match relay {
+            Ok(relay) => Ok(Self { channel, relay }),
+            Err(err) => {
+                unsafe { ffi::ibv_destroy_comp_channel(channel) };
+                Err(err)
+            }
+        }
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut ffi::ibv_comp_channel {
+        self.channel
+    }
+}
+
+impl Drop for CompChannel {
+    fn drop(&mut self) {
+        self.relay.stop();
+        unsafe { ffi::ibv_destroy_comp_channel(self.channel) };
+    }
+}
+
+/// Forward the events of `channel` until `stop` is set.
+fn relay_cq_events(channel: &SendPtr<ffi::ibv_comp_channel>, stop: &AtomicBool) {
+    let channel = channel.0;
+    let fd = unsafe { (*channel).fd };
+
+    while !stop.load(Ordering::Relaxed) {
+        if !readable(fd) {
+            continue;
+        }
+
+        let mut cq = core::ptr::null_mut();
+        let mut cq_context = core::ptr::null_mut();
+        if unsafe { ffi::ibv_get_cq_event(channel, &raw mut cq, &raw mut cq_context) } != 0 {
+            continue;
+        }
+        // Safety: every shadow CQ on the channel carries its notifier, freed only after the CQ is destroyed, which
+        // waits for this event to be acknowledged.
+        if let Some(notifier) = unsafe { cq_context.cast::<CqNotifier>().as_ref() } {
+            notifier.notify();
+        }
+        unsafe { ffi::ibv_ack_cq_events(cq, 1) };
+    }
+}
+
+/// Shadow object created on the rxe context
+///
+/// Every urdma object owns exactly one shadow and the glue resolves the urdma handle to it, so the shadow is only ever
+/// handed to rxe's own verbs and no verbs struct is mutated in flight.
+pub struct Shadow<T>(NonNull<T>);
+
+impl<T> Shadow<T> {
+    /// Wrap the object returned by a rxe verb, `None` if the verb failed.
+    pub(crate) fn new(ptr: *mut T) -> Option<Self> {
+        NonNull::new(ptr).map(Self)
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut T {
+        self.0.as_ptr()
+    }
+}
+
+// Safety: shadows are only accessed through rxe verbs, which are thread safe.
+unsafe impl<T> Send for Shadow<T> {}
+unsafe impl<T> Sync for Shadow<T> {}
+
+/// protection domain allocated on the rxe context
+pub type RxePd = Shadow<ffi::ibv_pd>;
+
+/// completion queue created on the rxe context
+pub type RxeCq = Shadow<ffi::ibv_cq>;
+
+/// queue pair created on the rxe context
+pub type RxeQp = Shadow<ffi::ibv_qp>;
+
+/// memory region registered on the rxe context
+pub type RxeMr = Shadow<ffi::ibv_mr>;
+
+/// shared receive queue created on the rxe context
+pub type RxeSrq = Shadow<ffi::ibv_srq>;
+
+/// memory window allocated on the rxe context
+pub type RxeMw = Shadow<ffi::ibv_mw>;
+
+/// address handle created on the rxe context
+pub type RxeAh = Shadow<ffi::ibv_ah>;
+
+impl QueuePair for RxeQp {
+    fn qp_num(&self) -> u32 {
+        unsafe { self.0.as_ref() }.qp_num
+    }
+}
+
+impl CompletionQueue for RxeCq {
+    fn cq_num(&self) -> u32 {
+        unsafe { self.0.as_ref() }.handle
+    }
+}
+
+impl SharedReceiveQueue for RxeSrq {
+    fn srq_num(&self) -> u32 {
+        unsafe { self.0.as_ref() }.handle
+    }
+}
+
+impl MemoryRegion for RxeMr {
+    fn lkey(&self) -> u32 {
+        unsafe { self.0.as_ref() }.lkey
+    }
+
+    fn rkey(&self) -> u32 {
+        unsafe { self.0.as_ref() }.rkey
+    }
+}
+
+impl MemoryWindow for RxeMw {
+    fn rkey(&self) -> u32 {
+        unsafe { self.0.as_ref() }.rkey
+    }
+}
@@ -0,0 +1,1421 @@
+//! Multi-process shared memory backend
+//!
+//! QPs of different processes on one host talk through files under `URDMA_SHM_DIR` (default `/dev/shm/urdma`). The
+//! file `qp.<qpn>` of a QP holds two rings: the requests its peer sends it, and the responses of the peer to its own
+//! requests. Each device runs a progress thread that executes incoming requests on the memory registered in its
+//! process and completes requests once their response arrives, the application's pages stay as they are.
+//!
+//! An RC QP keeps one request in flight. A SEND, or an RDMA WRITE with immediate, that finds no receive request is
+//! answered by an RNR NAK and sent again after the responder's RNR timer, at most `rnr_retry` times. Atomics are
+//! executed by the responder's thread, so they are atomic among all requesters. UC requests complete once they are
+//! placed in the peer's ring and get no response.
+//!
+//! A process that dies leaves its QP files behind, they are removed when another process opens the directory. A ring
+//! lock it held is taken over by the next producer.
+
+mod ops;
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, PoisonError, RwLock, Weak};
+use std::time::{Duration, Instant};
+
+use ffi::{ibv_access_flags, ibv_qp_state, ibv_wc_opcode, ibv_wc_status};
+use provider::{CompletionQueue, MemoryRegion, Payload, QueuePair, SendOp, SendWr, VerbsError};
+
+use crate::loopback::{atomic, sge_list};
+use crate::roce::{RNR_RETRY_INFINITE, RNR_TIMER_US};
+
+const ENV_SHM_DIR: &str = "URDMA_SHM_DIR";
+const DEFAULT_SHM_DIR: &str = "/dev/shm/urdma";
+
+/// marks an initialized shared file
+const MAGIC: u32 = u32::from_be_bytes(*b"urdm");
+
+const RING_SLOTS: usize = 64;
+/// largest message a request or response can carry
+const SLOT_SIZE: usize = 16 << 10;
+
+/// How often the progress thread checks timers when no message arrives
+const TICK: Duration = Duration::from_millis(1);
+
+/// Local ACK timeout exponent until one is set by `modify_qp`
+const DEFAULT_TIMEOUT: u8 = 14;
+
+/// How long a producer waits for a ring lock before checking its holder is alive
+const LOCK_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// State of a QP its peers read, at the start of its file
+#[repr(C)]
+struct QpHeader {
+    magic: AtomicU32,
+    /// cleared when the QP is destroyed, peers drop their mapping
+    alive: AtomicU32,
+    state: AtomicU32,
+    /// pid of the process of the QP
+    owner: AtomicU32,
+    /// requests of the peer
+    requests: Ring,
+    /// responses of the peer to requests of the QP
+    responses: Ring,
+}
+
+/// Indices of a ring of slots
+#[repr(C)]
+struct Ring {
+    /// serializes producers, pid of the holder or 0
+    lock: AtomicU32,
+    /// next slot the owner consumes
+    head: AtomicU32,
+    /// next slot a producer fills
+    tail: AtomicU32,
+}
+
+/// Message in a ring
+#[repr(C)]
+struct Slot {
+    kind: u32,
+    src_qp: u32,
+    seq: u32,
+    arg: u32,
+    imm_data: u32,
+    has_imm: u32,
+    rkey: u32,
+    /// bytes used in `payload`
+    len: u32,
+    addr: u64,
+    operands: [u64; 2],
+    payload: [u8; SLOT_SIZE],
+}
+
+/// Offset of the slots of the request ring, those of the response ring follow
+const SLOTS_OFFSET: usize = size_of::<QpHeader>().next_multiple_of(align_of::<Slot>());
+
+const QP_FILE_LEN: usize = SLOTS_OFFSET + 2 * RING_SLOTS * size_of::<Slot>();
+
+/// Kinds of [`Message`]
+mod kind {
+    pub const SEND: u32 = 0;
+    pub const RDMA_WRITE: u32 = 1;
+    pub const RDMA_READ: u32 = 2;
+    pub const CMP_AND_SWP: u32 = 3;
+    pub const FETCH_AND_ADD: u32 = 4;
+    /// response with the status of a request, and the data of an RDMA READ or atomic
+    pub const ACK: u32 = 5;
+    /// response to a request that found no receive request
+    pub const RNR_NAK: u32 = 6;
+}
+
+/// Message read from or written to a ring
+#[derive(Default)]
+struct Message {
+    kind: u32,
+    src_qp: u32,
+    /// sequence number of the request, echoed by its response
+    seq: u32,
+    /// length of an RDMA READ, `ibv_wc_status` of an ACK, encoded RNR timer of an RNR NAK
+    arg: u32,
+    imm_data: Option<u32>,
+    rkey: u32,
+    addr: u64,
+    /// compare and swap values of an atomic, or the value to add
+    operands: [u64; 2],
+    payload: Vec<u8>,
+}
+
+/// Ring of a QP file
+#[derive(Clone, Copy)]
+enum Queue {
+    Requests,
+    Responses,
+}
+
+/// Shared file mapped into this process
+struct Mapping {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// Safety: the mapping is plain memory, concurrent access is synchronized by the atomics in it.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// Map the first `len` bytes of `file`.
+    fn new(file: &File, len: usize) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                core::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            ptr: NonNull::new(ptr.cast()).unwrap(),
+            len,
+        })
+    }
+
+    fn qp_header(&self) -> &QpHeader {
+        debug_assert!(self.len >= QP_FILE_LEN);
+        // Safety: QP files start with a header made of atomics.
+        unsafe { self.ptr.cast().as_ref() }
+    }
+
+    fn ring(&self, queue: Queue) -> &Ring {
+        let header = self.qp_header();
+        match queue {
+            Queue::Requests => &header.requests,
+            Queue::Responses => &header.responses,
+        }
+    }
+
+    fn slot(&self, queue: Queue, index: u32) -> *mut Slot {
+        let first = match queue {
+            Queue::Requests => 0,
+            Queue::Responses => RING_SLOTS,
+        };
+        let offset = SLOTS_OFFSET + (first + index as usize % RING_SLOTS) * size_of::<Slot>();
+        // Safety: the slots follow the header in QP files.
+        unsafe { self.ptr.as_ptr().add(offset) }.cast()
+    }
+
+    /// Append `msg` to a ring, returns `false` if it is full or its lock is held for too long.
+    fn enqueue(&self, queue: Queue, msg: &Message) -> bool {
+        debug_assert!(msg.payload.len() <= SLOT_SIZE);
+        let ring = self.ring(queue);
+        if !ring.lock() {
+            return false;
+        }
+
+        let head = ring.head.load(Ordering::Acquire);
+        let tail = ring.tail.load(Ordering::Relaxed);
+        let full = tail.wrapping_sub(head) as usize >= RING_SLOTS;
+        if !full {
+            let slot = self.slot(queue, tail);
+            // Safety: the slot is between tail and head, no one else accesses it until `tail` is published.
+            unsafe {
+                (&raw mut (*slot).kind).write(msg.kind);
+                (&raw mut (*slot).src_qp).write(msg.src_qp);
+                (&raw mut (*slot).seq).write(msg.seq);
+                (&raw mut (*slot).arg).write(msg.arg);
+                (&raw mut (*slot).imm_data).write(msg.imm_data.unwrap_or_default());
+                (&raw mut (*slot).has_imm).write(msg.imm_data.is_some().into());
+                (&raw mut (*slot).rkey).write(msg.rkey);
+                (&raw mut (*slot).addr).write(msg.addr);
+                (&raw mut (*slot).operands).write(msg.operands);
+                (&raw mut (*slot).len).write(msg.payload.len() as u32);
+                let payload = (&raw mut (*slot).payload).cast::<u8>();
+                core::ptr::copy_nonoverlapping(msg.payload.as_ptr(), payload, msg.payload.len());
+            }
+            ring.tail.store(tail.wrapping_add(1), Ordering::Release);
+        }
+
+        ring.unlock();
+        !full
+    }
+
+    /// Take the oldest message of a ring, only called by the owner with its QP state locked.
+    fn dequeue(&self, queue: Queue) -> Option<Message> {
+        let ring = self.ring(queue);
+        let head = ring.head.load(Ordering::Relaxed);
+        if head == ring.tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let slot = self.slot(queue, head);
+        // Safety: the slot was published by `tail` and is not reused until `head` moves past it.
+        let msg = unsafe {
+            let len = ((&raw const (*slot).len).read() as usize).min(SLOT_SIZE);
+            let payload = (&raw const (*slot).payload).cast::<u8>();
+            Message {
+                kind: (&raw const (*slot).kind).read(),
+                src_qp: (&raw const (*slot).src_qp).read(),
+                seq: (&raw const (*slot).seq).read(),
+                arg: (&raw const (*slot).arg).read(),
+                imm_data: ((&raw const (*slot).has_imm).read() != 0).then(|| (&raw const (*slot).imm_data).read()),
+                rkey: (&raw const (*slot).rkey).read(),
+                addr: (&raw const (*slot).addr).read(),
+                operands: (&raw const (*slot).operands).read(),
+                payload: core::slice::from_raw_parts(payload, len).to_vec(),
+            }
+        };
+        ring.head.store(head.wrapping_add(1), Ordering::Release);
+
+        Some(msg)
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.as_ptr().cast(), self.len) };
+    }
+}
+
+impl Ring {
+    /// Take the producer lock, `false` if a live process holds it past [`LOCK_TIMEOUT`].
+    fn lock(&self) -> bool {
+        let pid = std::process::id();
+        let deadline = Instant::now() + LOCK_TIMEOUT;
+        loop {
+            let holder = match self
+                .lock
+                .compare_exchange_weak(0, pid, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => return true,
+                Err(holder) => holder,
+            };
+            if holder != 0 && Instant::now() >= deadline {
+                // a holder that died never published its slot, the ring is as it was before it locked
+                return holder != pid
+                    && !alive(holder)
+                    && self
+                        .lock
+                        .compare_exchange(holder, pid, Ordering::Acquire, Ordering::Relaxed)
+                        .is_ok();
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn unlock(&self) {
+        self.lock.store(0, Ordering::Release);
+    }
+}
+
+/// Whether process `pid` exists.
+fn alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Safety: signal 0 only checks for the process.
+    let rc = unsafe { libc::kill(pid, 0) };
+    rc == 0 || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+impl QpHeader {
+    fn accepts(&self) -> bool {
+        matches!(
+            self.state.load(Ordering::Acquire),
+            ibv_qp_state::IBV_QPS_RTR | ibv_qp_state::IBV_QPS_RTS
+        )
+    }
+}
+
+/// Process local view of the shared directory
+struct Fabric {
+    dir: PathBuf,
+    next_id: AtomicU32,
+    /// QP files of peers
+    peers: Mutex<HashMap<u32, Arc<Mapping>>>,
+    /// regions registered by this process
+    mrs: RwLock<HashMap<u32, MrEntry>>,
+}
+
+static FABRIC: LazyLock<Fabric> = LazyLock::new(|| {
+    let fabric = Fabric {
+        dir: std::env::var_os(ENV_SHM_DIR).map_or_else(|| DEFAULT_SHM_DIR.into(), PathBuf::from),
+        // spread the ids of concurrent processes apart
+        next_id: AtomicU32::new(std::process::id().wrapping_mul(0x9e37_79b1)),
+        peers: Mutex::default(),
+        mrs: RwLock::default(),
+    };
+    fabric.sweep();
+    fabric
+});
+
+impl Fabric {
+    fn alloc_id(&self) -> u32 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn path(&self, qp_num: u32) -> PathBuf {
+        self.dir.join(format!("qp.{qp_num:06x}"))
+    }
+
+    /// Create the file of a QP under an unused number.
+    fn create(&self) -> io::Result<(u32, File)> {
+        use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)?;
+
+        loop {
+            // QP numbers are 24 bits, 0 and 1 are reserved for SMI and GSI
+            let qp_num = self.alloc_id() & 0xff_ffff;
+            if qp_num < 2 {
+                continue;
+            }
+
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(self.path(qp_num));
+            match file {
+                Ok(file) => {
+                    file.set_len(QP_FILE_LEN as u64)?;
+                    return Ok((qp_num, file));
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Map the file of QP `qp_num`, `None` if it does not exist or is not initialized yet.
+    fn open(&self, qp_num: u32) -> Option<Mapping> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.path(qp_num))
+            .ok()?;
+        let len = usize::try_from(file.metadata().ok()?.len()).ok()?;
+        if len < QP_FILE_LEN {
+            return None;
+        }
+
+        let mapping = Mapping::new(&file, QP_FILE_LEN).ok()?;
+        (mapping.qp_header().magic.load(Ordering::Acquire) == MAGIC).then_some(mapping)
+    }
+
+    /// Remove the QP files of processes that died without destroying their QPs.
+    fn sweep(&self) {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let qp_num = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("qp."))
+                .and_then(|qp_num| u32::from_str_radix(qp_num, 16).ok());
+            // files without a header may still be being created
+            let Some(mapping) = qp_num.and_then(|qp_num| self.open(qp_num)) else {
+                continue;
+            };
+            let header = mapping.qp_header();
+            let owner = header.owner.load(Ordering::Acquire);
+            if !alive(owner) {
+                log::debug!("Removing {name:?} of dead process {owner}");
+                // peers that still map it drop their mapping
+                header.alive.store(0, Ordering::Release);
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+
+    /// QP file of peer `qp_num`.
+    fn peer(&self, qp_num: u32) -> Option<Arc<Mapping>> {
+        let mut peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(peer) = peers.get(&qp_num) {
+            if peer.qp_header().alive.load(Ordering::Acquire) != 0 {
+                return Some(Arc::clone(peer));
+            }
+            // destroyed, the number may be in use by a new QP
+            peers.remove(&qp_num);
+        }
+
+        let peer = Arc::new(self.open(qp_num)?);
+        peers.insert(qp_num, Arc::clone(&peer));
+        Some(peer)
+    }
+
+    /// Resolve `len` bytes at I/O virtual address `addr` through `key` and run `f` on them.
+    ///
+    /// The region must belong to `pd` and allow `access`, an empty access only requires the key to be valid. It cannot
+    /// be deregistered before `f` returns, so `f` must not resolve another key or register memory.
+    fn resolve<R>(
+        &self,
+        key: u32,
+        pd: u64,
+        addr: u64,
+        len: usize,
+        access: ibv_access_flags,
+        f: impl FnOnce(*mut u8) -> R,
+    ) -> Option<R> {
+        let mrs = self.mrs.read().unwrap_or_else(PoisonError::into_inner);
+        let mr = mrs
+            .get(&key)
+            .filter(|mr| mr.pd == pd && (mr.access & access) == access)?;
+
+        let offset = usize::try_from(addr.checked_sub(mr.iova)?).ok()?;
+        if offset.checked_add(len)? > mr.length {
+            return None;
+        }
+
+        Some(f(mr.addr.wrapping_add(offset) as *mut u8))
+    }
+
+    /// Copy the memory `payload` describes into one buffer.
+    fn gather(&self, pd: u64, payload: &Payload) -> Result<Vec<u8>, ibv_wc_status::Type> {
+        let sges = match payload {
+            Payload::Sges(sges) => sges,
+            Payload::Inline(data) => return Ok(data.clone()),
+        };
+        let mut buf = Vec::new();
+
+        for sge in sges {
+            let len = sge.length as usize;
+            self.resolve(sge.lkey, pd, sge.addr, len, ibv_access_flags(0), |src| {
+                // Safety: the range lies in a registered region.
+                buf.extend_from_slice(unsafe { core::slice::from_raw_parts(src, len) });
+            })
+            .ok_or(ibv_wc_status::IBV_WC_LOC_PROT_ERR)?;
+        }
+
+        Ok(buf)
+    }
+
+    /// Copy `data` into the memory described by `sges`.
+    fn scatter(&self, pd: u64, sges: &[ffi::ibv_sge], mut data: &[u8]) -> Result<(), ibv_wc_status::Type> {
+        let capacity: usize = sges.iter().map(|sge| sge.length as usize).sum();
+        if capacity < data.len() {
+            return Err(ibv_wc_status::IBV_WC_LOC_LEN_ERR);
+        }
+
+        for sge in sges {
+            if data.is_empty() {
+                break;
+            }
+            let len = data.len().min(sge.length as usize);
+            self.resolve(
+                sge.lkey,
+                pd,
+                sge.addr,
+                len,
+                ibv_access_flags::IBV_ACCESS_LOCAL_WRITE,
+                |dst| {
+                    // Safety: the range lies in a writable registered region.
+                    unsafe { core::ptr::copy(data.as_ptr(), dst, len) };
+                },
+            )
+            .ok_or(ibv_wc_status::IBV_WC_LOC_PROT_ERR)?;
+            data = &data[len..];
+        }
+
+        Ok(())
+    }
+}
+
+/// Region registered by this process
+struct MrEntry {
+    pd: u64,
+    addr: usize,
+    length: usize,
+    /// address peers and SGEs use for the first byte
+    iova: u64,
+    access: ibv_access_flags,
+}
+
+/// Shared memory device
+pub struct Shm {
+    name: String,
+    dev: Arc<Device>,
+    /// GIDs of port 1, from the local addresses
+    gids: Vec<ffi::ibv_gid_entry>,
+}
+
+/// State shared with the progress thread
+struct Device {
+    qps: RwLock<HashMap<u32, Arc<Qp>>>,
+}
+
+/// Shared memory protection domain, the id is unique among processes
+pub struct ShmPd {
+    id: u64,
+}
+
+/// Shared memory completion queue
+pub struct ShmCq(Arc<Cq>);
+
+/// Shared memory queue pair
+pub struct ShmQp(Arc<Qp>);
+
+/// Shared memory region, the same key serves as lkey and rkey
+pub struct ShmMr {
+    key: u32,
+}
+
+impl QueuePair for ShmQp {
+    fn qp_num(&self) -> u32 {
+        self.0.qp_num
+    }
+}
+
+impl CompletionQueue for ShmCq {
+    fn cq_num(&self) -> u32 {
+        self.0.cq_num
+    }
+}
+
+impl MemoryRegion for ShmMr {
+    fn lkey(&self) -> u32 {
+        self.key
+    }
+
+    fn rkey(&self) -> u32 {
+        self.key
+    }
+}
+
+struct Cq {
+    cq_num: u32,
+    entries: Mutex<VecDeque<ffi::ibv_wc>>,
+}
+
+impl Cq {
+    fn push(&self, wc: ffi::ibv_wc) {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push_back(wc);
+    }
+}
+
+impl Device {
+    /// Start the progress thread of device `name`.
+    fn start(name: &str) -> io::Result<Arc<Self>> {
+        let dev = Arc::new(Self { qps: RwLock::default() });
+
+        let weak = Arc::downgrade(&dev);
+        std::thread::Builder::new()
+            .name(format!("{name}-shm"))
+            .spawn(move || progress(&weak))?;
+
+        Ok(dev)
+    }
+}
+
+/// Serve the rings of the QPs and fire their timers until the device is gone.
+fn progress(dev: &Weak<Device>) {
+    while let Some(dev) = dev.upgrade() {
+        let qps: Vec<_> = dev
+            .qps
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .cloned()
+            .collect();
+        drop(dev);
+
+        let now = Instant::now();
+        let mut busy = false;
+        for qp in qps {
+            busy |= qp.progress(now);
+        }
+        if !busy {
+            std::thread::sleep(TICK);
+        }
+    }
+}
+
+struct Qp {
+    qp_num: u32,
+    pd: u64,
+    qp_type: ffi::ibv_qp_type::Type,
+    sq_sig_all: bool,
+    cap: ffi::ibv_qp_cap,
+    send_cq: Arc<Cq>,
+    recv_cq: Arc<Cq>,
+    /// own QP file
+    file: Mapping,
+    state: Mutex<QpState>,
+}
+
+struct QpState {
+    state: ibv_qp_state::Type,
+    dest_qp_num: u32,
+    access: ibv_access_flags,
+    timeout: u8,
+    retry_cnt: u8,
+    rnr_retry: u8,
+    /// encoded RNR timer sent with RNR NAKs
+    min_rnr_timer: u8,
+    /// posted receive requests
+    recv: VecDeque<RecvWqe>,
+    /// send requests not completed yet, in order
+    send: VecDeque<SendWqe>,
+    /// sequence number of the latest request
+    seq: u32,
+    /// the first send request was sent and is not completed
+    in_flight: bool,
+    /// the request in flight is sent again at `deadline` rather than failing
+    resend: bool,
+    /// when the response to the request in flight is overdue, `None` waits forever
+    deadline: Option<Instant>,
+    /// RNR NAKs the request in flight may still get
+    rnr_left: u8,
+}
+
+impl QpState {
+    fn new() -> Self {
+        Self {
+            state: ibv_qp_state::IBV_QPS_RESET,
+            dest_qp_num: 0,
+            access: ibv_access_flags(0),
+            timeout: DEFAULT_TIMEOUT,
+            retry_cnt: 0,
+            rnr_retry: 0,
+            min_rnr_timer: 0,
+            recv: VecDeque::new(),
+            send: VecDeque::new(),
+            seq: 0,
+            in_flight: false,
+            resend: false,
+            deadline: None,
+            rnr_left: 0,
+        }
+    }
+
+    /// Time to wait for the response to a request, `None` for ever.
+    fn ack_timeout(&self) -> Option<Duration> {
+        // 4.096 µs * 2^timeout for each try
+        (self.timeout != 0).then(|| Duration::from_nanos((4096 << self.timeout) * (u64::from(self.retry_cnt) + 1)))
+    }
+
+    /// Forget all requests, the rings are left to the caller.
+    fn reset(&mut self) {
+        self.recv.clear();
+        self.send.clear();
+        self.in_flight = false;
+        self.resend = false;
+        self.deadline = None;
+    }
+}
+
+struct RecvWqe {
+    wr_id: u64,
+    sges: Vec<ffi::ibv_sge>,
+}
+
+struct SendWqe {
+    wr_id: u64,
+    opcode: ibv_wc_opcode::Type,
+    byte_len: u32,
+    signaled: bool,
+    request: Message,
+    /// where the data of an RDMA READ or atomic response goes
+    sges: Vec<ffi::ibv_sge>,
+    /// status the request completes with, without being sent
+    error: Option<ibv_wc_status::Type>,
+}
+
+/// Outcome of a request at the responder
+enum Reply {
+    Ack(Vec<u8>),
+    Nak(ibv_wc_status::Type),
+    Rnr,
+}
+
+impl Qp {
+    fn set_state(&self, state: &mut QpState, new_state: ibv_qp_state::Type) {
+        state.state = new_state;
+        self.file.qp_header().state.store(new_state, Ordering::Release);
+    }
+
+    /// Move to the error state with `state` locked, flushing outstanding requests.
+    fn enter_error(&self, state: &mut QpState) {
+        self.set_state(state, ibv_qp_state::IBV_QPS_ERR);
+        for wqe in state.recv.drain(..) {
+            let mut wc = ffi::ibv_wc::new(
+                wqe.wr_id,
+                ibv_wc_status::IBV_WC_WR_FLUSH_ERR,
+                ibv_wc_opcode::IBV_WC_RECV,
+                0,
+            );
+            wc.qp_num = self.qp_num;
+            self.recv_cq.push(wc);
+        }
+        for wqe in state.send.drain(..) {
+            let mut wc = ffi::ibv_wc::new(wqe.wr_id, ibv_wc_status::IBV_WC_WR_FLUSH_ERR, wqe.opcode, 0);
+            wc.qp_num = self.qp_num;
+            self.send_cq.push(wc);
+        }
+        state.in_flight = false;
+        state.resend = false;
+        state.deadline = None;
+    }
+
+    /// Complete a send request, errors are always completed and move the QP to the error state.
+    fn complete(&self, state: &mut QpState, wqe: SendWqe, status: ibv_wc_status::Type) {
+        let success = status == ibv_wc_status::IBV_WC_SUCCESS;
+        if success && !wqe.signaled {
+            return;
+        }
+
+        let mut wc = ffi::ibv_wc::new(wqe.wr_id, status, wqe.opcode, wqe.byte_len);
+        wc.qp_num = self.qp_num;
+        self.send_cq.push(wc);
+
+        if !success {
+            self.enter_error(state);
+        }
+    }
+
+    /// Complete the first send request with error `status`.
+    fn fail(&self, state: &mut QpState, status: ibv_wc_status::Type) {
+        state.in_flight = false;
+        state.resend = false;
+        state.deadline = None;
+        if let Some(wqe) = state.send.pop_front() {
+            self.complete(state, wqe, status);
+        }
+    }
+
+    /// Serve the rings and fire the timer, returns whether a message arrived.
+    fn progress(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut busy = false;
+
+        while let Some(req) = self.file.dequeue(Queue::Requests) {
+            busy = true;
+            self.respond(&mut state, req);
+        }
+        while let Some(resp) = self.file.dequeue(Queue::Responses) {
+            busy = true;
+            self.handle_response(&mut state, resp, now);
+        }
+        self.advance(&mut state, now);
+
+        busy
+    }
+
+    /// Execute a request of the peer and answer it.
+    fn respond(&self, state: &mut QpState, req: Message) {
+        // only the connected peer may use the QP
+        if req.src_qp != state.dest_qp_num {
+            log::debug!("QP {:#x}: dropped request of QP {:#x}", self.qp_num, req.src_qp);
+            return;
+        }
+
+        let reply = if matches!(state.state, ibv_qp_state::IBV_QPS_RTR | ibv_qp_state::IBV_QPS_RTS) {
+            self.execute(state, &req)
+        } else {
+            // what the requester would end up with if nobody answered
+            Reply::Nak(ibv_wc_status::IBV_WC_RETRY_EXC_ERR)
+        };
+
+        if self.qp_type != ffi::ibv_qp_type::IBV_QPT_RC {
+            if matches!(reply, Reply::Rnr) {
+                log::debug!("QP {:#x}: dropped message without receive request", self.qp_num);
+            }
+            return;
+        }
+
+        let (kind, arg, payload) = match reply {
+            Reply::Ack(data) => (kind::ACK, ibv_wc_status::IBV_WC_SUCCESS, data),
+            Reply::Nak(status) => (kind::ACK, status, Vec::new()),
+            Reply::Rnr => (kind::RNR_NAK, state.min_rnr_timer.into(), Vec::new()),
+        };
+        let resp = Message {
+            kind,
+            src_qp: self.qp_num,
+            seq: req.seq,
+            arg,
+            payload,
+            ..Message::default()
+        };
+        // the requester waits for this response, it has room for it unless it is gone
+        let sent = FABRIC
+            .peer(req.src_qp)
+            .is_some_and(|peer| peer.enqueue(Queue::Responses, &resp));
+        if !sent {
+            log::debug!("QP {:#x}: response to QP {:#x} dropped", self.qp_num, req.src_qp);
+        }
+    }
+
+    fn execute(&self, state: &mut QpState, req: &Message) -> Reply {
+        match req.kind {
+            kind::SEND | kind::RDMA_WRITE => {
+                let consumes = req.kind == kind::SEND || req.imm_data.is_some();
+                if consumes && state.recv.is_empty() {
+                    return Reply::Rnr;
+                }
+
+                if req.kind == kind::RDMA_WRITE {
+                    let len = req.payload.len();
+                    let written = self.remote(state, req, len, REMOTE_WRITE, |dst| {
+                        // Safety: the range lies in a remotely writable region.
+                        unsafe { core::ptr::copy(req.payload.as_ptr(), dst, len) };
+                    });
+                    if let Err(status) = written {
+                        return Reply::Nak(status);
+                    }
+                }
+
+                if consumes {
+                    let wqe = state.recv.pop_front().unwrap();
+                    let (opcode, data) = match req.kind {
+                        kind::SEND => (ibv_wc_opcode::IBV_WC_RECV, &req.payload[..]),
+                        _ => (ibv_wc_opcode::IBV_WC_RECV_RDMA_WITH_IMM, &[][..]),
+                    };
+                    let status = match FABRIC.scatter(self.pd, &wqe.sges, data) {
+                        Ok(()) => ibv_wc_status::IBV_WC_SUCCESS,
+                        Err(status) => status,
+                    };
+
+                    let mut wc = ffi::ibv_wc::new(wqe.wr_id, status, opcode, req.payload.len() as u32);
+                    wc.qp_num = self.qp_num;
+                    wc.src_qp = req.src_qp;
+                    if let Some(imm_data) = req.imm_data {
+                        wc.imm_data = imm_data;
+                        wc.wc_flags = ffi::ibv_wc_flags::IBV_WC_WITH_IMM;
+                    }
+                    self.recv_cq.push(wc);
+
+                    if status != ibv_wc_status::IBV_WC_SUCCESS {
+                        log::debug!("QP {:#x}: receive failed with status {status}", self.qp_num);
+                        self.enter_error(state);
+                        return Reply::Nak(ibv_wc_status::IBV_WC_REM_OP_ERR);
+                    }
+                }
+
+                Reply::Ack(Vec::new())
+            }
+            kind::RDMA_READ => {
+                let len = (req.arg as usize).min(SLOT_SIZE);
+                let data = self.remote(state, req, len, REMOTE_READ, |src| {
+                    // Safety: the range lies in a remotely readable region.
+                    unsafe { core::slice::from_raw_parts(src, len) }.to_vec()
+                });
+                match data {
+                    Ok(data) => Reply::Ack(data),
+                    Err(status) => Reply::Nak(status),
+                }
+            }
+            kind::CMP_AND_SWP | kind::FETCH_AND_ADD => {
+                let remote = provider::Remote {
+                    addr: req.addr,
+                    rkey: req.rkey,
+                };
+                let op = match req.kind {
+                    kind::CMP_AND_SWP => SendOp::AtomicCmpAndSwp {
+                        remote,
+                        compare: req.operands[0],
+                        swap: req.operands[1],
+                    },
+                    _ => SendOp::AtomicFetchAndAdd {
+                        remote,
+                        add: req.operands[0],
+                    },
+                };
+                // Safety: the range lies in a region allowing remote atomics.
+                match self
+                    .remote(state, req, 8, REMOTE_ATOMIC, |dst| unsafe { atomic(op, dst) })
+                    .flatten()
+                {
+                    Ok(original) => Reply::Ack(original.to_ne_bytes().to_vec()),
+                    Err(status) => Reply::Nak(status),
+                }
+            }
+            _ => Reply::Nak(ibv_wc_status::IBV_WC_REM_INV_REQ_ERR),
+        }
+    }
+
+    /// Run `f` on the `len` bytes request `req` targets, if the QP and the region allow `access`.
+    fn remote<R>(
+        &self,
+        state: &QpState,
+        req: &Message,
+        len: usize,
+        access: ibv_access_flags,
+        f: impl FnOnce(*mut u8) -> R,
+    ) -> Result<R, ibv_wc_status::Type> {
+        if (state.access & access) != access {
+            return Err(ibv_wc_status::IBV_WC_REM_ACCESS_ERR);
+        }
+        FABRIC
+            .resolve(req.rkey, self.pd, req.addr, len, access, f)
+            .ok_or(ibv_wc_status::IBV_WC_REM_ACCESS_ERR)
+    }
+
+    /// Complete the request in flight, or schedule it again, on the response `resp`.
+    fn handle_response(&self, state: &mut QpState, resp: Message, now: Instant) {
+        if !state.in_flight || state.resend || resp.seq != state.seq || resp.src_qp != state.dest_qp_num {
+            log::trace!("QP {:#x}: stale response of QP {:#x}", self.qp_num, resp.src_qp);
+            return;
+        }
+
+        if resp.kind == kind::RNR_NAK {
+            if state.rnr_left == 0 {
+                return self.fail(state, ibv_wc_status::IBV_WC_RNR_RETRY_EXC_ERR);
+            }
+            if state.rnr_left != RNR_RETRY_INFINITE {
+                state.rnr_left -= 1;
+            }
+            state.resend = true;
+            state.deadline = Some(now + Duration::from_micros(RNR_TIMER_US[(resp.arg & 0x1f) as usize]));
+            return;
+        }
+
+        state.in_flight = false;
+        state.deadline = None;
+        let Some(wqe) = state.send.pop_front() else {
+            return;
+        };
+        let status = match (resp.arg, wqe.request.kind) {
+            // the response carries data
+            (ibv_wc_status::IBV_WC_SUCCESS, kind::RDMA_READ | kind::CMP_AND_SWP | kind::FETCH_AND_ADD) => {
+                match FABRIC.scatter(self.pd, &wqe.sges, &resp.payload) {
+                    Ok(()) => ibv_wc_status::IBV_WC_SUCCESS,
+                    Err(status) => status,
+                }
+            }
+            (status, _) => status,
+        };
+        self.complete(state, wqe, status);
+    }
+
+    /// Send the next request, or act on the timer of the one in flight.
+    fn advance(&self, state: &mut QpState, now: Instant) {
+        loop {
+            if state.in_flight {
+                if state.deadline.is_none_or(|deadline| now < deadline) {
+                    return;
+                }
+                if state.resend {
+                    self.transmit(state, now);
+                } else {
+                    log::debug!("QP {:#x}: no response from QP {:#x}", self.qp_num, state.dest_qp_num);
+                    self.fail(state, ibv_wc_status::IBV_WC_RETRY_EXC_ERR);
+                }
+                return;
+            }
+
+            if state.state != ibv_qp_state::IBV_QPS_RTS {
+                return;
+            }
+            let Some(wqe) = state.send.front() else {
+                return;
+            };
+            if let Some(status) = wqe.error {
+                let wqe = state.send.pop_front().unwrap();
+                self.complete(state, wqe, status);
+                continue;
+            }
+
+            state.seq = state.seq.wrapping_add(1);
+            state.rnr_left = state.rnr_retry;
+            self.transmit(state, now);
+        }
+    }
+
+    /// Place the first send request in the peer's ring.
+    fn transmit(&self, state: &mut QpState, now: Instant) {
+        let dest_qp_num = state.dest_qp_num;
+        let Some(peer) = FABRIC.peer(dest_qp_num).filter(|peer| peer.qp_header().accepts()) else {
+            log::debug!(
+                "QP {:#x}: destination QP {dest_qp_num:#x} does not accept requests",
+                self.qp_num
+            );
+            return self.fail(state, ibv_wc_status::IBV_WC_RETRY_EXC_ERR);
+        };
+
+        let seq = state.seq;
+        let Some(wqe) = state.send.front_mut() else {
+            return;
+        };
+        wqe.request.seq = seq;
+        state.in_flight = true;
+        if !peer.enqueue(Queue::Requests, &wqe.request) {
+            // the peer does not keep up, try again on the next tick
+            state.resend = true;
+            state.deadline = Some(now + TICK);
+            return;
+        }
+
+        if self.qp_type != ffi::ibv_qp_type::IBV_QPT_RC {
+            // nothing answers unreliable requests
+            state.in_flight = false;
+            let wqe = state.send.pop_front().unwrap();
+            return self.complete(state, wqe, ibv_wc_status::IBV_WC_SUCCESS);
+        }
+        state.resend = false;
+        state.deadline = state.ack_timeout().map(|timeout| now + timeout);
+    }
+
+    /// Queue one send request.
+    ///
+    /// Failures of the transfer itself are reported by a completion, `Err` rejects the request without one.
+    fn post_send(&self, wr: &SendWr) -> provider::Result {
+        let sges = match &wr.payload {
+            Payload::Sges(sges) => sges.as_slice(),
+            Payload::Inline(_) => &[],
+        };
+        if sges.len() > self.cap.max_send_sge as usize {
+            return Err(VerbsError::InvalidArgument);
+        }
+
+        let reliable = self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC;
+        let kind = match wr.op {
+            SendOp::Send | SendOp::SendWithImm(_) => kind::SEND,
+            SendOp::RdmaWrite(_) | SendOp::RdmaWriteWithImm(..) => kind::RDMA_WRITE,
+            SendOp::RdmaRead(_) if reliable => kind::RDMA_READ,
+            SendOp::AtomicCmpAndSwp { .. } if reliable => kind::CMP_AND_SWP,
+            SendOp::AtomicFetchAndAdd { .. } if reliable => kind::FETCH_AND_ADD,
+            _ => {
+                log::debug!("QP {:#x}: unsupported send operation {:?}", self.qp_num, wr.op);
+                return Err(VerbsError::InvalidArgument);
+            }
+        };
+        let byte_len = match wr.op {
+            SendOp::RdmaRead(_) => sges.iter().map(|sge| sge.length).sum(),
+            SendOp::AtomicCmpAndSwp { .. } | SendOp::AtomicFetchAndAdd { .. } => 8,
+            _ => 0,
+        };
+
+        let mut request = Message {
+            kind,
+            src_qp: self.qp_num,
+            imm_data: wr.op.imm_data(),
+            ..Message::default()
+        };
+        match wr.op {
+            SendOp::RdmaWrite(remote) | SendOp::RdmaWriteWithImm(remote, _) | SendOp::RdmaRead(remote) => {
+                (request.rkey, request.addr) = (remote.rkey, remote.addr);
+            }
+            SendOp::AtomicCmpAndSwp { remote, compare, swap } => {
+                (request.rkey, request.addr) = (remote.rkey, remote.addr);
+                request.operands = [compare, swap];
+            }
+            SendOp::AtomicFetchAndAdd { remote, add } => {
+                (request.rkey, request.addr) = (remote.rkey, remote.addr);
+                request.operands = [add, 0];
+            }
+            _ => {}
+        }
+        let error = match kind {
+            kind::SEND | kind::RDMA_WRITE => match FABRIC.gather(self.pd, &wr.payload) {
+                Ok(payload) if payload.len() > SLOT_SIZE => Some(ibv_wc_status::IBV_WC_LOC_LEN_ERR),
+                Ok(payload) => {
+                    request.payload = payload;
+                    None
+                }
+                Err(status) => Some(status),
+            },
+            kind::RDMA_READ => {
+                request.arg = byte_len;
+                (byte_len as usize > SLOT_SIZE).then_some(ibv_wc_status::IBV_WC_LOC_LEN_ERR)
+            }
+            _ => None,
+        };
+
+        let wqe = SendWqe {
+            wr_id: wr.wr_id,
+            opcode: wr.op.wc_opcode(),
+            byte_len,
+            signaled: self.sq_sig_all || wr.signaled(),
+            request,
+            sges: sges.to_vec(),
+            error,
+        };
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        match state.state {
+            ibv_qp_state::IBV_QPS_RTS => {}
+            ibv_qp_state::IBV_QPS_ERR => {
+                self.complete(&mut state, wqe, ibv_wc_status::IBV_WC_WR_FLUSH_ERR);
+                return Ok(());
+            }
+            _ => return Err(VerbsError::InvalidArgument),
+        }
+        if state.send.len() >= self.cap.max_send_wr as usize {
+            return Err(VerbsError::OutOfResources);
+        }
+
+        state.send.push_back(wqe);
+        self.advance(&mut state, Instant::now());
+
+        Ok(())
+    }
+
+    /// Post one receive request.
+    fn post_recv(&self, wr: &ffi::ibv_recv_wr) -> provider::Result {
+        // Safety: the application hands us `num_sge` valid entries.
+        let sges = unsafe { sge_list(wr.sg_list, wr.num_sge) };
+        if sges.len() > self.cap.max_recv_sge as usize {
+            return Err(VerbsError::InvalidArgument);
+        }
+        let wqe = RecvWqe {
+            wr_id: wr.wr_id,
+            sges: sges.to_vec(),
+        };
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        match state.state {
+            ibv_qp_state::IBV_QPS_RESET => return Err(VerbsError::InvalidArgument),
+            _ if state.recv.len() >= self.cap.max_recv_wr as usize => return Err(VerbsError::OutOfResources),
+            ibv_qp_state::IBV_QPS_ERR => {
+                state.recv.push_back(wqe);
+                self.enter_error(&mut state);
+            }
+            _ => state.recv.push_back(wqe),
+        }
+
+        Ok(())
+    }
+}
+
+const REMOTE_WRITE: ibv_access_flags = ibv_access_flags::IBV_ACCESS_REMOTE_WRITE;
+const REMOTE_READ: ibv_access_flags = ibv_access_flags::IBV_ACCESS_REMOTE_READ;
+const REMOTE_ATOMIC: ibv_access_flags = ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC;
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::fd::{FromRawFd, OwnedFd};
+    use std::process::Command;
+
+    use provider::{Provider, Remote};
+
+    use super::*;
+    use crate::testing::{self, ACCESS};
+
+    /// QP number of the parent, set when the test binary runs as the peer process
+    const ENV_PEER: &str = "URDMA_SHM_TEST_PEER";
+    /// Pipe the peer reports to the parent on
+    const ENV_PIPE: &str = "URDMA_SHM_TEST_PIPE";
+
+    type Side = testing::Side<Shm>;
+
+    /// RC QP on a new device `name`.
+    fn side(name: &str) -> Side {
+        Side::new(Shm::new(name, provider::AsyncEvents::default()).unwrap(), 4096)
+    }
+
+    /// Connect the QP of `side` to `dest_qp_num`, retrying `rnr_retry` times on RNR NAKs.
+    fn connect(side: &Side, dest_qp_num: u32, rnr_retry: u8) {
+        let attr = ffi::ibv_qp_attr {
+            rnr_retry,
+            min_rnr_timer: 1,
+            ..Default::default()
+        };
+        let mask = ffi::ibv_qp_attr_mask::IBV_QP_RNR_RETRY | ffi::ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER;
+        side.connect(dest_qp_num, attr, mask);
+    }
+
+    /// Two connected QPs of this process, the second one with `rnr_retry`.
+    fn pair(rnr_retry: u8) -> (Side, Side) {
+        let a = side("urdma0");
+        let b = side("urdma1");
+        connect(&a, b.qp.qp_num(), 0);
+        connect(&b, a.qp.qp_num(), rnr_retry);
+        (a, b)
+    }
+
+    #[test]
+    fn send_waits_for_receive() {
+        let (a, mut b) = pair(RNR_RETRY_INFINITE);
+        b.bytes_mut()[..4].copy_from_slice(b"late");
+
+        b.post_send(0, SendOp::Send, b.sge(0, 4));
+        std::thread::sleep(Duration::from_millis(20));
+        // not consumed yet
+        assert!(b.poll().is_empty());
+
+        a.post_recv(3, a.sge(0, 16));
+        let wc = a.wait(1)[0];
+        assert_eq!((wc.wr_id(), wc.len()), (3, 4));
+        assert_eq!(&a.bytes()[..4], b"late");
+        assert!(b.wait(1)[0].is_valid());
+    }
+
+    #[test]
+    fn rnr_retry_exceeded() {
+        let (_a, b) = pair(1);
+
+        b.post_send(0, SendOp::Send, b.sge(0, 4));
+        assert_eq!(
+            b.wait(1)[0].error().map(|(status, _)| status),
+            Some(ibv_wc_status::IBV_WC_RNR_RETRY_EXC_ERR)
+        );
+    }
+
+    #[test]
+    fn overlapping_registrations() {
+        let (mut a, b) = pair(0);
+        a.bytes_mut()[64..68].copy_from_slice(b"data");
+
+        // the second region covers part of the first one
+        let mr = a
+            .dev
+            .reg_mr(&a.pd, (a.addr() + 64) as *mut _, 64, 0x1000, ACCESS)
+            .unwrap();
+        b.post_send(
+            0,
+            SendOp::RdmaRead(Remote {
+                addr: 0x1000,
+                rkey: mr.rkey(),
+            }),
+            b.sge(0, 4),
+        );
+        assert!(b.wait(1)[0].is_valid());
+        assert_eq!(&b.bytes()[..4], b"data");
+
+        a.dev.dereg_mr(&mr).unwrap();
+        // the application still owns its memory
+        a.bytes_mut()[64..68].copy_from_slice(b"more");
+        assert_eq!(&a.bytes()[64..68], b"more");
+    }
+
+    /// pid of a process that exited.
+    fn dead_pid() -> u32 {
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        child.id()
+    }
+
+    #[test]
+    fn lock_of_dead_process_is_taken_over() {
+        let a = side("urdma0");
+        let file = FABRIC.peer(a.qp.qp_num()).unwrap();
+        let ring = file.ring(Queue::Requests);
+
+        ring.lock.store(dead_pid(), Ordering::Release);
+        assert!(file.enqueue(Queue::Requests, &Message::default()));
+        assert_eq!(ring.lock.load(Ordering::Acquire), 0);
+
+        // a live holder keeps it
+        ring.lock.store(std::process::id(), Ordering::Release);
+        assert!(!file.enqueue(Queue::Requests, &Message::default()));
+        ring.unlock();
+    }
+
+    #[test]
+    fn files_of_dead_processes_are_removed() {
+        let dir = std::env::temp_dir().join(format!("urdma-sweep-{}", std::process::id()));
+        let fabric = Fabric {
+            dir: dir.clone(),
+            next_id: AtomicU32::new(2),
+            peers: Mutex::default(),
+            mrs: RwLock::default(),
+        };
+        let qp_file = |owner| {
+            let (qp_num, file) = fabric.create().unwrap();
+            let mapping = Mapping::new(&file, QP_FILE_LEN).unwrap();
+            mapping.qp_header().owner.store(owner, Ordering::Relaxed);
+            mapping.qp_header().magic.store(MAGIC, Ordering::Release);
+            fabric.path(qp_num)
+        };
+        let dead = qp_file(dead_pid());
+        let live = qp_file(std::process::id());
+
+        fabric.sweep();
+        assert!(!dead.exists());
+        assert!(live.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn two_processes() {
+        let mut a = side("urdma0");
+        a.bytes_mut()[..4].copy_from_slice(b"ping");
+        a.bytes_mut()[8..13].copy_from_slice(b"hello");
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // Safety: `pipe` just created both descriptors.
+        let (reader, writer) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "shm::tests::peer", "--test-threads=1"])
+            .env(ENV_PEER, a.qp.qp_num().to_string())
+            .env(ENV_PIPE, fds[1].to_string())
+            .spawn()
+            .unwrap();
+        // the child holds the only write end now, its exit ends the reports
+        drop(writer);
+        let mut reports = BufReader::new(File::from(reader)).lines().map(Result::unwrap);
+
+        // <qpn> <addr> <rkey>
+        let peer = reports.next().unwrap();
+        let peer: Vec<u64> = peer.split_whitespace().map(|n| n.parse().unwrap()).collect();
+        connect(&a, peer[0] as u32, 0);
+
+        a.post_send(
+            0,
+            SendOp::RdmaWrite(Remote {
+                addr: peer[1] + 32,
+                rkey: peer[2] as u32,
+            }),
+            a.sge(0, 4),
+        );
+        assert!(a.wait(1)[0].is_valid());
+
+        let counter = Remote {
+            addr: peer[1] + 40,
+            rkey: peer[2] as u32,
+        };
+        a.post_send(
+            0,
+            SendOp::AtomicFetchAndAdd {
+                remote: counter,
+                add: 7,
+            },
+            a.sge(16, 8),
+        );
+        let wc = a.wait(1)[0];
+        assert_eq!((wc.opcode(), wc.len()), (ibv_wc_opcode::IBV_WC_FETCH_ADD, 8));
+        a.post_send(
+            0,
+            SendOp::AtomicCmpAndSwp {
+                remote: counter,
+                compare: 7,
+                swap: 9,
+            },
+            a.sge(24, 8),
+        );
+        assert_eq!(a.wait(1)[0].opcode(), ibv_wc_opcode::IBV_WC_COMP_SWAP);
+        assert_eq!(a.bytes()[16..32], [0_u64.to_ne_bytes(), 7_u64.to_ne_bytes()].concat());
+
+        a.post_send(0, SendOp::Send, a.sge(8, 5));
+        assert!(a.wait(1)[0].is_valid());
+
+        assert_eq!(reports.next().unwrap(), "hello ping 9");
+        assert!(child.wait().unwrap().success());
+    }
+
+    /// Peer of [`two_processes`], does nothing unless spawned by it.
+    #[test]
+    fn peer() {
+        let (Ok(qpn), Ok(pipe)) = (std::env::var(ENV_PEER), std::env::var(ENV_PIPE)) else {
+            return;
+        };
+        // Safety: the parent passes the write end of its pipe, inherited by this process.
+        let mut parent = unsafe { File::from_raw_fd(pipe.parse().unwrap()) };
+
+        let b = side("urdma1");
+        connect(&b, qpn.parse().unwrap(), 0);
+        b.post_recv(7, b.sge(0, 16));
+        writeln!(parent, "{} {} {}", b.qp.qp_num(), b.addr(), b.mr.rkey()).unwrap();
+
+        let wc = b.wait(1)[0];
+        assert!(wc.is_valid());
+        assert_eq!((wc.wr_id(), wc.opcode(), wc.len()), (7, ibv_wc_opcode::IBV_WC_RECV, 5));
+        let bytes = b.bytes();
+        writeln!(
+            parent,
+            "{} {} {}",
+            String::from_utf8_lossy(&bytes[0..5]),
+            String::from_utf8_lossy(&bytes[32..36]),
+            u64::from_ne_bytes(bytes[40..48].try_into().unwrap())
+        )
+        .unwrap();
+    }
+}
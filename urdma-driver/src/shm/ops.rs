@@ -0,0 +1,393 @@
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use ffi::{ibv_access_flags, ibv_qp_attr_mask, ibv_qp_state};
+use provider::{AsyncEvents, Capabilities, CqNotifier, QpInitAttr, Result, SendWr, UdDest, Verbs, VerbsError};
+
+use super::{
+    Cq, Device, FABRIC, MAGIC, Mapping, MrEntry, QP_FILE_LEN, Qp, QpState, Queue, SLOT_SIZE, Shm, ShmCq, ShmMr, ShmPd,
+    ShmQp,
+};
+use crate::loopback::{mapped, page_size};
+use crate::{config, gid, urdma};
+
+const FW_VER: &str = "shm";
+
+const MAX_QP_WR: u32 = 1 << 14;
+const MAX_SGE: u32 = 32;
+const MAX_INLINE_DATA: u32 = 1 << 12;
+const MAX_CQE: u32 = 1 << 20;
+
+impl provider::Provider for Shm {
+    type Ah = core::convert::Infallible;
+    type Cq = ShmCq;
+    type Mr = ShmMr;
+    type Mw = core::convert::Infallible;
+    type Pd = ShmPd;
+    type Qp = ShmQp;
+    /// no shared receive queues
+    type Srq = core::convert::Infallible;
+
+    fn init() -> Result {
+        let _ = env_logger::try_init();
+
+        let profile = config::profile().map_err(|err| {
+            log::error!("{err}");
+            VerbsError::InvalidArgument
+        })?;
+        profile.install();
+        Ok(())
+    }
+
+    unsafe fn from_ibv_device(ibdev: *mut ffi::ibv_device) -> Result<*const Self> {
+        unsafe { urdma::driver_data(ibdev) }.map(|driver_data| driver_data.cast())
+    }
+
+    fn new(sysfs_name: &str, _events: AsyncEvents) -> Result<Arc<Self>> {
+        log::info!("{sysfs_name}: backed by shared memory in {}", FABRIC.dir.display());
+
+        Ok(Arc::new(Shm {
+            name: sysfs_name.to_owned(),
+            dev: Device::start(sysfs_name)?,
+            gids: gid::local(sysfs_name),
+        }))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            verbs: Verbs::ALL.without(
+                Verbs::SRQ | Verbs::REQ_NOTIFY_CQ | Verbs::MW | Verbs::AH | Verbs::REREG_MR | Verbs::REG_DMABUF_MR,
+            ),
+            qp_types: &[ffi::ibv_qp_type::IBV_QPT_RC, ffi::ibv_qp_type::IBV_QPT_UC],
+        }
+    }
+
+    fn alloc_pd(&self) -> Result<ShmPd> {
+        log::info!("{}: Allocating protection domain", self.name);
+
+        let id = FABRIC.alloc_id();
+        Ok(ShmPd {
+            id: (u64::from(std::process::id()) << 32) | u64::from(id),
+        })
+    }
+
+    fn dealloc_pd(&self, _pd: &ShmPd) -> Result {
+        log::info!("{}: Deallocating protection domain", self.name);
+
+        Ok(())
+    }
+
+    fn query_device(&self, device_attr: &mut ffi::ibv_device_attr) -> Result {
+        log::info!("{}: Querying device attributes", self.name);
+
+        for (dst, src) in device_attr.fw_ver.iter_mut().zip(FW_VER.bytes()) {
+            *dst = src as _;
+        }
+        device_attr.max_mr_size = u64::MAX;
+        device_attr.page_size_cap = page_size() as _;
+        device_attr.max_qp = 1 << 16;
+        device_attr.max_qp_wr = MAX_QP_WR as _;
+        device_attr.max_sge = MAX_SGE as _;
+        device_attr.max_sge_rd = MAX_SGE as _;
+        device_attr.max_cq = 1 << 16;
+        device_attr.max_cqe = MAX_CQE as _;
+        device_attr.max_mr = 1 << 16;
+        device_attr.max_pd = 1 << 16;
+        device_attr.max_qp_rd_atom = 128;
+        device_attr.max_qp_init_rd_atom = 128;
+        device_attr.atomic_cap = ffi::IBV_ATOMIC_HCA;
+        device_attr.max_pkeys = 1;
+        device_attr.phys_port_cnt = 1;
+
+        Ok(())
+    }
+
+    fn query_port(&self, port_num: u8, port_attr: &mut ffi::ibv_port_attr) -> Result {
+        log::info!("{}: Querying port attributes", self.name);
+
+        if port_num != 1 {
+            return Err(VerbsError::InvalidArgument);
+        }
+
+        port_attr.state = ffi::ibv_port_state::IBV_PORT_ACTIVE;
+        port_attr.max_mtu = ffi::IBV_MTU_4096;
+        port_attr.active_mtu = ffi::IBV_MTU_4096;
+        port_attr.gid_tbl_len = self.gids.len().max(1) as _;
+        port_attr.max_msg_sz = SLOT_SIZE as _;
+        port_attr.pkey_tbl_len = 1;
+        port_attr.active_width = 1;
+        port_attr.active_speed = 1;
+        // LinkUp
+        port_attr.phys_state = 5;
+        port_attr.link_layer = ffi::IBV_LINK_LAYER_ETHERNET as _;
+
+        Ok(())
+    }
+
+    fn query_gid_table(&self, port_num: u8) -> Result<Vec<ffi::ibv_gid_entry>> {
+        log::info!("{}: Querying GID table", self.name);
+
+        if port_num != 1 {
+            return Err(VerbsError::InvalidArgument);
+        }
+
+        Ok(self.gids.clone())
+    }
+
+    fn create_cq(
+        &self,
+        cqe: core::ffi::c_int,
+        _notifier: Option<CqNotifier>,
+        _comp_vector: core::ffi::c_int,
+    ) -> Result<ShmCq> {
+        log::info!("{}: Creating completion queue", self.name);
+
+        match u32::try_from(cqe) {
+            Ok(1..=MAX_CQE) => {}
+            _ => return Err(VerbsError::InvalidArgument),
+        }
+
+        Ok(ShmCq(Arc::new(Cq {
+            cq_num: FABRIC.alloc_id(),
+            entries: Mutex::new(VecDeque::with_capacity(cqe as usize)),
+        })))
+    }
+
+    fn destroy_cq(&self, cq: &ShmCq) -> Result {
+        log::info!("{}: Destroying completion queue", self.name);
+
+        // still referenced by a QP
+        if Arc::strong_count(&cq.0) > 1 {
+            return Err(VerbsError::Busy);
+        }
+
+        Ok(())
+    }
+
+    fn create_qp(&self, pd: &ShmPd, init_attr: &mut QpInitAttr<'_, Self>) -> Result<ShmQp> {
+        log::info!("{}: Creating queue pair", self.name);
+
+        let cap = &mut init_attr.cap;
+        if cap.max_send_wr > MAX_QP_WR
+            || cap.max_recv_wr > MAX_QP_WR
+            || cap.max_send_sge > MAX_SGE
+            || cap.max_recv_sge > MAX_SGE
+            || cap.max_inline_data > MAX_INLINE_DATA
+        {
+            return Err(VerbsError::InvalidArgument);
+        }
+        cap.max_inline_data = MAX_INLINE_DATA;
+
+        let (qp_num, file) = FABRIC.create()?;
+        let file = Mapping::new(&file, QP_FILE_LEN).map_err(|err| {
+            let _ = std::fs::remove_file(FABRIC.path(qp_num));
+            VerbsError::from(err)
+        })?;
+
+        let header = file.qp_header();
+        header.state.store(ibv_qp_state::IBV_QPS_RESET, Ordering::Relaxed);
+        header.alive.store(1, Ordering::Relaxed);
+        header.owner.store(std::process::id(), Ordering::Relaxed);
+        header.magic.store(MAGIC, Ordering::Release);
+
+        let qp = Arc::new(Qp {
+            qp_num,
+            pd: pd.id,
+            qp_type: init_attr.qp_type,
+            sq_sig_all: init_attr.sq_sig_all,
+            cap: *cap,
+            send_cq: Arc::clone(&init_attr.send_cq.0),
+            recv_cq: Arc::clone(&init_attr.recv_cq.0),
+            file,
+            state: Mutex::new(QpState::new()),
+        });
+        self.dev
+            .qps
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(qp_num, Arc::clone(&qp));
+
+        Ok(ShmQp(qp))
+    }
+
+    fn destroy_qp(&self, qp: &ShmQp) -> Result {
+        log::info!("{}: Destroying queue pair", self.name);
+
+        let qp = &qp.0;
+        self.dev
+            .qps
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&qp.qp_num);
+        let mut state = qp.state.lock().unwrap_or_else(PoisonError::into_inner);
+        // outstanding requests are discarded
+        state.reset();
+        qp.set_state(&mut state, ibv_qp_state::IBV_QPS_ERR);
+        qp.file.qp_header().alive.store(0, Ordering::Release);
+
+        let _ = std::fs::remove_file(FABRIC.path(qp.qp_num));
+
+        Ok(())
+    }
+
+    fn modify_qp(&self, qp: &ShmQp, attr: &mut ffi::ibv_qp_attr, attr_mask: ibv_qp_attr_mask) -> Result {
+        log::info!("{}: Modifying queue pair", self.name);
+
+        let qp = &qp.0;
+        let mut state = qp.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let has = |flag: ibv_qp_attr_mask| (attr_mask & flag).0 != 0;
+
+        if has(ibv_qp_attr_mask::IBV_QP_DEST_QPN) {
+            state.dest_qp_num = attr.dest_qp_num;
+        }
+        if has(ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS) {
+            state.access = ibv_access_flags(attr.qp_access_flags as _);
+        }
+        if has(ibv_qp_attr_mask::IBV_QP_TIMEOUT) {
+            state.timeout = attr.timeout;
+        }
+        if has(ibv_qp_attr_mask::IBV_QP_RETRY_CNT) {
+            state.retry_cnt = attr.retry_cnt;
+        }
+        if has(ibv_qp_attr_mask::IBV_QP_RNR_RETRY) {
+            state.rnr_retry = attr.rnr_retry;
+        }
+        if has(ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER) {
+            state.min_rnr_timer = attr.min_rnr_timer;
+        }
+
+        if has(ibv_qp_attr_mask::IBV_QP_STATE) {
+            match attr.qp_state {
+                ibv_qp_state::IBV_QPS_ERR => qp.enter_error(&mut state),
+                ibv_qp_state::IBV_QPS_RESET => {
+                    state.reset();
+                    // drop queued messages
+                    while qp.file.dequeue(Queue::Requests).is_some() {}
+                    while qp.file.dequeue(Queue::Responses).is_some() {}
+                }
+                _ => {}
+            }
+            qp.set_state(&mut state, attr.qp_state);
+        }
+
+        Ok(())
+    }
+
+    fn query_qp(
+        &self,
+        qp: &ShmQp,
+        attr: &mut ffi::ibv_qp_attr,
+        _attr_mask: ibv_qp_attr_mask,
+        init_attr: &mut ffi::ibv_qp_init_attr,
+    ) -> Result {
+        log::info!("{}: Querying queue pair", self.name);
+
+        let qp = &qp.0;
+        let state = qp.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        attr.qp_state = state.state;
+        attr.cur_qp_state = state.state;
+        attr.path_mtu = ffi::IBV_MTU_4096;
+        attr.dest_qp_num = state.dest_qp_num;
+        attr.qp_access_flags = state.access.0 as _;
+        attr.timeout = state.timeout;
+        attr.retry_cnt = state.retry_cnt;
+        attr.rnr_retry = state.rnr_retry;
+        attr.min_rnr_timer = state.min_rnr_timer;
+        attr.cap = qp.cap;
+        attr.port_num = 1;
+
+        init_attr.cap = qp.cap;
+        init_attr.qp_type = qp.qp_type;
+        init_attr.sq_sig_all = qp.sq_sig_all.into();
+
+        Ok(())
+    }
+
+    fn reg_mr(
+        &self,
+        pd: &ShmPd,
+        addr: *mut ::std::os::raw::c_void,
+        length: usize,
+        hca_va: u64,
+        access: ibv_access_flags,
+    ) -> Result<ShmMr> {
+        log::info!("{}: Registering memory region", self.name);
+
+        // remote write and atomics are only allowed on locally writable memory
+        let needs_local_write = ibv_access_flags::IBV_ACCESS_REMOTE_WRITE | ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC;
+        if (access & needs_local_write).0 != 0 && (access & ibv_access_flags::IBV_ACCESS_LOCAL_WRITE).0 == 0 {
+            return Err(VerbsError::InvalidArgument);
+        }
+        // on-demand paging is not advertised
+        if (access & ibv_access_flags::IBV_ACCESS_ON_DEMAND).0 != 0 {
+            return Err(VerbsError::NotSupported);
+        }
+        if !mapped(addr as usize, length) {
+            return Err(VerbsError::Errno(libc::EFAULT));
+        }
+
+        let key = FABRIC.alloc_id();
+        FABRIC.mrs.write().unwrap_or_else(PoisonError::into_inner).insert(
+            key,
+            MrEntry {
+                pd: pd.id,
+                addr: addr as usize,
+                length,
+                iova: hca_va,
+                access,
+            },
+        );
+
+        Ok(ShmMr { key })
+    }
+
+    fn dereg_mr(&self, mr: &ShmMr) -> Result {
+        log::info!("{}: Deregistering memory region", self.name);
+
+        match FABRIC
+            .mrs
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&mr.key)
+        {
+            Some(_) => Ok(()),
+            None => Err(VerbsError::InvalidArgument),
+        }
+    }
+
+    fn post_send(&self, qp: &ShmQp, wr: &SendWr, _ud: Option<&UdDest<'_, Self>>) -> Result {
+        log::trace!("{}: Posting send work request", self.name);
+
+        qp.0.post_send(wr)
+    }
+
+    fn post_recv(&self, qp: &ShmQp, wr: *mut ffi::ibv_recv_wr, bad_wr: &mut *mut ffi::ibv_recv_wr) -> Result {
+        log::trace!("{}: Posting receive work request", self.name);
+
+        let mut cur = wr;
+        // Safety: the application hands us a valid, null terminated list.
+        while let Some(wr) = unsafe { cur.as_ref() } {
+            if let Err(err) = qp.0.post_recv(wr) {
+                *bad_wr = cur;
+                return Err(err);
+            }
+            cur = wr.next;
+        }
+
+        Ok(())
+    }
+
+    fn poll_cq(&self, cq: &ShmCq, wc: &mut [ffi::ibv_wc]) -> Result<usize> {
+        log::trace!("{}: Polling completion queue", self.name);
+
+        let mut entries = cq.0.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let polled = wc.len().min(entries.len());
+        for (dst, src) in wc.iter_mut().zip(entries.drain(..polled)) {
+            *dst = src;
+        }
+
+        Ok(polled)
+    }
+}
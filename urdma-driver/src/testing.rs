@@ -0,0 +1,170 @@
+//! Fixture shared by the tests of the software backends
+
+use std::mem::ManuallyDrop;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ffi::{ibv_access_flags, ibv_qp_attr_mask, ibv_qp_state};
+use provider::{MemoryRegion, Payload, Provider, Remote, SendOp, SendWr};
+
+pub(crate) const ACCESS: ibv_access_flags = ibv_access_flags(
+    ibv_access_flags::IBV_ACCESS_LOCAL_WRITE.0
+        | ibv_access_flags::IBV_ACCESS_REMOTE_WRITE.0
+        | ibv_access_flags::IBV_ACCESS_REMOTE_READ.0
+        | ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC.0,
+);
+
+/// A QP with its own PD and CQ, and a buffer registered with [`ACCESS`]
+pub(crate) struct Side<P: Provider> {
+    pub(crate) dev: Arc<P>,
+    pub(crate) pd: P::Pd,
+    pub(crate) cq: P::Cq,
+    /// dropped once destroyed, it keeps the CQ busy
+    pub(crate) qp: ManuallyDrop<P::Qp>,
+    /// 8 byte aligned for atomics
+    buf: Box<[u64]>,
+    pub(crate) mr: P::Mr,
+}
+
+impl<P: Provider> Side<P> {
+    /// RC QP on `dev`, with `len` bytes of registered memory.
+    pub(crate) fn new(dev: Arc<P>, len: usize) -> Self {
+        Self::with_qp(dev, len, None, ffi::ibv_qp_type::IBV_QPT_RC)
+    }
+
+    /// QP of `qp_type` on `dev`, taking its receive requests from `srq` if any.
+    pub(crate) fn with_qp(dev: Arc<P>, len: usize, srq: Option<&P::Srq>, qp_type: ffi::ibv_qp_type::Type) -> Self {
+        let pd = dev.alloc_pd().unwrap();
+        let cq = dev.create_cq(16, None, 0).unwrap();
+        let qp = dev
+            .create_qp(
+                &pd,
+                &mut provider::QpInitAttr {
+                    send_cq: &cq,
+                    recv_cq: &cq,
+                    srq,
+                    cap: ffi::ibv_qp_cap {
+                        max_send_wr: 16,
+                        max_recv_wr: 16,
+                        max_send_sge: 1,
+                        max_recv_sge: 1,
+                        max_inline_data: 0,
+                    },
+                    qp_type,
+                    sq_sig_all: false,
+                },
+            )
+            .unwrap();
+        let mut buf = vec![0_u64; len.div_ceil(8)].into_boxed_slice();
+        let mr = dev
+            .reg_mr(&pd, buf.as_mut_ptr().cast(), len, buf.as_ptr() as u64, ACCESS)
+            .unwrap();
+
+        Self {
+            dev,
+            pd,
+            cq,
+            qp: ManuallyDrop::new(qp),
+            buf,
+            mr,
+        }
+    }
+
+    /// Move the QP through INIT, RTR and RTS towards `dest_qp_num`, also setting the attributes of `attr` in `mask`.
+    pub(crate) fn connect(&self, dest_qp_num: u32, mut attr: ffi::ibv_qp_attr, mask: ibv_qp_attr_mask) {
+        attr.dest_qp_num = dest_qp_num;
+        attr.qp_access_flags = ACCESS.0 as _;
+        let mask = mask
+            | ibv_qp_attr_mask::IBV_QP_STATE
+            | ibv_qp_attr_mask::IBV_QP_DEST_QPN
+            | ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS;
+        for state in [
+            ibv_qp_state::IBV_QPS_INIT,
+            ibv_qp_state::IBV_QPS_RTR,
+            ibv_qp_state::IBV_QPS_RTS,
+        ] {
+            attr.qp_state = state;
+            self.dev.modify_qp(&self.qp, &mut attr, mask).unwrap();
+        }
+    }
+
+    pub(crate) fn addr(&self) -> u64 {
+        self.buf.as_ptr() as u64
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        // Safety: any initialized memory is valid as bytes.
+        unsafe { core::slice::from_raw_parts(self.buf.as_ptr().cast(), size_of_val(&*self.buf)) }
+    }
+
+    pub(crate) fn bytes_mut(&mut self) -> &mut [u8] {
+        let len = size_of_val(&*self.buf);
+        // Safety: as above, and any bytes are a valid `u64`.
+        unsafe { core::slice::from_raw_parts_mut(self.buf.as_mut_ptr().cast(), len) }
+    }
+
+    pub(crate) fn sge(&self, offset: usize, length: usize) -> ffi::ibv_sge {
+        ffi::ibv_sge {
+            addr: self.addr() + offset as u64,
+            length: length as u32,
+            lkey: self.mr.lkey(),
+        }
+    }
+
+    pub(crate) fn remote(&self, offset: usize) -> Remote {
+        Remote {
+            addr: self.addr() + offset as u64,
+            rkey: self.mr.rkey(),
+        }
+    }
+
+    pub(crate) fn post_recv(&self, wr_id: u64, mut sge: ffi::ibv_sge) {
+        let mut wr = ffi::ibv_recv_wr {
+            wr_id,
+            next: core::ptr::null_mut(),
+            sg_list: &raw mut sge,
+            num_sge: 1,
+        };
+        let mut bad_wr = core::ptr::null_mut();
+        self.dev.post_recv(&self.qp, &raw mut wr, &mut bad_wr).unwrap();
+    }
+
+    pub(crate) fn post_send(&self, wr_id: u64, op: SendOp, sge: ffi::ibv_sge) {
+        let wr = SendWr {
+            wr_id,
+            op,
+            flags: ffi::ibv_send_flags::IBV_SEND_SIGNALED,
+            payload: Payload::Sges(vec![sge]),
+        };
+        self.dev.post_send(&self.qp, &wr, None).unwrap();
+    }
+
+    /// Completions available right now.
+    pub(crate) fn poll(&self) -> Vec<ffi::ibv_wc> {
+        let mut wc = [ffi::ibv_wc::default(); 8];
+        let polled = self.dev.poll_cq(&self.cq, &mut wc).unwrap();
+        wc[..polled].to_vec()
+    }
+
+    /// Poll until `n` completions arrived, or a few seconds passed.
+    pub(crate) fn wait(&self, n: usize) -> Vec<ffi::ibv_wc> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut done = Vec::new();
+        while done.len() < n && Instant::now() < deadline {
+            done.extend(self.poll());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        done
+    }
+}
+
+impl<P: Provider> Drop for Side<P> {
+    fn drop(&mut self) {
+        self.dev.destroy_qp(&self.qp).unwrap();
+        // Safety: `qp` is not used past this point.
+        unsafe { ManuallyDrop::drop(&mut self.qp) };
+        self.dev.destroy_cq(&self.cq).unwrap();
+        self.dev.dereg_mr(&self.mr).unwrap();
+        self.dev.dealloc_pd(&self.pd).unwrap();
+    }
+}
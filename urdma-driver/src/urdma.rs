@@ -1,11 +1,18 @@
-#![allow(non_upper_case_globals)]
-#![allow(non_camel_case_types)]
-#![allow(non_snake_case)]
-#![allow(missing_docs)]
-// Suppress expected warnings from bindgen-generated code.
-// See https://github.com/rust-lang/rust-bindgen/issues/1651.
-#![allow(deref_nullptr)]
-#![allow(unsafe_op_in_unsafe_fn)]
-#![allow(unused)]
+use provider::{Result, VerbsError};
 
-include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+/// Get `driver_data` of the urdma device containing `ibdev`, i.e. the provider created by `urdma_new_device`.
+///
+/// Safety: `ibdev` must be null or the `verbs_dev` of a live `urdma_device`.
+pub unsafe fn driver_data(ibdev: *mut ffi::ibv_device) -> Result<*const core::ffi::c_void> {
+    if ibdev.is_null() {
+        return Err(VerbsError::InvalidArgument);
+    }
+    let offset = core::mem::offset_of!(ffi::urdma_device, verbs_dev);
+    let urdma = unsafe { ibdev.cast::<u8>().sub(offset) }.cast::<ffi::urdma_device>();
+    let driver_data = unsafe { (*urdma).driver_data };
+    if driver_data.is_null() {
+        // the device was not created by `urdma_new_device`
+        return Err(VerbsError::InvalidArgument);
+    }
+    Ok(driver_data.cast_const())
+}
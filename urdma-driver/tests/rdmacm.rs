@@ -0,0 +1,227 @@
+//! Connection through librdmacm
+//!
+//! Needs the urdma module loaded with `netdev=<interface>` and the provider installed with a software backend. The
+//! endpoints use the address in `URDMA_RDMACM_ADDR`, or else the first IPv4 address of the interface. The test is
+//! ignored by default, run it with `--ignored`.
+
+use core::ffi::{CStr, c_int};
+use std::ffi::CString;
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ffi::rdma_cm_id;
+
+// only the test uses librdmacm, the provider links libibverbs alone
+#[link(name = "rdmacm")]
+unsafe extern "C" {}
+
+const PORT: &str = "18515";
+
+fn check(rc: c_int, what: &str) {
+    assert_eq!(rc, 0, "{what}: {}", std::io::Error::last_os_error());
+}
+
+/// Network interface the first urdma device is bound to, from `<ibdev_path>/ports/1/gid_attrs/ndevs/0`.
+fn urdma_netdev() -> Option<String> {
+    let mut num_devices = 0;
+    let list = unsafe { ffi::ibv_get_device_list(&mut num_devices) };
+    if list.is_null() {
+        return None;
+    }
+
+    let devices = unsafe { core::slice::from_raw_parts(list, num_devices as usize) };
+    let netdev = devices.iter().find_map(|&device| {
+        let device = unsafe { &*device };
+        let name = unsafe { CStr::from_ptr(device.name.as_ptr()) }.to_string_lossy();
+        if !name.starts_with("urdma") {
+            return None;
+        }
+        let ibdev_path = unsafe { CStr::from_ptr(device.ibdev_path.as_ptr()) }.to_string_lossy();
+        let netdev = std::fs::read_to_string(format!("{ibdev_path}/ports/1/gid_attrs/ndevs/0")).ok()?;
+        Some(netdev.trim().to_owned())
+    });
+    unsafe { ffi::ibv_free_device_list(list) };
+    netdev
+}
+
+/// First IPv4 address of `netdev`.
+fn ipv4_addr(netdev: &str) -> Option<Ipv4Addr> {
+    let mut ifaddrs = core::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return None;
+    }
+
+    let mut found = None;
+    let mut ifaddr = ifaddrs;
+    while let Some(entry) = unsafe { ifaddr.as_ref() } {
+        ifaddr = entry.ifa_next;
+        let name = unsafe { CStr::from_ptr(entry.ifa_name) };
+        if entry.ifa_addr.is_null() || name.to_bytes() != netdev.as_bytes() {
+            continue;
+        }
+        if c_int::from(unsafe { (*entry.ifa_addr).sa_family }) == libc::AF_INET {
+            let addr = unsafe { &*entry.ifa_addr.cast::<libc::sockaddr_in>() };
+            found = Some(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)));
+            break;
+        }
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    found
+}
+
+/// Synchronous endpoint on `addr`, listening if `passive`.
+fn endpoint(addr: &str, passive: bool) -> *mut rdma_cm_id {
+    let node = CString::new(addr).unwrap();
+    let service = CString::new(PORT).unwrap();
+    let mut hints = ffi::rdma_addrinfo {
+        ai_port_space: ffi::RDMA_PS_TCP as c_int,
+        ..Default::default()
+    };
+    if passive {
+        hints.ai_flags = ffi::RAI_PASSIVE as c_int;
+    }
+
+    let mut res = core::ptr::null_mut();
+    check(
+        unsafe { ffi::rdma_getaddrinfo(node.as_ptr(), service.as_ptr(), &hints, &mut res) },
+        "rdma_getaddrinfo",
+    );
+
+    let mut init_attr = ffi::ibv_qp_init_attr {
+        cap: ffi::ibv_qp_cap {
+            max_send_wr: 4,
+            max_recv_wr: 4,
+            max_send_sge: 1,
+            max_recv_sge: 1,
+            ..Default::default()
+        },
+        qp_type: ffi::ibv_qp_type::IBV_QPT_RC,
+        sq_sig_all: 1,
+        ..Default::default()
+    };
+    let mut id = core::ptr::null_mut();
+    let rc = unsafe { ffi::rdma_create_ep(&mut id, res, core::ptr::null_mut(), &mut init_attr) };
+    unsafe { ffi::rdma_freeaddrinfo(res) };
+    check(rc, "rdma_create_ep");
+    id
+}
+
+/// Memory registered on the protection domain of the QP of `id`.
+struct Buffer {
+    buf: Vec<u8>,
+    mr: *mut ffi::ibv_mr,
+}
+
+impl Buffer {
+    fn new(id: *mut rdma_cm_id) -> Self {
+        let mut buf = vec![0; 64];
+        let pd = unsafe { (*(*id).qp).pd };
+        let access = ffi::ibv_access_flags::IBV_ACCESS_LOCAL_WRITE.0 as c_int;
+        let mr = unsafe { ffi::ibv_reg_mr(pd, buf.as_mut_ptr().cast(), buf.len(), access) };
+        assert!(!mr.is_null(), "ibv_reg_mr: {}", std::io::Error::last_os_error());
+        Self { buf, mr }
+    }
+
+    fn sge(&mut self) -> ffi::ibv_sge {
+        ffi::ibv_sge {
+            addr: self.buf.as_mut_ptr() as u64,
+            length: self.buf.len() as u32,
+            lkey: unsafe { (*self.mr).lkey },
+        }
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        unsafe { ffi::ibv_dereg_mr(self.mr) };
+    }
+}
+
+/// Wait for one completion of the send or receive CQ of the QP of `id`.
+fn poll(id: *mut rdma_cm_id, recv: bool) -> ffi::ibv_wc {
+    let qp = unsafe { (*id).qp };
+    let cq = unsafe { if recv { (*qp).recv_cq } else { (*qp).send_cq } };
+    let poll_cq = unsafe { (*(*qp).context).ops.poll_cq.unwrap() };
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut wc = ffi::ibv_wc::default();
+    while unsafe { poll_cq(cq, 1, &mut wc) } == 0 {
+        assert!(Instant::now() < deadline, "no completion");
+        thread::yield_now();
+    }
+    wc
+}
+
+#[test]
+#[ignore = "needs a urdma device bound to a netdev"]
+fn connect_and_send() {
+    let netdev = urdma_netdev().expect("no urdma device bound to a network interface");
+    let addr = match std::env::var("URDMA_RDMACM_ADDR") {
+        Ok(addr) => addr,
+        Err(_) => ipv4_addr(&netdev)
+            .unwrap_or_else(|| panic!("{netdev} has no IPv4 address and URDMA_RDMACM_ADDR is not set"))
+            .to_string(),
+    };
+
+    let listen = endpoint(&addr, true);
+    check(unsafe { ffi::rdma_listen(listen, 1) }, "rdma_listen");
+
+    // the raw pointers don't cross threads, the address does
+    let listen_addr = listen as usize;
+    let server = thread::spawn(move || {
+        let listen = listen_addr as *mut rdma_cm_id;
+        let mut id = core::ptr::null_mut();
+        check(unsafe { ffi::rdma_get_request(listen, &mut id) }, "rdma_get_request");
+
+        let mut buf = Buffer::new(id);
+        let mut sge = buf.sge();
+        let mut wr = ffi::ibv_recv_wr {
+            wr_id: 2,
+            next: core::ptr::null_mut(),
+            sg_list: &mut sge,
+            num_sge: 1,
+        };
+        let qp = unsafe { (*id).qp };
+        let post_recv = unsafe { (*(*qp).context).ops.post_recv.unwrap() };
+        let mut bad = core::ptr::null_mut();
+        check(unsafe { post_recv(qp, &mut wr, &mut bad) }, "post_recv");
+
+        check(unsafe { ffi::rdma_accept(id, core::ptr::null_mut()) }, "rdma_accept");
+        let wc = poll(id, true);
+        assert!(wc.is_valid(), "{:?}", wc.error());
+        assert_eq!((wc.wr_id(), wc.len()), (2, 4));
+        assert_eq!(&buf.buf[..4], b"ping");
+
+        drop(buf);
+        unsafe { ffi::rdma_disconnect(id) };
+        unsafe { ffi::rdma_destroy_ep(id) };
+    });
+
+    let id = endpoint(&addr, false);
+    check(unsafe { ffi::rdma_connect(id, core::ptr::null_mut()) }, "rdma_connect");
+
+    let mut buf = Buffer::new(id);
+    buf.buf[..4].copy_from_slice(b"ping");
+    let mut sge = buf.sge();
+    sge.length = 4;
+    // Safety: all-zero is a valid `ibv_send_wr`.
+    let mut wr: ffi::ibv_send_wr = unsafe { core::mem::zeroed() };
+    wr.wr_id = 1;
+    wr.sg_list = &mut sge;
+    wr.num_sge = 1;
+    wr.opcode = ffi::ibv_wr_opcode::IBV_WR_SEND;
+    let qp = unsafe { (*id).qp };
+    let post_send = unsafe { (*(*qp).context).ops.post_send.unwrap() };
+    let mut bad = core::ptr::null_mut();
+    check(unsafe { post_send(qp, &mut wr, &mut bad) }, "post_send");
+    let wc = poll(id, false);
+    assert!(wc.is_valid(), "{:?}", wc.error());
+    assert_eq!(wc.wr_id(), 1);
+
+    server.join().unwrap();
+    drop(buf);
+    unsafe { ffi::rdma_disconnect(id) };
+    unsafe { ffi::rdma_destroy_ep(id) };
+    unsafe { ffi::rdma_destroy_ep(listen) };
+}
@@ -2,60 +2,85 @@
 
 use std::env;
 use std::path::{Path, PathBuf};
-use std::process::Command;
 
 fn main() {
     let manifest_dir = env::var("CARGO_MANIFEST_DIR").expect("failed to get current directory");
     let rdma_core_dir = format!("{manifest_dir}/../rdma-core-v55");
-    println!("cargo:include={rdma_core_dir}/build/include");
-    println!("cargo:rustc-link-search=native={rdma_core_dir}/build/lib");
     println!("cargo:rustc-link-lib=ibverbs");
+    println!("cargo:rustc-check-cfg=cfg(urdma_h)");
 
-    if Path::new(&format!("{rdma_core_dir}/CMakeLists.txt")).exists() {
-        // don't touch source dir if not necessary
-    } else if Path::new(".git").is_dir() {
-        // initialize and update submodules
-        Command::new("git")
-            .args(["submodule", "update", "--init"])
-            .status()
-            .expect("Failed to update submodules.");
-    } else {
-        assert!(Path::new(&rdma_core_dir).is_dir(), "vendor source not included");
-    }
+    // the headers are taken from the rdma-core submodule when it is checked out, otherwise from the vendored copy of
+    // the ones the bindings need, so the crate also builds against an installed libibverbs
+    let (verbs_h, driver_h, rdma_cma_h, urdma_h, include_dir) =
+        if Path::new(&format!("{rdma_core_dir}/CMakeLists.txt")).exists() {
+            println!("cargo:rustc-link-search=native={rdma_core_dir}/build/lib");
 
-    // build rdma-core
-    // note that we only build it to generate the bindings!
-    eprintln!("run cmake");
-    let built_in = cmake::Config::new(&rdma_core_dir)
-        .define("NO_MAN_PAGES", "1")
-        // cmake crate defaults CMAKE_INSTALL_PREFIX to the output directory
-        //
-        //   https://github.com/rust-lang/cmake-rs/blob/94da9de2ea79ab6cad572e908864a160cf4847a9/src/lib.rs#L699-L703
-        //
-        // this results in overly long runtime paths on docs.rs, which then fail the build. it also
-        // causes sadness for users trying to build since the bindings may fail to build for the
-        // same reason (see https://github.com/jonhoo/rust-ibverbs/pull/41 for what was an
-        // incomplete fix).
-        //
-        // since we never actually _install_ anything when building here, we should be able to
-        // safely set this to any short path. simply by convention we set it to `/usr`.
-        .define("CMAKE_INSTALL_PREFIX", "/usr")
-        .no_build_target(true)
-        .build();
-    let built_in = built_in.to_str().expect("build directory path is not valid UTF-8");
+            // build rdma-core
+            // note that we only build it to generate the bindings!
+            eprintln!("run cmake");
+            let built_in = cmake::Config::new(&rdma_core_dir)
+                .define("NO_MAN_PAGES", "1")
+                // cmake crate defaults CMAKE_INSTALL_PREFIX to the output directory
+                //
+                //   https://github.com/rust-lang/cmake-rs/blob/94da9de2ea79ab6cad572e908864a160cf4847a9/src/lib.rs#L699-L703
+                //
+                // this results in overly long runtime paths on docs.rs, which then fail the build. it also
+                // causes sadness for users trying to build since the bindings may fail to build for the
+                // same reason (see https://github.com/jonhoo/rust-ibverbs/pull/41 for what was an
+                // incomplete fix).
+                //
+                // since we never actually _install_ anything when building here, we should be able to
+                // safely set this to any short path. simply by convention we set it to `/usr`.
+                .define("CMAKE_INSTALL_PREFIX", "/usr")
+                .no_build_target(true)
+                .build();
+            let built_in = built_in.to_str().expect("build directory path is not valid UTF-8");
+            (
+                format!("{rdma_core_dir}/libibverbs/verbs.h"),
+                format!("{rdma_core_dir}/libibverbs/driver.h"),
+                format!("{rdma_core_dir}/librdmacm/rdma_cma.h"),
+                Some(format!("{rdma_core_dir}/providers/urdma/urdma.h")),
+                format!("{built_in}/build/include"),
+            )
+        } else {
+            let include_dir = format!("{manifest_dir}/vendor/include");
+            println!("cargo:rerun-if-changed={include_dir}");
+            (
+                format!("{include_dir}/infiniband/verbs.h"),
+                format!("{include_dir}/infiniband/driver.h"),
+                format!("{include_dir}/rdma/rdma_cma.h"),
+                // the urdma provider is not vendored, lib.rs declares `struct urdma_device` instead
+                None,
+                include_dir,
+            )
+        };
+    println!("cargo:include={include_dir}");
 
     // generate the bindings
     eprintln!("run bindgen");
-    let bindings = bindgen::Builder::default()
-        .header(format!("{rdma_core_dir}/libibverbs/verbs.h"))
-        .header(format!("{rdma_core_dir}/libibverbs/driver.h"))
-        .clang_arg(format!("-I{built_in}/build/include/"))
+    let mut builder = bindgen::Builder::default()
+        .header(verbs_h)
+        .header(driver_h)
+        .header(rdma_cma_h);
+    if let Some(urdma_h) = urdma_h {
+        println!("cargo:rustc-cfg=urdma_h");
+        builder = builder.header(urdma_h).allowlist_type("urdma_.*");
+    }
+    let bindings = builder
+        .clang_arg(format!("-I{include_dir}"))
         .allowlist_function("ibv_.*")
+        // exported behind the inline ibv_query_gid_table()
+        .allowlist_function("_ibv_query_gid_table")
         .allowlist_type("ibv_.*")
         .allowlist_type("verbs_.*")
         .allowlist_var("IBV_LINK_LAYER_.*")
+        // librdmacm, for the tests connecting through the kernel CM
+        .allowlist_function("rdma_.*")
+        .allowlist_type("rdma_.*")
+        .allowlist_var("RAI_.*")
         .bitfield_enum("ibv_access_flags")
         .bitfield_enum("ibv_qp_attr_mask")
+        .bitfield_enum("ibv_srq_attr_mask")
         .bitfield_enum("ibv_wc_flags")
         .bitfield_enum("ibv_send_flags")
         .bitfield_enum("ibv_port_cap_flags")
@@ -58,6 +58,19 @@ pub struct ibv_wc {
 
 #[allow(clippy::len_without_is_empty)]
 impl ibv_wc {
+    /// Creates a work completion, for providers that generate completions in software.
+    ///
+    /// The remaining public fields (`imm_data`, `qp_num`, `src_qp`, `wc_flags`, ...) start zeroed.
+    pub fn new(wr_id: u64, status: ibv_wc_status::Type, opcode: ibv_wc_opcode::Type, byte_len: u32) -> Self {
+        ibv_wc {
+            wr_id,
+            status,
+            opcode,
+            byte_len,
+            ..Default::default()
+        }
+    }
+
     /// Returns the 64 bit value that was associated with the corresponding Work Request.
     pub fn wr_id(&self) -> u64 {
         self.wr_id
@@ -191,62 +204,62 @@ fn bindgen_test_layout_ibv_wc() {
         concat!("Alignment of ", stringify!(ibv_wc))
     );
     assert_eq!(
-        unsafe { &(*(0 as *const ibv_wc)).wr_id as *const _ as usize },
+        ::std::mem::offset_of!(ibv_wc, wr_id),
         0usize,
         concat!("Alignment of field: ", stringify!(ibv_wc), "::", stringify!(wr_id))
     );
     assert_eq!(
-        unsafe { &(*(0 as *const ibv_wc)).status as *const _ as usize },
+        ::std::mem::offset_of!(ibv_wc, status),
         8usize,
         concat!("Alignment of field: ", stringify!(ibv_wc), "::", stringify!(status))
     );
     assert_eq!(
-        unsafe { &(*(0 as *const ibv_wc)).opcode as *const _ as usize },
+        ::std::mem::offset_of!(ibv_wc, opcode),
         12usize,
         concat!("Alignment of field: ", stringify!(ibv_wc), "::", stringify!(opcode))
     );
     assert_eq!(
-        unsafe { &(*(0 as *const ibv_wc)).vendor_err as *const _ as usize },
+        ::std::mem::offset_of!(ibv_wc, vendor_err),
         16usize,
         concat!("Alignment of field: ", stringify!(ibv_wc), "::", stringify!(vendor_err))
     );
     assert_eq!(
-        unsafe { &(*(0 as *const ibv_wc)).byte_len as *const _ as usize },
+        ::std::mem::offset_of!(ibv_wc, byte_len),
         20usize,
         concat!("Alignment of field: ", stringify!(ibv_wc), "::", stringify!(byte_len))
     );
     assert_eq!(
-        unsafe { &(*(0 as *const ibv_wc)).qp_num as *const _ as usize },
+        ::std::mem::offset_of!(ibv_wc, qp_num),
         28usize,
         concat!("Alignment of field: ", stringify!(ibv_wc), "::", stringify!(qp_num))
     );
     assert_eq!(
-        unsafe { &(*(0 as *const ibv_wc)).src_qp as *const _ as usize },
+        ::std::mem::offset_of!(ibv_wc, src_qp),
         32usize,
         concat!("Alignment of field: ", stringify!(ibv_wc), "::", stringify!(src_qp))
     );
     assert_eq!(
-        unsafe { &(*(0 as *const ibv_wc)).wc_flags as *const _ as usize },
+        ::std::mem::offset_of!(ibv_wc, wc_flags),
         36usize,
         concat!("Alignment of field: ", stringify!(ibv_wc), "::", stringify!(wc_flags))
     );
     assert_eq!(
-        unsafe { &(*(0 as *const ibv_wc)).pkey_index as *const _ as usize },
+        ::std::mem::offset_of!(ibv_wc, pkey_index),
         40usize,
         concat!("Alignment of field: ", stringify!(ibv_wc), "::", stringify!(pkey_index))
     );
     assert_eq!(
-        unsafe { &(*(0 as *const ibv_wc)).slid as *const _ as usize },
+        ::std::mem::offset_of!(ibv_wc, slid),
         42usize,
         concat!("Alignment of field: ", stringify!(ibv_wc), "::", stringify!(slid))
     );
     assert_eq!(
-        unsafe { &(*(0 as *const ibv_wc)).sl as *const _ as usize },
+        ::std::mem::offset_of!(ibv_wc, sl),
         44usize,
         concat!("Alignment of field: ", stringify!(ibv_wc), "::", stringify!(sl))
     );
     assert_eq!(
-        unsafe { &(*(0 as *const ibv_wc)).dlid_path_bits as *const _ as usize },
+        ::std::mem::offset_of!(ibv_wc, dlid_path_bits),
         45usize,
         concat!(
             "Alignment of field: ",
@@ -276,3 +289,20 @@ impl Default for ibv_wc {
         }
     }
 }
+
+/// `struct urdma_device` of the urdma provider in rdma-core (`providers/urdma/urdma.h`), which is generated from the
+/// header when the rdma-core submodule is checked out.
+#[cfg(not(urdma_h))]
+#[repr(C)]
+#[derive(Debug)]
+pub struct urdma_device {
+    pub verbs_dev: verbs_device,
+    pub driver_data: *mut ::core::ffi::c_void,
+}
+
+// holds for the generated struct as well, so the declaration above cannot drift from the header
+const _: () = {
+    assert!(::std::mem::offset_of!(urdma_device, verbs_dev) == 0);
+    assert!(::std::mem::offset_of!(urdma_device, driver_data) == size_of::<verbs_device>());
+    assert!(size_of::<urdma_device>() == size_of::<verbs_device>() + size_of::<*mut ::core::ffi::c_void>());
+};
@@ -0,0 +1,172 @@
+use core::fmt;
+use core::ops::{BitOr, BitOrAssign};
+
+/// Set of verbs a backend implements
+///
+/// The glue only installs the `verbs_context_ops` entries of verbs in the set, libibverbs fails the others with
+/// `EOPNOTSUPP` without calling into the provider. Only the ops table of ABI version 1 holds every entry.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Verbs(u64);
+
+impl Verbs {
+    /// Every address handle verb
+    pub const AH: Self = Self(0b11 << 26);
+    /// Every verb of the [`Provider`](crate::Provider) trait
+    pub const ALL: Self = {
+        let mut all = Self::NONE;
+        let mut i = 0;
+        while i < NAMES.len() {
+            all.0 |= NAMES[i].0.0;
+            i += 1;
+        }
+        all
+    };
+    pub const ALLOC_MW: Self = Self(1 << 23);
+    pub const ALLOC_PD: Self = Self(1 << 0);
+    pub const BIND_MW: Self = Self(1 << 25);
+    pub const CREATE_AH: Self = Self(1 << 26);
+    pub const CREATE_CQ: Self = Self(1 << 4);
+    pub const CREATE_CQ_EX: Self = Self(1 << 21);
+    pub const CREATE_QP: Self = Self(1 << 6);
+    pub const CREATE_QP_EX: Self = Self(1 << 22);
+    pub const CREATE_SRQ: Self = Self(1 << 15);
+    pub const DEALLOC_MW: Self = Self(1 << 24);
+    pub const DEALLOC_PD: Self = Self(1 << 1);
+    pub const DEREG_MR: Self = Self(1 << 11);
+    pub const DESTROY_AH: Self = Self(1 << 27);
+    pub const DESTROY_CQ: Self = Self(1 << 5);
+    pub const DESTROY_QP: Self = Self(1 << 7);
+    pub const DESTROY_SRQ: Self = Self(1 << 18);
+    pub const MODIFY_QP: Self = Self(1 << 8);
+    pub const MODIFY_SRQ: Self = Self(1 << 16);
+    /// Every memory window verb
+    pub const MW: Self = Self(0b111 << 23);
+    /// No verbs
+    pub const NONE: Self = Self(0);
+    pub const POLL_CQ: Self = Self(1 << 14);
+    pub const POST_RECV: Self = Self(1 << 13);
+    pub const POST_SEND: Self = Self(1 << 12);
+    pub const POST_SRQ_RECV: Self = Self(1 << 19);
+    pub const QUERY_DEVICE: Self = Self(1 << 2);
+    pub const QUERY_PORT: Self = Self(1 << 3);
+    pub const QUERY_QP: Self = Self(1 << 9);
+    pub const QUERY_SRQ: Self = Self(1 << 17);
+    pub const REG_DMABUF_MR: Self = Self(1 << 29);
+    pub const REG_MR: Self = Self(1 << 10);
+    pub const REQ_NOTIFY_CQ: Self = Self(1 << 20);
+    pub const REREG_MR: Self = Self(1 << 28);
+    /// Every shared receive queue verb
+    pub const SRQ: Self = Self(0b11111 << 15);
+
+    /// Whether every verb of `other` is in the set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The set without the verbs of `other`.
+    #[must_use]
+    pub const fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+/// Names of the verbs, for logging
+const NAMES: [(Verbs, &str); 30] = [
+    (Verbs::ALLOC_PD, "alloc_pd"),
+    (Verbs::DEALLOC_PD, "dealloc_pd"),
+    (Verbs::QUERY_DEVICE, "query_device"),
+    (Verbs::QUERY_PORT, "query_port"),
+    (Verbs::CREATE_CQ, "create_cq"),
+    (Verbs::DESTROY_CQ, "destroy_cq"),
+    (Verbs::CREATE_QP, "create_qp"),
+    (Verbs::DESTROY_QP, "destroy_qp"),
+    (Verbs::MODIFY_QP, "modify_qp"),
+    (Verbs::QUERY_QP, "query_qp"),
+    (Verbs::REG_MR, "reg_mr"),
+    (Verbs::DEREG_MR, "dereg_mr"),
+    (Verbs::POST_SEND, "post_send"),
+    (Verbs::POST_RECV, "post_recv"),
+    (Verbs::POLL_CQ, "poll_cq"),
+    (Verbs::CREATE_SRQ, "create_srq"),
+    (Verbs::MODIFY_SRQ, "modify_srq"),
+    (Verbs::QUERY_SRQ, "query_srq"),
+    (Verbs::DESTROY_SRQ, "destroy_srq"),
+    (Verbs::POST_SRQ_RECV, "post_srq_recv"),
+    (Verbs::REQ_NOTIFY_CQ, "req_notify_cq"),
+    (Verbs::CREATE_CQ_EX, "create_cq_ex"),
+    (Verbs::CREATE_QP_EX, "create_qp_ex"),
+    (Verbs::ALLOC_MW, "alloc_mw"),
+    (Verbs::DEALLOC_MW, "dealloc_mw"),
+    (Verbs::BIND_MW, "bind_mw"),
+    (Verbs::CREATE_AH, "create_ah"),
+    (Verbs::DESTROY_AH, "destroy_ah"),
+    (Verbs::REREG_MR, "rereg_mr"),
+    (Verbs::REG_DMABUF_MR, "reg_dmabuf_mr"),
+];
+
+impl BitOr for Verbs {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Verbs {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl fmt::Debug for Verbs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set()
+            .entries(
+                NAMES
+                    .iter()
+                    .filter(|(verb, _)| self.contains(*verb))
+                    .map(|(_, name)| name),
+            )
+            .finish()
+    }
+}
+
+/// What a device supports, queried once per context when the device is opened
+///
+/// Must not change over the life of the device, contexts opened earlier keep the ops table built from it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// verbs the backend implements
+    pub verbs: Verbs,
+    /// transport types `create_qp` accepts, the glue fails others with `EOPNOTSUPP`
+    pub qp_types: &'static [ffi::ibv_qp_type::Type],
+}
+
+impl Capabilities {
+    /// Whether QPs of `qp_type` can be created.
+    pub fn supports_qp_type(&self, qp_type: ffi::ibv_qp_type::Type) -> bool {
+        self.verbs.contains(Verbs::CREATE_QP) && self.qp_types.contains(&qp_type)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verb_sets() {
+        let verbs = Verbs::ALL.without(Verbs::QUERY_QP);
+
+        assert!(verbs.contains(Verbs::ALLOC_PD | Verbs::POLL_CQ));
+        assert!(!verbs.contains(Verbs::QUERY_QP));
+        assert!(!verbs.contains(Verbs::QUERY_QP | Verbs::ALLOC_PD));
+        assert!(Verbs::ALL.contains(Verbs::NONE));
+        assert_eq!(
+            format!("{:?}", Verbs::ALLOC_PD | Verbs::POLL_CQ),
+            r#"{"alloc_pd", "poll_cq"}"#
+        );
+        // every verb has a bit of its own
+        assert_eq!(Verbs::ALL.0.count_ones() as usize, NAMES.len());
+        assert!(Verbs::ALL.contains(Verbs::AH | Verbs::MW | Verbs::SRQ));
+    }
+}
@@ -0,0 +1,284 @@
+//! Completion channels
+//!
+//! libibverbs creates the fd of an `ibv_comp_channel` in the kernel, which never signals it for urdma CQs. The first CQ
+//! created on a channel replaces that fd by an eventfd the glue writes CQ handles to: `ibv_get_cq_event` reads one
+//! handle and calls back `cq_event`, which writes the next pending one. The eventfd holds at most one handle at a time,
+//! so its counter is the handle itself.
+
+use std::collections::VecDeque;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+use crate::{Result, VerbsError};
+
+/// Channels taken over, by address of their `ibv_comp_channel`
+static CHANNELS: Mutex<Vec<(usize, Weak<Channel>)>> = Mutex::new(Vec::new());
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+struct Channel {
+    /// the eventfd installed on the channel
+    eventfd: OwnedFd,
+    queue: Mutex<Queue>,
+}
+
+#[derive(Default)]
+struct Queue {
+    /// handle in the eventfd, not read yet
+    outstanding: Option<usize>,
+    /// handles waiting for the outstanding one to be read
+    pending: VecDeque<usize>,
+}
+
+impl Channel {
+    /// Get the channel installed on `channel`, installing it first if no live CQ uses it.
+    ///
+    /// Safety: `channel` must point to a live `ibv_comp_channel`.
+    unsafe fn attach(channel: *mut ffi::ibv_comp_channel) -> Result<Arc<Self>> {
+        let mut channels = lock(&CHANNELS);
+        channels.retain(|(_, attached)| attached.strong_count() > 0);
+        if let Some(attached) = channels
+            .iter()
+            .find(|(key, _)| *key == channel as usize)
+            .and_then(|(_, attached)| attached.upgrade())
+        {
+            return Ok(attached);
+        }
+
+        let fd = unsafe { (*channel).fd };
+        // keep the blocking mode the application may have set already
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(VerbsError::last_os_error());
+        }
+        let nonblock = if flags & libc::O_NONBLOCK != 0 {
+            libc::EFD_NONBLOCK
+        } else {
+            0
+        };
+
+        let eventfd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | nonblock) };
+        if eventfd < 0 {
+            return Err(VerbsError::last_os_error());
+        }
+        // Safety: `eventfd` was just created and is owned by nobody else.
+        let eventfd = unsafe { OwnedFd::from_raw_fd(eventfd) };
+        // the kernel channel is closed, libibverbs closes the eventfd when the channel is destroyed
+        if unsafe { libc::dup3(eventfd.as_raw_fd(), fd, libc::O_CLOEXEC) } < 0 {
+            return Err(VerbsError::last_os_error());
+        }
+
+        let attached = Arc::new(Self {
+            eventfd,
+            queue: Mutex::default(),
+        });
+        channels.push((channel as usize, Arc::downgrade(&attached)));
+        Ok(attached)
+    }
+
+    /// Channel installed on `channel`, if any.
+    fn get(channel: *mut ffi::ibv_comp_channel) -> Option<Arc<Self>> {
+        lock(&CHANNELS)
+            .iter()
+            .find(|(key, _)| *key == channel as usize)
+            .and_then(|(_, attached)| attached.upgrade())
+    }
+
+    fn write(&self, cq: usize) {
+        let value = cq as u64;
+        // only fails on counter overflow, impossible with a single handle in it
+        let _ = unsafe { libc::write(self.eventfd.as_raw_fd(), (&raw const value).cast(), size_of::<u64>()) };
+    }
+
+    /// Queue an event of `cq`.
+    fn signal(&self, cq: usize) {
+        let mut queue = lock(&self.queue);
+        if queue.outstanding.is_none() {
+            self.write(cq);
+            queue.outstanding = Some(cq);
+        } else if queue.outstanding != Some(cq) && !queue.pending.contains(&cq) {
+            queue.pending.push_back(cq);
+        }
+    }
+
+    /// The outstanding handle was read, write the next one.
+    fn consumed(&self) {
+        let mut queue = lock(&self.queue);
+        queue.outstanding = queue.pending.pop_front();
+        if let Some(cq) = queue.outstanding {
+            self.write(cq);
+        }
+    }
+
+    /// Drop events of `cq`, which is being destroyed.
+    fn forget(&self, cq: usize) {
+        let mut queue = lock(&self.queue);
+        queue.pending.retain(|&pending| pending != cq);
+        if queue.outstanding != Some(cq) {
+            return;
+        }
+
+        // take the handle back unless the application is reading it right now
+        let mut pollfd = libc::pollfd {
+            fd: self.eventfd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&raw mut pollfd, 1, 0) } == 1 {
+            let mut value = 0u64;
+            let _ = unsafe { libc::read(self.eventfd.as_raw_fd(), (&raw mut value).cast(), size_of::<u64>()) };
+        }
+        drop(queue);
+        self.consumed();
+    }
+}
+
+const DISARMED: u8 = 0;
+const NEXT_COMPLETION: u8 = 1;
+const SOLICITED_ONLY: u8 = 2;
+
+/// Completion event source of a CQ created on a completion channel
+///
+/// Backends keep it with their CQ, call [`CqNotifier::arm`] from `req_notify_cq` and [`CqNotifier::completed`] for
+/// every completion they add. Backends that learn about events some other way call [`CqNotifier::notify`] instead.
+#[derive(Clone)]
+pub struct CqNotifier(Arc<Notifier>);
+
+struct Notifier {
+    channel: Arc<Channel>,
+    /// the `ibv_cq` handed to the application, 0 until it exists
+    cq: AtomicUsize,
+    armed: AtomicU8,
+}
+
+impl CqNotifier {
+    /// Event source delivering on `channel`, whose fd is taken over.
+    ///
+    /// # Safety
+    ///
+    /// `channel` must point to a live `ibv_comp_channel`, and outlive the notifier.
+    pub unsafe fn new(channel: *mut ffi::ibv_comp_channel) -> Result<Self> {
+        Ok(Self(Arc::new(Notifier {
+            channel: unsafe { Channel::attach(channel) }?,
+            cq: AtomicUsize::new(0),
+            armed: AtomicU8::new(DISARMED),
+        })))
+    }
+
+    /// Deliver events as `cq`.
+    pub(crate) fn bind(&self, cq: *mut ffi::ibv_cq) {
+        self.0.cq.store(cq as usize, Ordering::Release);
+    }
+
+    /// Request an event for the next completion, or only the next solicited or error one.
+    pub fn arm(&self, solicited_only: bool) {
+        let mode = if solicited_only {
+            SOLICITED_ONLY
+        } else {
+            NEXT_COMPLETION
+        };
+        // a pending request for any completion is not narrowed down
+        let _ = self.0.armed.fetch_update(Ordering::AcqRel, Ordering::Acquire, |armed| {
+            (armed != NEXT_COMPLETION).then_some(mode)
+        });
+    }
+
+    /// A completion was added to the CQ, `solicited` if it is a solicited receive or an error.
+    ///
+    /// Generates an event if the CQ is armed for it, which disarms it.
+    pub fn completed(&self, solicited: bool) {
+        let fire = |armed| match armed {
+            NEXT_COMPLETION => Some(DISARMED),
+            SOLICITED_ONLY if solicited => Some(DISARMED),
+            _ => None,
+        };
+        if self
+            .0
+            .armed
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, fire)
+            .is_ok()
+        {
+            self.notify();
+        }
+    }
+
+    /// Generate an event, regardless of how the CQ is armed.
+    pub fn notify(&self) {
+        match self.0.cq.load(Ordering::Acquire) {
+            0 => log::warn!("Completion event before the CQ exists, dropped"),
+            cq => self.0.channel.signal(cq),
+        }
+    }
+}
+
+/// The application read the event of a CQ on `channel`.
+pub(crate) fn consumed(channel: *mut ffi::ibv_comp_channel) {
+    if let Some(channel) = Channel::get(channel) {
+        channel.consumed();
+    }
+}
+
+/// `cq` on `channel` is being destroyed, its events must not be read anymore.
+pub(crate) fn forget(channel: *mut ffi::ibv_comp_channel, cq: *mut ffi::ibv_cq) {
+    if let Some(channel) = Channel::get(channel) {
+        channel.forget(cq as usize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(fd: i32) -> Option<u64> {
+        let mut value = 0u64;
+        let len = unsafe { libc::read(fd, (&raw mut value).cast(), size_of::<u64>()) };
+        (len == size_of::<u64>() as isize).then_some(value)
+    }
+
+    #[test]
+    fn events_are_read_one_at_a_time() {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK) };
+        let mut channel = ffi::ibv_comp_channel {
+            fd,
+            ..Default::default()
+        };
+        let channel = &raw mut channel;
+        let (cq1, cq2) = (0x1000 as *mut ffi::ibv_cq, 0x2000 as *mut ffi::ibv_cq);
+
+        let first = unsafe { CqNotifier::new(channel) }.unwrap();
+        let second = unsafe { CqNotifier::new(channel) }.unwrap();
+        first.bind(cq1);
+        second.bind(cq2);
+
+        // not armed
+        first.completed(true);
+        assert_eq!(read(fd), None);
+
+        first.arm(true);
+        first.completed(false);
+        assert_eq!(read(fd), None);
+        first.completed(true);
+        // disarmed by the event
+        first.completed(true);
+
+        second.arm(false);
+        second.completed(false);
+
+        assert_eq!(read(fd), Some(0x1000));
+        assert_eq!(read(fd), None);
+        consumed(channel);
+        assert_eq!(read(fd), Some(0x2000));
+        consumed(channel);
+
+        // the handle of a destroyed CQ is taken back
+        second.notify();
+        forget(channel, cq2);
+        assert_eq!(read(fd), None);
+
+        drop((first, second));
+        unsafe { libc::close(fd) };
+    }
+}
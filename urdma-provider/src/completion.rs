@@ -0,0 +1,131 @@
+//! Extended completion queues
+//!
+//! Every CQ the glue creates starts with an `ibv_cq_ex`, whose head is the `ibv_cq` the legacy verbs see. The extended
+//! polling API reads one completion at a time: `start_poll` and `next_poll` advance a [`Completions`] iterator, which
+//! fetches batches from the backend, and the `read_*` callbacks read the completion it yielded last. Completions
+//! fetched but not read yet stay buffered with the CQ for the next poll, legacy `poll_cq` included.
+
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{Provider, Result};
+
+/// Completion fields of [`Completion`] the extended polling API can read
+pub(crate) const WC_FLAGS: u64 = (ffi::IBV_WC_EX_WITH_BYTE_LEN
+    | ffi::IBV_WC_EX_WITH_IMM
+    | ffi::IBV_WC_EX_WITH_QP_NUM
+    | ffi::IBV_WC_EX_WITH_SRC_QP
+    | ffi::IBV_WC_EX_WITH_SLID
+    | ffi::IBV_WC_EX_WITH_SL
+    | ffi::IBV_WC_EX_WITH_DLID_PATH_BITS
+    | ffi::IBV_WC_EX_WITH_COMPLETION_TIMESTAMP
+    | ffi::IBV_WC_EX_WITH_COMPLETION_TIMESTAMP_WALLCLOCK) as u64;
+
+/// Fields that need a backend recording when completions are generated
+pub(crate) const WC_FLAGS_TIMESTAMP: u64 =
+    (ffi::IBV_WC_EX_WITH_COMPLETION_TIMESTAMP | ffi::IBV_WC_EX_WITH_COMPLETION_TIMESTAMP_WALLCLOCK) as u64;
+
+/// Work completion as read by the extended polling API
+#[derive(Debug, Clone, Copy)]
+pub struct Completion {
+    pub wc: ffi::ibv_wc,
+    /// when the completion was generated, in nanoseconds since the Unix epoch
+    ///
+    /// Reported both as the device clock, which counts nanoseconds, and as the wall clock.
+    pub timestamp: Option<u64>,
+}
+
+impl Completion {
+    /// Completion without a timestamp.
+    pub fn new(wc: ffi::ibv_wc) -> Self {
+        Self { wc, timestamp: None }
+    }
+
+    /// Completion generated now.
+    pub fn stamped(wc: ffi::ibv_wc) -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Self {
+            wc,
+            timestamp: Some(u64::try_from(now.as_nanos()).unwrap_or(u64::MAX)),
+        }
+    }
+}
+
+impl Default for Completion {
+    fn default() -> Self {
+        Self::new(ffi::ibv_wc::default())
+    }
+}
+
+/// Completions fetched from the backend and not consumed yet
+#[derive(Default)]
+pub(crate) struct Polled {
+    batch: VecDeque<Completion>,
+    /// completion the `read_*` callbacks read, between `start_poll` and `end_poll`
+    pub(crate) current: Option<Completion>,
+}
+
+impl Polled {
+    /// Completions the backend returns in one call
+    const BATCH: usize = 16;
+
+    /// Take up to `len` buffered completions, for legacy polling.
+    pub(crate) fn drain(&mut self, len: usize) -> impl Iterator<Item = Completion> + '_ {
+        let len = len.min(self.batch.len());
+        self.batch.drain(..len)
+    }
+}
+
+/// Completions of a CQ, fetched from the backend in batches
+pub(crate) struct Completions<'a, P: Provider> {
+    provider: &'a P,
+    cq: &'a P::Cq,
+    polled: &'a mut Polled,
+}
+
+impl<'a, P: Provider> Completions<'a, P> {
+    pub(crate) fn new(provider: &'a P, cq: &'a P::Cq, polled: &'a mut Polled) -> Self {
+        Self { provider, cq, polled }
+    }
+}
+
+impl<P: Provider> Iterator for Completions<'_, P> {
+    type Item = Result<Completion>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.polled.batch.is_empty() {
+            let mut batch = [Completion::default(); Polled::BATCH];
+            match self.provider.poll_cq_ex(self.cq, &mut batch) {
+                Ok(len) => self.polled.batch.extend(&batch[..len.min(Polled::BATCH)]),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+
+        self.polled.batch.pop_front().map(Ok)
+    }
+}
+
+/// Head of every CQ object, see the module documentation
+#[repr(C)]
+pub(crate) struct CqHead {
+    pub(crate) ibv: ffi::ibv_cq_ex,
+    polled: Mutex<Polled>,
+}
+
+impl CqHead {
+    pub(crate) fn new(ibv: ffi::ibv_cq_ex) -> Self {
+        Self {
+            ibv,
+            polled: Mutex::default(),
+        }
+    }
+
+    /// Completions buffered with the CQ at `cq`.
+    ///
+    /// Safety: `cq` must be the `ibv_cq` or `ibv_cq_ex` of a live CQ object.
+    pub(crate) unsafe fn polled<'a, T>(cq: *mut T) -> MutexGuard<'a, Polled> {
+        let head = unsafe { &*cq.cast::<Self>() };
+        head.polled.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
@@ -0,0 +1,117 @@
+//! State of the contexts opened on urdma devices
+//!
+//! Built by the glue when a context is opened and dropped when it is closed, so verbs use what the device reported at
+//! open instead of asking the backend again.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::{Capabilities, Result, VerbsError};
+
+/// State of the open contexts, by context address
+static CONTEXTS: RwLock<Option<HashMap<usize, Arc<Context>>>> = RwLock::new(None);
+
+/// State of an open context
+pub(crate) struct Context {
+    /// capabilities of the device, the ops table of the context is built from them
+    pub(crate) caps: Capabilities,
+    /// GID tables queried so far, by port number
+    gids: Mutex<HashMap<u8, Vec<ffi::ibv_gid_entry>>>,
+}
+
+impl Context {
+    /// Remember the state of `context`, which is being opened.
+    pub(crate) fn open(context: *mut ffi::ibv_context, caps: Capabilities) -> Arc<Self> {
+        let state = Arc::new(Self {
+            caps,
+            gids: Mutex::default(),
+        });
+        CONTEXTS
+            .write()
+            .unwrap_or_else(|err| err.into_inner())
+            .get_or_insert_default()
+            .insert(context as usize, state.clone());
+        state
+    }
+
+    /// Forget the state of `context`, which is being closed.
+    pub(crate) fn close(context: *mut ffi::ibv_context) {
+        if let Some(contexts) = CONTEXTS.write().unwrap_or_else(|err| err.into_inner()).as_mut() {
+            contexts.remove(&(context as usize));
+        }
+    }
+
+    /// State of `context`, which must be open.
+    pub(crate) fn of(context: *mut ffi::ibv_context) -> Result<Arc<Self>> {
+        CONTEXTS
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .as_ref()
+            .and_then(|contexts| contexts.get(&(context as usize)))
+            .cloned()
+            .ok_or(VerbsError::InvalidArgument)
+    }
+
+    /// Whether port `port_num` has a GID at `gid_index`, or reports no GIDs at all. The table of the port is taken from
+    /// `query` the first time, and again if it lacks `gid_index`, as addresses may have changed since.
+    pub(crate) fn has_gid(
+        &self,
+        port_num: u8,
+        gid_index: u32,
+        query: impl FnOnce() -> Result<Vec<ffi::ibv_gid_entry>>,
+    ) -> Result<bool> {
+        let holds =
+            |table: &[ffi::ibv_gid_entry]| table.is_empty() || table.iter().any(|entry| entry.gid_index == gid_index);
+
+        let mut gids = self.gids.lock().unwrap_or_else(|err| err.into_inner());
+        if gids.get(&port_num).is_some_and(|table| holds(table)) {
+            return Ok(true);
+        }
+        let table = query()?;
+        let found = holds(&table);
+        gids.insert(port_num, table);
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contexts_are_forgotten_when_closed() {
+        let mut context = ffi::ibv_context::default();
+        Context::open(&raw mut context, Capabilities::default());
+        assert!(Context::of(&raw mut context).is_ok());
+
+        Context::close(&raw mut context);
+        assert_eq!(Context::of(&raw mut context).err(), Some(VerbsError::InvalidArgument));
+    }
+
+    #[test]
+    fn gid_tables_are_cached() {
+        let mut context = ffi::ibv_context::default();
+        Context::open(&raw mut context, Capabilities::default());
+        let state = Context::of(&raw mut context).unwrap();
+        let table = |len: u32| {
+            (0..len)
+                .map(|gid_index| ffi::ibv_gid_entry {
+                    gid_index,
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(state.has_gid(1, 1, || Ok(table(2))), Ok(true));
+        // held in the cache
+        assert_eq!(state.has_gid(1, 0, || unreachable!()), Ok(true));
+        // queried again on a miss
+        assert_eq!(state.has_gid(1, 2, || Ok(table(2))), Ok(false));
+        assert_eq!(state.has_gid(1, 2, || Ok(table(3))), Ok(true));
+        // no table, no check
+        assert_eq!(state.has_gid(2, 5, || Ok(Vec::new())), Ok(true));
+        assert_eq!(state.has_gid(2, 5, || unreachable!()), Ok(true));
+
+        Context::close(&raw mut context);
+    }
+}
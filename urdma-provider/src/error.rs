@@ -0,0 +1,120 @@
+use core::ffi::c_int;
+use core::fmt;
+
+/// Error of a verb
+///
+/// libibverbs reports errors as errno values, [`VerbsError::errno`] is the only conversion the glue uses: callbacks
+/// returning `int` return it as is, callbacks returning a pointer store it in `errno` and `poll_cq` returns it negated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum VerbsError {
+    /// `EINVAL`, an argument or the object state does not allow the verb
+    InvalidArgument,
+    /// `ENOMEM`, out of memory or a queue is full
+    OutOfResources,
+    /// `EOPNOTSUPP`, the backend does not implement the verb or the requested feature
+    NotSupported,
+    /// `EBUSY`, the object is still referenced
+    Busy,
+    /// `ENODEV`, the backing device does not exist or went away
+    DeviceGone,
+    /// Any other errno, e.g. reported by the backing device or the OS
+    Errno(c_int),
+}
+
+pub type Result<T = ()> = core::result::Result<T, VerbsError>;
+
+impl VerbsError {
+    /// Error for a positive errno.
+    pub fn from_errno(errno: c_int) -> Self {
+        match errno {
+            libc::EINVAL => Self::InvalidArgument,
+            libc::ENOMEM => Self::OutOfResources,
+            libc::EOPNOTSUPP => Self::NotSupported,
+            libc::EBUSY => Self::Busy,
+            libc::ENODEV => Self::DeviceGone,
+            // not an errno, whoever produced it lost the real cause
+            ..=0 => Self::Errno(libc::EIO),
+            errno => Self::Errno(errno),
+        }
+    }
+
+    /// Positive errno for libibverbs.
+    pub fn errno(self) -> c_int {
+        match self {
+            Self::InvalidArgument => libc::EINVAL,
+            Self::OutOfResources => libc::ENOMEM,
+            Self::NotSupported => libc::EOPNOTSUPP,
+            Self::Busy => libc::EBUSY,
+            Self::DeviceGone => libc::ENODEV,
+            Self::Errno(errno) if errno > 0 => errno,
+            Self::Errno(_) => libc::EIO,
+        }
+    }
+
+    /// Error left in `errno` by the last failed call.
+    pub fn last_os_error() -> Self {
+        std::io::Error::last_os_error().into()
+    }
+
+    /// Check the return code of a libibverbs call returning `int`.
+    ///
+    /// Providers return a positive errno, some return `-1` with `errno` set or a negative errno instead.
+    pub fn check(rc: c_int) -> Result {
+        match rc {
+            0 => Ok(()),
+            -1 => Err(Self::last_os_error()),
+            rc => Err(Self::from_errno(rc.saturating_abs())),
+        }
+    }
+}
+
+impl From<std::io::Error> for VerbsError {
+    fn from(err: std::io::Error) -> Self {
+        err.raw_os_error().map_or(Self::Errno(libc::EIO), Self::from_errno)
+    }
+}
+
+impl fmt::Display for VerbsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidArgument => f.write_str("invalid argument"),
+            Self::OutOfResources => f.write_str("out of resources"),
+            Self::NotSupported => f.write_str("operation not supported"),
+            Self::Busy => f.write_str("resource busy"),
+            Self::DeviceGone => f.write_str("no such device"),
+            Self::Errno(_) => write!(f, "{}", std::io::Error::from_raw_os_error(self.errno())),
+        }
+    }
+}
+
+impl std::error::Error for VerbsError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errno_roundtrip() {
+        for errno in [
+            libc::EINVAL,
+            libc::ENOMEM,
+            libc::EOPNOTSUPP,
+            libc::EBUSY,
+            libc::ENODEV,
+            libc::EAGAIN,
+        ] {
+            assert_eq!(VerbsError::from_errno(errno).errno(), errno);
+        }
+        assert_eq!(VerbsError::from_errno(libc::EAGAIN), VerbsError::Errno(libc::EAGAIN));
+        assert_eq!(VerbsError::from_errno(-1).errno(), libc::EIO);
+        assert_eq!(VerbsError::Errno(0).errno(), libc::EIO);
+    }
+
+    #[test]
+    fn check_return_codes() {
+        assert_eq!(VerbsError::check(0), Ok(()));
+        assert_eq!(VerbsError::check(libc::EBUSY), Err(VerbsError::Busy));
+        assert_eq!(VerbsError::check(-libc::ENOMEM), Err(VerbsError::OutOfResources));
+    }
+}
@@ -0,0 +1,394 @@
+//! Asynchronous events
+//!
+//! libibverbs reads async events from the `async_fd` of a context, which the kernel never signals for urdma devices.
+//! When a context is opened the glue replaces that fd by the read end of a pipe, then writes every event a backend
+//! posts to the [`AsyncEvents`] of its device in the record format `ibv_get_async_event` expects. Events of an object
+//! go to the context it was created on, port and device events to every context of the device. Like with kernel
+//! providers, destroying an object waits until the application acknowledged the events delivered for it.
+
+use std::collections::HashMap;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+use crate::{Result, VerbsError};
+
+/// Sinks of the live devices, by address of their provider
+static DEVICES: RwLock<Vec<(usize, AsyncEvents)>> = RwLock::new(Vec::new());
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Event of a queue pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QpEvent {
+    /// an error not reported by a completion moved the QP to the error state
+    Fatal,
+    /// the QP received a request it cannot execute
+    RequestError,
+    /// a remote request violated the access rights of the QP
+    AccessError,
+    /// the first packet arrived while the QP is in RTR
+    CommunicationEstablished,
+    /// the send queue of a QP in SQD has drained
+    SqDrained,
+    /// the QP migrated to its alternate path
+    PathMigrated,
+    /// the alternate path could not be migrated to
+    PathMigrationError,
+    /// a QP on a shared receive queue entered the error state and will consume no more receive requests
+    LastWqeReached,
+}
+
+/// Event of a completion queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CqEvent {
+    /// the CQ overflowed or is otherwise unusable
+    Error,
+}
+
+/// Event of a shared receive queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SrqEvent {
+    /// the SRQ is unusable
+    Error,
+    /// fewer receive requests than the armed limit are posted, the limit is disarmed
+    LimitReached,
+}
+
+/// Event of a port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortEvent {
+    Active,
+    Error,
+    LidChange,
+    PkeyChange,
+    GidChange,
+    SmChange,
+    ClientReregister,
+}
+
+/// Asynchronous event, objects are identified by their numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsyncEvent {
+    /// event of the QP with [`QueuePair::qp_num`](crate::QueuePair::qp_num)
+    Qp(u32, QpEvent),
+    /// event of the CQ with [`CompletionQueue::cq_num`](crate::CompletionQueue::cq_num)
+    Cq(u32, CqEvent),
+    /// event of the SRQ with [`SharedReceiveQueue::srq_num`](crate::SharedReceiveQueue::srq_num)
+    Srq(u32, SrqEvent),
+    /// event of a port
+    Port(u8, PortEvent),
+    /// the device is unusable
+    DeviceFatal,
+}
+
+impl AsyncEvent {
+    /// Event type reported by `ibv_get_async_event`.
+    pub fn event_type(self) -> ffi::ibv_event_type {
+        match self {
+            Self::Qp(_, event) => match event {
+                QpEvent::Fatal => ffi::IBV_EVENT_QP_FATAL,
+                QpEvent::RequestError => ffi::IBV_EVENT_QP_REQ_ERR,
+                QpEvent::AccessError => ffi::IBV_EVENT_QP_ACCESS_ERR,
+                QpEvent::CommunicationEstablished => ffi::IBV_EVENT_COMM_EST,
+                QpEvent::SqDrained => ffi::IBV_EVENT_SQ_DRAINED,
+                QpEvent::PathMigrated => ffi::IBV_EVENT_PATH_MIG,
+                QpEvent::PathMigrationError => ffi::IBV_EVENT_PATH_MIG_ERR,
+                QpEvent::LastWqeReached => ffi::IBV_EVENT_QP_LAST_WQE_REACHED,
+            },
+            Self::Cq(_, CqEvent::Error) => ffi::IBV_EVENT_CQ_ERR,
+            Self::Srq(_, event) => match event {
+                SrqEvent::Error => ffi::IBV_EVENT_SRQ_ERR,
+                SrqEvent::LimitReached => ffi::IBV_EVENT_SRQ_LIMIT_REACHED,
+            },
+            Self::Port(_, event) => match event {
+                PortEvent::Active => ffi::IBV_EVENT_PORT_ACTIVE,
+                PortEvent::Error => ffi::IBV_EVENT_PORT_ERR,
+                PortEvent::LidChange => ffi::IBV_EVENT_LID_CHANGE,
+                PortEvent::PkeyChange => ffi::IBV_EVENT_PKEY_CHANGE,
+                PortEvent::GidChange => ffi::IBV_EVENT_GID_CHANGE,
+                PortEvent::SmChange => ffi::IBV_EVENT_SM_CHANGE,
+                PortEvent::ClientReregister => ffi::IBV_EVENT_CLIENT_REREGISTER,
+            },
+            Self::DeviceFatal => ffi::IBV_EVENT_DEVICE_FATAL,
+        }
+    }
+}
+
+/// Kind of object an event refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Scope {
+    Qp,
+    Cq,
+    Srq,
+}
+
+/// Record read by `ibv_get_async_event`, `struct ib_uverbs_async_event_desc`
+#[repr(C)]
+struct EventDesc {
+    /// object handle, or port number
+    element: u64,
+    event_type: u32,
+    reserved: u32,
+}
+
+/// Sink for the asynchronous events of a device
+///
+/// Created by the glue for every device and handed to [`Provider::new`](crate::Provider::new), backends keep clones
+/// wherever events originate. Events of objects the application has not created, or already destroyed, are dropped.
+#[derive(Clone, Default)]
+pub struct AsyncEvents(Arc<Sink>);
+
+#[derive(Default)]
+struct Sink {
+    /// write ends of the pipes installed on the contexts, by context address
+    contexts: Mutex<Vec<(usize, OwnedFd)>>,
+    /// objects the application created, by scope and number
+    objects: Mutex<HashMap<(Scope, u32), Object>>,
+}
+
+/// Object events are delivered for
+struct Object {
+    /// address of the context it was created on
+    context: usize,
+    /// handle the application knows it by
+    handle: usize,
+    /// events written to the context, the application acknowledges each with `ibv_ack_async_event`
+    reported: u32,
+}
+
+impl AsyncEvents {
+    /// Deliver `event` to the application.
+    pub fn post(&self, event: AsyncEvent) {
+        log::debug!("Async event {event:?}");
+
+        let key = match event {
+            AsyncEvent::Qp(num, _) => (Scope::Qp, num),
+            AsyncEvent::Cq(num, _) => (Scope::Cq, num),
+            AsyncEvent::Srq(num, _) => (Scope::Srq, num),
+            AsyncEvent::Port(port_num, _) => {
+                self.write(event, port_num.into(), None);
+                return;
+            }
+            AsyncEvent::DeviceFatal => {
+                self.write(event, 0, None);
+                return;
+            }
+        };
+
+        // held until the event is counted, so that destroying the object waits for its acknowledgement
+        let mut objects = lock(&self.0.objects);
+        let Some(object) = objects.get_mut(&key) else {
+            log::debug!("Async event {event:?} of an unknown object, dropped");
+            return;
+        };
+        if self.write(event, object.handle, Some(object.context)) {
+            object.reported += 1;
+        }
+    }
+
+    /// Write `event` of `element` to `target`, or every context. Whether it was written to any.
+    fn write(&self, event: AsyncEvent, element: usize, target: Option<usize>) -> bool {
+        let desc = EventDesc {
+            element: element as u64,
+            event_type: event.event_type(),
+            reserved: 0,
+        };
+        let mut reported = false;
+        for (context, fd) in lock(&self.0.contexts).iter() {
+            if target.is_some_and(|target| target != *context) {
+                continue;
+            }
+            let written = unsafe { libc::write(fd.as_raw_fd(), (&raw const desc).cast(), size_of::<EventDesc>()) };
+            if written == size_of::<EventDesc>() as isize {
+                reported = true;
+            } else {
+                log::warn!("Async event {event:?} dropped, the application does not read them");
+            }
+        }
+        reported
+    }
+
+    /// Replace the async fd of `context` by a pipe fed by this sink.
+    ///
+    /// Safety: `context` must point to a live `ibv_context`.
+    pub(crate) unsafe fn attach(&self, context: *mut ffi::ibv_context) -> Result {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+            return Err(VerbsError::last_os_error());
+        }
+        // Safety: both ends were just created and are owned by nobody else.
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        // a full pipe drops events instead of blocking the backend
+        if unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) } < 0 {
+            return Err(VerbsError::last_os_error());
+        }
+        // libibverbs closes the read end with the context
+        if unsafe { libc::dup3(read.as_raw_fd(), (*context).async_fd, libc::O_CLOEXEC) } < 0 {
+            return Err(VerbsError::last_os_error());
+        }
+
+        lock(&self.0.contexts).push((context as usize, write));
+        Ok(())
+    }
+
+    /// Stop delivering to `context`, which is being closed.
+    pub(crate) fn detach(&self, context: *mut ffi::ibv_context) {
+        lock(&self.0.contexts).retain(|(attached, _)| *attached != context as usize);
+        lock(&self.0.objects).retain(|_, object| object.context != context as usize);
+    }
+
+    /// Deliver events of object `num` as `handle` on `context`.
+    pub(crate) fn register<T>(&self, scope: Scope, num: u32, context: *mut ffi::ibv_context, handle: *mut T) {
+        let object = Object {
+            context: context as usize,
+            handle: handle as usize,
+            reported: 0,
+        };
+        lock(&self.0.objects).insert((scope, num), object);
+    }
+
+    /// Stop delivering events of object `num`, which is being destroyed. Returns how many were delivered.
+    pub(crate) fn unregister(&self, scope: Scope, num: u32) -> u32 {
+        lock(&self.0.objects)
+            .remove(&(scope, num))
+            .map_or(0, |object| object.reported)
+    }
+}
+
+/// Wait until the application acknowledged `reported` events of an object being destroyed, the handles of which it
+/// must not receive after it is freed.
+///
+/// `ibv_ack_async_event` counts the acknowledged events in `completed` under `mutex` and signals `cond`.
+///
+/// Safety: the arguments must be the fields of a live verbs object.
+pub(crate) unsafe fn wait_acked(
+    mutex: *mut ffi::pthread_mutex_t,
+    cond: *mut ffi::pthread_cond_t,
+    completed: *const u32,
+    reported: u32,
+) {
+    let (mutex, cond) = (
+        mutex.cast::<libc::pthread_mutex_t>(),
+        cond.cast::<libc::pthread_cond_t>(),
+    );
+    unsafe {
+        libc::pthread_mutex_lock(mutex);
+        while completed.read_volatile() != reported {
+            libc::pthread_cond_wait(cond, mutex);
+        }
+        libc::pthread_mutex_unlock(mutex);
+    }
+}
+
+/// Sink of the device whose provider is at `provider`.
+pub(crate) fn of<P>(provider: &P) -> Option<AsyncEvents> {
+    let key = core::ptr::from_ref(provider) as usize;
+    DEVICES
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .iter()
+        .find(|(device, _)| *device == key)
+        .map(|(_, events)| events.clone())
+}
+
+/// Remember `events` as the sink of the device whose provider is at `provider`.
+pub(crate) fn insert<P>(provider: &P, events: AsyncEvents) {
+    let key = core::ptr::from_ref(provider) as usize;
+    let mut devices = DEVICES.write().unwrap_or_else(|err| err.into_inner());
+    devices.retain(|(device, _)| *device != key);
+    devices.push((key, events));
+}
+
+/// Forget the sink of a device being freed.
+pub(crate) fn remove<P>(provider: &P) {
+    let key = core::ptr::from_ref(provider) as usize;
+    DEVICES
+        .write()
+        .unwrap_or_else(|err| err.into_inner())
+        .retain(|(device, _)| *device != key);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(fd: i32) -> Option<(u64, u32)> {
+        let mut desc = EventDesc {
+            element: 0,
+            event_type: 0,
+            reserved: 0,
+        };
+        let len = unsafe { libc::read(fd, (&raw mut desc).cast(), size_of::<EventDesc>()) };
+        (len == size_of::<EventDesc>() as isize).then_some((desc.element, desc.event_type))
+    }
+
+    #[test]
+    fn events_reach_their_context() {
+        let events = AsyncEvents::default();
+        let fds: Vec<_> = (0..2).map(|_| unsafe { libc::eventfd(0, 0) }).collect();
+        let mut contexts: Vec<_> = fds
+            .iter()
+            .map(|&async_fd| ffi::ibv_context {
+                async_fd,
+                ..Default::default()
+            })
+            .collect();
+        let (first, second) = (&raw mut contexts[0], &raw mut contexts[1]);
+        for context in [first, second] {
+            unsafe { events.attach(context) }.unwrap();
+            unsafe { libc::fcntl((*context).async_fd, libc::F_SETFL, libc::O_NONBLOCK) };
+        }
+
+        let qp = 0x1000 as *mut ffi::ibv_qp;
+        events.register(Scope::Qp, 7, second, qp);
+        events.post(AsyncEvent::Qp(7, QpEvent::LastWqeReached));
+        // not created on this device
+        events.post(AsyncEvent::Qp(8, QpEvent::Fatal));
+        events.post(AsyncEvent::Port(1, PortEvent::Active));
+
+        assert_eq!(read(fds[0]), Some((1, ffi::IBV_EVENT_PORT_ACTIVE)));
+        assert_eq!(read(fds[0]), None);
+        assert_eq!(read(fds[1]), Some((0x1000, ffi::IBV_EVENT_QP_LAST_WQE_REACHED)));
+        assert_eq!(read(fds[1]), Some((1, ffi::IBV_EVENT_PORT_ACTIVE)));
+
+        // the application has the handle of the event to acknowledge
+        assert_eq!(events.unregister(Scope::Qp, 7), 1);
+        events.post(AsyncEvent::Qp(7, QpEvent::Fatal));
+        events.detach(first);
+        events.post(AsyncEvent::DeviceFatal);
+        assert_eq!(read(fds[0]), None);
+        assert_eq!(read(fds[1]), Some((0, ffi::IBV_EVENT_DEVICE_FATAL)));
+
+        for fd in fds {
+            unsafe { libc::close(fd) };
+        }
+    }
+
+    #[test]
+    fn destroy_waits_for_acknowledgements() {
+        let mut qp = ffi::ibv_qp::default();
+        let addr = &raw mut qp as usize;
+
+        // what `ibv_ack_async_event` does for each event
+        let application = std::thread::spawn(move || {
+            let qp = addr as *mut ffi::ibv_qp;
+            for _ in 0..2 {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                unsafe {
+                    let mutex = (&raw mut (*qp).mutex).cast::<libc::pthread_mutex_t>();
+                    libc::pthread_mutex_lock(mutex);
+                    (*qp).events_completed += 1;
+                    libc::pthread_cond_signal((&raw mut (*qp).cond).cast());
+                    libc::pthread_mutex_unlock(mutex);
+                }
+            }
+        });
+
+        unsafe { wait_acked(&raw mut qp.mutex, &raw mut qp.cond, &raw const qp.events_completed, 2) };
+        assert_eq!(qp.events_completed, 2);
+        application.join().unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+//! Keep panics from unwinding into C
+//!
+//! Unwinding across the `extern "C"` callbacks is undefined behaviour, so the glue runs every call into a
+//! [`Provider`](crate::Provider) under [`call`]. Verbs the backend does not implement return `NotSupported` and never
+//! get here; a panic leaves the backend in an unknown state, so its device is marked failed and every later verb on it
+//! fails with `EIO` and every context gets an `IBV_EVENT_DEVICE_FATAL` async event.
+
+use core::any::Any;
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::RwLock;
+
+use crate::{AsyncEvent, Result, VerbsError, events};
+
+/// Addresses of providers that panicked
+static FAILED: RwLock<Vec<usize>> = RwLock::new(Vec::new());
+
+/// Whether [`FAILED`] may be non-empty, keeps the lock off the data path of healthy devices
+static ANY_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Error of every verb on a failed device
+const DEVICE_FAILED: VerbsError = VerbsError::Errno(libc::EIO);
+
+fn key<P>(provider: &P) -> usize {
+    core::ptr::from_ref(provider) as usize
+}
+
+fn is_failed<P>(provider: &P) -> bool {
+    ANY_FAILED.load(Ordering::Acquire)
+        && FAILED
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .contains(&key(provider))
+}
+
+fn mark_failed<P>(provider: &P) {
+    let mut failed = FAILED.write().unwrap_or_else(|err| err.into_inner());
+    if failed.contains(&key(provider)) {
+        return;
+    }
+    failed.push(key(provider));
+    ANY_FAILED.store(true, Ordering::Release);
+    drop(failed);
+
+    // tell applications waiting for events rather than verbs
+    if let Some(events) = events::of(provider) {
+        events.post(AsyncEvent::DeviceFatal);
+    }
+}
+
+/// Forget `provider` once it is freed, its address may be reused by a new device.
+pub(crate) fn forget<P>(provider: &P) {
+    if ANY_FAILED.load(Ordering::Acquire) {
+        FAILED
+            .write()
+            .unwrap_or_else(|err| err.into_inner())
+            .retain(|&failed| failed != key(provider));
+    }
+}
+
+fn message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("<non-string payload>")
+}
+
+/// Run `f`, `None` if it panicked.
+fn unwind<T>(verb: &str, f: impl FnOnce() -> Result<T>) -> Option<Result<T>> {
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .map_err(|payload| log::error!("{verb}: backend panicked: {}", message(&*payload)))
+        .ok()
+}
+
+/// Run `f` outside of any device, turning a panic into an error.
+pub(crate) fn catch<T>(verb: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    unwind(verb, f).unwrap_or(Err(DEVICE_FAILED))
+}
+
+/// Run verb `f` on `provider`, failing the device if it panics.
+pub(crate) fn call<P, T>(provider: &P, verb: &str, f: impl FnOnce(&P) -> Result<T>) -> Result<T> {
+    if is_failed(provider) {
+        return Err(DEVICE_FAILED);
+    }
+
+    unwind(verb, || f(provider)).unwrap_or_else(|| {
+        log::error!("{verb}: marking the device failed, later verbs fail with EIO");
+        mark_failed(provider);
+        Err(DEVICE_FAILED)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panics_fail_the_device() {
+        let (healthy, broken) = (Box::new(0u8), Box::new(0u8));
+
+        assert_eq!(
+            call(&*broken, "alloc_pd", |_| -> Result { Err(VerbsError::NotSupported) }),
+            Err(VerbsError::NotSupported)
+        );
+        assert_eq!(call(&*broken, "alloc_pd", |_| Ok(1)), Ok(1));
+
+        assert_eq!(
+            call(&*broken, "post_send", |_| -> Result { panic!("bug") }),
+            Err(DEVICE_FAILED)
+        );
+        assert_eq!(call(&*broken, "post_send", |_| Ok(1)), Err(DEVICE_FAILED));
+        assert_eq!(call(&*healthy, "post_send", |_| Ok(1)), Ok(1));
+
+        // errors returned by the backend leave the device alone
+        assert_eq!(
+            call(&*healthy, "post_send", |_| -> Result { Err(DEVICE_FAILED) }),
+            Err(DEVICE_FAILED)
+        );
+        assert_eq!(call(&*healthy, "post_send", |_| Ok(1)), Ok(1));
+
+        forget(&*broken);
+        assert_eq!(call(&*broken, "post_send", |_| Ok(1)), Ok(1));
+    }
+}
@@ -1,8 +1,27 @@
+mod capabilities;
+mod channel;
+mod completion;
+mod context;
+mod error;
+mod events;
+mod guard;
 mod macros;
+mod object;
+mod profile;
 mod provider;
-mod raw;
+mod qp_state;
+#[doc(hidden)]
+pub mod raw;
+mod work_request;
 
-pub use provider::Provider;
-
-pub type VerbsError = ::std::os::raw::c_int;
-pub type Result<T = ()> = core::result::Result<T, VerbsError>;
+pub use capabilities::{Capabilities, Verbs};
+pub use channel::CqNotifier;
+pub use completion::Completion;
+pub use error::{Result, VerbsError};
+pub use events::{AsyncEvent, AsyncEvents, CqEvent, PortEvent, QpEvent, SrqEvent};
+pub use profile::Profile;
+pub use provider::{
+    CompletionQueue, DeviceAttrEx, MemoryRegion, MemoryWindow, MrRereg, MwBind, Provider, QpInitAttr, QueuePair,
+    SharedReceiveQueue, UdDest,
+};
+pub use work_request::{Payload, Remote, SendOp, SendWr};
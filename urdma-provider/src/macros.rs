@@ -1 +1,98 @@
+/// Export the C entry points of a [`Provider`](crate::Provider) implementation.
+///
+/// The urdma provider in rdma-core calls `urdma_init` once, `urdma_new_device` for every urdma device and stores the
+/// returned pointer as `driver_data`, then installs the table filled by `urdma_device_context_ops` for the device on
+/// every context. `urdma_init_context` and `urdma_uninit_context` are called when a context is opened and closed.
+/// `urdma_abi_version` returns [`ABI_VERSION`](crate::raw::ABI_VERSION), the provider refuses a library it does not
+/// know the entry points of. A provider of version 1 calls `urdma_context_ops` instead, which is not told the device.
+///
+/// ```ignore
+/// provider::export_provider!(crate::rxe::Rxe);
+/// ```
+#[macro_export]
+macro_rules! export_provider {
+    ($provider:ty) => {
+        /// version of the entry points below
+        #[unsafe(no_mangle)]
+        pub extern "C" fn urdma_abi_version() -> ::core::ffi::c_uint {
+            $crate::raw::ABI_VERSION
+        }
 
+        /// init driver
+        #[unsafe(no_mangle)]
+        pub extern "C" fn urdma_init() -> ::core::ffi::c_int {
+            $crate::raw::init::<$provider>()
+        }
+
+        /// new device
+        ///
+        /// # Safety
+        ///
+        /// `sysfs_name` must be a valid C string.
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn urdma_new_device(
+            sysfs_name: *const ::core::ffi::c_char,
+        ) -> *const ::core::ffi::c_void {
+            unsafe { $crate::raw::new_device::<$provider>(sysfs_name) }
+        }
+
+        /// free device
+        ///
+        /// # Safety
+        ///
+        /// `driver_data` must come from `urdma_new_device` and not be used afterwards.
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn urdma_free_device(driver_data: *const ::core::ffi::c_void) {
+            unsafe { $crate::raw::free_device::<$provider>(driver_data) }
+        }
+
+        /// fill context ops, ABI version 1
+        ///
+        /// # Safety
+        ///
+        /// `ops` must be valid for writes.
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn urdma_context_ops(ops: *mut $crate::raw::ffi::verbs_context_ops) {
+            unsafe { ops.write($crate::raw::context_ops::<$provider>()) }
+        }
+
+        /// fill context ops of a device
+        ///
+        /// # Safety
+        ///
+        /// `driver_data` must come from `urdma_new_device`, `ops` must be valid for writes.
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn urdma_device_context_ops(
+            driver_data: *const ::core::ffi::c_void,
+            ops: *mut $crate::raw::ffi::verbs_context_ops,
+        ) {
+            unsafe { ops.write($crate::raw::device_context_ops::<$provider>(driver_data)) }
+        }
+
+        /// init context
+        ///
+        /// # Safety
+        ///
+        /// `driver_data` must come from `urdma_new_device`, `context` must be a context being opened on its device.
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn urdma_init_context(
+            driver_data: *const ::core::ffi::c_void,
+            context: *mut $crate::raw::ffi::ibv_context,
+        ) -> ::core::ffi::c_int {
+            unsafe { $crate::raw::init_context::<$provider>(driver_data, context) }
+        }
+
+        /// uninit context
+        ///
+        /// # Safety
+        ///
+        /// `driver_data` must come from `urdma_new_device`, `context` must be a context being closed on its device.
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn urdma_uninit_context(
+            driver_data: *const ::core::ffi::c_void,
+            context: *mut $crate::raw::ffi::ibv_context,
+        ) {
+            unsafe { $crate::raw::uninit_context::<$provider>(driver_data, context) }
+        }
+    };
+}
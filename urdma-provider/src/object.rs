@@ -0,0 +1,89 @@
+//! C-facing verbs objects
+//!
+//! Each object starts with the `ibv_*` struct handed to libibverbs, followed by the backend object, so the urdma handle
+//! coming back from C maps to its backend object by a fixed offset, the same way a device maps to its provider.
+
+use crate::Provider;
+use crate::completion::CqHead;
+use crate::work_request::QpHead;
+
+macro_rules! verbs_object {
+    ($(#[$meta:meta])* $name:ident, $ibv:ty, $inner:ident) => {
+        $(#[$meta])*
+        #[repr(C)]
+        pub(crate) struct $name<P: Provider> {
+            ibv: $ibv,
+            inner: P::$inner,
+        }
+
+        impl<P: Provider> $name<P> {
+            /// Move into a heap allocation owned by the C side.
+            pub(crate) fn into_raw(ibv: $ibv, inner: P::$inner) -> *mut $ibv {
+                Box::into_raw(Box::new(Self { ibv, inner })).cast()
+            }
+
+            /// Get backend object.
+            ///
+            /// Safety: `ptr` must come from [`Self::into_raw`] and not be freed yet.
+            pub(crate) unsafe fn inner<'a>(ptr: *mut $ibv) -> &'a P::$inner {
+                unsafe { &(*ptr.cast::<Self>()).inner }
+            }
+
+            /// Free object.
+            ///
+            /// Safety: `ptr` must come from [`Self::into_raw`] and not be used afterwards.
+            pub(crate) unsafe fn free(ptr: *mut $ibv) {
+                drop(unsafe { Box::from_raw(ptr.cast::<Self>()) });
+            }
+        }
+    };
+}
+
+verbs_object!(
+    /// protection domain
+    Pd,
+    ffi::ibv_pd,
+    Pd
+);
+
+verbs_object!(
+    /// completion queue, legacy verbs pass the `ibv_cq` at the start of its head
+    Cq,
+    CqHead,
+    Cq
+);
+
+verbs_object!(
+    /// queue pair, legacy verbs pass the `ibv_qp` at the start of its head
+    Qp,
+    QpHead,
+    Qp
+);
+
+verbs_object!(
+    /// memory region
+    Mr,
+    ffi::verbs_mr,
+    Mr
+);
+
+verbs_object!(
+    /// shared receive queue
+    Srq,
+    ffi::ibv_srq,
+    Srq
+);
+
+verbs_object!(
+    /// memory window
+    Mw,
+    ffi::ibv_mw,
+    Mw
+);
+
+verbs_object!(
+    /// address handle
+    Ah,
+    ffi::ibv_ah,
+    Ah
+);
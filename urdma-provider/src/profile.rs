@@ -0,0 +1,288 @@
+//! Attribute overrides
+//!
+//! A [`Profile`] replaces selected device and port attributes the backend reports, so applications can be tested
+//! against the limits of other hardware. The glue also enforces the limits it sets when objects are created, on top of
+//! whatever the backend enforces itself.
+
+use core::ffi::c_int;
+use std::sync::{Mutex, RwLock};
+
+use crate::{Result, SendOp, SendWr, VerbsError};
+
+static PROFILE: RwLock<Profile> = RwLock::new(Profile::NONE);
+
+/// Live QPs of each device, by provider address
+static QPS: Mutex<Vec<(usize, u32)>> = Mutex::new(Vec::new());
+
+/// Device and port attributes overriding the ones of the backend, `None` keeps the backend value
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// live QPs of each device
+    pub max_qp: Option<u32>,
+    /// scatter/gather entries per work request, of QPs and SRQs
+    pub max_sge: Option<u32>,
+    pub max_mr_size: Option<u64>,
+    /// `IBV_ATOMIC_NONE` fails atomic work requests
+    pub atomic_cap: Option<ffi::ibv_atomic_cap>,
+    /// the largest path MTU QPs accept
+    pub active_mtu: Option<ffi::ibv_mtu>,
+    /// `IBV_SPEED_*` value of `ibv_port_attr`
+    pub active_speed: Option<u8>,
+    /// `IBV_WIDTH_*` value of `ibv_port_attr`
+    pub active_width: Option<u8>,
+    /// `IBV_LINK_LAYER_*`
+    pub link_layer: Option<u8>,
+}
+
+impl Profile {
+    /// Profile overriding nothing.
+    pub const NONE: Self = Self {
+        max_qp: None,
+        max_sge: None,
+        max_mr_size: None,
+        atomic_cap: None,
+        active_mtu: None,
+        active_speed: None,
+        active_width: None,
+        link_layer: None,
+    };
+
+    /// Apply `self` to all devices of the process, usually from [`Provider::init`](crate::Provider::init).
+    pub fn install(self) {
+        if self != Self::NONE {
+            log::info!("Overriding attributes with {self:?}");
+        }
+        *PROFILE.write().unwrap_or_else(|err| err.into_inner()) = self;
+    }
+
+    /// The installed profile.
+    pub(crate) fn current() -> Self {
+        *PROFILE.read().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub(crate) fn apply_device(&self, attr: &mut ffi::ibv_device_attr) {
+        let int = |value: u32| c_int::try_from(value).unwrap_or(c_int::MAX);
+
+        if let Some(max_qp) = self.max_qp {
+            attr.max_qp = int(max_qp);
+        }
+        if let Some(max_sge) = self.max_sge {
+            attr.max_sge = int(max_sge);
+            attr.max_sge_rd = attr.max_sge_rd.min(int(max_sge));
+            attr.max_srq_sge = attr.max_srq_sge.min(int(max_sge));
+        }
+        if let Some(max_mr_size) = self.max_mr_size {
+            attr.max_mr_size = max_mr_size;
+        }
+        if let Some(atomic_cap) = self.atomic_cap {
+            attr.atomic_cap = atomic_cap;
+        }
+    }
+
+    pub(crate) fn apply_port(&self, attr: &mut ffi::ibv_port_attr) {
+        if let Some(active_mtu) = self.active_mtu {
+            // the profile may lower the MTU, not raise it beyond what the port supports
+            attr.active_mtu = active_mtu.min(attr.max_mtu);
+        }
+        if let Some(active_speed) = self.active_speed {
+            attr.active_speed = active_speed;
+        }
+        if let Some(active_width) = self.active_width {
+            attr.active_width = active_width;
+        }
+        if let Some(link_layer) = self.link_layer {
+            attr.link_layer = link_layer;
+        }
+    }
+
+    pub(crate) fn check_qp_cap(&self, cap: &ffi::ibv_qp_cap) -> Result {
+        self.check_sge(cap.max_send_sge.max(cap.max_recv_sge))
+    }
+
+    pub(crate) fn check_sge(&self, max_sge: u32) -> Result {
+        if self.max_sge.is_some_and(|limit| max_sge > limit) {
+            log::debug!("{max_sge} SGEs exceed the profile limit");
+            return Err(VerbsError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_mr(&self, length: usize) -> Result {
+        if self.max_mr_size.is_some_and(|limit| length as u64 > limit) {
+            log::debug!("{length} bytes exceed the profile MR size");
+            return Err(VerbsError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_send(&self, wr: &SendWr) -> Result {
+        let atomic = matches!(wr.op, SendOp::AtomicCmpAndSwp { .. } | SendOp::AtomicFetchAndAdd { .. });
+        if atomic && self.atomic_cap == Some(ffi::IBV_ATOMIC_NONE) {
+            return Err(VerbsError::NotSupported);
+        }
+        Ok(())
+    }
+
+    /// Check the path MTU against the active MTU the port reports, the one of the profile clamped to `max_mtu` of the
+    /// port, which is only queried when the profile sets one.
+    pub(crate) fn check_modify_qp(
+        &self,
+        attr: &ffi::ibv_qp_attr,
+        attr_mask: ffi::ibv_qp_attr_mask,
+        max_mtu: impl FnOnce() -> Result<ffi::ibv_mtu>,
+    ) -> Result {
+        let path_mtu = (attr_mask & ffi::ibv_qp_attr_mask::IBV_QP_PATH_MTU).0 != 0;
+        let Some(active_mtu) = self.active_mtu.filter(|_| path_mtu) else {
+            return Ok(());
+        };
+        if attr.path_mtu > active_mtu.min(max_mtu()?) {
+            log::debug!("path MTU exceeds the active MTU of the profile");
+            return Err(VerbsError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    /// Count a QP being created on `provider`, failing once the profile limit is reached.
+    pub(crate) fn reserve_qp<P>(&self, provider: &P) -> Result {
+        let key = core::ptr::from_ref(provider) as usize;
+        let mut qps = QPS.lock().unwrap_or_else(|err| err.into_inner());
+
+        let index = match qps.iter().position(|(device, _)| *device == key) {
+            Some(index) => index,
+            None => {
+                qps.push((key, 0));
+                qps.len() - 1
+            }
+        };
+        let live = &mut qps[index].1;
+        if self.max_qp.is_some_and(|max_qp| *live >= max_qp) {
+            return Err(VerbsError::OutOfResources);
+        }
+        *live += 1;
+        Ok(())
+    }
+
+    /// Release a QP counted by [`reserve_qp`](Self::reserve_qp).
+    pub(crate) fn release_qp<P>(provider: &P) {
+        let key = core::ptr::from_ref(provider) as usize;
+        let mut qps = QPS.lock().unwrap_or_else(|err| err.into_inner());
+
+        if let Some((_, live)) = qps.iter_mut().find(|(device, _)| *device == key) {
+            *live = live.saturating_sub(1);
+        }
+    }
+
+    /// Forget the QPs of a device being freed, its address may be reused by a new device.
+    pub(crate) fn forget<P>(provider: &P) {
+        let key = core::ptr::from_ref(provider) as usize;
+        QPS.lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .retain(|(device, _)| *device != key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Payload, Remote};
+
+    #[test]
+    fn overrides_and_limits() {
+        let profile = Profile {
+            max_qp: Some(1),
+            max_sge: Some(4),
+            atomic_cap: Some(ffi::IBV_ATOMIC_NONE),
+            active_mtu: Some(ffi::IBV_MTU_1024),
+            ..Profile::NONE
+        };
+
+        let mut attr = ffi::ibv_device_attr {
+            max_qp: 1 << 16,
+            max_sge: 32,
+            max_sge_rd: 32,
+            atomic_cap: ffi::IBV_ATOMIC_HCA,
+            ..Default::default()
+        };
+        profile.apply_device(&mut attr);
+        assert_eq!((attr.max_qp, attr.max_sge, attr.max_sge_rd), (1, 4, 4));
+        assert_eq!(attr.atomic_cap, ffi::IBV_ATOMIC_NONE);
+
+        let cap = ffi::ibv_qp_cap {
+            max_send_sge: 5,
+            ..Default::default()
+        };
+        assert_eq!(profile.check_qp_cap(&cap), Err(VerbsError::InvalidArgument));
+
+        let atomic = SendWr {
+            wr_id: 0,
+            op: SendOp::AtomicFetchAndAdd {
+                remote: Remote { addr: 0, rkey: 0 },
+                add: 1,
+            },
+            flags: ffi::ibv_send_flags(0),
+            payload: Payload::Sges(Vec::new()),
+        };
+        assert_eq!(profile.check_send(&atomic), Err(VerbsError::NotSupported));
+
+        let attr = ffi::ibv_qp_attr {
+            path_mtu: ffi::IBV_MTU_4096,
+            ..Default::default()
+        };
+        assert!(
+            profile
+                .check_modify_qp(&attr, ffi::ibv_qp_attr_mask::IBV_QP_STATE, || unreachable!())
+                .is_ok()
+        );
+        assert_eq!(
+            profile.check_modify_qp(&attr, ffi::ibv_qp_attr_mask::IBV_QP_PATH_MTU, || Ok(ffi::IBV_MTU_4096)),
+            Err(VerbsError::InvalidArgument)
+        );
+        // the port cannot reach the MTU of the profile, so neither can the path
+        let attr = ffi::ibv_qp_attr {
+            path_mtu: ffi::IBV_MTU_1024,
+            ..Default::default()
+        };
+        assert!(
+            profile
+                .check_modify_qp(&attr, ffi::ibv_qp_attr_mask::IBV_QP_PATH_MTU, || Ok(ffi::IBV_MTU_1024))
+                .is_ok()
+        );
+        assert_eq!(
+            profile.check_modify_qp(&attr, ffi::ibv_qp_attr_mask::IBV_QP_PATH_MTU, || Ok(ffi::IBV_MTU_512)),
+            Err(VerbsError::InvalidArgument)
+        );
+
+        let mut attr = ffi::ibv_port_attr {
+            max_mtu: ffi::IBV_MTU_4096,
+            active_mtu: ffi::IBV_MTU_4096,
+            ..Default::default()
+        };
+        profile.apply_port(&mut attr);
+        assert_eq!((attr.max_mtu, attr.active_mtu), (ffi::IBV_MTU_4096, ffi::IBV_MTU_1024));
+        attr.max_mtu = ffi::IBV_MTU_512;
+        profile.apply_port(&mut attr);
+        assert_eq!((attr.max_mtu, attr.active_mtu), (ffi::IBV_MTU_512, ffi::IBV_MTU_512));
+    }
+
+    #[test]
+    fn qps_are_counted_per_device() {
+        let profile = Profile {
+            max_qp: Some(1),
+            ..Profile::NONE
+        };
+        // any two distinct addresses stand in for providers
+        let (a, b) = (0_u8, 0_u8);
+
+        profile.reserve_qp(&a).unwrap();
+        assert_eq!(profile.reserve_qp(&a), Err(VerbsError::OutOfResources));
+        profile.reserve_qp(&b).unwrap();
+        Profile::release_qp(&a);
+        profile.reserve_qp(&a).unwrap();
+
+        // a new device at the address of a freed one starts from zero
+        Profile::forget(&a);
+        profile.reserve_qp(&a).unwrap();
+        Profile::forget(&a);
+        Profile::forget(&b);
+    }
+}
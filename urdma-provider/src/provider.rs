@@ -1,131 +1,483 @@
 use std::sync::Arc;
 
-use super::Result;
+use super::{AsyncEvents, Capabilities, Completion, CqNotifier, Result, SendWr, VerbsError, completion};
 
 /// verbs provider
 ///
 /// Sized because we do not want store a fat pointer
 /// 'static because it will be stored in C side
-pub trait Provider: Sized + 'static {
+/// Send + Sync because applications may call verbs from any thread
+///
+/// Verbs objects are provider-defined associated types, the glue in [`crate::raw`] owns the `ibv_*` structs handed to
+/// libibverbs and passes the provider a reference to its own object. The same object may be used by several threads at
+/// once, e.g. two threads posting to one QP.
+///
+/// Only verbs listed in [`Provider::capabilities`] are installed on a context, the others never reach the provider.
+/// Verbs left at their default fail with `EOPNOTSUPP`. A panic never reaches the application, but fails the device.
+pub trait Provider: Sized + Send + Sync + 'static {
+    /// protection domain
+    type Pd: Send + Sync + 'static;
+
+    /// completion queue
+    type Cq: CompletionQueue + Send + Sync + 'static;
+
+    /// queue pair
+    type Qp: QueuePair + Send + Sync + 'static;
+
+    /// memory region
+    type Mr: MemoryRegion + Send + Sync + 'static;
+
+    /// shared receive queue
+    type Srq: SharedReceiveQueue + Send + Sync + 'static;
+
+    /// memory window
+    type Mw: MemoryWindow + Send + Sync + 'static;
+
+    /// address handle
+    type Ah: Send + Sync + 'static;
+
     /// init context
     ///
     /// guarantee to be called only once
-    fn init(/* TODO(fh): args? */) -> Result;
+    fn init() -> Result;
 
-    /// Get from ibv_device
+    /// Get from ibv_device, failing if `ibdev` holds no provider
+    ///
+    /// # Safety
     ///
-    /// Safety: Caller must ensure `ibdev` is point to a valid container of provider.
-    unsafe fn from_ibv_device(ibdev: *mut ffi::ibv_device) -> *const Self;
+    /// Caller must ensure `ibdev` is point to a valid container of provider.
+    unsafe fn from_ibv_device(ibdev: *mut ffi::ibv_device) -> Result<*const Self>;
 
     /// new driver
-    fn new(_sysfs_name: &str) -> Result<Arc<Self>>;
+    ///
+    /// `events` delivers the asynchronous events of the device to every context opened on it.
+    fn new(_sysfs_name: &str, _events: AsyncEvents) -> Result<Arc<Self>>;
+
+    /// verbs and QP types the device supports
+    ///
+    /// Queried whenever the device is opened, see [`Capabilities`].
+    fn capabilities(&self) -> Capabilities;
 
     /// alloc pd
-    fn alloc_pd(&self) -> *mut ffi::ibv_pd {
-        unimplemented!()
+    fn alloc_pd(&self) -> Result<Self::Pd> {
+        Err(VerbsError::NotSupported)
     }
 
     /// dealloc pd
-    fn dealloc_pd(&self, _pd: *mut ffi::ibv_pd) -> Result {
-        unimplemented!()
+    ///
+    /// The glue frees `pd` only if this returns `Ok`.
+    fn dealloc_pd(&self, _pd: &Self::Pd) -> Result {
+        Err(VerbsError::NotSupported)
     }
 
-    // TODO(fh): wrap pointers
     /// query device
-    fn query_device(
-        &self,
-        _input: *const ffi::ibv_query_device_ex_input,
-        _device_attr: *mut ffi::ibv_device_attr,
-        _attr_size: usize,
-    ) -> Result {
-        unimplemented!()
+    fn query_device(&self, _device_attr: &mut ffi::ibv_device_attr) -> Result {
+        Err(VerbsError::NotSupported)
     }
 
-    // TODO(fh): wrap pointers
-    fn query_port(&self, _port_num: u8, _port_attr: *mut ffi::ibv_port_attr) -> Result {
-        unimplemented!()
+    /// query device ex
+    ///
+    /// `attr` starts zeroed, the default only fills the legacy attributes through `query_device`.
+    fn query_device_ex(&self, attr: &mut DeviceAttrEx) -> Result {
+        self.query_device(&mut attr.orig_attr)
+    }
+
+    /// query port
+    fn query_port(&self, _port_num: u8, _port_attr: &mut ffi::ibv_port_attr) -> Result {
+        Err(VerbsError::NotSupported)
+    }
+
+    /// query gid table
+    ///
+    /// GIDs of port `port_num`, by `gid_index`. The glue checks the source GID index of address vectors against it,
+    /// the default reports no table, which leaves the check to the backend. Each context keeps the table it got, and
+    /// queries it again when an address vector names an index the table lacks.
+    fn query_gid_table(&self, _port_num: u8) -> Result<Vec<ffi::ibv_gid_entry>> {
+        Ok(Vec::new())
     }
 
-    // TODO(fh): wrap pointers
+    /// create cq
+    ///
+    /// `notifier` is set if the CQ is created on a completion channel, the glue only passes one if the backend
+    /// supports `req_notify_cq`.
     fn create_cq(
         &self,
         _cqe: core::ffi::c_int,
-        _channel: *mut ffi::ibv_comp_channel,
+        _notifier: Option<CqNotifier>,
         _comp_vector: core::ffi::c_int,
-    ) -> *mut ffi::ibv_cq {
-        unimplemented!()
+    ) -> Result<Self::Cq> {
+        Err(VerbsError::NotSupported)
     }
 
-    // TODO(fh): API design
-    fn destroy_cq(&self, _cq: *mut ffi::ibv_cq) -> Result {
-        unimplemented!()
+    /// create cq ex
+    ///
+    /// `wc_flags` are the `IBV_WC_EX_WITH_*` fields the application reads, the glue only passes fields
+    /// [`Completion`] carries. The default creates a plain CQ, which cannot report timestamps.
+    fn create_cq_ex(
+        &self,
+        cqe: core::ffi::c_int,
+        notifier: Option<CqNotifier>,
+        comp_vector: core::ffi::c_int,
+        wc_flags: u64,
+    ) -> Result<Self::Cq> {
+        if wc_flags & completion::WC_FLAGS_TIMESTAMP != 0 {
+            return Err(VerbsError::NotSupported);
+        }
+        self.create_cq(cqe, notifier, comp_vector)
     }
 
-    // TODO(fh): API design
-    fn create_qp(&self, _pd: *mut ffi::ibv_pd, _init_attr: *mut ffi::ibv_qp_init_attr) -> *mut ffi::ibv_qp {
-        unimplemented!()
+    /// destroy cq
+    ///
+    /// The glue frees `cq` only if this returns `Ok`.
+    fn destroy_cq(&self, _cq: &Self::Cq) -> Result {
+        Err(VerbsError::NotSupported)
     }
 
-    // TODO(fh): API design
-    fn destroy_qp(&self, _qp: *mut ffi::ibv_qp) -> Result {
-        unimplemented!()
+    /// create qp
+    ///
+    /// `init_attr.cap` is in/out, the backend writes back the capabilities it actually allocated.
+    fn create_qp(&self, _pd: &Self::Pd, _init_attr: &mut QpInitAttr<'_, Self>) -> Result<Self::Qp> {
+        Err(VerbsError::NotSupported)
     }
 
-    // TODO(fh): API design
-    fn modify_qp(&self, _qp: *mut ffi::ibv_qp, _attr: *mut ffi::ibv_qp_attr, _attr_mask: core::ffi::c_int) -> Result {
-        unimplemented!()
+    /// create qp ex
+    ///
+    /// `send_ops` are the `IBV_QP_EX_WITH_*` operations the application builds with the `ibv_wr_*` API, the glue only
+    /// passes operations [`SendOp`](crate::SendOp) carries. Requests reach [`Provider::post_send`] whichever API built
+    /// them, so the default creates a plain QP.
+    fn create_qp_ex(&self, pd: &Self::Pd, init_attr: &mut QpInitAttr<'_, Self>, _send_ops: u64) -> Result<Self::Qp> {
+        self.create_qp(pd, init_attr)
+    }
+
+    /// destroy qp
+    ///
+    /// The glue frees `qp` only if this returns `Ok`.
+    fn destroy_qp(&self, _qp: &Self::Qp) -> Result {
+        Err(VerbsError::NotSupported)
     }
 
-    // TODO(fh): API design
+    /// modify qp
+    fn modify_qp(&self, _qp: &Self::Qp, _attr: &mut ffi::ibv_qp_attr, _attr_mask: ffi::ibv_qp_attr_mask) -> Result {
+        Err(VerbsError::NotSupported)
+    }
+
+    /// query qp
+    ///
+    /// Object pointers in `init_attr` are filled in by the glue, backends only need to fill `cap`, `qp_type` and
+    /// `sq_sig_all`.
     fn query_qp(
         &self,
-        _qp: *mut ffi::ibv_qp,
-        _attr: *mut ffi::ibv_qp_attr,
-        _attr_mask: core::ffi::c_int,
-        _init_attr: *mut ffi::ibv_qp_init_attr,
+        _qp: &Self::Qp,
+        _attr: &mut ffi::ibv_qp_attr,
+        _attr_mask: ffi::ibv_qp_attr_mask,
+        _init_attr: &mut ffi::ibv_qp_init_attr,
     ) -> Result {
-        unimplemented!()
+        Err(VerbsError::NotSupported)
     }
 
-    // TODO(fh): API design
+    /// reg mr
+    ///
+    /// Peers and SGEs address the region from `hca_va` on. `access` may ask for `IBV_ACCESS_ON_DEMAND`, backends that
+    /// cannot leave the memory unpinned until it is accessed fail with `NotSupported`.
     fn reg_mr(
         &self,
-        _pd: *mut ffi::ibv_pd,
+        _pd: &Self::Pd,
         _addr: *mut ::std::os::raw::c_void,
         _length: usize,
         _hca_va: u64,
-        _access: core::ffi::c_int,
-    ) -> *mut ffi::ibv_mr {
-        unimplemented!()
+        _access: ffi::ibv_access_flags,
+    ) -> Result<Self::Mr> {
+        Err(VerbsError::NotSupported)
+    }
+
+    /// reg dmabuf mr
+    ///
+    /// Registers `length` bytes at `offset` of the dma-buf `fd`, addressed from `iova` on. The glue does not keep
+    /// `fd`, backends needing it after returning must duplicate it.
+    fn reg_dmabuf_mr(
+        &self,
+        _pd: &Self::Pd,
+        _offset: u64,
+        _length: usize,
+        _iova: u64,
+        _fd: ::std::os::raw::c_int,
+        _access: ffi::ibv_access_flags,
+    ) -> Result<Self::Mr> {
+        Err(VerbsError::NotSupported)
+    }
+
+    /// dereg mr
+    ///
+    /// The glue frees `mr` only if this returns `Ok`.
+    fn dereg_mr(&self, _mr: &Self::Mr) -> Result {
+        Err(VerbsError::NotSupported)
+    }
+
+    /// rereg mr
+    ///
+    /// The keys may change, the glue reads them back from `mr`. On `Err` the region must be left as it was.
+    fn rereg_mr(&self, _mr: &Self::Mr, _rereg: &MrRereg<'_, Self>) -> Result {
+        Err(VerbsError::NotSupported)
+    }
+
+    /// alloc mw
+    fn alloc_mw(&self, _pd: &Self::Pd, _mw_type: ffi::ibv_mw_type) -> Result<Self::Mw> {
+        Err(VerbsError::NotSupported)
+    }
+
+    /// dealloc mw
+    ///
+    /// The glue frees `mw` only if this returns `Ok`.
+    fn dealloc_mw(&self, _mw: &Self::Mw) -> Result {
+        Err(VerbsError::NotSupported)
+    }
+
+    /// bind mw
+    ///
+    /// Post a bind of `mw` to the send queue of `qp`, in posting order with [`Provider::post_send`]. Type 1 windows
+    /// are bound by `ibv_bind_mw`, type 2 windows by `IBV_WR_BIND_MW` requests. Returns the rkey of the window once
+    /// the bind completes, the glue hands it to the application for type 1 windows.
+    fn bind_mw(&self, _qp: &Self::Qp, _mw: &Self::Mw, _bind: &MwBind<'_, Self>) -> Result<u32> {
+        Err(VerbsError::NotSupported)
+    }
+
+    /// create ah
+    fn create_ah(&self, _pd: &Self::Pd, _attr: &ffi::ibv_ah_attr) -> Result<Self::Ah> {
+        Err(VerbsError::NotSupported)
+    }
+
+    /// destroy ah
+    ///
+    /// The glue frees `ah` only if this returns `Ok`.
+    fn destroy_ah(&self, _ah: &Self::Ah) -> Result {
+        Err(VerbsError::NotSupported)
+    }
+
+    /// post send
+    ///
+    /// Called once per request, in posting order, for `ibv_post_send` and `ibv_wr_complete` alike. `Err` rejects `wr`
+    /// without a completion and the glue posts none of the requests after it. Requests to UD QPs carry their
+    /// destination in `ud`, it is `None` for every other QP type.
+    fn post_send(&self, _qp: &Self::Qp, _wr: &SendWr, _ud: Option<&UdDest<'_, Self>>) -> Result {
+        Err(VerbsError::NotSupported)
+    }
+
+    /// post recv
+    ///
+    /// `wr` is the list the application posted, in order. On `Err` the backend sets `bad_wr` to the first request it
+    /// did not post.
+    fn post_recv(&self, _qp: &Self::Qp, _wr: *mut ffi::ibv_recv_wr, _bad_wr: &mut *mut ffi::ibv_recv_wr) -> Result {
+        Err(VerbsError::NotSupported)
+    }
+
+    /// poll cq
+    ///
+    /// Returns the number of completions written to the front of `wc`.
+    fn poll_cq(&self, _cq: &Self::Cq, _wc: &mut [ffi::ibv_wc]) -> Result<usize> {
+        Err(VerbsError::NotSupported)
+    }
+
+    /// poll cq ex
+    ///
+    /// Like [`Provider::poll_cq`], for the extended polling API. The default polls through `poll_cq`, without
+    /// timestamps.
+    fn poll_cq_ex(&self, cq: &Self::Cq, completions: &mut [Completion]) -> Result<usize> {
+        let mut wc = [ffi::ibv_wc::default(); 16];
+        let len = completions.len().min(wc.len());
+        let polled = self.poll_cq(cq, &mut wc[..len])?.min(len);
+
+        for (completion, wc) in completions.iter_mut().zip(&wc[..polled]) {
+            *completion = Completion::new(*wc);
+        }
+        Ok(polled)
+    }
+
+    /// req notify cq
+    ///
+    /// Arm the [`CqNotifier`] of `cq` for its next completion, or next solicited or error completion if
+    /// `solicited_only`.
+    fn req_notify_cq(&self, _cq: &Self::Cq, _solicited_only: bool) -> Result {
+        Err(VerbsError::NotSupported)
     }
 
-    // TODO(fh): API design
-    fn dereg_mr(&self, _mr: *mut ffi::ibv_mr) -> Result {
-        unimplemented!()
+    /// create srq
+    ///
+    /// `attr` is in/out, the backend writes back the `max_wr` and `max_sge` it actually allocated.
+    fn create_srq(&self, _pd: &Self::Pd, _attr: &mut ffi::ibv_srq_attr) -> Result<Self::Srq> {
+        Err(VerbsError::NotSupported)
     }
 
-    // TODO(fh): API design
-    fn post_send(
+    /// modify srq
+    fn modify_srq(
         &self,
-        _qp: *mut ffi::ibv_qp,
-        _wr: *mut ffi::ibv_send_wr,
-        _bad_wr: *mut *mut ffi::ibv_send_wr,
+        _srq: &Self::Srq,
+        _attr: &mut ffi::ibv_srq_attr,
+        _attr_mask: ffi::ibv_srq_attr_mask,
     ) -> Result {
-        unimplemented!()
+        Err(VerbsError::NotSupported)
     }
 
-    // TODO(fh): API design
-    fn post_recv(
+    /// query srq
+    fn query_srq(&self, _srq: &Self::Srq, _attr: &mut ffi::ibv_srq_attr) -> Result {
+        Err(VerbsError::NotSupported)
+    }
+
+    /// destroy srq
+    ///
+    /// The glue frees `srq` only if this returns `Ok`, backends refuse while a QP still uses it.
+    fn destroy_srq(&self, _srq: &Self::Srq) -> Result {
+        Err(VerbsError::NotSupported)
+    }
+
+    /// post srq recv
+    ///
+    /// Like [`Provider::post_recv`], for the shared receive queue `srq`.
+    fn post_srq_recv(
         &self,
-        _qp: *mut ffi::ibv_qp,
+        _srq: &Self::Srq,
         _wr: *mut ffi::ibv_recv_wr,
-        _bad_wr: *mut *mut ffi::ibv_recv_wr,
+        _bad_wr: &mut *mut ffi::ibv_recv_wr,
     ) -> Result {
-        unimplemented!()
+        Err(VerbsError::NotSupported)
     }
+}
+
+/// Backend queue pair handle
+pub trait QueuePair {
+    /// QP number reported to the application, peers use it to address this QP.
+    fn qp_num(&self) -> u32;
+}
 
-    // TODO(fh): API design
-    fn poll_cq(&self, _cq: *mut ffi::ibv_cq, _num_entries: ::std::os::raw::c_int, _wc: *mut ffi::ibv_wc) -> Result {
-        unimplemented!()
+/// Backend completion queue handle
+pub trait CompletionQueue {
+    /// CQ number, identifies the CQ in [`AsyncEvent::Cq`](crate::AsyncEvent::Cq).
+    fn cq_num(&self) -> u32;
+}
+
+/// Backend shared receive queue handle
+pub trait SharedReceiveQueue {
+    /// SRQ number, identifies the SRQ in [`AsyncEvent::Srq`](crate::AsyncEvent::Srq).
+    fn srq_num(&self) -> u32;
+}
+
+/// Backends without shared receive queues use `Infallible` as [`Provider::Srq`].
+impl SharedReceiveQueue for core::convert::Infallible {
+    fn srq_num(&self) -> u32 {
+        match *self {}
     }
 }
+
+/// Backend memory window handle
+pub trait MemoryWindow {
+    /// remote key the window has when allocated
+    fn rkey(&self) -> u32;
+}
+
+/// Backends without memory windows use `Infallible` as [`Provider::Mw`].
+impl MemoryWindow for core::convert::Infallible {
+    fn rkey(&self) -> u32 {
+        match *self {}
+    }
+}
+
+/// Backend memory region handle
+pub trait MemoryRegion {
+    /// local key
+    fn lkey(&self) -> u32;
+
+    /// remote key
+    fn rkey(&self) -> u32;
+}
+
+/// QP creation attributes with completion queues already resolved to backend objects
+pub struct QpInitAttr<'a, P: Provider> {
+    /// completion queue of the send queue
+    pub send_cq: &'a P::Cq,
+    /// completion queue of the receive queue
+    pub recv_cq: &'a P::Cq,
+    /// shared receive queue taking the place of the QP's own receive queue
+    pub srq: Option<&'a P::Srq>,
+    /// requested capabilities, updated with the actual ones
+    pub cap: ffi::ibv_qp_cap,
+    /// transport type
+    pub qp_type: ffi::ibv_qp_type::Type,
+    /// generate a completion for every send request
+    pub sq_sig_all: bool,
+}
+
+/// Destination of a UD send request with the address handle already resolved to its backend object
+pub struct UdDest<'a, P: Provider> {
+    pub ah: &'a P::Ah,
+    pub remote_qpn: u32,
+    /// Q_Key to send with, one with the high bit set asks for the Q_Key of the QP
+    pub remote_qkey: u32,
+}
+
+/// Changes `rereg_mr` makes to a memory region, `None` keeps the current value
+pub struct MrRereg<'a, P: Provider> {
+    /// protection domain the region moves to
+    pub pd: Option<&'a P::Pd>,
+    /// new start and length, the I/O virtual address becomes the start
+    pub translation: Option<(*mut ::std::os::raw::c_void, usize)>,
+    pub access: Option<ffi::ibv_access_flags>,
+}
+
+/// Extended device attributes filled by `query_device_ex`
+///
+/// Only the first [`size`](Self::size) bytes reach the application, whose `ibv_device_attr_ex` may predate the later
+/// fields.
+pub struct DeviceAttrEx {
+    attr: ffi::ibv_device_attr_ex,
+    size: usize,
+}
+
+impl DeviceAttrEx {
+    /// Zeroed attributes for an application struct of `size` bytes.
+    pub fn new(size: usize) -> Self {
+        Self {
+            attr: ffi::ibv_device_attr_ex::default(),
+            size: size.min(size_of::<ffi::ibv_device_attr_ex>()),
+        }
+    }
+
+    /// Bytes of the attributes the application gets back.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The attributes the application gets back.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        // Safety: plain C struct, `size` never exceeds it.
+        unsafe { core::slice::from_raw_parts((&raw const self.attr).cast(), self.size) }
+    }
+}
+
+impl core::ops::Deref for DeviceAttrEx {
+    type Target = ffi::ibv_device_attr_ex;
+
+    fn deref(&self) -> &Self::Target {
+        &self.attr
+    }
+}
+
+impl core::ops::DerefMut for DeviceAttrEx {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.attr
+    }
+}
+
+/// Memory window bind with the memory region already resolved to its backend object
+pub struct MwBind<'a, P: Provider> {
+    pub wr_id: u64,
+    /// `IBV_SEND_*` flags
+    pub flags: ffi::ibv_send_flags,
+    /// region the window is bound to, `None` unbinds the window
+    pub mr: Option<&'a P::Mr>,
+    /// start of the window, in the I/O virtual addresses of `mr`
+    pub addr: u64,
+    pub length: u64,
+    /// `IBV_ACCESS_REMOTE_*` access through the window
+    pub access: ffi::ibv_access_flags,
+    /// rkey the application chose for a type 2 window, type 1 windows get theirs from the backend
+    pub rkey: Option<u32>,
+}
@@ -0,0 +1,334 @@
+//! QP state machine
+//!
+//! Every `ibv_modify_qp` is checked against the state transitions of the IB specification and the attributes each of
+//! them requires and accepts for the type of the QP, the table the kernel checks in `ib_modify_qp_is_ok`, before it
+//! reaches the backend. The current state is the one of `ibv_qp`, which the glue keeps up to date.
+
+use ffi::ibv_qp_attr_mask as Mask;
+use ffi::ibv_qp_state::{self, Type as State};
+use ffi::ibv_qp_type::{self, Type as QpType};
+
+use crate::{Result, VerbsError};
+
+macro_rules! mask {
+    ($($bit:ident)|*) => {
+        Mask(0 $(| Mask::$bit.0)*)
+    };
+}
+
+/// Attribute mask bits, by name
+const ATTRS: [(Mask, &str); 21] = [
+    (Mask::IBV_QP_STATE, "STATE"),
+    (Mask::IBV_QP_CUR_STATE, "CUR_STATE"),
+    (Mask::IBV_QP_EN_SQD_ASYNC_NOTIFY, "EN_SQD_ASYNC_NOTIFY"),
+    (Mask::IBV_QP_ACCESS_FLAGS, "ACCESS_FLAGS"),
+    (Mask::IBV_QP_PKEY_INDEX, "PKEY_INDEX"),
+    (Mask::IBV_QP_PORT, "PORT"),
+    (Mask::IBV_QP_QKEY, "QKEY"),
+    (Mask::IBV_QP_AV, "AV"),
+    (Mask::IBV_QP_PATH_MTU, "PATH_MTU"),
+    (Mask::IBV_QP_TIMEOUT, "TIMEOUT"),
+    (Mask::IBV_QP_RETRY_CNT, "RETRY_CNT"),
+    (Mask::IBV_QP_RNR_RETRY, "RNR_RETRY"),
+    (Mask::IBV_QP_RQ_PSN, "RQ_PSN"),
+    (Mask::IBV_QP_MAX_QP_RD_ATOMIC, "MAX_QP_RD_ATOMIC"),
+    (Mask::IBV_QP_ALT_PATH, "ALT_PATH"),
+    (Mask::IBV_QP_MIN_RNR_TIMER, "MIN_RNR_TIMER"),
+    (Mask::IBV_QP_SQ_PSN, "SQ_PSN"),
+    (Mask::IBV_QP_MAX_DEST_RD_ATOMIC, "MAX_DEST_RD_ATOMIC"),
+    (Mask::IBV_QP_PATH_MIG_STATE, "PATH_MIG_STATE"),
+    (Mask::IBV_QP_CAP, "CAP"),
+    (Mask::IBV_QP_DEST_QPN, "DEST_QPN"),
+];
+
+fn attr_names(mask: Mask) -> Vec<&'static str> {
+    ATTRS
+        .iter()
+        .filter(|(bit, _)| (mask & *bit).0 != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+fn state_name(state: State) -> &'static str {
+    match state {
+        ibv_qp_state::IBV_QPS_RESET => "RESET",
+        ibv_qp_state::IBV_QPS_INIT => "INIT",
+        ibv_qp_state::IBV_QPS_RTR => "RTR",
+        ibv_qp_state::IBV_QPS_RTS => "RTS",
+        ibv_qp_state::IBV_QPS_SQD => "SQD",
+        ibv_qp_state::IBV_QPS_SQE => "SQE",
+        ibv_qp_state::IBV_QPS_ERR => "ERR",
+        _ => "unknown state",
+    }
+}
+
+fn type_name(qp_type: QpType) -> &'static str {
+    match qp_type {
+        ibv_qp_type::IBV_QPT_RC => "RC",
+        ibv_qp_type::IBV_QPT_UC => "UC",
+        ibv_qp_type::IBV_QPT_UD => "UD",
+        ibv_qp_type::IBV_QPT_RAW_PACKET => "raw packet",
+        _ => "unknown type",
+    }
+}
+
+/// Attributes required and accepted besides the state to move a `qp_type` QP from `cur` to `next`, `None` if it
+/// cannot.
+fn transition(qp_type: QpType, cur: State, next: State) -> Option<(Mask, Mask)> {
+    use ffi::ibv_qp_state::*;
+    use ffi::ibv_qp_type::{IBV_QPT_RAW_PACKET as RAW, IBV_QPT_UC as UC, IBV_QPT_UD as UD};
+
+    let none = Mask(0);
+    let attrs = match (cur, next) {
+        (IBV_QPS_RESET..=IBV_QPS_ERR, IBV_QPS_RESET | IBV_QPS_ERR) => (none, none),
+        (IBV_QPS_RESET, IBV_QPS_INIT) => match qp_type {
+            UD => (mask!(IBV_QP_PKEY_INDEX | IBV_QP_PORT | IBV_QP_QKEY), none),
+            RAW => (mask!(IBV_QP_PORT), none),
+            _ => (mask!(IBV_QP_PKEY_INDEX | IBV_QP_PORT | IBV_QP_ACCESS_FLAGS), none),
+        },
+        (IBV_QPS_INIT, IBV_QPS_INIT) => match qp_type {
+            UD => (none, mask!(IBV_QP_PKEY_INDEX | IBV_QP_PORT | IBV_QP_QKEY)),
+            RAW => (none, mask!(IBV_QP_PORT)),
+            _ => (none, mask!(IBV_QP_PKEY_INDEX | IBV_QP_PORT | IBV_QP_ACCESS_FLAGS)),
+        },
+        (IBV_QPS_INIT, IBV_QPS_RTR) => match qp_type {
+            UD => (none, mask!(IBV_QP_PKEY_INDEX | IBV_QP_QKEY)),
+            RAW => (none, none),
+            UC => (
+                mask!(IBV_QP_AV | IBV_QP_PATH_MTU | IBV_QP_DEST_QPN | IBV_QP_RQ_PSN),
+                mask!(IBV_QP_ALT_PATH | IBV_QP_ACCESS_FLAGS | IBV_QP_PKEY_INDEX),
+            ),
+            _ => (
+                mask!(
+                    IBV_QP_AV
+                        | IBV_QP_PATH_MTU
+                        | IBV_QP_DEST_QPN
+                        | IBV_QP_RQ_PSN
+                        | IBV_QP_MAX_DEST_RD_ATOMIC
+                        | IBV_QP_MIN_RNR_TIMER
+                ),
+                mask!(IBV_QP_ALT_PATH | IBV_QP_ACCESS_FLAGS | IBV_QP_PKEY_INDEX),
+            ),
+        },
+        (IBV_QPS_RTR, IBV_QPS_RTS) => match qp_type {
+            UD => (mask!(IBV_QP_SQ_PSN), mask!(IBV_QP_CUR_STATE | IBV_QP_QKEY)),
+            RAW => (none, none),
+            UC => (
+                mask!(IBV_QP_SQ_PSN),
+                mask!(IBV_QP_CUR_STATE | IBV_QP_ALT_PATH | IBV_QP_ACCESS_FLAGS | IBV_QP_PATH_MIG_STATE),
+            ),
+            _ => (
+                mask!(IBV_QP_TIMEOUT | IBV_QP_RETRY_CNT | IBV_QP_RNR_RETRY | IBV_QP_SQ_PSN | IBV_QP_MAX_QP_RD_ATOMIC),
+                mask!(
+                    IBV_QP_CUR_STATE
+                        | IBV_QP_ALT_PATH
+                        | IBV_QP_ACCESS_FLAGS
+                        | IBV_QP_MIN_RNR_TIMER
+                        | IBV_QP_PATH_MIG_STATE
+                ),
+            ),
+        },
+        (IBV_QPS_RTS | IBV_QPS_SQD, IBV_QPS_RTS) => match qp_type {
+            UD => (none, mask!(IBV_QP_CUR_STATE | IBV_QP_QKEY)),
+            RAW => (none, none),
+            UC => (
+                none,
+                mask!(IBV_QP_CUR_STATE | IBV_QP_ALT_PATH | IBV_QP_ACCESS_FLAGS | IBV_QP_PATH_MIG_STATE),
+            ),
+            _ => (
+                none,
+                mask!(
+                    IBV_QP_CUR_STATE
+                        | IBV_QP_ALT_PATH
+                        | IBV_QP_ACCESS_FLAGS
+                        | IBV_QP_MIN_RNR_TIMER
+                        | IBV_QP_PATH_MIG_STATE
+                ),
+            ),
+        },
+        (IBV_QPS_RTS, IBV_QPS_SQD) => (none, mask!(IBV_QP_EN_SQD_ASYNC_NOTIFY)),
+        (IBV_QPS_SQD, IBV_QPS_SQD) => match qp_type {
+            UD => (none, mask!(IBV_QP_PKEY_INDEX | IBV_QP_QKEY)),
+            RAW => (none, none),
+            UC => (
+                none,
+                mask!(IBV_QP_AV | IBV_QP_ALT_PATH | IBV_QP_ACCESS_FLAGS | IBV_QP_PKEY_INDEX | IBV_QP_PATH_MIG_STATE),
+            ),
+            _ => (
+                none,
+                mask!(
+                    IBV_QP_PORT
+                        | IBV_QP_AV
+                        | IBV_QP_TIMEOUT
+                        | IBV_QP_RETRY_CNT
+                        | IBV_QP_RNR_RETRY
+                        | IBV_QP_MAX_QP_RD_ATOMIC
+                        | IBV_QP_MAX_DEST_RD_ATOMIC
+                        | IBV_QP_ALT_PATH
+                        | IBV_QP_ACCESS_FLAGS
+                        | IBV_QP_PKEY_INDEX
+                        | IBV_QP_MIN_RNR_TIMER
+                        | IBV_QP_PATH_MIG_STATE
+                ),
+            ),
+        },
+        // only unreliable QPs enter SQE
+        (IBV_QPS_SQE, IBV_QPS_RTS) => match qp_type {
+            UD => (none, mask!(IBV_QP_CUR_STATE | IBV_QP_QKEY)),
+            UC => (none, mask!(IBV_QP_CUR_STATE | IBV_QP_ACCESS_FLAGS)),
+            _ => (none, none),
+        },
+        _ => return None,
+    };
+    Some(attrs)
+}
+
+/// Check `ibv_modify_qp` of a `qp_type` QP in `state` with `attr` and `attr_mask`.
+pub(crate) fn check_modify(qp_type: QpType, state: State, attr: &ffi::ibv_qp_attr, attr_mask: Mask) -> Result {
+    let has = |bit: Mask| (attr_mask & bit).0 != 0;
+
+    // the current state is only ever asserted in the states the QP may leave on its own
+    let cur = if has(Mask::IBV_QP_CUR_STATE) {
+        if !matches!(
+            attr.cur_qp_state,
+            ibv_qp_state::IBV_QPS_RTR
+                | ibv_qp_state::IBV_QPS_RTS
+                | ibv_qp_state::IBV_QPS_SQD
+                | ibv_qp_state::IBV_QPS_SQE
+        ) {
+            log::warn!(
+                "QP current state cannot be asserted as {}",
+                state_name(attr.cur_qp_state)
+            );
+            return Err(VerbsError::InvalidArgument);
+        }
+        attr.cur_qp_state
+    } else {
+        state
+    };
+    let next = if has(Mask::IBV_QP_STATE) { attr.qp_state } else { cur };
+
+    let Some((required, optional)) = transition(qp_type, cur, next) else {
+        log::warn!(
+            "{} QP cannot move from {} to {}",
+            type_name(qp_type),
+            state_name(cur),
+            state_name(next)
+        );
+        return Err(VerbsError::InvalidArgument);
+    };
+
+    let missing = Mask(required.0 & !attr_mask.0);
+    if missing.0 != 0 {
+        log::warn!(
+            "{} QP moving from {} to {} misses attributes {:?}",
+            type_name(qp_type),
+            state_name(cur),
+            state_name(next),
+            attr_names(missing)
+        );
+        return Err(VerbsError::InvalidArgument);
+    }
+    let unexpected = Mask(attr_mask.0 & !(required.0 | optional.0 | Mask::IBV_QP_STATE.0));
+    if unexpected.0 != 0 {
+        log::warn!(
+            "{} QP moving from {} to {} does not take attributes {:?}",
+            type_name(qp_type),
+            state_name(cur),
+            state_name(next),
+            attr_names(unexpected)
+        );
+        return Err(VerbsError::InvalidArgument);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transitions_and_masks() {
+        let rc = ibv_qp_type::IBV_QPT_RC;
+        let attr = |qp_state| ffi::ibv_qp_attr {
+            qp_state,
+            ..Default::default()
+        };
+        let modify = |qp_type, state, next, mask: Mask| check_modify(qp_type, state, &attr(next), mask);
+
+        let init = mask!(IBV_QP_STATE | IBV_QP_PKEY_INDEX | IBV_QP_PORT | IBV_QP_ACCESS_FLAGS);
+        let rtr = mask!(
+            IBV_QP_STATE
+                | IBV_QP_AV
+                | IBV_QP_PATH_MTU
+                | IBV_QP_DEST_QPN
+                | IBV_QP_RQ_PSN
+                | IBV_QP_MAX_DEST_RD_ATOMIC
+                | IBV_QP_MIN_RNR_TIMER
+        );
+        let rts = mask!(
+            IBV_QP_STATE
+                | IBV_QP_TIMEOUT
+                | IBV_QP_RETRY_CNT
+                | IBV_QP_RNR_RETRY
+                | IBV_QP_SQ_PSN
+                | IBV_QP_MAX_QP_RD_ATOMIC
+        );
+        assert!(modify(rc, ibv_qp_state::IBV_QPS_RESET, ibv_qp_state::IBV_QPS_INIT, init).is_ok());
+        assert!(modify(rc, ibv_qp_state::IBV_QPS_INIT, ibv_qp_state::IBV_QPS_RTR, rtr).is_ok());
+        assert!(modify(rc, ibv_qp_state::IBV_QPS_RTR, ibv_qp_state::IBV_QPS_RTS, rts).is_ok());
+        assert!(
+            modify(
+                rc,
+                ibv_qp_state::IBV_QPS_RTS,
+                ibv_qp_state::IBV_QPS_RESET,
+                Mask::IBV_QP_STATE
+            )
+            .is_ok()
+        );
+
+        // skipped states, missing and unexpected attributes
+        let invalid = Err(VerbsError::InvalidArgument);
+        assert_eq!(
+            modify(rc, ibv_qp_state::IBV_QPS_RESET, ibv_qp_state::IBV_QPS_RTS, rts),
+            invalid
+        );
+        assert_eq!(
+            modify(
+                rc,
+                ibv_qp_state::IBV_QPS_INIT,
+                ibv_qp_state::IBV_QPS_RTR,
+                Mask(rtr.0 & !Mask::IBV_QP_AV.0)
+            ),
+            invalid
+        );
+        assert_eq!(
+            modify(
+                rc,
+                ibv_qp_state::IBV_QPS_RESET,
+                ibv_qp_state::IBV_QPS_INIT,
+                init | Mask::IBV_QP_QKEY
+            ),
+            invalid
+        );
+
+        // UD takes a Q_Key instead of access flags, and recovers from SQE
+        let ud = ibv_qp_type::IBV_QPT_UD;
+        let ud_init = mask!(IBV_QP_STATE | IBV_QP_PKEY_INDEX | IBV_QP_PORT | IBV_QP_QKEY);
+        assert!(modify(ud, ibv_qp_state::IBV_QPS_RESET, ibv_qp_state::IBV_QPS_INIT, ud_init).is_ok());
+        assert_eq!(
+            modify(ud, ibv_qp_state::IBV_QPS_RESET, ibv_qp_state::IBV_QPS_INIT, init),
+            invalid
+        );
+        let mut sqe = attr(ibv_qp_state::IBV_QPS_RTS);
+        sqe.cur_qp_state = ibv_qp_state::IBV_QPS_SQE;
+        let recover = mask!(IBV_QP_STATE | IBV_QP_CUR_STATE);
+        assert!(check_modify(ud, ibv_qp_state::IBV_QPS_RTS, &sqe, recover).is_ok());
+        sqe.cur_qp_state = ibv_qp_state::IBV_QPS_INIT;
+        assert_eq!(check_modify(ud, ibv_qp_state::IBV_QPS_RTS, &sqe, recover), invalid);
+
+        // without a state, attributes are modified in the current one
+        assert!(modify(ud, ibv_qp_state::IBV_QPS_RTS, 0, Mask::IBV_QP_QKEY).is_ok());
+        assert_eq!(modify(ud, ibv_qp_state::IBV_QPS_RESET, 0, Mask::IBV_QP_QKEY), invalid);
+    }
+}
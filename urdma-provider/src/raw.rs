@@ -1 +1,1522 @@
+//! `verbs_context_ops` callbacks forwarding to a [`Provider`]
+//!
+//! Every callback recovers the provider from the `ibv_context` and the backend objects from the `ibv_*` pointers, so
+//! backends never touch the structs owned by libibverbs. Calls into the provider run under [`guard`], no panic
+//! unwinds into C.
 
+use core::ffi::{CStr, c_char, c_int, c_uint, c_void};
+use core::ptr;
+use std::sync::{Arc, MutexGuard};
+
+pub use ffi;
+
+use crate::completion::{self, Completions, CqHead};
+use crate::context::Context;
+use crate::events::{self, Scope};
+use crate::object::{Ah, Cq, Mr, Mw, Pd, Qp, Srq};
+use crate::work_request::{self, Batch, BindRequest, QpHead, Request, UdAddr};
+use crate::{
+    AsyncEvents, Completion, CompletionQueue, CqNotifier, DeviceAttrEx, MemoryRegion, MemoryWindow, MrRereg, MwBind,
+    Payload, Profile, Provider, QpInitAttr, QueuePair, Remote, Result, SendOp, SendWr, SharedReceiveQueue, UdDest,
+    Verbs, VerbsError, channel, guard, qp_state,
+};
+
+/// Version of the C entry points exported by [`export_provider`](crate::export_provider)
+///
+/// 1. `urdma_init`, `urdma_new_device`, `urdma_free_device` and `urdma_context_ops(ops)`
+/// 2. `urdma_device_context_ops(driver_data, ops)`, `urdma_init_context` and `urdma_uninit_context`
+///
+/// The entry points of earlier versions stay exported, a provider built for one of them keeps working.
+pub const ABI_VERSION: c_uint = 2;
+
+/// Get provider of `context`.
+///
+/// Safety: `context` must be a context opened on an urdma device.
+unsafe fn provider<'a, P: Provider>(context: *mut ffi::ibv_context) -> Result<&'a P> {
+    let device = unsafe { (*context).device };
+    Ok(unsafe { &*P::from_ibv_device(device)? })
+}
+
+/// Run `f` on `provider` under [`guard::call`], failing if the provider was not found.
+fn call<P: Provider, T>(provider: Result<&P>, verb: &str, f: impl FnOnce(&P) -> Result<T>) -> Result<T> {
+    guard::call(provider?, verb, f)
+}
+
+/// Check `obj` was created on `context` before resolving it to its backend object.
+///
+/// Safety: `obj` must be null or point to a verbs object, all of which start with their context.
+unsafe fn on_context<T>(obj: *mut T, context: *mut ffi::ibv_context) -> bool {
+    !obj.is_null() && unsafe { *obj.cast::<*mut ffi::ibv_context>() } == context
+}
+
+/// Dereference an out parameter of a verb.
+///
+/// Safety: `ptr` must be null or valid for writes.
+unsafe fn out<'a, T>(ptr: *mut T) -> Result<&'a mut T> {
+    unsafe { ptr.as_mut() }.ok_or(VerbsError::InvalidArgument)
+}
+
+fn errno(rc: Result) -> c_int {
+    match rc {
+        Ok(()) => 0,
+        Err(err) => err.errno(),
+    }
+}
+
+fn null_with_errno<T>(err: VerbsError) -> *mut T {
+    // Safety: errno is thread local.
+    unsafe { *libc::__errno_location() = err.errno() };
+    ptr::null_mut()
+}
+
+fn ptr_or_errno<T>(rc: Result<*mut T>) -> *mut T {
+    rc.unwrap_or_else(null_with_errno)
+}
+
+/// Init driver.
+pub fn init<P: Provider>() -> c_int {
+    errno(guard::catch("init", P::init))
+}
+
+/// Create provider for device `sysfs_name`, the returned pointer is stored as `driver_data` of the urdma device.
+///
+/// Safety: `sysfs_name` must be a valid C string.
+pub unsafe fn new_device<P: Provider>(sysfs_name: *const c_char) -> *const c_void {
+    let Ok(sysfs_name) = unsafe { CStr::from_ptr(sysfs_name) }.to_str() else {
+        return null_with_errno(VerbsError::InvalidArgument);
+    };
+
+    let events = AsyncEvents::default();
+    match guard::catch("new_device", || P::new(sysfs_name, events.clone())) {
+        Ok(provider) => {
+            events::insert(&*provider, events);
+            Arc::into_raw(provider).cast()
+        }
+        Err(err) => {
+            log::error!("Failed to create device {sysfs_name}: {err}");
+            null_with_errno(err)
+        }
+    }
+}
+
+/// Free provider returned by [`new_device`].
+///
+/// Safety: `driver_data` must come from [`new_device`] and not be used afterwards.
+pub unsafe fn free_device<P: Provider>(driver_data: *const c_void) {
+    let provider = unsafe { Arc::from_raw(driver_data.cast::<P>()) };
+    guard::forget(&*provider);
+    events::remove(&*provider);
+    Profile::forget(&*provider);
+
+    // a panicking destructor must not unwind into C either
+    let _ = guard::catch("free_device", || {
+        drop(provider);
+        Ok(())
+    });
+}
+
+/// Set up `context` of the device `driver_data`, which is being opened, and deliver the asynchronous events on it.
+///
+/// Safety: `driver_data` must come from [`new_device`], `context` must point to a live context of its device.
+pub unsafe fn init_context<P: Provider>(driver_data: *const c_void, context: *mut ffi::ibv_context) -> c_int {
+    let provider = unsafe { &*driver_data.cast::<P>() };
+
+    errno(guard::call(provider, "init_context", |provider| {
+        let events = events::of(provider).ok_or(VerbsError::InvalidArgument)?;
+        unsafe { events.attach(context) }?;
+        Context::open(context, provider.capabilities());
+        Ok(())
+    }))
+}
+
+/// Tear down `context`, which is being closed.
+///
+/// Safety: `driver_data` must come from [`new_device`].
+pub unsafe fn uninit_context<P: Provider>(driver_data: *const c_void, context: *mut ffi::ibv_context) {
+    let provider = unsafe { &*driver_data.cast::<P>() };
+
+    Context::close(context);
+    if let Some(events) = events::of(provider) {
+        events.detach(context);
+    }
+}
+
+/// Register an object the application created, so that its events reach `context`.
+fn register<P: Provider, T>(provider: &P, scope: Scope, num: u32, context: *mut ffi::ibv_context, handle: *mut T) {
+    if let Some(events) = events::of(provider) {
+        events.register(scope, num, context, handle);
+    }
+}
+
+/// Unregister an object being destroyed, returns the number of events delivered for it.
+fn unregister<P: Provider>(provider: &P, scope: Scope, num: u32) -> u32 {
+    events::of(provider).map_or(0, |events| events.unregister(scope, num))
+}
+
+/// State of `context`. A provider of ABI version 1 never calls `urdma_init_context`, so its contexts are set up on
+/// first use instead, and get no asynchronous events.
+fn state<P: Provider>(provider: &P, context: *mut ffi::ibv_context) -> Arc<Context> {
+    Context::of(context).unwrap_or_else(|_| Context::open(context, provider.capabilities()))
+}
+
+/// Build the ops table installed on a context of the device `driver_data`, with only the verbs it supports.
+///
+/// Safety: `driver_data` must come from [`new_device`].
+pub unsafe fn device_context_ops<P: Provider>(driver_data: *const c_void) -> ffi::verbs_context_ops {
+    let provider = unsafe { &*driver_data.cast::<P>() };
+
+    // a device that cannot tell what it supports gets no verbs at all
+    let caps = guard::call(provider, "capabilities", |provider| Ok(provider.capabilities())).unwrap_or_default();
+    log::info!("Device capabilities: {caps:?}");
+    ops_table::<P>(caps.verbs)
+}
+
+/// Build the ops table of ABI version 1, which is not told the device. Every verb is installed, those the device does
+/// not support fail when called.
+pub fn context_ops<P: Provider>() -> ffi::verbs_context_ops {
+    ops_table::<P>(Verbs::ALL)
+}
+
+fn ops_table<P: Provider>(verbs: Verbs) -> ffi::verbs_context_ops {
+    let has = |verb| verbs.contains(verb);
+    ffi::verbs_context_ops {
+        alloc_pd: has(Verbs::ALLOC_PD).then_some(alloc_pd::<P> as _),
+        dealloc_pd: has(Verbs::DEALLOC_PD).then_some(dealloc_pd::<P> as _),
+        query_device_ex: has(Verbs::QUERY_DEVICE).then_some(query_device_ex::<P> as _),
+        query_port: has(Verbs::QUERY_PORT).then_some(query_port::<P> as _),
+        create_cq: has(Verbs::CREATE_CQ).then_some(create_cq::<P> as _),
+        create_cq_ex: has(Verbs::CREATE_CQ_EX).then_some(create_cq_ex::<P> as _),
+        destroy_cq: has(Verbs::DESTROY_CQ).then_some(destroy_cq::<P> as _),
+        create_qp: has(Verbs::CREATE_QP).then_some(create_qp::<P> as _),
+        create_qp_ex: has(Verbs::CREATE_QP_EX).then_some(create_qp_ex::<P> as _),
+        destroy_qp: has(Verbs::DESTROY_QP).then_some(destroy_qp::<P> as _),
+        modify_qp: has(Verbs::MODIFY_QP).then_some(modify_qp::<P> as _),
+        query_qp: has(Verbs::QUERY_QP).then_some(query_qp::<P> as _),
+        reg_mr: has(Verbs::REG_MR).then_some(reg_mr::<P> as _),
+        reg_dmabuf_mr: has(Verbs::REG_DMABUF_MR).then_some(reg_dmabuf_mr::<P> as _),
+        dereg_mr: has(Verbs::DEREG_MR).then_some(dereg_mr::<P> as _),
+        rereg_mr: has(Verbs::REREG_MR).then_some(rereg_mr::<P> as _),
+        alloc_mw: has(Verbs::ALLOC_MW).then_some(alloc_mw::<P> as _),
+        dealloc_mw: has(Verbs::DEALLOC_MW).then_some(dealloc_mw::<P> as _),
+        bind_mw: has(Verbs::BIND_MW).then_some(bind_mw::<P> as _),
+        create_ah: has(Verbs::CREATE_AH).then_some(create_ah::<P> as _),
+        destroy_ah: has(Verbs::DESTROY_AH).then_some(destroy_ah::<P> as _),
+        post_send: has(Verbs::POST_SEND).then_some(post_send::<P> as _),
+        post_recv: has(Verbs::POST_RECV).then_some(post_recv::<P> as _),
+        poll_cq: has(Verbs::POLL_CQ).then_some(poll_cq::<P> as _),
+        create_srq: has(Verbs::CREATE_SRQ).then_some(create_srq::<P> as _),
+        modify_srq: has(Verbs::MODIFY_SRQ).then_some(modify_srq::<P> as _),
+        query_srq: has(Verbs::QUERY_SRQ).then_some(query_srq::<P> as _),
+        destroy_srq: has(Verbs::DESTROY_SRQ).then_some(destroy_srq::<P> as _),
+        post_srq_recv: has(Verbs::POST_SRQ_RECV).then_some(post_srq_recv::<P> as _),
+        req_notify_cq: has(Verbs::REQ_NOTIFY_CQ).then_some(req_notify_cq::<P> as _),
+        cq_event: has(Verbs::REQ_NOTIFY_CQ).then_some(cq_event as _),
+        ..Default::default()
+    }
+}
+
+unsafe extern "C" fn alloc_pd<P: Provider>(context: *mut ffi::ibv_context) -> *mut ffi::ibv_pd {
+    let provider = unsafe { provider::<P>(context) };
+
+    ptr_or_errno(call(provider, "alloc_pd", |provider| {
+        let pd = provider.alloc_pd()?;
+        Ok(Pd::<P>::into_raw(
+            ffi::ibv_pd {
+                context,
+                ..Default::default()
+            },
+            pd,
+        ))
+    }))
+}
+
+unsafe extern "C" fn dealloc_pd<P: Provider>(pd: *mut ffi::ibv_pd) -> c_int {
+    let provider = unsafe { provider::<P>((*pd).context) };
+
+    errno(call(provider, "dealloc_pd", |provider| {
+        provider.dealloc_pd(unsafe { Pd::<P>::inner(pd) })?;
+        unsafe { Pd::<P>::free(pd) };
+        Ok(())
+    }))
+}
+
+unsafe extern "C" fn query_device_ex<P: Provider>(
+    context: *mut ffi::ibv_context,
+    input: *const ffi::ibv_query_device_ex_input,
+    attr: *mut ffi::ibv_device_attr_ex,
+    attr_size: usize,
+) -> c_int {
+    let provider = unsafe { provider::<P>(context) };
+
+    if attr.is_null() || attr_size < size_of::<ffi::ibv_device_attr>() {
+        return VerbsError::InvalidArgument.errno();
+    }
+    // no input fields are defined
+    if unsafe { input.as_ref() }.is_some_and(|input| input.comp_mask != 0) {
+        return VerbsError::InvalidArgument.errno();
+    }
+
+    errno(call(provider, "query_device", |provider| {
+        let mut ex = DeviceAttrEx::new(attr_size);
+        provider.query_device_ex(&mut ex)?;
+        Profile::current().apply_device(&mut ex.orig_attr);
+
+        // fields newer than our bindings stay zeroed
+        let bytes = ex.as_bytes();
+        unsafe {
+            attr.cast::<u8>().write_bytes(0, attr_size);
+            ptr::copy_nonoverlapping(bytes.as_ptr(), attr.cast::<u8>(), bytes.len());
+        }
+        Ok(())
+    }))
+}
+
+unsafe extern "C" fn query_port<P: Provider>(
+    context: *mut ffi::ibv_context,
+    port_num: u8,
+    port_attr: *mut ffi::ibv_port_attr,
+) -> c_int {
+    let provider = unsafe { provider::<P>(context) };
+
+    errno(call(provider, "query_port", |provider| {
+        let port_attr = unsafe { out(port_attr) }?;
+        provider.query_port(port_num, port_attr)?;
+        Profile::current().apply_port(port_attr);
+        Ok(())
+    }))
+}
+
+unsafe extern "C" fn create_cq<P: Provider>(
+    context: *mut ffi::ibv_context,
+    cqe: c_int,
+    channel: *mut ffi::ibv_comp_channel,
+    comp_vector: c_int,
+) -> *mut ffi::ibv_cq {
+    let provider = unsafe { provider::<P>(context) };
+
+    ptr_or_errno(call(provider, "create_cq", |provider| {
+        let notifier = unsafe { notifier(provider, context, channel) }?;
+        let cq = provider.create_cq(cqe, notifier.clone(), comp_vector)?;
+        Ok(new_cq::<P>(provider, context, channel, cqe, notifier, cq).cast())
+    }))
+}
+
+/// Completion event source for a CQ created on `channel`, if any.
+///
+/// Safety: `channel` must be null or point to a live `ibv_comp_channel`.
+unsafe fn notifier<P: Provider>(
+    provider: &P,
+    context: *mut ffi::ibv_context,
+    channel: *mut ffi::ibv_comp_channel,
+) -> Result<Option<CqNotifier>> {
+    if channel.is_null() {
+        Ok(None)
+    } else if state(provider, context).caps.verbs.contains(Verbs::REQ_NOTIFY_CQ) {
+        Ok(Some(unsafe { CqNotifier::new(channel) }?))
+    } else {
+        // events would never come
+        Err(VerbsError::NotSupported)
+    }
+}
+
+/// Hand backend CQ `cq` to the application.
+fn new_cq<P: Provider>(
+    provider: &P,
+    context: *mut ffi::ibv_context,
+    channel: *mut ffi::ibv_comp_channel,
+    cqe: c_int,
+    notifier: Option<CqNotifier>,
+    cq: P::Cq,
+) -> *mut CqHead {
+    let cq_num = cq.cq_num();
+    let cq = Cq::<P>::into_raw(
+        CqHead::new(ffi::ibv_cq_ex {
+            context,
+            channel,
+            cqe,
+            ..Default::default()
+        }),
+        cq,
+    );
+    if let Some(notifier) = notifier {
+        notifier.bind(cq.cast());
+    }
+    register(provider, Scope::Cq, cq_num, context, cq);
+    cq
+}
+
+unsafe extern "C" fn create_cq_ex<P: Provider>(
+    context: *mut ffi::ibv_context,
+    attr: *mut ffi::ibv_cq_init_attr_ex,
+) -> *mut ffi::ibv_cq_ex {
+    let provider = unsafe { provider::<P>(context) };
+
+    ptr_or_errno(call(provider, "create_cq_ex", |provider| {
+        let attr = unsafe { out(attr) }?;
+
+        // creation flags are hints, parent domains do not exist
+        if attr.comp_mask & !ffi::IBV_CQ_INIT_ATTR_MASK_FLAGS != 0 || attr.wc_flags & !completion::WC_FLAGS != 0 {
+            return Err(VerbsError::NotSupported);
+        }
+        let cqe = c_int::try_from(attr.cqe).map_err(|_| VerbsError::InvalidArgument)?;
+        let comp_vector = c_int::try_from(attr.comp_vector).map_err(|_| VerbsError::InvalidArgument)?;
+
+        let notifier = unsafe { notifier(provider, context, attr.channel) }?;
+        let cq = provider.create_cq_ex(cqe, notifier.clone(), comp_vector, attr.wc_flags)?;
+        let cq = new_cq::<P>(provider, context, attr.channel, cqe, notifier, cq).cast::<ffi::ibv_cq_ex>();
+
+        // libibverbs leaves initializing extended CQs to the provider
+        unsafe {
+            (*cq).cq_context = attr.cq_context;
+            if !attr.channel.is_null() {
+                let mutex = (&raw mut (*context).mutex).cast::<libc::pthread_mutex_t>();
+                libc::pthread_mutex_lock(mutex);
+                (*attr.channel).refcnt += 1;
+                libc::pthread_mutex_unlock(mutex);
+            }
+
+            (*cq).start_poll = Some(start_poll::<P>);
+            (*cq).next_poll = Some(next_poll::<P>);
+            (*cq).end_poll = Some(end_poll);
+            (*cq).read_opcode = Some(read_opcode);
+            (*cq).read_vendor_err = Some(read_vendor_err);
+            (*cq).read_byte_len = Some(read_byte_len);
+            (*cq).read_imm_data = Some(read_imm_data);
+            (*cq).read_qp_num = Some(read_qp_num);
+            (*cq).read_src_qp = Some(read_src_qp);
+            (*cq).read_wc_flags = Some(read_wc_flags);
+            (*cq).read_slid = Some(read_slid);
+            (*cq).read_sl = Some(read_sl);
+            (*cq).read_dlid_path_bits = Some(read_dlid_path_bits);
+            (*cq).read_completion_ts = Some(read_completion_ts);
+            (*cq).read_completion_wallclock_ns = Some(read_completion_ts);
+        }
+        Ok(cq)
+    }))
+}
+
+/// Make the next completion of `cq` current, `ENOENT` if there is none.
+///
+/// Safety: `cq` must be the `ibv_cq_ex` of a live CQ object.
+unsafe fn advance<P: Provider>(cq: *mut ffi::ibv_cq_ex, verb: &str) -> c_int {
+    let provider = unsafe { provider::<P>((*cq).context) };
+
+    errno(call(provider, verb, |provider| {
+        let mut polled = unsafe { CqHead::polled(cq) };
+        let next = Completions::new(provider, unsafe { Cq::<P>::inner(cq.cast()) }, &mut polled)
+            .next()
+            .transpose()?;
+        polled.current = next;
+
+        let completion = next.ok_or(VerbsError::Errno(libc::ENOENT))?;
+        // read by the application directly, without a callback
+        unsafe {
+            (*cq).status = completion
+                .wc
+                .error()
+                .map_or(ffi::ibv_wc_status::IBV_WC_SUCCESS, |(status, _)| status);
+            (*cq).wr_id = completion.wc.wr_id();
+        }
+        Ok(())
+    }))
+}
+
+unsafe extern "C" fn start_poll<P: Provider>(cq: *mut ffi::ibv_cq_ex, attr: *mut ffi::ibv_poll_cq_attr) -> c_int {
+    // no poll attributes are defined
+    if unsafe { attr.as_ref() }.is_none_or(|attr| attr.comp_mask != 0) {
+        return VerbsError::InvalidArgument.errno();
+    }
+
+    unsafe { advance::<P>(cq, "start_poll") }
+}
+
+unsafe extern "C" fn next_poll<P: Provider>(cq: *mut ffi::ibv_cq_ex) -> c_int {
+    unsafe { advance::<P>(cq, "next_poll") }
+}
+
+unsafe extern "C" fn end_poll(cq: *mut ffi::ibv_cq_ex) {
+    unsafe { CqHead::polled(cq) }.current = None;
+}
+
+/// Completion the `read_*` callbacks of `cq` read.
+///
+/// Safety: `cq` must be the `ibv_cq_ex` of a live CQ object.
+unsafe fn current(cq: *mut ffi::ibv_cq_ex) -> Completion {
+    unsafe { CqHead::polled(cq) }.current.unwrap_or_default()
+}
+
+unsafe extern "C" fn read_opcode(cq: *mut ffi::ibv_cq_ex) -> ffi::ibv_wc_opcode::Type {
+    unsafe { current(cq) }.wc.opcode()
+}
+
+unsafe extern "C" fn read_vendor_err(cq: *mut ffi::ibv_cq_ex) -> u32 {
+    unsafe { current(cq) }
+        .wc
+        .error()
+        .map_or(0, |(_, vendor_err)| vendor_err)
+}
+
+unsafe extern "C" fn read_byte_len(cq: *mut ffi::ibv_cq_ex) -> u32 {
+    // came from a `u32`
+    unsafe { current(cq) }.wc.len() as u32
+}
+
+unsafe extern "C" fn read_imm_data(cq: *mut ffi::ibv_cq_ex) -> ffi::__be32 {
+    unsafe { current(cq) }.wc.imm_data
+}
+
+unsafe extern "C" fn read_qp_num(cq: *mut ffi::ibv_cq_ex) -> u32 {
+    unsafe { current(cq) }.wc.qp_num
+}
+
+unsafe extern "C" fn read_src_qp(cq: *mut ffi::ibv_cq_ex) -> u32 {
+    unsafe { current(cq) }.wc.src_qp
+}
+
+unsafe extern "C" fn read_wc_flags(cq: *mut ffi::ibv_cq_ex) -> c_uint {
+    unsafe { current(cq) }.wc.wc_flags.0 as c_uint
+}
+
+unsafe extern "C" fn read_slid(cq: *mut ffi::ibv_cq_ex) -> u32 {
+    unsafe { current(cq) }.wc.slid.into()
+}
+
+unsafe extern "C" fn read_sl(cq: *mut ffi::ibv_cq_ex) -> u8 {
+    unsafe { current(cq) }.wc.sl
+}
+
+unsafe extern "C" fn read_dlid_path_bits(cq: *mut ffi::ibv_cq_ex) -> u8 {
+    unsafe { current(cq) }.wc.dlid_path_bits
+}
+
+/// Timestamps are in nanoseconds, so the device clock and the wall clock read the same.
+unsafe extern "C" fn read_completion_ts(cq: *mut ffi::ibv_cq_ex) -> u64 {
+    unsafe { current(cq) }.timestamp.unwrap_or(0)
+}
+
+unsafe extern "C" fn destroy_cq<P: Provider>(cq: *mut ffi::ibv_cq) -> c_int {
+    let provider = unsafe { provider::<P>((*cq).context) };
+
+    errno(call(provider, "destroy_cq", |provider| {
+        let inner = unsafe { Cq::<P>::inner(cq.cast()) };
+        provider.destroy_cq(inner)?;
+        let reported = unregister(provider, Scope::Cq, inner.cq_num());
+        unsafe {
+            events::wait_acked(
+                &raw mut (*cq).mutex,
+                &raw mut (*cq).cond,
+                &raw const (*cq).async_events_completed,
+                reported,
+            );
+        }
+        let channel = unsafe { (*cq).channel };
+        if !channel.is_null() {
+            channel::forget(channel, cq);
+        }
+        unsafe { Cq::<P>::free(cq.cast()) };
+        Ok(())
+    }))
+}
+
+unsafe extern "C" fn req_notify_cq<P: Provider>(cq: *mut ffi::ibv_cq, solicited_only: c_int) -> c_int {
+    let provider = unsafe { provider::<P>((*cq).context) };
+
+    errno(call(provider, "req_notify_cq", |provider| {
+        provider.req_notify_cq(unsafe { Cq::<P>::inner(cq.cast()) }, solicited_only != 0)
+    }))
+}
+
+/// Called by `ibv_get_cq_event` once it read the event of `cq`.
+unsafe extern "C" fn cq_event(cq: *mut ffi::ibv_cq) {
+    channel::consumed(unsafe { (*cq).channel });
+}
+
+unsafe extern "C" fn create_qp<P: Provider>(
+    pd: *mut ffi::ibv_pd,
+    attr: *mut ffi::ibv_qp_init_attr,
+) -> *mut ffi::ibv_qp {
+    let provider = unsafe { provider::<P>((*pd).context) };
+
+    ptr_or_errno(call(provider, "create_qp", |provider| {
+        Ok(unsafe { new_qp::<P>(provider, pd, out(attr)?, None) }?.cast())
+    }))
+}
+
+/// Create a QP from the attributes shared by `ibv_qp_init_attr` and `ibv_qp_init_attr_ex`, with the `ibv_wr_*` API
+/// if `send_ops` is set.
+///
+/// Safety: `pd` must be a live PD, the CQs and SRQ of `attr` must be null or live objects.
+unsafe fn new_qp<P: Provider>(
+    provider: &P,
+    pd: *mut ffi::ibv_pd,
+    attr: &mut ffi::ibv_qp_init_attr,
+    send_ops: Option<u64>,
+) -> Result<*mut QpHead> {
+    let context = unsafe { (*pd).context };
+
+    // objects of another device must not be resolved as ours
+    if unsafe { !on_context(attr.send_cq, context) || !on_context(attr.recv_cq, context) } {
+        return Err(VerbsError::InvalidArgument);
+    }
+    if unsafe { !attr.srq.is_null() && !on_context(attr.srq, context) } {
+        return Err(VerbsError::InvalidArgument);
+    }
+    let state = state(provider, context);
+    if !state.caps.supports_qp_type(attr.qp_type) {
+        return Err(VerbsError::NotSupported);
+    }
+    let profile = Profile::current();
+    profile.check_qp_cap(&attr.cap)?;
+
+    let mut init_attr = QpInitAttr::<P> {
+        send_cq: unsafe { Cq::<P>::inner(attr.send_cq.cast()) },
+        recv_cq: unsafe { Cq::<P>::inner(attr.recv_cq.cast()) },
+        srq: (!attr.srq.is_null()).then(|| unsafe { Srq::<P>::inner(attr.srq) }),
+        cap: attr.cap,
+        qp_type: attr.qp_type,
+        sq_sig_all: attr.sq_sig_all != 0,
+    };
+
+    let pd_inner = unsafe { Pd::<P>::inner(pd) };
+    profile.reserve_qp(provider)?;
+    let qp = match send_ops {
+        Some(send_ops) => provider.create_qp_ex(pd_inner, &mut init_attr, send_ops),
+        None => provider.create_qp(pd_inner, &mut init_attr),
+    }
+    .inspect_err(|_| Profile::release_qp(provider))?;
+    attr.cap = init_attr.cap;
+
+    let qp_num = qp.qp_num();
+    let mut qp_ex = ffi::ibv_qp_ex {
+        qp_base: ffi::ibv_qp {
+            context,
+            qp_context: attr.qp_context,
+            pd,
+            send_cq: attr.send_cq,
+            recv_cq: attr.recv_cq,
+            srq: attr.srq,
+            qp_num,
+            state: ffi::ibv_qp_state::IBV_QPS_RESET,
+            qp_type: attr.qp_type,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    // `ibv_qp_to_qp_ex` only hands out QPs created with send ops
+    let comp_mask = match send_ops {
+        Some(_) => {
+            qp_ex.wr_start = Some(wr_start);
+            qp_ex.wr_complete = Some(wr_complete::<P>);
+            qp_ex.wr_abort = Some(wr_abort);
+            qp_ex.wr_send = Some(wr_send);
+            qp_ex.wr_send_imm = Some(wr_send_imm);
+            qp_ex.wr_rdma_write = Some(wr_rdma_write);
+            qp_ex.wr_rdma_write_imm = Some(wr_rdma_write_imm);
+            qp_ex.wr_rdma_read = Some(wr_rdma_read);
+            qp_ex.wr_atomic_cmp_swp = Some(wr_atomic_cmp_swp);
+            qp_ex.wr_atomic_fetch_add = Some(wr_atomic_fetch_add);
+            qp_ex.wr_bind_mw = Some(wr_bind_mw);
+            qp_ex.wr_local_inv = Some(wr_local_inv);
+            qp_ex.wr_send_inv = Some(wr_send_inv);
+            qp_ex.wr_set_sge = Some(wr_set_sge);
+            qp_ex.wr_set_sge_list = Some(wr_set_sge_list);
+            qp_ex.wr_set_inline_data = Some(wr_set_inline_data);
+            qp_ex.wr_set_inline_data_list = Some(wr_set_inline_data_list);
+            qp_ex.wr_set_ud_addr = Some(wr_set_ud_addr);
+            ffi::VERBS_QP_EX
+        }
+        None => 0,
+    };
+
+    let qp = Qp::<P>::into_raw(
+        QpHead::new(ffi::verbs_qp {
+            __bindgen_anon_1: ffi::verbs_qp__bindgen_ty_1 { qp_ex },
+            comp_mask,
+            xrcd: ptr::null_mut(),
+        }),
+        qp,
+    );
+    register(provider, Scope::Qp, qp_num, context, qp);
+    Ok(qp)
+}
+
+unsafe extern "C" fn create_qp_ex<P: Provider>(
+    context: *mut ffi::ibv_context,
+    attr: *mut ffi::ibv_qp_init_attr_ex,
+) -> *mut ffi::ibv_qp {
+    let provider = unsafe { provider::<P>(context) };
+
+    ptr_or_errno(call(provider, "create_qp_ex", |provider| {
+        let attr = unsafe { out(attr) }?;
+
+        // XRC, TSO and RSS QPs do not exist, creation flags all ask for hardware offloads
+        let supported =
+            ffi::IBV_QP_INIT_ATTR_PD | ffi::IBV_QP_INIT_ATTR_CREATE_FLAGS | ffi::IBV_QP_INIT_ATTR_SEND_OPS_FLAGS;
+        if attr.comp_mask & !supported != 0 || attr.create_flags != 0 {
+            return Err(VerbsError::NotSupported);
+        }
+        if attr.comp_mask & ffi::IBV_QP_INIT_ATTR_PD == 0 || unsafe { !on_context(attr.pd, context) } {
+            return Err(VerbsError::InvalidArgument);
+        }
+        let send_ops = (attr.comp_mask & ffi::IBV_QP_INIT_ATTR_SEND_OPS_FLAGS != 0).then_some(attr.send_ops_flags);
+        let supported = if state(provider, context).caps.verbs.contains(Verbs::MW) {
+            work_request::SEND_OPS_FLAGS
+        } else {
+            work_request::SEND_OPS_FLAGS & !work_request::MW_SEND_OPS_FLAGS
+        };
+        if send_ops.is_some_and(|send_ops| send_ops & !supported != 0) {
+            return Err(VerbsError::NotSupported);
+        }
+
+        // the legacy attributes are the head of the extended ones
+        let legacy = unsafe { &mut *(&raw mut *attr).cast::<ffi::ibv_qp_init_attr>() };
+        Ok(unsafe { new_qp::<P>(provider, attr.pd, legacy, send_ops) }?.cast())
+    }))
+}
+
+/// Batch of the QP `qp`.
+///
+/// Safety: `qp` must be the `ibv_qp_ex` of a live QP object.
+unsafe fn batch<'a>(qp: *mut ffi::ibv_qp_ex) -> MutexGuard<'a, Batch> {
+    unsafe { QpHead::batch(qp) }
+}
+
+unsafe extern "C" fn wr_start(qp: *mut ffi::ibv_qp_ex) {
+    unsafe { batch(qp) }.start();
+}
+
+unsafe extern "C" fn wr_complete<P: Provider>(qp: *mut ffi::ibv_qp_ex) -> c_int {
+    let provider = unsafe { provider::<P>((*qp).qp_base.context) };
+
+    errno(call(provider, "wr_complete", |provider| {
+        let wrs = unsafe { batch(qp) }.take()?;
+        wrs.iter()
+            .try_for_each(|wr| unsafe { post::<P>(provider, qp.cast(), wr) })
+    }))
+}
+
+unsafe extern "C" fn wr_abort(qp: *mut ffi::ibv_qp_ex) {
+    unsafe { batch(qp) }.start();
+}
+
+/// Start building a request of `op`, with the `wr_id` and `wr_flags` the application set on `qp`.
+///
+/// Safety: `qp` must be the `ibv_qp_ex` of a live QP object.
+unsafe fn build(qp: *mut ffi::ibv_qp_ex, op: SendOp) {
+    let (wr_id, flags) = unsafe { ((*qp).wr_id, (*qp).wr_flags) };
+    unsafe { batch(qp) }.push(Request::Send {
+        wr: SendWr {
+            wr_id,
+            op,
+            // inline data is whatever `wr_set_inline_data` sets
+            flags: ffi::ibv_send_flags(flags & !ffi::ibv_send_flags::IBV_SEND_INLINE.0),
+            payload: Payload::Sges(Vec::new()),
+        },
+        ud: None,
+    });
+}
+
+unsafe extern "C" fn wr_send(qp: *mut ffi::ibv_qp_ex) {
+    unsafe { build(qp, SendOp::Send) };
+}
+
+unsafe extern "C" fn wr_send_imm(qp: *mut ffi::ibv_qp_ex, imm_data: ffi::__be32) {
+    unsafe { build(qp, SendOp::SendWithImm(imm_data)) };
+}
+
+unsafe extern "C" fn wr_rdma_write(qp: *mut ffi::ibv_qp_ex, rkey: u32, remote_addr: u64) {
+    let remote = Remote {
+        addr: remote_addr,
+        rkey,
+    };
+    unsafe { build(qp, SendOp::RdmaWrite(remote)) };
+}
+
+unsafe extern "C" fn wr_rdma_write_imm(qp: *mut ffi::ibv_qp_ex, rkey: u32, remote_addr: u64, imm_data: ffi::__be32) {
+    let remote = Remote {
+        addr: remote_addr,
+        rkey,
+    };
+    unsafe { build(qp, SendOp::RdmaWriteWithImm(remote, imm_data)) };
+}
+
+unsafe extern "C" fn wr_rdma_read(qp: *mut ffi::ibv_qp_ex, rkey: u32, remote_addr: u64) {
+    let remote = Remote {
+        addr: remote_addr,
+        rkey,
+    };
+    unsafe { build(qp, SendOp::RdmaRead(remote)) };
+}
+
+unsafe extern "C" fn wr_atomic_cmp_swp(qp: *mut ffi::ibv_qp_ex, rkey: u32, remote_addr: u64, compare: u64, swap: u64) {
+    let remote = Remote {
+        addr: remote_addr,
+        rkey,
+    };
+    unsafe { build(qp, SendOp::AtomicCmpAndSwp { remote, compare, swap }) };
+}
+
+unsafe extern "C" fn wr_atomic_fetch_add(qp: *mut ffi::ibv_qp_ex, rkey: u32, remote_addr: u64, add: u64) {
+    let remote = Remote {
+        addr: remote_addr,
+        rkey,
+    };
+    unsafe { build(qp, SendOp::AtomicFetchAndAdd { remote, add }) };
+}
+
+unsafe extern "C" fn wr_bind_mw(
+    qp: *mut ffi::ibv_qp_ex,
+    mw: *mut ffi::ibv_mw,
+    rkey: u32,
+    bind_info: *const ffi::ibv_mw_bind_info,
+) {
+    let (wr_id, flags) = unsafe { ((*qp).wr_id, (*qp).wr_flags) };
+    let mut batch = unsafe { batch(qp) };
+    match unsafe { bind_info.as_ref() } {
+        Some(&bind_info) => batch.push(Request::BindMw(BindRequest {
+            wr_id,
+            flags: ffi::ibv_send_flags(flags),
+            mw,
+            rkey: Some(rkey),
+            bind_info,
+        })),
+        None => batch.fail(VerbsError::InvalidArgument),
+    }
+}
+
+unsafe extern "C" fn wr_local_inv(qp: *mut ffi::ibv_qp_ex, invalidate_rkey: u32) {
+    unsafe { build(qp, SendOp::LocalInv(invalidate_rkey)) };
+}
+
+unsafe extern "C" fn wr_send_inv(qp: *mut ffi::ibv_qp_ex, invalidate_rkey: u32) {
+    unsafe { build(qp, SendOp::SendWithInv(invalidate_rkey)) };
+}
+
+unsafe extern "C" fn wr_set_sge(qp: *mut ffi::ibv_qp_ex, lkey: u32, addr: u64, length: u32) {
+    let sge = ffi::ibv_sge { addr, length, lkey };
+    unsafe { batch(qp) }.set_payload(Payload::Sges(vec![sge]));
+}
+
+unsafe extern "C" fn wr_set_sge_list(qp: *mut ffi::ibv_qp_ex, num_sge: usize, sg_list: *const ffi::ibv_sge) {
+    let mut batch = unsafe { batch(qp) };
+    match num_sge {
+        0 => batch.set_payload(Payload::Sges(Vec::new())),
+        _ if sg_list.is_null() => batch.fail(VerbsError::InvalidArgument),
+        // Safety: the application hands us `num_sge` valid entries.
+        _ => batch.set_payload(Payload::Sges(
+            unsafe { core::slice::from_raw_parts(sg_list, num_sge) }.to_vec(),
+        )),
+    }
+}
+
+unsafe extern "C" fn wr_set_inline_data(qp: *mut ffi::ibv_qp_ex, addr: *mut c_void, length: usize) {
+    let buf = ffi::ibv_data_buf { addr, length };
+    unsafe { wr_set_inline_data_list(qp, 1, &raw const buf) };
+}
+
+unsafe extern "C" fn wr_set_inline_data_list(
+    qp: *mut ffi::ibv_qp_ex,
+    num_buf: usize,
+    buf_list: *const ffi::ibv_data_buf,
+) {
+    let mut batch = unsafe { batch(qp) };
+    let bufs = match num_buf {
+        0 => &[][..],
+        _ if buf_list.is_null() => return batch.fail(VerbsError::InvalidArgument),
+        // Safety: the application hands us `num_buf` valid entries.
+        _ => unsafe { core::slice::from_raw_parts(buf_list, num_buf) },
+    };
+
+    let mut data = Vec::new();
+    for buf in bufs {
+        if buf.length == 0 {
+            continue;
+        }
+        if buf.addr.is_null() {
+            return batch.fail(VerbsError::InvalidArgument);
+        }
+        // Safety: the buffer is readable until the call returns, hence the copy.
+        data.extend_from_slice(unsafe { core::slice::from_raw_parts(buf.addr.cast::<u8>(), buf.length) });
+    }
+    batch.set_payload(Payload::Inline(data));
+}
+
+unsafe extern "C" fn wr_set_ud_addr(qp: *mut ffi::ibv_qp_ex, ah: *mut ffi::ibv_ah, remote_qpn: u32, remote_qkey: u32) {
+    unsafe { batch(qp) }.set_ud_addr(UdAddr {
+        ah,
+        remote_qpn,
+        remote_qkey,
+    });
+}
+
+unsafe extern "C" fn destroy_qp<P: Provider>(qp: *mut ffi::ibv_qp) -> c_int {
+    let provider = unsafe { provider::<P>((*qp).context) };
+
+    errno(call(provider, "destroy_qp", |provider| {
+        let inner = unsafe { Qp::<P>::inner(qp.cast()) };
+        provider.destroy_qp(inner)?;
+        Profile::release_qp(provider);
+        let reported = unregister(provider, Scope::Qp, inner.qp_num());
+        unsafe {
+            events::wait_acked(
+                &raw mut (*qp).mutex,
+                &raw mut (*qp).cond,
+                &raw const (*qp).events_completed,
+                reported,
+            )
+        };
+        unsafe { Qp::<P>::free(qp.cast()) };
+        Ok(())
+    }))
+}
+
+unsafe extern "C" fn modify_qp<P: Provider>(
+    qp: *mut ffi::ibv_qp,
+    attr: *mut ffi::ibv_qp_attr,
+    attr_mask: c_int,
+) -> c_int {
+    let provider = unsafe { provider::<P>((*qp).context) };
+
+    errno(call(provider, "modify_qp", |provider| {
+        let attr = unsafe { out(attr) }?;
+        let attr_mask = ffi::ibv_qp_attr_mask(attr_mask as _);
+        unsafe { qp_state::check_modify((*qp).qp_type, (*qp).state, attr, attr_mask) }?;
+        Profile::current().check_modify_qp(attr, attr_mask, || {
+            // urdma devices have a single port
+            let mut port_attr = ffi::ibv_port_attr::default();
+            provider.query_port(1, &mut port_attr)?;
+            Ok(port_attr.max_mtu)
+        })?;
+        if (attr_mask & ffi::ibv_qp_attr_mask::IBV_QP_AV).0 != 0 {
+            check_sgid(provider, unsafe { (*qp).context }, &attr.ah_attr)?;
+        }
+
+        provider.modify_qp(unsafe { Qp::<P>::inner(qp.cast()) }, attr, attr_mask)?;
+        if (attr_mask & ffi::ibv_qp_attr_mask::IBV_QP_STATE).0 != 0 {
+            unsafe { (*qp).state = attr.qp_state };
+        }
+        Ok(())
+    }))
+}
+
+unsafe extern "C" fn query_qp<P: Provider>(
+    qp: *mut ffi::ibv_qp,
+    attr: *mut ffi::ibv_qp_attr,
+    attr_mask: c_int,
+    init_attr: *mut ffi::ibv_qp_init_attr,
+) -> c_int {
+    let provider = unsafe { provider::<P>((*qp).context) };
+
+    errno(call(provider, "query_qp", |provider| {
+        let init_attr = unsafe { out(init_attr) }?;
+        let attr = unsafe { out(attr) }?;
+        let attr_mask = ffi::ibv_qp_attr_mask(attr_mask as _);
+
+        provider.query_qp(unsafe { Qp::<P>::inner(qp.cast()) }, attr, attr_mask, init_attr)?;
+
+        // the backend may have moved the QP on its own, e.g. to ERR
+        if (attr_mask & ffi::ibv_qp_attr_mask::IBV_QP_STATE).0 != 0 {
+            unsafe { (*qp).state = attr.qp_state };
+        }
+
+        // object pointers must be the ones owned by the application, not the backend ones
+        unsafe {
+            init_attr.qp_context = (*qp).qp_context;
+            init_attr.send_cq = (*qp).send_cq;
+            init_attr.recv_cq = (*qp).recv_cq;
+            init_attr.srq = (*qp).srq;
+        }
+        Ok(())
+    }))
+}
+
+unsafe extern "C" fn reg_mr<P: Provider>(
+    pd: *mut ffi::ibv_pd,
+    addr: *mut c_void,
+    length: usize,
+    hca_va: u64,
+    access: c_int,
+) -> *mut ffi::ibv_mr {
+    let context = unsafe { (*pd).context };
+    let provider = unsafe { provider::<P>(context) };
+
+    ptr_or_errno(call(provider, "reg_mr", |provider| {
+        Profile::current().check_mr(length)?;
+        let mr = provider.reg_mr(
+            unsafe { Pd::<P>::inner(pd) },
+            addr,
+            length,
+            hca_va,
+            ffi::ibv_access_flags(access as _),
+        )?;
+        Ok(unsafe { new_mr::<P>(pd, addr, length, ffi::IBV_MR_TYPE_MR, access, mr) })
+    }))
+}
+
+unsafe extern "C" fn reg_dmabuf_mr<P: Provider>(
+    pd: *mut ffi::ibv_pd,
+    offset: u64,
+    length: usize,
+    iova: u64,
+    fd: c_int,
+    access: c_int,
+) -> *mut ffi::ibv_mr {
+    let provider = unsafe { provider::<P>((*pd).context) };
+
+    ptr_or_errno(call(provider, "reg_dmabuf_mr", |provider| {
+        Profile::current().check_mr(length)?;
+        let mr = provider.reg_dmabuf_mr(
+            unsafe { Pd::<P>::inner(pd) },
+            offset,
+            length,
+            iova,
+            fd,
+            ffi::ibv_access_flags(access as _),
+        )?;
+        // like the kernel providers, a dma-buf region reports its offset as address
+        let addr = offset as usize as *mut c_void;
+        Ok(unsafe { new_mr::<P>(pd, addr, length, ffi::IBV_MR_TYPE_DMABUF_MR, access, mr) })
+    }))
+}
+
+/// Wrap the region `mr` registered on `pd`.
+///
+/// Safety: `pd` must point to a live PD.
+unsafe fn new_mr<P: Provider>(
+    pd: *mut ffi::ibv_pd,
+    addr: *mut c_void,
+    length: usize,
+    mr_type: ffi::ibv_mr_type,
+    access: c_int,
+    mr: P::Mr,
+) -> *mut ffi::ibv_mr {
+    Mr::<P>::into_raw(
+        ffi::verbs_mr {
+            ibv_mr: ffi::ibv_mr {
+                context: unsafe { (*pd).context },
+                pd,
+                addr,
+                length,
+                lkey: mr.lkey(),
+                rkey: mr.rkey(),
+                ..Default::default()
+            },
+            mr_type,
+            access,
+        },
+        mr,
+    )
+    .cast()
+}
+
+unsafe extern "C" fn dereg_mr<P: Provider>(vmr: *mut ffi::verbs_mr) -> c_int {
+    let provider = unsafe { provider::<P>((*vmr).ibv_mr.context) };
+
+    errno(call(provider, "dereg_mr", |provider| {
+        provider.dereg_mr(unsafe { Mr::<P>::inner(vmr) })?;
+        unsafe { Mr::<P>::free(vmr) };
+        Ok(())
+    }))
+}
+
+unsafe extern "C" fn rereg_mr<P: Provider>(
+    vmr: *mut ffi::verbs_mr,
+    flags: c_int,
+    pd: *mut ffi::ibv_pd,
+    addr: *mut c_void,
+    length: usize,
+    access: c_int,
+) -> c_int {
+    let context = unsafe { (*vmr).ibv_mr.context };
+    let provider = unsafe { provider::<P>(context) };
+
+    errno(call(provider, "rereg_mr", |provider| {
+        let supported =
+            ffi::IBV_REREG_MR_CHANGE_TRANSLATION | ffi::IBV_REREG_MR_CHANGE_PD | ffi::IBV_REREG_MR_CHANGE_ACCESS;
+        let flags = flags as ffi::ibv_rereg_mr_flags;
+        if flags & !supported != 0 {
+            return Err(VerbsError::NotSupported);
+        }
+        let has = |flag: ffi::ibv_rereg_mr_flags| flags & flag != 0;
+
+        let pd = if !has(ffi::IBV_REREG_MR_CHANGE_PD) {
+            None
+        } else if unsafe { on_context(pd, context) } {
+            Some(unsafe { Pd::<P>::inner(pd) })
+        } else {
+            return Err(VerbsError::InvalidArgument);
+        };
+        let access = has(ffi::IBV_REREG_MR_CHANGE_ACCESS).then_some(access);
+        if has(ffi::IBV_REREG_MR_CHANGE_TRANSLATION) {
+            Profile::current().check_mr(length)?;
+        }
+
+        let mr = unsafe { Mr::<P>::inner(vmr) };
+        provider.rereg_mr(
+            mr,
+            &MrRereg {
+                pd,
+                translation: has(ffi::IBV_REREG_MR_CHANGE_TRANSLATION).then_some((addr, length)),
+                access: access.map(|access| ffi::ibv_access_flags(access as _)),
+            },
+        )?;
+
+        // libibverbs updates the PD and the translation once we succeed, the keys and access are ours to update
+        unsafe {
+            (*vmr).ibv_mr.lkey = mr.lkey();
+            (*vmr).ibv_mr.rkey = mr.rkey();
+            if let Some(access) = access {
+                (*vmr).access = access;
+            }
+        }
+        Ok(())
+    }))
+}
+
+/// Check the source GID index of the address vector `ah_attr` is in the GID table of its port.
+fn check_sgid<P: Provider>(provider: &P, context: *mut ffi::ibv_context, ah_attr: &ffi::ibv_ah_attr) -> Result {
+    if ah_attr.is_global == 0 {
+        return Ok(());
+    }
+
+    let sgid_index = u32::from(ah_attr.grh.sgid_index);
+    let found = state(provider, context).has_gid(ah_attr.port_num, sgid_index, || {
+        provider.query_gid_table(ah_attr.port_num)
+    })?;
+    if !found {
+        log::debug!("no GID {sgid_index} on port {}", ah_attr.port_num);
+        return Err(VerbsError::InvalidArgument);
+    }
+    Ok(())
+}
+
+unsafe extern "C" fn create_ah<P: Provider>(pd: *mut ffi::ibv_pd, attr: *mut ffi::ibv_ah_attr) -> *mut ffi::ibv_ah {
+    let context = unsafe { (*pd).context };
+    let provider = unsafe { provider::<P>(context) };
+
+    ptr_or_errno(call(provider, "create_ah", |provider| {
+        let attr = unsafe { out(attr) }?;
+        check_sgid(provider, context, attr)?;
+
+        let ah = provider.create_ah(unsafe { Pd::<P>::inner(pd) }, attr)?;
+        Ok(Ah::<P>::into_raw(
+            ffi::ibv_ah {
+                context,
+                pd,
+                ..Default::default()
+            },
+            ah,
+        ))
+    }))
+}
+
+unsafe extern "C" fn destroy_ah<P: Provider>(ah: *mut ffi::ibv_ah) -> c_int {
+    let provider = unsafe { provider::<P>((*ah).context) };
+
+    errno(call(provider, "destroy_ah", |provider| {
+        provider.destroy_ah(unsafe { Ah::<P>::inner(ah) })?;
+        unsafe { Ah::<P>::free(ah) };
+        Ok(())
+    }))
+}
+
+unsafe extern "C" fn alloc_mw<P: Provider>(pd: *mut ffi::ibv_pd, mw_type: ffi::ibv_mw_type) -> *mut ffi::ibv_mw {
+    let context = unsafe { (*pd).context };
+    let provider = unsafe { provider::<P>(context) };
+
+    ptr_or_errno(call(provider, "alloc_mw", |provider| {
+        if mw_type != ffi::IBV_MW_TYPE_1 && mw_type != ffi::IBV_MW_TYPE_2 {
+            return Err(VerbsError::InvalidArgument);
+        }
+
+        let mw = provider.alloc_mw(unsafe { Pd::<P>::inner(pd) }, mw_type)?;
+        Ok(Mw::<P>::into_raw(
+            ffi::ibv_mw {
+                context,
+                pd,
+                rkey: mw.rkey(),
+                type_: mw_type,
+                ..Default::default()
+            },
+            mw,
+        ))
+    }))
+}
+
+unsafe extern "C" fn dealloc_mw<P: Provider>(mw: *mut ffi::ibv_mw) -> c_int {
+    let provider = unsafe { provider::<P>((*mw).context) };
+
+    errno(call(provider, "dealloc_mw", |provider| {
+        provider.dealloc_mw(unsafe { Mw::<P>::inner(mw) })?;
+        unsafe { Mw::<P>::free(mw) };
+        Ok(())
+    }))
+}
+
+unsafe extern "C" fn bind_mw<P: Provider>(
+    qp: *mut ffi::ibv_qp,
+    mw: *mut ffi::ibv_mw,
+    mw_bind: *mut ffi::ibv_mw_bind,
+) -> c_int {
+    let provider = unsafe { provider::<P>((*qp).context) };
+
+    errno(call(provider, "bind_mw", |provider| {
+        let mw_bind = unsafe { out(mw_bind) }?;
+        let request = BindRequest {
+            wr_id: mw_bind.wr_id,
+            flags: ffi::ibv_send_flags(mw_bind.send_flags),
+            mw,
+            rkey: None,
+            bind_info: mw_bind.bind_info,
+        };
+        unsafe { post::<P>(provider, qp, &Request::BindMw(request)) }
+    }))
+}
+
+/// Post `request` to the send queue of `qp` once the request is checked, resolving the address handle of a UD send
+/// and the window and region of a bind.
+///
+/// Safety: `qp` must be a live QP object, the objects `request` refers to must be null or live objects.
+unsafe fn post<P: Provider>(provider: &P, qp: *mut ffi::ibv_qp, request: &Request) -> Result {
+    let inner = unsafe { Qp::<P>::inner(qp.cast()) };
+    let context = unsafe { (*qp).context };
+    let qp_type = unsafe { (*qp).qp_type };
+    if let Request::Send { wr, .. } = request {
+        wr.check(qp_type)?;
+        Profile::current().check_send(wr)?;
+    }
+    let bind = match request {
+        // only UD QPs are addressed per request
+        Request::Send { wr, .. } if qp_type != ffi::ibv_qp_type::IBV_QPT_UD => {
+            return provider.post_send(inner, wr, None);
+        }
+        Request::Send { wr, ud } => {
+            let ud = ud.ok_or(VerbsError::InvalidArgument)?;
+            if unsafe { !on_context(ud.ah, context) } {
+                return Err(VerbsError::InvalidArgument);
+            }
+            let dest = UdDest {
+                ah: unsafe { Ah::<P>::inner(ud.ah) },
+                remote_qpn: ud.remote_qpn,
+                remote_qkey: ud.remote_qkey,
+            };
+            return provider.post_send(inner, wr, Some(&dest));
+        }
+        Request::BindMw(bind) => bind,
+    };
+
+    // objects of another device must not be resolved as ours, and each window type has its own verb
+    if unsafe { !on_context(bind.mw, context) || (*bind.mw).type_ != bind.mw_type() } {
+        return Err(VerbsError::InvalidArgument);
+    }
+    let info = &bind.bind_info;
+    let mr = if info.mr.is_null() && info.length == 0 {
+        None
+    } else if unsafe { on_context(info.mr, context) } {
+        Some(unsafe { Mr::<P>::inner(info.mr.cast()) })
+    } else {
+        return Err(VerbsError::InvalidArgument);
+    };
+
+    let rkey = provider.bind_mw(
+        inner,
+        unsafe { Mw::<P>::inner(bind.mw) },
+        &MwBind {
+            wr_id: bind.wr_id,
+            flags: bind.flags,
+            mr,
+            addr: info.addr,
+            length: info.length,
+            access: ffi::ibv_access_flags(info.mw_access_flags as _),
+            rkey: bind.rkey,
+        },
+    )?;
+    // applications read the key of a bound window from it, type 1 windows only learn theirs here
+    unsafe { (*bind.mw).rkey = rkey };
+    Ok(())
+}
+
+unsafe extern "C" fn post_send<P: Provider>(
+    qp: *mut ffi::ibv_qp,
+    wr: *mut ffi::ibv_send_wr,
+    bad_wr: *mut *mut ffi::ibv_send_wr,
+) -> c_int {
+    let provider = unsafe { provider::<P>((*qp).context) };
+
+    errno(call(provider, "post_send", |provider| {
+        let bad_wr = unsafe { out(bad_wr) }?;
+        let ud = unsafe { (*qp).qp_type } == ffi::ibv_qp_type::IBV_QPT_UD;
+
+        let mut cur = wr;
+        // Safety: the application hands us a valid, null terminated list.
+        while let Some(wr) = unsafe { cur.as_ref() } {
+            if let Err(err) =
+                unsafe { Request::from_ibv(wr, ud) }.and_then(|wr| unsafe { post::<P>(provider, qp, &wr) })
+            {
+                *bad_wr = cur;
+                return Err(err);
+            }
+            cur = wr.next;
+        }
+        Ok(())
+    }))
+}
+
+unsafe extern "C" fn post_recv<P: Provider>(
+    qp: *mut ffi::ibv_qp,
+    wr: *mut ffi::ibv_recv_wr,
+    bad_wr: *mut *mut ffi::ibv_recv_wr,
+) -> c_int {
+    let provider = unsafe { provider::<P>((*qp).context) };
+
+    errno(call(provider, "post_recv", |provider| {
+        provider.post_recv(unsafe { Qp::<P>::inner(qp.cast()) }, wr, unsafe { out(bad_wr) }?)
+    }))
+}
+
+unsafe extern "C" fn poll_cq<P: Provider>(cq: *mut ffi::ibv_cq, num_entries: c_int, wc: *mut ffi::ibv_wc) -> c_int {
+    let provider = unsafe { provider::<P>((*cq).context) };
+
+    let polled = call(provider, "poll_cq", |provider| {
+        let wc = match usize::try_from(num_entries) {
+            Ok(0) => &mut [][..],
+            Ok(_) if wc.is_null() => return Err(VerbsError::InvalidArgument),
+            Ok(len) => unsafe { core::slice::from_raw_parts_mut(wc, len) },
+            Err(_) => return Err(VerbsError::InvalidArgument),
+        };
+
+        // completions the extended polling API fetched already go first
+        let len = wc.len();
+        let mut buffered = 0;
+        for (wc, completion) in wc.iter_mut().zip(unsafe { CqHead::polled(cq) }.drain(len)) {
+            *wc = completion.wc;
+            buffered += 1;
+        }
+
+        let polled = match &mut wc[buffered..] {
+            [] => 0,
+            rest => match provider.poll_cq(unsafe { Cq::<P>::inner(cq.cast()) }, rest) {
+                Ok(polled) => polled.min(rest.len()),
+                // the buffered completions are gone already, the error shows up on the next poll
+                Err(_) if buffered > 0 => 0,
+                Err(err) => return Err(err),
+            },
+        };
+        // never report more than the caller asked for, which fits as it came from `num_entries`
+        Ok((buffered + polled) as c_int)
+    });
+
+    // the completion count, or a negative errno
+    polled.unwrap_or_else(|err| -err.errno())
+}
+
+unsafe extern "C" fn create_srq<P: Provider>(
+    pd: *mut ffi::ibv_pd,
+    init_attr: *mut ffi::ibv_srq_init_attr,
+) -> *mut ffi::ibv_srq {
+    let context = unsafe { (*pd).context };
+    let provider = unsafe { provider::<P>(context) };
+
+    ptr_or_errno(call(provider, "create_srq", |provider| {
+        let init_attr = unsafe { out(init_attr) }?;
+        Profile::current().check_sge(init_attr.attr.max_sge)?;
+
+        let srq = provider.create_srq(unsafe { Pd::<P>::inner(pd) }, &mut init_attr.attr)?;
+        let srq_num = srq.srq_num();
+        let srq = Srq::<P>::into_raw(
+            ffi::ibv_srq {
+                context,
+                srq_context: init_attr.srq_context,
+                pd,
+                ..Default::default()
+            },
+            srq,
+        );
+        register(provider, Scope::Srq, srq_num, context, srq);
+        Ok(srq)
+    }))
+}
+
+unsafe extern "C" fn modify_srq<P: Provider>(
+    srq: *mut ffi::ibv_srq,
+    attr: *mut ffi::ibv_srq_attr,
+    attr_mask: c_int,
+) -> c_int {
+    let provider = unsafe { provider::<P>((*srq).context) };
+
+    errno(call(provider, "modify_srq", |provider| {
+        provider.modify_srq(
+            unsafe { Srq::<P>::inner(srq) },
+            unsafe { out(attr) }?,
+            ffi::ibv_srq_attr_mask(attr_mask as _),
+        )
+    }))
+}
+
+unsafe extern "C" fn query_srq<P: Provider>(srq: *mut ffi::ibv_srq, attr: *mut ffi::ibv_srq_attr) -> c_int {
+    let provider = unsafe { provider::<P>((*srq).context) };
+
+    errno(call(provider, "query_srq", |provider| {
+        provider.query_srq(unsafe { Srq::<P>::inner(srq) }, unsafe { out(attr) }?)
+    }))
+}
+
+unsafe extern "C" fn destroy_srq<P: Provider>(srq: *mut ffi::ibv_srq) -> c_int {
+    let provider = unsafe { provider::<P>((*srq).context) };
+
+    errno(call(provider, "destroy_srq", |provider| {
+        let inner = unsafe { Srq::<P>::inner(srq) };
+        provider.destroy_srq(inner)?;
+        let reported = unregister(provider, Scope::Srq, inner.srq_num());
+        unsafe {
+            events::wait_acked(
+                &raw mut (*srq).mutex,
+                &raw mut (*srq).cond,
+                &raw const (*srq).events_completed,
+                reported,
+            );
+        }
+        unsafe { Srq::<P>::free(srq) };
+        Ok(())
+    }))
+}
+
+unsafe extern "C" fn post_srq_recv<P: Provider>(
+    srq: *mut ffi::ibv_srq,
+    wr: *mut ffi::ibv_recv_wr,
+    bad_wr: *mut *mut ffi::ibv_recv_wr,
+) -> c_int {
+    let provider = unsafe { provider::<P>((*srq).context) };
+
+    errno(call(provider, "post_srq_recv", |provider| {
+        provider.post_srq_recv(unsafe { Srq::<P>::inner(srq) }, wr, unsafe { out(bad_wr) }?)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Capabilities;
+
+    struct Partial;
+
+    struct Handle;
+
+    impl QueuePair for Handle {
+        fn qp_num(&self) -> u32 {
+            0
+        }
+    }
+
+    impl CompletionQueue for Handle {
+        fn cq_num(&self) -> u32 {
+            0
+        }
+    }
+
+    impl SharedReceiveQueue for Handle {
+        fn srq_num(&self) -> u32 {
+            0
+        }
+    }
+
+    impl MemoryRegion for Handle {
+        fn lkey(&self) -> u32 {
+            0
+        }
+
+        fn rkey(&self) -> u32 {
+            0
+        }
+    }
+
+    impl Provider for Partial {
+        type Ah = Handle;
+        type Cq = Handle;
+        type Mr = Handle;
+        type Mw = core::convert::Infallible;
+        type Pd = Handle;
+        type Qp = Handle;
+        type Srq = Handle;
+
+        fn init() -> Result {
+            Ok(())
+        }
+
+        unsafe fn from_ibv_device(_ibdev: *mut ffi::ibv_device) -> Result<*const Self> {
+            unreachable!("the test provider is not registered with libibverbs")
+        }
+
+        fn new(_sysfs_name: &str, _events: AsyncEvents) -> Result<Arc<Self>> {
+            Ok(Arc::new(Self))
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                verbs: Verbs::ALL.without(Verbs::QUERY_QP | Verbs::QUERY_DEVICE),
+                qp_types: &[ffi::ibv_qp_type::IBV_QPT_RC],
+            }
+        }
+
+        /// Three completions with `wr_id` 0 to 2 on every call
+        fn poll_cq(&self, _cq: &Handle, wc: &mut [ffi::ibv_wc]) -> Result<usize> {
+            let polled = wc.len().min(3);
+            for (wr_id, wc) in wc[..polled].iter_mut().enumerate() {
+                *wc = ffi::ibv_wc::new(wr_id as u64, ffi::ibv_wc_status::IBV_WC_SUCCESS, 0, 0);
+            }
+            Ok(polled)
+        }
+    }
+
+    #[test]
+    fn only_supported_verbs_are_installed() {
+        let provider = Arc::new(Partial);
+
+        let ops = unsafe { device_context_ops::<Partial>(Arc::as_ptr(&provider).cast()) };
+
+        assert!(ops.alloc_pd.is_some());
+        assert!(ops.post_send.is_some());
+        assert!(ops.query_qp.is_none());
+        assert!(ops.query_device_ex.is_none());
+        assert!(ops.create_cq_ex.is_some());
+        assert!(ops.create_qp_ex.is_some());
+        assert!(ops.bind_mw.is_some());
+        assert!(ops.rereg_mr.is_some());
+        assert!(ops.reg_dmabuf_mr.is_some());
+
+        let caps = provider.capabilities();
+        assert!(caps.supports_qp_type(ffi::ibv_qp_type::IBV_QPT_RC));
+        assert!(!caps.supports_qp_type(ffi::ibv_qp_type::IBV_QPT_UD));
+    }
+
+    #[test]
+    fn abi_v1_contexts_are_set_up_on_first_use() {
+        // the device is unknown, so every verb is installed
+        let ops = context_ops::<Partial>();
+        assert!(ops.query_qp.is_some());
+        assert!(ops.query_device_ex.is_some());
+
+        let mut context = ffi::ibv_context::default();
+        assert!(Context::of(&raw mut context).is_err());
+        let caps = state(&Partial, &raw mut context).caps;
+        assert!(!caps.verbs.contains(Verbs::QUERY_QP));
+        assert!(Context::of(&raw mut context).is_ok());
+        Context::close(&raw mut context);
+    }
+
+    #[test]
+    fn completions_are_fetched_in_batches() {
+        let mut polled = completion::Polled::default();
+
+        let wr_ids: Vec<_> = Completions::new(&Partial, &Handle, &mut polled)
+            .take(4)
+            .map(|completion| completion.unwrap().wc.wr_id())
+            .collect();
+        assert_eq!(wr_ids, [0, 1, 2, 0]);
+
+        // the rest of the second batch stays buffered
+        let buffered: Vec<_> = polled.drain(8).map(|completion| completion.wc.wr_id()).collect();
+        assert_eq!(buffered, [1, 2]);
+        assert_eq!(polled.drain(8).count(), 0);
+    }
+}
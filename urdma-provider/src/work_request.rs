@@ -0,0 +1,558 @@
+//! Send work requests
+//!
+//! Backends receive every send request as a [`SendWr`], whichever API the application posted it with. The glue
+//! converts the `ibv_send_wr` lists of `ibv_post_send`, and QPs created with send ops collect the requests the
+//! `ibv_wr_*` callbacks build in a [`Batch`] until `ibv_wr_complete` posts them. Binds of type 2 memory windows are
+//! posted the same way, as a [`Request::BindMw`] the glue resolves to [`crate::MwBind`], and so are the address
+//! handles of UD requests. Every QP the glue creates starts with a `verbs_qp`, whose head is the `ibv_qp` the legacy
+//! verbs see.
+
+use std::sync::{Mutex, MutexGuard};
+
+use crate::{Result, VerbsError};
+
+/// `IBV_QP_EX_WITH_*` operations [`SendOp`] carries
+pub(crate) const SEND_OPS_FLAGS: u64 = (ffi::IBV_QP_EX_WITH_RDMA_WRITE
+    | ffi::IBV_QP_EX_WITH_RDMA_WRITE_WITH_IMM
+    | ffi::IBV_QP_EX_WITH_SEND
+    | ffi::IBV_QP_EX_WITH_SEND_WITH_IMM
+    | ffi::IBV_QP_EX_WITH_RDMA_READ
+    | ffi::IBV_QP_EX_WITH_ATOMIC_CMP_AND_SWP
+    | ffi::IBV_QP_EX_WITH_ATOMIC_FETCH_AND_ADD
+    | ffi::IBV_QP_EX_WITH_LOCAL_INV
+    | ffi::IBV_QP_EX_WITH_BIND_MW
+    | ffi::IBV_QP_EX_WITH_SEND_WITH_INV) as u64;
+
+/// `IBV_QP_EX_WITH_*` operations on memory windows, only for backends supporting them
+pub(crate) const MW_SEND_OPS_FLAGS: u64 =
+    (ffi::IBV_QP_EX_WITH_LOCAL_INV | ffi::IBV_QP_EX_WITH_BIND_MW | ffi::IBV_QP_EX_WITH_SEND_WITH_INV) as u64;
+
+/// Remote memory targeted by an RDMA or atomic operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Remote {
+    pub addr: u64,
+    pub rkey: u32,
+}
+
+/// Operation of a send request
+///
+/// Immediate data stays in network byte order, as the application passed it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendOp {
+    Send,
+    SendWithImm(u32),
+    RdmaWrite(Remote),
+    RdmaWriteWithImm(Remote, u32),
+    RdmaRead(Remote),
+    AtomicCmpAndSwp {
+        remote: Remote,
+        compare: u64,
+        swap: u64,
+    },
+    AtomicFetchAndAdd {
+        remote: Remote,
+        add: u64,
+    },
+    /// invalidate the memory window with this rkey, locally
+    LocalInv(u32),
+    /// send, then invalidate the responder's memory window with this rkey
+    SendWithInv(u32),
+}
+
+impl SendOp {
+    /// Opcode of the requester's completion.
+    pub fn wc_opcode(&self) -> ffi::ibv_wc_opcode::Type {
+        match self {
+            Self::Send | Self::SendWithImm(_) | Self::SendWithInv(_) => ffi::ibv_wc_opcode::IBV_WC_SEND,
+            Self::RdmaWrite(_) | Self::RdmaWriteWithImm(..) => ffi::ibv_wc_opcode::IBV_WC_RDMA_WRITE,
+            Self::RdmaRead(_) => ffi::ibv_wc_opcode::IBV_WC_RDMA_READ,
+            Self::AtomicCmpAndSwp { .. } => ffi::ibv_wc_opcode::IBV_WC_COMP_SWAP,
+            Self::AtomicFetchAndAdd { .. } => ffi::ibv_wc_opcode::IBV_WC_FETCH_ADD,
+            Self::LocalInv(_) => ffi::ibv_wc_opcode::IBV_WC_LOCAL_INV,
+        }
+    }
+
+    /// Opcode of the equivalent `ibv_send_wr`.
+    pub fn wr_opcode(&self) -> ffi::ibv_wr_opcode::Type {
+        match self {
+            Self::Send => ffi::ibv_wr_opcode::IBV_WR_SEND,
+            Self::SendWithImm(_) => ffi::ibv_wr_opcode::IBV_WR_SEND_WITH_IMM,
+            Self::RdmaWrite(_) => ffi::ibv_wr_opcode::IBV_WR_RDMA_WRITE,
+            Self::RdmaWriteWithImm(..) => ffi::ibv_wr_opcode::IBV_WR_RDMA_WRITE_WITH_IMM,
+            Self::RdmaRead(_) => ffi::ibv_wr_opcode::IBV_WR_RDMA_READ,
+            Self::AtomicCmpAndSwp { .. } => ffi::ibv_wr_opcode::IBV_WR_ATOMIC_CMP_AND_SWP,
+            Self::AtomicFetchAndAdd { .. } => ffi::ibv_wr_opcode::IBV_WR_ATOMIC_FETCH_AND_ADD,
+            Self::LocalInv(_) => ffi::ibv_wr_opcode::IBV_WR_LOCAL_INV,
+            Self::SendWithInv(_) => ffi::ibv_wr_opcode::IBV_WR_SEND_WITH_INV,
+        }
+    }
+
+    /// Immediate data delivered with the message, in network byte order.
+    pub fn imm_data(&self) -> Option<u32> {
+        match *self {
+            Self::SendWithImm(imm_data) | Self::RdmaWriteWithImm(_, imm_data) => Some(imm_data),
+            _ => None,
+        }
+    }
+
+    /// rkey of the memory window the operation invalidates.
+    pub fn invalidate_rkey(&self) -> Option<u32> {
+        match *self {
+            Self::LocalInv(rkey) | Self::SendWithInv(rkey) => Some(rkey),
+            _ => None,
+        }
+    }
+
+    /// Remote memory the operation accesses.
+    pub fn remote(&self) -> Option<Remote> {
+        match *self {
+            Self::Send | Self::SendWithImm(_) | Self::LocalInv(_) | Self::SendWithInv(_) => None,
+            Self::RdmaWrite(remote)
+            | Self::RdmaWriteWithImm(remote, _)
+            | Self::RdmaRead(remote)
+            | Self::AtomicCmpAndSwp { remote, .. }
+            | Self::AtomicFetchAndAdd { remote, .. } => Some(remote),
+        }
+    }
+}
+
+/// Local memory of a send request
+#[derive(Debug, Clone)]
+pub enum Payload {
+    /// gather list, or scatter list of RDMA reads and atomics, over registered memory
+    Sges(Vec<ffi::ibv_sge>),
+    /// `IBV_SEND_INLINE` data, copied when the request was posted
+    Inline(Vec<u8>),
+}
+
+impl Payload {
+    /// Bytes the request transfers.
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Sges(sges) => sges.iter().map(|sge| sge.length as usize).sum(),
+            Self::Inline(data) => data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Send work request
+#[derive(Debug, Clone)]
+pub struct SendWr {
+    pub wr_id: u64,
+    pub op: SendOp,
+    /// `IBV_SEND_*` flags, `IBV_SEND_INLINE` is never set, see [`Payload::Inline`]
+    pub flags: ffi::ibv_send_flags,
+    pub payload: Payload,
+}
+
+impl SendWr {
+    /// Whether the request generates a completion, regardless of `sq_sig_all`.
+    pub fn signaled(&self) -> bool {
+        self.flags.0 & ffi::ibv_send_flags::IBV_SEND_SIGNALED.0 != 0
+    }
+
+    /// Whether the responder's completion is solicited.
+    pub fn solicited(&self) -> bool {
+        self.flags.0 & ffi::ibv_send_flags::IBV_SEND_SOLICITED.0 != 0
+    }
+
+    /// Check the constraints of the operation on a QP of `qp_type`.
+    ///
+    /// Atomics only exist on RC QPs, target an 8 byte aligned remote address and return the original value into a
+    /// single 8 byte SGE.
+    pub(crate) fn check(&self, qp_type: ffi::ibv_qp_type::Type) -> Result {
+        if !matches!(
+            self.op,
+            SendOp::AtomicCmpAndSwp { .. } | SendOp::AtomicFetchAndAdd { .. }
+        ) {
+            return Ok(());
+        }
+        let aligned = self.op.remote().is_some_and(|remote| remote.addr % 8 == 0);
+        let single = matches!(&self.payload, Payload::Sges(sges) if matches!(sges[..], [sge] if sge.length == 8));
+        if qp_type != ffi::ibv_qp_type::IBV_QPT_RC || !aligned || !single {
+            log::debug!("Invalid atomic request {:#x}", self.wr_id);
+            return Err(VerbsError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    /// Convert a request of `ibv_post_send`, copying inline data.
+    ///
+    /// Safety: `wr.sg_list` must point to `wr.num_sge` entries, pointing to readable data if `IBV_SEND_INLINE` is
+    /// set.
+    pub(crate) unsafe fn from_ibv(wr: &ffi::ibv_send_wr) -> Result<Self> {
+        // Safety: the members are plain integers, the opcode tells which one is meaningful.
+        let (imm_data, invalidate_rkey, rdma, atomic) = unsafe {
+            (
+                wr.__bindgen_anon_1.imm_data,
+                wr.__bindgen_anon_1.invalidate_rkey,
+                wr.wr.rdma,
+                wr.wr.atomic,
+            )
+        };
+        let rdma = Remote {
+            addr: rdma.remote_addr,
+            rkey: rdma.rkey,
+        };
+        let atomic_remote = Remote {
+            addr: atomic.remote_addr,
+            rkey: atomic.rkey,
+        };
+
+        let op = match wr.opcode {
+            ffi::ibv_wr_opcode::IBV_WR_SEND => SendOp::Send,
+            ffi::ibv_wr_opcode::IBV_WR_SEND_WITH_IMM => SendOp::SendWithImm(imm_data),
+            ffi::ibv_wr_opcode::IBV_WR_RDMA_WRITE => SendOp::RdmaWrite(rdma),
+            ffi::ibv_wr_opcode::IBV_WR_RDMA_WRITE_WITH_IMM => SendOp::RdmaWriteWithImm(rdma, imm_data),
+            ffi::ibv_wr_opcode::IBV_WR_RDMA_READ => SendOp::RdmaRead(rdma),
+            ffi::ibv_wr_opcode::IBV_WR_ATOMIC_CMP_AND_SWP => SendOp::AtomicCmpAndSwp {
+                remote: atomic_remote,
+                compare: atomic.compare_add,
+                swap: atomic.swap,
+            },
+            ffi::ibv_wr_opcode::IBV_WR_ATOMIC_FETCH_AND_ADD => SendOp::AtomicFetchAndAdd {
+                remote: atomic_remote,
+                add: atomic.compare_add,
+            },
+            ffi::ibv_wr_opcode::IBV_WR_LOCAL_INV => SendOp::LocalInv(invalidate_rkey),
+            ffi::ibv_wr_opcode::IBV_WR_SEND_WITH_INV => SendOp::SendWithInv(invalidate_rkey),
+            opcode => {
+                log::debug!("Unsupported send opcode {opcode}");
+                return Err(VerbsError::InvalidArgument);
+            }
+        };
+
+        let sges = match usize::try_from(wr.num_sge) {
+            Ok(0) => &[][..],
+            Ok(_) if wr.sg_list.is_null() => return Err(VerbsError::InvalidArgument),
+            Ok(len) => unsafe { core::slice::from_raw_parts(wr.sg_list, len) },
+            Err(_) => return Err(VerbsError::InvalidArgument),
+        };
+        // reads, atomics and local invalidations have no data of their own to inline
+        let inline = wr.send_flags & ffi::ibv_send_flags::IBV_SEND_INLINE.0 != 0
+            && !matches!(
+                op,
+                SendOp::RdmaRead(_)
+                    | SendOp::AtomicCmpAndSwp { .. }
+                    | SendOp::AtomicFetchAndAdd { .. }
+                    | SendOp::LocalInv(_)
+            );
+        let payload = if inline {
+            let mut data = Vec::new();
+            for sge in sges {
+                // Safety: inline data is only read when posting, the application guarantees it is readable.
+                data.extend_from_slice(unsafe {
+                    core::slice::from_raw_parts(sge.addr as usize as *const u8, sge.length as usize)
+                });
+            }
+            Payload::Inline(data)
+        } else {
+            Payload::Sges(sges.to_vec())
+        };
+
+        Ok(Self {
+            wr_id: wr.wr_id,
+            op,
+            flags: ffi::ibv_send_flags(wr.send_flags & !ffi::ibv_send_flags::IBV_SEND_INLINE.0),
+            payload,
+        })
+    }
+}
+
+/// Destination of a UD request, as the application posted it
+///
+/// The address handle is only resolved when the request is posted, see [`crate::UdDest`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct UdAddr {
+    pub(crate) ah: *mut ffi::ibv_ah,
+    pub(crate) remote_qpn: u32,
+    pub(crate) remote_qkey: u32,
+}
+
+/// Bind of a type 1 or 2 memory window, as the application posted it
+///
+/// The window and the region are only resolved when the request is posted, see [`crate::MwBind`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BindRequest {
+    pub(crate) wr_id: u64,
+    pub(crate) flags: ffi::ibv_send_flags,
+    pub(crate) mw: *mut ffi::ibv_mw,
+    /// rkey the application chose, type 1 windows have theirs chosen by the backend
+    pub(crate) rkey: Option<u32>,
+    pub(crate) bind_info: ffi::ibv_mw_bind_info,
+}
+
+impl BindRequest {
+    /// Type of window the request binds, type 1 windows are bound by `ibv_bind_mw` only.
+    pub(crate) fn mw_type(&self) -> ffi::ibv_mw_type {
+        match self.rkey {
+            Some(_) => ffi::IBV_MW_TYPE_2,
+            None => ffi::IBV_MW_TYPE_1,
+        }
+    }
+}
+
+/// Request posted to a send queue
+#[derive(Debug, Clone)]
+pub(crate) enum Request {
+    /// send, with its destination if posted to a UD QP
+    Send {
+        wr: SendWr,
+        ud: Option<UdAddr>,
+    },
+    BindMw(BindRequest),
+}
+
+impl Request {
+    /// Convert a request of `ibv_post_send` to a QP, a UD one if `ud` is set, see [`SendWr::from_ibv`].
+    ///
+    /// Safety: as for [`SendWr::from_ibv`].
+    pub(crate) unsafe fn from_ibv(wr: &ffi::ibv_send_wr, ud: bool) -> Result<Self> {
+        if wr.opcode != ffi::ibv_wr_opcode::IBV_WR_BIND_MW {
+            // Safety: the members are plain integers and a pointer only resolved on posting, requests to UD QPs
+            // set `ud`.
+            let ud = ud.then_some(unsafe { wr.wr.ud }).map(|ud| UdAddr {
+                ah: ud.ah,
+                remote_qpn: ud.remote_qpn,
+                remote_qkey: ud.remote_qkey,
+            });
+            return unsafe { SendWr::from_ibv(wr) }.map(|wr| Self::Send { wr, ud });
+        }
+
+        // Safety: the opcode tells `bind_mw` is the meaningful member.
+        let bind_mw = unsafe { wr.__bindgen_anon_2.bind_mw };
+        Ok(Self::BindMw(BindRequest {
+            wr_id: wr.wr_id,
+            flags: ffi::ibv_send_flags(wr.send_flags),
+            mw: bind_mw.mw,
+            rkey: Some(bind_mw.rkey),
+            bind_info: bind_mw.bind_info,
+        }))
+    }
+}
+
+/// Requests built by the `ibv_wr_*` API of a QP and not posted yet
+#[derive(Default)]
+pub(crate) struct Batch {
+    wrs: Vec<Request>,
+    /// first misuse of the API since `ibv_wr_start`, reported by `ibv_wr_complete`
+    error: Option<VerbsError>,
+}
+
+impl Batch {
+    /// Start a new batch, dropping whatever the last one left.
+    pub(crate) fn start(&mut self) {
+        self.wrs.clear();
+        self.error = None;
+    }
+
+    /// Start building request `wr`, the payload of a send is set afterwards.
+    pub(crate) fn push(&mut self, wr: Request) {
+        self.wrs.push(wr);
+    }
+
+    /// Set the payload of the send built last.
+    pub(crate) fn set_payload(&mut self, payload: Payload) {
+        match self.wrs.last_mut() {
+            Some(Request::Send { wr, .. }) => wr.payload = payload,
+            // binds carry no payload
+            Some(Request::BindMw(_)) | None => self.fail(VerbsError::InvalidArgument),
+        }
+    }
+
+    /// Set the destination of the send built last.
+    pub(crate) fn set_ud_addr(&mut self, addr: UdAddr) {
+        match self.wrs.last_mut() {
+            Some(Request::Send { ud, .. }) => *ud = Some(addr),
+            Some(Request::BindMw(_)) | None => self.fail(VerbsError::InvalidArgument),
+        }
+    }
+
+    /// Remember the first error, the batch is rejected as a whole.
+    pub(crate) fn fail(&mut self, err: VerbsError) {
+        self.error.get_or_insert(err);
+    }
+
+    /// Take the requests to post, or the error that invalidated them.
+    pub(crate) fn take(&mut self) -> Result<Vec<Request>> {
+        let wrs = core::mem::take(&mut self.wrs);
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(wrs),
+        }
+    }
+}
+
+/// Head of every QP object, see the module documentation
+#[repr(C)]
+pub(crate) struct QpHead {
+    pub(crate) ibv: ffi::verbs_qp,
+    batch: Mutex<Batch>,
+}
+
+impl QpHead {
+    pub(crate) fn new(ibv: ffi::verbs_qp) -> Self {
+        Self {
+            ibv,
+            batch: Mutex::default(),
+        }
+    }
+
+    /// Requests built on the QP at `qp`.
+    ///
+    /// Safety: `qp` must be the `ibv_qp` or `ibv_qp_ex` of a live QP object.
+    pub(crate) unsafe fn batch<'a, T>(qp: *mut T) -> MutexGuard<'a, Batch> {
+        let head = unsafe { &*qp.cast::<Self>() };
+        head.batch.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_requests_are_converted() {
+        let mut data = *b"inline";
+        let mut sge = ffi::ibv_sge {
+            addr: data.as_mut_ptr() as u64,
+            length: data.len() as u32,
+            lkey: 0,
+        };
+        let mut wr = ffi::ibv_send_wr {
+            wr_id: 7,
+            sg_list: &raw mut sge,
+            num_sge: 1,
+            opcode: ffi::ibv_wr_opcode::IBV_WR_RDMA_WRITE_WITH_IMM,
+            send_flags: (ffi::ibv_send_flags::IBV_SEND_INLINE | ffi::ibv_send_flags::IBV_SEND_SIGNALED).0,
+            ..Default::default()
+        };
+        wr.__bindgen_anon_1.imm_data = 0x1234_u32.to_be();
+        wr.wr.rdma.remote_addr = 0x1000;
+        wr.wr.rdma.rkey = 3;
+
+        let converted = unsafe { SendWr::from_ibv(&wr) }.unwrap();
+        // the application may reuse its buffer once posted
+        data.fill(0);
+
+        assert_eq!(converted.wr_id, 7);
+        let remote = Remote { addr: 0x1000, rkey: 3 };
+        assert_eq!(converted.op, SendOp::RdmaWriteWithImm(remote, 0x1234_u32.to_be()));
+        assert!(converted.signaled());
+        assert_eq!(converted.flags.0 & ffi::ibv_send_flags::IBV_SEND_INLINE.0, 0);
+        assert!(matches!(&converted.payload, Payload::Inline(data) if data == b"inline"));
+
+        wr.opcode = ffi::ibv_wr_opcode::IBV_WR_TSO;
+        assert_eq!(
+            unsafe { SendWr::from_ibv(&wr) }.unwrap_err(),
+            VerbsError::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn misused_batches_are_rejected() {
+        let mut batch = Batch::default();
+
+        batch.start();
+        batch.set_payload(Payload::Inline(Vec::new()));
+        assert_eq!(batch.take().unwrap_err(), VerbsError::InvalidArgument);
+
+        batch.start();
+        batch.push(Request::Send {
+            wr: SendWr {
+                wr_id: 1,
+                op: SendOp::Send,
+                flags: ffi::ibv_send_flags(0),
+                payload: Payload::Sges(Vec::new()),
+            },
+            ud: None,
+        });
+        batch.set_payload(Payload::Inline(vec![1, 2]));
+        batch.set_ud_addr(UdAddr {
+            ah: core::ptr::null_mut(),
+            remote_qpn: 5,
+            remote_qkey: 0x1111,
+        });
+        let wrs = batch.take().unwrap();
+        assert_eq!(wrs.len(), 1);
+        assert!(matches!(&wrs[0], Request::Send { wr, ud: Some(ud) } if wr.payload.len() == 2 && ud.remote_qpn == 5));
+
+        batch.start();
+        batch.push(Request::BindMw(BindRequest {
+            wr_id: 2,
+            flags: ffi::ibv_send_flags(0),
+            mw: core::ptr::null_mut(),
+            rkey: Some(0x100),
+            bind_info: ffi::ibv_mw_bind_info::default(),
+        }));
+        batch.set_payload(Payload::Sges(Vec::new()));
+        assert_eq!(batch.take().unwrap_err(), VerbsError::InvalidArgument);
+    }
+
+    #[test]
+    fn memory_window_requests_are_converted() {
+        let mut wr = ffi::ibv_send_wr {
+            wr_id: 3,
+            opcode: ffi::ibv_wr_opcode::IBV_WR_SEND_WITH_INV,
+            ..Default::default()
+        };
+        wr.__bindgen_anon_1.invalidate_rkey = 0x42;
+        let converted = unsafe { SendWr::from_ibv(&wr) }.unwrap();
+        assert_eq!(converted.op, SendOp::SendWithInv(0x42));
+        assert_eq!(converted.op.invalidate_rkey(), Some(0x42));
+        assert_eq!(converted.op.wc_opcode(), ffi::ibv_wc_opcode::IBV_WC_SEND);
+
+        wr.opcode = ffi::ibv_wr_opcode::IBV_WR_BIND_MW;
+        wr.__bindgen_anon_2.bind_mw.rkey = 0x101;
+        wr.__bindgen_anon_2.bind_mw.bind_info.length = 64;
+        let Request::BindMw(bind) = unsafe { Request::from_ibv(&wr, false) }.unwrap() else {
+            panic!("bind converted to a send");
+        };
+        assert_eq!(bind.wr_id, 3);
+        assert_eq!(bind.rkey, Some(0x101));
+        assert_eq!(bind.mw_type(), ffi::IBV_MW_TYPE_2);
+        assert_eq!(bind.bind_info.length, 64);
+    }
+
+    #[test]
+    fn atomic_requests_are_checked() {
+        let sge = |length| ffi::ibv_sge {
+            addr: 0x2000,
+            length,
+            lkey: 1,
+        };
+        let mut wr = SendWr {
+            wr_id: 4,
+            op: SendOp::AtomicFetchAndAdd {
+                remote: Remote { addr: 0x1008, rkey: 2 },
+                add: 1,
+            },
+            flags: ffi::ibv_send_flags(0),
+            payload: Payload::Sges(vec![sge(8)]),
+        };
+        assert_eq!(wr.check(ffi::ibv_qp_type::IBV_QPT_RC), Ok(()));
+        assert_eq!(wr.check(ffi::ibv_qp_type::IBV_QPT_UC), Err(VerbsError::InvalidArgument));
+
+        wr.payload = Payload::Sges(vec![sge(4), sge(4)]);
+        assert_eq!(wr.check(ffi::ibv_qp_type::IBV_QPT_RC), Err(VerbsError::InvalidArgument));
+        wr.payload = Payload::Inline(vec![0; 8]);
+        assert_eq!(wr.check(ffi::ibv_qp_type::IBV_QPT_RC), Err(VerbsError::InvalidArgument));
+
+        wr.payload = Payload::Sges(vec![sge(8)]);
+        wr.op = SendOp::AtomicCmpAndSwp {
+            remote: Remote { addr: 0x1004, rkey: 2 },
+            compare: 0,
+            swap: 1,
+        };
+        assert_eq!(wr.check(ffi::ibv_qp_type::IBV_QPT_RC), Err(VerbsError::InvalidArgument));
+
+        // other operations are not constrained
+        wr.op = SendOp::Send;
+        wr.payload = Payload::Inline(vec![0; 3]);
+        assert_eq!(wr.check(ffi::ibv_qp_type::IBV_QPT_UD), Ok(()));
+    }
+}
@@ -1,6 +1,16 @@
 /* SPDX-License-Identifier: GPL-2.0 OR BSD-3-Clause */
 
 #include <linux/module.h>
+#include <linux/inetdevice.h>
+#include <linux/ip.h>
+#include <linux/netdevice.h>
+#include <linux/rtnetlink.h>
+#include <net/addrconf.h>
+#include <net/if_inet6.h>
+#include <net/ip.h>
+#include <net/ipv6.h>
+#include <net/net_namespace.h>
+#include <rdma/ib_cache.h>
 
 #include "urdma.h"
 
@@ -11,22 +21,339 @@ MODULE_LICENSE("Dual BSD/GPL");
 #define NUM_DEV 2
 static struct urdma_dev *urdma_devs[NUM_DEV] = {};
 
+static char *netdev;
+module_param(netdev, charp, 0444);
+MODULE_PARM_DESC(netdev,
+		 "Network device of the ports, whose addresses become GIDs");
+
+#pragma region gsi
+
+/*
+ * The kernel CM of a RoCE port talks through MADs on QP1. There is no wire
+ * below the urdma devices, so QP1 sends are delivered right away to QP1 of
+ * the urdma device holding the destination GID, which makes rdma_cm work
+ * between urdma devices of this host.
+ */
+
+static void urdma_cq_push(struct urdma_cq *cq, const struct ib_wc *wc)
+{
+	struct urdma_wc *entry;
+	unsigned long flags;
+	bool notify;
+
+	entry = kmalloc(sizeof(*entry), GFP_ATOMIC);
+	if (!entry) {
+		pr_err("completion lost\n");
+		return;
+	}
+	entry->wc = *wc;
+
+	spin_lock_irqsave(&cq->lock, flags);
+	list_add_tail(&entry->list, &cq->wcs);
+	notify = cq->armed;
+	cq->armed = false;
+	spin_unlock_irqrestore(&cq->lock, flags);
+
+	if (notify && cq->ibcq.comp_handler)
+		cq->ibcq.comp_handler(&cq->ibcq, cq->ibcq.cq_context);
+}
+
+static bool urdma_has_gid(struct urdma_dev *urdma, const union ib_gid *gid)
+{
+	const struct ib_gid_attr *attr;
+
+	if (!urdma || !urdma->roce || !rcu_access_pointer(urdma->gsi))
+		return false;
+	attr = rdma_find_gid_by_port(&urdma->ibdev, gid,
+				     IB_GID_TYPE_ROCE_UDP_ENCAP, 1, NULL);
+	if (IS_ERR(attr))
+		return false;
+	rdma_put_gid_attr(attr);
+	return true;
+}
+
+/*
+ * QP1 of the device holding `dgid`, the sending device first. Valid until
+ * rcu_read_unlock, destroying QP1 waits for a grace period.
+ */
+static struct urdma_qp *urdma_find_gsi(struct urdma_dev *from,
+				       const union ib_gid *dgid)
+{
+	int i;
+
+	if (urdma_has_gid(from, dgid))
+		return rcu_dereference(from->gsi);
+	for (i = 0; i < NUM_DEV; i++) {
+		if (urdma_devs[i] != from && urdma_has_gid(urdma_devs[i], dgid))
+			return rcu_dereference(urdma_devs[i]->gsi);
+	}
+	return NULL;
+}
+
+/*
+ * The first 40 bytes of a RoCEv2 receive hold the IP header, an IPv4 one in
+ * the last 20 bytes of them.
+ */
+static enum rdma_network_type urdma_write_hdr(void *buf,
+					      const struct urdma_ah *ah,
+					      size_t len)
+{
+	memset(buf, 0, sizeof(struct ib_grh));
+
+	if (ipv6_addr_v4mapped((const struct in6_addr *)&ah->dgid)) {
+		struct iphdr *iph = buf + sizeof(struct ib_grh) - sizeof(*iph);
+
+		iph->version = 4;
+		iph->ihl = 5;
+		iph->ttl = ah->hop_limit;
+		iph->protocol = IPPROTO_UDP;
+		iph->tot_len = htons(sizeof(*iph) + len);
+		memcpy(&iph->saddr, &ah->sgid.raw[12], sizeof(iph->saddr));
+		memcpy(&iph->daddr, &ah->dgid.raw[12], sizeof(iph->daddr));
+		iph->check = ip_fast_csum((u8 *)iph, iph->ihl);
+		return RDMA_NETWORK_IPV4;
+	} else {
+		struct ib_grh *grh = buf;
+
+		grh->version_tclass_flow = cpu_to_be32(6 << 28);
+		grh->paylen = cpu_to_be16(len);
+		grh->next_hdr = IPPROTO_UDP;
+		grh->hop_limit = ah->hop_limit;
+		grh->sgid = ah->sgid;
+		grh->dgid = ah->dgid;
+		return RDMA_NETWORK_IPV6;
+	}
+}
+
+/* sge addresses are kernel virtual addresses, the device uses virt DMA */
+static void urdma_gather(void *dst, const struct ib_sge *sge, int num_sge)
+{
+	int i;
+
+	for (i = 0; i < num_sge; i++) {
+		memcpy(dst, (void *)(uintptr_t)sge[i].addr, sge[i].length);
+		dst += sge[i].length;
+	}
+}
+
+static size_t urdma_scatter(const struct ib_sge *sge, int num_sge,
+			    const void *src, size_t len)
+{
+	size_t copied = 0;
+	int i;
+
+	for (i = 0; i < num_sge && copied < len; i++) {
+		size_t n = min_t(size_t, sge[i].length, len - copied);
+
+		memcpy((void *)(uintptr_t)sge[i].addr, src + copied, n);
+		copied += n;
+	}
+	return copied;
+}
+
+static int urdma_gsi_send(struct urdma_qp *qp, const struct ib_ud_wr *wr)
+{
+	struct urdma_ah *ah = to_uah(wr->ah);
+	struct urdma_recv *recv = NULL;
+	struct urdma_qp *dest;
+	struct ib_wc wc = {};
+	unsigned long flags;
+	size_t len = 0;
+	void *buf;
+	int i;
+
+	for (i = 0; i < wr->wr.num_sge; i++)
+		len += wr->wr.sg_list[i].length;
+	buf = kmalloc(sizeof(struct ib_grh) + len, GFP_ATOMIC);
+	if (!buf)
+		return -ENOMEM;
+
+	rcu_read_lock();
+	dest = urdma_find_gsi(to_udev(qp->ibqp.device), &ah->dgid);
+	if (dest) {
+		spin_lock_irqsave(&dest->lock, flags);
+		recv = list_first_entry_or_null(&dest->recvs,
+						struct urdma_recv, list);
+		if (recv)
+			list_del(&recv->list);
+		spin_unlock_irqrestore(&dest->lock, flags);
+	}
+
+	/* like on a wire, a MAD nobody receives is lost */
+	if (recv) {
+		wc.network_hdr_type = urdma_write_hdr(buf, ah, len);
+		urdma_gather(buf + sizeof(struct ib_grh), wr->wr.sg_list,
+			     wr->wr.num_sge);
+		wc.byte_len = urdma_scatter(recv->sge, recv->num_sge, buf,
+					    sizeof(struct ib_grh) + len);
+		wc.wr_id = recv->wr_id;
+		wc.status = IB_WC_SUCCESS;
+		wc.opcode = IB_WC_RECV;
+		wc.qp = &dest->ibqp;
+		wc.src_qp = qp->ibqp.qp_num;
+		wc.pkey_index = wr->pkey_index;
+		wc.port_num = 1;
+		wc.wc_flags = IB_WC_GRH | IB_WC_WITH_NETWORK_HDR_TYPE;
+		urdma_cq_push(to_ucq(dest->ibqp.recv_cq), &wc);
+		kfree(recv);
+	}
+	rcu_read_unlock();
+	kfree(buf);
+
+	if (qp->sq_sig_all || (wr->wr.send_flags & IB_SEND_SIGNALED)) {
+		memset(&wc, 0, sizeof(wc));
+		wc.wr_id = wr->wr.wr_id;
+		wc.status = IB_WC_SUCCESS;
+		wc.opcode = IB_WC_SEND;
+		wc.qp = &qp->ibqp;
+		urdma_cq_push(to_ucq(qp->ibqp.send_cq), &wc);
+	}
+	return 0;
+}
+
+#pragma endregion gsi
+
+#pragma region gids
+
+/*
+ * Without a network device the port is not RoCE, and the core reads its GIDs
+ * through query_gid. They are the addresses of all network devices, IPv4 ones
+ * first, like the GID table the provider builds when it finds no GIDs here, so
+ * that libibverbs GID queries return them.
+ */
+
+static void urdma_add_gid(union ib_gid *gids, int *len,
+			  const union ib_gid *gid)
+{
+	int i;
+
+	for (i = 0; i < *len; i++) {
+		if (!memcmp(&gids[i], gid, sizeof(*gid)))
+			return;
+	}
+	if (*len < URDMA_GID_TBL_LEN)
+		gids[(*len)++] = *gid;
+}
+
+static void urdma_fill_gids(struct urdma_dev *urdma)
+{
+	union ib_gid gids[URDMA_GID_TBL_LEN] = {};
+	struct inet6_ifaddr *ifp;
+	struct in_ifaddr *ifa;
+	struct inet6_dev *in6_dev;
+	struct in_device *in_dev;
+	struct net_device *ndev;
+	union ib_gid gid;
+	int len = 0;
+
+	rtnl_lock();
+	for_each_netdev(&init_net, ndev) {
+		in_dev = __in_dev_get_rtnl(ndev);
+		if (!in_dev)
+			continue;
+		in_dev_for_each_ifa_rtnl(ifa, in_dev) {
+			ipv6_addr_set_v4mapped(ifa->ifa_address,
+					       (struct in6_addr *)&gid);
+			urdma_add_gid(gids, &len, &gid);
+		}
+	}
+	for_each_netdev(&init_net, ndev) {
+		in6_dev = __in6_dev_get(ndev);
+		if (!in6_dev)
+			continue;
+		read_lock_bh(&in6_dev->lock);
+		list_for_each_entry(ifp, &in6_dev->addr_list, if_list) {
+			memcpy(&gid, &ifp->addr, sizeof(gid));
+			urdma_add_gid(gids, &len, &gid);
+		}
+		read_unlock_bh(&in6_dev->lock);
+	}
+	rtnl_unlock();
+
+	spin_lock(&urdma->gid_lock);
+	memcpy(urdma->gids, gids, sizeof(gids));
+	spin_unlock(&urdma->gid_lock);
+}
+
+static void urdma_gid_work(struct work_struct *work)
+{
+	struct urdma_dev *urdma = container_of(work, struct urdma_dev, gid_work);
+	struct ib_event event = {
+		.device = &urdma->ibdev,
+		.event = IB_EVENT_GID_CHANGE,
+		.element.port_num = 1,
+	};
+
+	urdma_fill_gids(urdma);
+	/* the core rereads the GIDs into its cache */
+	ib_dispatch_event(&event);
+}
+
+static int urdma_addr_event(struct notifier_block *nb, unsigned long action,
+			    void *ptr)
+{
+	int i;
+
+	for (i = 0; i < NUM_DEV; i++) {
+		if (urdma_devs[i] && !urdma_devs[i]->roce)
+			schedule_work(&urdma_devs[i]->gid_work);
+	}
+	return NOTIFY_DONE;
+}
+
+static struct notifier_block urdma_inetaddr_nb = {
+	.notifier_call = urdma_addr_event,
+};
+
+static struct notifier_block urdma_inet6addr_nb = {
+	.notifier_call = urdma_addr_event,
+};
+
+#pragma endregion gids
+
 #pragma region operations
 
 static int urdma_query_port(struct ib_device *ibdev, u32 port_num,
 			    struct ib_port_attr *attr)
 {
 	memset(attr, 0, sizeof(*attr));
-	attr->gid_tbl_len = 1;
+	attr->gid_tbl_len = URDMA_GID_TBL_LEN;
+	attr->pkey_tbl_len = 1;
 	attr->state = IB_PORT_ACTIVE;
 	attr->phys_state = IB_PORT_PHYS_STATE_LINK_UP;
 	return 0;
 }
+
 static int urdma_get_port_immutable(struct ib_device *ibdev, u32 port_num,
 				    struct ib_port_immutable *immutable)
 {
+	struct urdma_dev *urdma = to_udev(ibdev);
+
 	memset(immutable, 0, sizeof(*immutable));
-	immutable->gid_tbl_len = 1;
+	immutable->gid_tbl_len = URDMA_GID_TBL_LEN;
+	if (urdma->roce) {
+		/* the core fills the GID table from the addresses of the netdev */
+		immutable->core_cap_flags = RDMA_CORE_PORT_IBA_ROCE_UDP_ENCAP;
+		immutable->pkey_tbl_len = 1;
+		immutable->max_mad_size = IB_MGMT_MAD_SIZE;
+	}
+	return 0;
+}
+
+static enum rdma_link_layer urdma_get_link_layer(struct ib_device *ibdev,
+						 u32 port_num)
+{
+	struct urdma_dev *urdma = to_udev(ibdev);
+
+	return urdma->roce ? IB_LINK_LAYER_ETHERNET : IB_LINK_LAYER_UNSPECIFIED;
+}
+
+static int urdma_query_pkey(struct ib_device *ibdev, u32 port_num, u16 index,
+			    u16 *pkey)
+{
+	if (index > 0)
+		return -EINVAL;
+	*pkey = IB_DEFAULT_PKEY_FULL;
 	return 0;
 }
 
@@ -46,7 +373,12 @@ static int urdma_query_gid(struct ib_device *ibdev, u32 port_num, int index,
 			   union ib_gid *gid)
 {
 	struct urdma_dev *urdma = to_udev(ibdev);
-	memcpy(gid, &urdma->gid, sizeof(*gid));
+
+	if (index < 0 || index >= URDMA_GID_TBL_LEN)
+		return -EINVAL;
+	spin_lock(&urdma->gid_lock);
+	*gid = urdma->gids[index];
+	spin_unlock(&urdma->gid_lock);
 	return 0;
 }
 
@@ -61,20 +393,46 @@ static int urdma_alloc_pd(struct ib_pd *pd, struct ib_udata *udata)
 {
 	return 0;
 }
+
 static int urdma_dealloc_pd(struct ib_pd *pd, struct ib_udata *udata)
 {
 	return 0;
 }
 
-static int urdma_create_qp(struct ib_qp *qp, struct ib_qp_init_attr *init_attr,
+static int urdma_create_qp(struct ib_qp *ibqp,
+			   struct ib_qp_init_attr *init_attr,
 			   struct ib_udata *udata)
 {
+	struct urdma_dev *urdma = to_udev(ibqp->device);
+	struct urdma_qp *qp = to_uqp(ibqp);
+
+	spin_lock_init(&qp->lock);
+	INIT_LIST_HEAD(&qp->recvs);
+	qp->sq_sig_all = init_attr->sq_sig_type == IB_SIGNAL_ALL_WR;
+
+	if (init_attr->qp_type == IB_QPT_GSI) {
+		ibqp->qp_num = 1;
+		rcu_assign_pointer(urdma->gsi, qp);
+	}
 	return 0;
 }
-static int urdma_destroy_qp(struct ib_qp *qp, struct ib_udata *udata)
+
+static int urdma_destroy_qp(struct ib_qp *ibqp, struct ib_udata *udata)
 {
+	struct urdma_dev *urdma = to_udev(ibqp->device);
+	struct urdma_qp *qp = to_uqp(ibqp);
+	struct urdma_recv *recv, *next;
+
+	if (rcu_access_pointer(urdma->gsi) == qp) {
+		RCU_INIT_POINTER(urdma->gsi, NULL);
+		/* senders may still be delivering to it */
+		synchronize_rcu();
+	}
+	list_for_each_entry_safe(recv, next, &qp->recvs, list)
+		kfree(recv);
 	return 0;
 }
+
 static int urdma_modify_qp(struct ib_qp *qp, struct ib_qp_attr *attr,
 			   int attr_mask, struct ib_udata *udata)
 {
@@ -84,11 +442,45 @@ static int urdma_modify_qp(struct ib_qp *qp, struct ib_qp_attr *attr,
 static int urdma_post_send(struct ib_qp *ibqp, const struct ib_send_wr *wr,
 			   const struct ib_send_wr **bad_wr)
 {
-	return 0;
+	int err = 0;
+
+	/* QP1 is the only kernel QP, the others live in user space */
+	if (ibqp->qp_type != IB_QPT_GSI) {
+		*bad_wr = wr;
+		return -EOPNOTSUPP;
+	}
+
+	for (; wr; wr = wr->next) {
+		err = urdma_gsi_send(to_uqp(ibqp), ud_wr(wr));
+		if (err) {
+			*bad_wr = wr;
+			break;
+		}
+	}
+	return err;
 }
+
 static int urdma_post_recv(struct ib_qp *ibqp, const struct ib_recv_wr *wr,
 			   const struct ib_recv_wr **bad_wr)
 {
+	struct urdma_qp *qp = to_uqp(ibqp);
+	struct urdma_recv *recv;
+	unsigned long flags;
+
+	for (; wr; wr = wr->next) {
+		recv = kmalloc(struct_size(recv, sge, wr->num_sge), GFP_ATOMIC);
+		if (!recv) {
+			*bad_wr = wr;
+			return -ENOMEM;
+		}
+		recv->wr_id = wr->wr_id;
+		recv->num_sge = wr->num_sge;
+		memcpy(recv->sge, wr->sg_list, wr->num_sge * sizeof(*wr->sg_list));
+
+		spin_lock_irqsave(&qp->lock, flags);
+		list_add_tail(&recv->list, &qp->recvs);
+		spin_unlock_irqrestore(&qp->lock, flags);
+	}
 	return 0;
 }
 
@@ -96,21 +488,57 @@ static int urdma_create_cq(struct ib_cq *ibcq,
 			   const struct ib_cq_init_attr *attr,
 			   struct ib_udata *udata)
 {
+	struct urdma_cq *cq = to_ucq(ibcq);
+
+	spin_lock_init(&cq->lock);
+	INIT_LIST_HEAD(&cq->wcs);
 	return 0;
 }
-static int urdma_destroy_cq(struct ib_cq *cq, struct ib_udata *udata)
+
+static int urdma_destroy_cq(struct ib_cq *ibcq, struct ib_udata *udata)
 {
+	struct urdma_cq *cq = to_ucq(ibcq);
+	struct urdma_wc *entry, *next;
+
+	list_for_each_entry_safe(entry, next, &cq->wcs, list)
+		kfree(entry);
 	return 0;
 }
+
 static int urdma_poll_cq(struct ib_cq *ibcq, int num_entries, struct ib_wc *wc)
 {
-	return 0;
+	struct urdma_cq *cq = to_ucq(ibcq);
+	struct urdma_wc *entry;
+	unsigned long flags;
+	int n = 0;
+
+	spin_lock_irqsave(&cq->lock, flags);
+	while (n < num_entries) {
+		entry = list_first_entry_or_null(&cq->wcs, struct urdma_wc,
+						 list);
+		if (!entry)
+			break;
+		list_del(&entry->list);
+		wc[n++] = entry->wc;
+		kfree(entry);
+	}
+	spin_unlock_irqrestore(&cq->lock, flags);
+	return n;
 }
 
 static int urdma_req_notify_cq(struct ib_cq *ibcq,
 			       enum ib_cq_notify_flags flags)
 {
-	return 0;
+	struct urdma_cq *cq = to_ucq(ibcq);
+	unsigned long irq_flags;
+	int missed = 0;
+
+	spin_lock_irqsave(&cq->lock, irq_flags);
+	cq->armed = true;
+	if ((flags & IB_CQ_REPORT_MISSED_EVENTS) && !list_empty(&cq->wcs))
+		missed = 1;
+	spin_unlock_irqrestore(&cq->lock, irq_flags);
+	return missed;
 }
 
 static struct ib_mr *urdma_get_dma_mr(struct ib_pd *ibpd, int access)
@@ -121,6 +549,7 @@ static struct ib_mr *urdma_get_dma_mr(struct ib_pd *ibpd, int access)
 		return ERR_PTR(-ENOMEM);
 	return mr;
 }
+
 static struct ib_mr *urdma_reg_user_mr(struct ib_pd *pd, u64 start, u64 length,
 				       u64 virt_addr, int access_flags,
 				       struct ib_udata *udata)
@@ -131,12 +560,36 @@ static struct ib_mr *urdma_reg_user_mr(struct ib_pd *pd, u64 start, u64 length,
 		return ERR_PTR(-ENOMEM);
 	return mr;
 }
+
 static int urdma_dereg_mr(struct ib_mr *mr, struct ib_udata *udata)
 {
 	kfree(mr);
 	return 0;
 }
 
+static int urdma_create_ah(struct ib_ah *ibah,
+			   struct rdma_ah_init_attr *init_attr,
+			   struct ib_udata *udata)
+{
+	struct rdma_ah_attr *attr = init_attr->ah_attr;
+	const struct ib_global_route *grh = rdma_ah_read_grh(attr);
+	struct urdma_ah *ah = to_uah(ibah);
+
+	/* RoCE addresses are GIDs */
+	if (!(rdma_ah_get_ah_flags(attr) & IB_AH_GRH) || !grh->sgid_attr)
+		return -EINVAL;
+
+	ah->sgid = grh->sgid_attr->gid;
+	ah->dgid = grh->dgid;
+	ah->hop_limit = grh->hop_limit;
+	return 0;
+}
+
+static int urdma_destroy_ah(struct ib_ah *ibah, u32 flags)
+{
+	return 0;
+}
+
 #pragma endregion operations
 
 static const struct ib_device_ops urdma_device_ops = {
@@ -171,7 +624,14 @@ static const struct ib_device_ops urdma_device_ops = {
 	.reg_user_mr = urdma_reg_user_mr,
 	.dereg_mr = urdma_dereg_mr,
 
+	// the MAD layer of RoCE ports, for rdma_cm
+	.create_ah = urdma_create_ah,
+	.destroy_ah = urdma_destroy_ah,
+	INIT_RDMA_OBJ_SIZE(ib_ah, urdma_ah, ibah),
+
 	.get_port_immutable = urdma_get_port_immutable,
+	.get_link_layer = urdma_get_link_layer,
+	.query_pkey = urdma_query_pkey,
 
 	// uverbs required methods
 	.alloc_ucontext = urdma_alloc_ucontext,
@@ -196,6 +656,27 @@ static int urdma_register_device(struct urdma_dev *urdma)
 
 	ib_set_device_ops(dev, &urdma_device_ops);
 
+	if (netdev) {
+		struct net_device *ndev = dev_get_by_name(&init_net, netdev);
+
+		if (!ndev) {
+			pr_err("no network device %s\n", netdev);
+			return -ENODEV;
+		}
+		dev->node_type = RDMA_NODE_IB_CA;
+		/* librdmacm tells devices apart by their node GUID */
+		addrconf_addr_eui48((u8 *)&dev->node_guid, ndev->dev_addr);
+		((u8 *)&dev->node_guid)[4] ^= urdma->id;
+		/* the core holds its own reference until unregistration */
+		err = ib_device_set_netdev(dev, ndev, 1);
+		dev_put(ndev);
+		if (err)
+			return err;
+		urdma->roce = true;
+	} else {
+		urdma_fill_gids(urdma);
+	}
+
 	err = ib_register_device(dev, "urdma%d", NULL);
 	if (err) {
 		pr_err("register device failed");
@@ -217,6 +698,8 @@ static struct urdma_dev *urdma_alloc_device(const int id)
 	}
 
 	urdma->id = id;
+	spin_lock_init(&urdma->gid_lock);
+	INIT_WORK(&urdma->gid_work, urdma_gid_work);
 	pr_info("alloc for id: %d\n", id);
 	return urdma;
 }
@@ -258,6 +741,9 @@ static int __init urdma_init_module(void)
 		}
 	}
 
+	register_inetaddr_notifier(&urdma_inetaddr_nb);
+	register_inet6addr_notifier(&urdma_inet6addr_nb);
+
 	pr_info("urdma module load success\n");
 	return 0;
 
@@ -275,8 +761,12 @@ static void __exit urdma_exit_module(void)
 	int i;
 	pr_info("urdma module unloaded\n");
 
+	unregister_inet6addr_notifier(&urdma_inet6addr_nb);
+	unregister_inetaddr_notifier(&urdma_inetaddr_nb);
+
 	for (i = 0; i < NUM_DEV; i++) {
 		if (urdma_devs[i]) {
+			cancel_work_sync(&urdma_devs[i]->gid_work);
 			ib_unregister_device(&urdma_devs[i]->ibdev);
 			urdma_dealloc_device(urdma_devs[i]);
 			urdma_devs[i] = NULL;
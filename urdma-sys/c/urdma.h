@@ -3,13 +3,27 @@
 #ifndef __URDMA_H__
 #define __URDMA_H__
 
+#include <linux/list.h>
+#include <linux/spinlock.h>
+#include <linux/workqueue.h>
 #include <rdma/ib_verbs.h>
 
+/* GIDs of a port, one per address of the network device, or of all of them */
+#define URDMA_GID_TBL_LEN 32
+
 struct urdma_dev {
 	struct ib_device ibdev;
 	int id;
+	/* bound to a network device, the port is RoCEv2 */
+	bool roce;
+	/* QP1 of the kernel MAD layer, which carries the CM of a RoCE port */
+	struct urdma_qp __rcu *gsi;
 
-	union ib_gid gid;
+	/* GIDs of a port without network device, zero GIDs are unused */
+	spinlock_t gid_lock;
+	union ib_gid gids[URDMA_GID_TBL_LEN];
+	/* refills gids when an address changes */
+	struct work_struct gid_work;
 };
 
 static inline struct urdma_dev *to_udev(struct ib_device *ibdev)
@@ -23,12 +37,57 @@ struct urdma_pd {
 
 struct urdma_cq {
 	struct ib_cq ibcq;
+
+	spinlock_t lock;
+	/* completions not polled yet, of urdma_wc */
+	struct list_head wcs;
+	/* a completion calls the completion handler */
+	bool armed;
+};
+
+static inline struct urdma_cq *to_ucq(struct ib_cq *ibcq)
+{
+	return container_of(ibcq, struct urdma_cq, ibcq);
+}
+
+struct urdma_wc {
+	struct list_head list;
+	struct ib_wc wc;
 };
 
 struct urdma_qp {
 	struct ib_qp ibqp;
+	bool sq_sig_all;
+
+	spinlock_t lock;
+	/* posted receive requests, of urdma_recv */
+	struct list_head recvs;
+};
+
+static inline struct urdma_qp *to_uqp(struct ib_qp *ibqp)
+{
+	return container_of(ibqp, struct urdma_qp, ibqp);
+}
+
+struct urdma_recv {
+	struct list_head list;
+	u64 wr_id;
+	int num_sge;
+	struct ib_sge sge[];
 };
 
+struct urdma_ah {
+	struct ib_ah ibah;
+	union ib_gid sgid;
+	union ib_gid dgid;
+	u8 hop_limit;
+};
+
+static inline struct urdma_ah *to_uah(struct ib_ah *ibah)
+{
+	return container_of(ibah, struct urdma_ah, ibah);
+}
+
 struct urdma_ucontext {
 	struct ib_ucontext ibuc;
 };